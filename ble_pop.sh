@@ -0,0 +1,9 @@
+#!/usr/bin/bash
+# Print the BLE provisioning proof of possession of the device with the given station MAC, for
+# its label. The firmware derives the same from the BLE_POP_SECRET it was built with.
+if [ -z "$BLE_POP_SECRET" ] || [ -z "$1" ]; then
+    echo "Usage: BLE_POP_SECRET=<secret> $0 <MAC, e.g. 7C:DF:A1:01:02:03>" >&2
+    exit 1
+fi
+MAC=$(echo "$1" | tr -d ':-' | tr 'a-f' 'A-F')
+printf '%s%s' "$BLE_POP_SECRET" "$MAC" | sha256sum | cut -c1-8
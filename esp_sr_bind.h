@@ -8,4 +8,5 @@
 #include "esp_mn_models.h"
 #include "esp_mn_speech_commands.h"
 #include "esp_tts.h"
-#include "esp_tts_voice_template.h"
\ No newline at end of file
+#include "esp_tts_voice_template.h"
+#include "model_path.h"
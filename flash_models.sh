@@ -1,2 +1,10 @@
 #!/usr/bin/bash
-esptool.py --chip esp32s3 -p /dev/ttyACM0 -b 460800 --before=default_reset --after=hard_reset write_flash --flash_mode dio --flash_freq 80m --flash_size 8MB 0x290000 /home/user1/code/ai-chatbox/target/xtensa-esp32s3-espidf/debug/build/esp-idf-sys-ac*/out/build/srmodels/srmodels.bin
+# Write the ESP-SR models to the "model" partition of partitions.csv, the firmware has to be
+# built with CONFIG_MODEL_IN_FLASH=y for the models to be loaded from there
+DIR=$(dirname "$0")
+OFFSET=$(awk -F, '$1 ~ /^model *$/ { gsub(/ /, "", $4); print $4 }' "$DIR/partitions.csv")
+if [ -z "$OFFSET" ]; then
+    echo "partitions.csv has no model partition, see the comments at its end" >&2
+    exit 1
+fi
+esptool.py --chip esp32s3 -p /dev/ttyACM0 -b 460800 --before=default_reset --after=hard_reset write_flash --flash_mode dio --flash_freq 80m --flash_size keep $OFFSET /home/user1/code/ai-chatbox/target/xtensa-esp32s3-espidf/debug/build/esp-idf-sys-ac*/out/build/srmodels/srmodels.bin
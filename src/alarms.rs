@@ -0,0 +1,535 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::AlarmsConfig;
+use crate::earcon::Earcon;
+use crate::playback;
+use crate::transcript_log::wall_clock_secs;
+use crate::transcription::TranscriptionMessage;
+
+/// NVS namespace with the pending timers and alarms
+const ALARMS_NVS_NAMESPACE: &str = "alarms";
+const KEY_PENDING: &str = "pending";
+const MAX_PENDING: usize = 20;
+/// The longest string NVS stores, 4000 bytes including the NUL; reminder texts have to fit in
+/// as well
+const MAX_JSON_LEN: usize = 3999;
+/// How often the clock is compared with the pending alarms
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Alarms due longer ago are dropped instead of ringing, e.g. while the device was off
+const MAX_LATENESS_SECS: u64 = 10 * 60;
+/// Times the sound and the announcement are played for each alarm
+const RING_REPEATS: usize = 2;
+/// Longest timer, a day
+const MAX_TIMER_SECS: u64 = 24 * 60 * 60;
+/// Reminders are set at most this many days ahead
+const MAX_REMINDER_DAYS: u64 = 365;
+/// Longest label of a timer, alarm or reminder, in characters
+const MAX_LABEL_CHARS: usize = 50;
+
+/// Set by `start`, None while alarms are turned off
+static ALARMS: Mutex<Option<Alarms>> = Mutex::new(None);
+
+/// A timer or an alarm waiting to ring
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    /// Unix seconds it rings at
+    pub due: u64,
+    /// Length of a timer, None for an alarm at a time of day
+    pub timer_secs: Option<u64>,
+    /// What to remind of, may be empty
+    #[serde(default)]
+    pub label: String,
+    /// Said without the alarm's words, "现在是8点整，提醒你吃药"
+    #[serde(default)]
+    pub reminder: bool,
+}
+
+impl Alarm {
+    /// Spoken when it rings, e.g. "3分钟计时结束"
+    fn announcement(&self, utc_offset_mins: i32) -> String {
+        let reminder = if self.label.is_empty() {
+            String::new()
+        } else {
+            format!("，提醒你{}", self.label)
+        };
+        match self.timer_secs {
+            Some(secs) => format!("{}计时结束{}", format_duration(secs), reminder),
+            None if self.reminder => {
+                let (hour, minute) = local_time_of_day(self.due, utc_offset_mins);
+                format!("现在是{}{}", format_time_of_day(hour, minute), reminder)
+            }
+            None => {
+                let (hour, minute) = local_time_of_day(self.due, utc_offset_mins);
+                format!(
+                    "现在是{}，闹钟响了{}",
+                    format_time_of_day(hour, minute),
+                    reminder
+                )
+            }
+        }
+    }
+}
+
+struct Alarms {
+    /// None when the namespace couldn't be opened, the alarms are lost on restart then
+    nvs: Option<EspNvs<NvsDefault>>,
+    pending: Vec<Alarm>,
+    utc_offset_mins: i32,
+}
+
+impl Alarms {
+    fn add(&mut self, alarm: Alarm) -> Result<(), String> {
+        if alarm.label.chars().count() > MAX_LABEL_CHARS {
+            return Err("要提醒的内容太长了，请说得简短一些".to_string());
+        }
+        self.pending.push(alarm);
+        let too_long =
+            serde_json::to_string(&self.pending).map_or(true, |json| json.len() > MAX_JSON_LEN);
+        if self.pending.len() > MAX_PENDING || too_long {
+            self.pending.pop();
+            return Err("计时、闹钟和提醒太多了，请先取消一些".to_string());
+        }
+        log::info!("Setting {:?}", self.pending.last());
+        self.save();
+        Ok(())
+    }
+
+    fn save(&self) {
+        if let Err(e) = self.try_save() {
+            log::warn!("Failed to save the alarms: {}", e);
+        }
+    }
+
+    fn try_save(&self) -> anyhow::Result<()> {
+        let Some(nvs) = self.nvs.as_ref() else {
+            return Ok(());
+        };
+        let json = serde_json::to_string(&self.pending)?;
+        nvs.set_str(KEY_PENDING, &json)?;
+        Ok(())
+    }
+}
+
+/// Ring the timers and alarms through `transcription_tx`, does nothing when they are turned off
+pub fn start(
+    config: &AlarmsConfig,
+    nvs_partition: EspDefaultNvsPartition,
+    transcription_tx: Sender<TranscriptionMessage>,
+) -> anyhow::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let nvs = match EspNvs::new(nvs_partition, ALARMS_NVS_NAMESPACE, true) {
+        Ok(nvs) => Some(nvs),
+        Err(e) => {
+            log::warn!("Failed to open NVS namespace for alarms: {}", e);
+            None
+        }
+    };
+    let pending = nvs.as_ref().map(load).unwrap_or_default();
+    if !pending.is_empty() {
+        log::info!("{} timers and alarms pending", pending.len());
+    }
+    *ALARMS.lock().unwrap() = Some(Alarms {
+        nvs,
+        pending,
+        utc_offset_mins: config.utc_offset_mins,
+    });
+
+    thread::Builder::new()
+        .name("alarms".to_string())
+        .stack_size(4 * 1024)
+        .spawn(move || ring_loop(transcription_tx))?;
+    Ok(())
+}
+
+/// Carry out a set_timer, set_alarm, set_reminder or cancel_alarms intent, None for any other
+/// intent
+///
+/// Returns what to say about it, the error is said instead of the LLM's reply.
+pub fn handle_intent(intent: &str, slots: &Map<String, Value>) -> Option<Result<String, String>> {
+    let label = match slots.get("label") {
+        Some(Value::String(label)) => label.trim(),
+        _ => "",
+    };
+    let result = match intent {
+        "set_timer" => match timer_secs(slots) {
+            Some(0) => Err("没听清要计时多久".to_string()),
+            // Too long to add up is too long for a timer
+            secs => set_timer(secs.unwrap_or(u64::MAX), label),
+        },
+        "set_alarm" => match (slot_u64(slots, "hour"), slot_u64(slots, "minute")) {
+            (Some(hour), minute) if hour < 24 && minute.unwrap_or(0) < 60 => {
+                set_alarm(hour as u32, minute.unwrap_or(0) as u32, label)
+            }
+            _ => Err("没听清闹钟定在几点".to_string()),
+        },
+        "set_reminder" => match (slot_u64(slots, "hour"), slot_u64(slots, "minute")) {
+            _ if label.is_empty() => Err("没听清要提醒你什么".to_string()),
+            (Some(hour), minute) if hour < 24 && minute.unwrap_or(0) < 60 => set_reminder(
+                slot_u64(slots, "days"),
+                hour as u32,
+                minute.unwrap_or(0) as u32,
+                label,
+            ),
+            _ => Err("没听清要在几点提醒你".to_string()),
+        },
+        "cancel_alarms" => Ok(cancel_all()),
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Ring in `secs` seconds, returns the confirmation or why it can't be set
+pub fn set_timer(secs: u64, label: &str) -> Result<String, String> {
+    if secs > MAX_TIMER_SECS {
+        return Err(format!("计时最长{}", format_duration(MAX_TIMER_SECS)));
+    }
+    with_alarms(|alarms, now| {
+        alarms.add(Alarm {
+            due: now.saturating_add(secs),
+            timer_secs: Some(secs),
+            label: label.to_string(),
+            reminder: false,
+        })?;
+        Ok(format!("好的，{}后提醒你", format_duration(secs)))
+    })
+}
+
+/// Ring at the next `hour`:`minute` local time, returns the confirmation or why it can't be set
+pub fn set_alarm(hour: u32, minute: u32, label: &str) -> Result<String, String> {
+    with_alarms(|alarms, now| {
+        alarms.add(Alarm {
+            due: next_occurrence(now, alarms.utc_offset_mins, hour, minute),
+            timer_secs: None,
+            label: label.to_string(),
+            reminder: false,
+        })?;
+        Ok(format!(
+            "好的，闹钟定在{}",
+            format_time_of_day(hour, minute)
+        ))
+    })
+}
+
+/// Say `text` at `hour`:`minute` local time, `days` days from today or, without `days`, at the
+/// next such time
+///
+/// Returns the confirmation or why it can't be set.
+pub fn set_reminder(
+    days: Option<u64>,
+    hour: u32,
+    minute: u32,
+    text: &str,
+) -> Result<String, String> {
+    if days.is_some_and(|days| days > MAX_REMINDER_DAYS) {
+        return Err(format!("最多只能提前{}天设置提醒", MAX_REMINDER_DAYS));
+    }
+    with_alarms(|alarms, now| {
+        let offset = alarms.utc_offset_mins;
+        let due = match days {
+            Some(days) => day_time(now, offset, days, hour, minute),
+            None => next_occurrence(now, offset, hour, minute),
+        };
+        if due <= now {
+            return Err("这个时间已经过了".to_string());
+        }
+        alarms.add(Alarm {
+            due,
+            timer_secs: None,
+            label: text.to_string(),
+            reminder: true,
+        })?;
+        let day = match (due - now + local_secs_of_day(now, offset)) / 86_400 {
+            0 => "今天",
+            1 => "明天",
+            2 => "后天",
+            _ => "",
+        };
+        Ok(format!(
+            "好的，{}{}提醒你{}",
+            day,
+            format_time_of_day(hour, minute),
+            text
+        ))
+    })
+}
+
+/// Cancel every pending timer and alarm, returns what to say about it
+pub fn cancel_all() -> String {
+    let mut alarms = ALARMS.lock().unwrap();
+    let Some(alarms) = alarms.as_mut() else {
+        return "现在没有计时、闹钟或提醒".to_string();
+    };
+    let count = alarms.pending.len();
+    if count == 0 {
+        return "现在没有计时、闹钟或提醒".to_string();
+    }
+    alarms.pending.clear();
+    alarms.save();
+    log::info!("Cancelled {} timers and alarms", count);
+    format!("已取消{}个计时、闹钟和提醒", count)
+}
+
+fn with_alarms(
+    f: impl FnOnce(&mut Alarms, u64) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut alarms = ALARMS.lock().unwrap();
+    let Some(alarms) = alarms.as_mut() else {
+        return Err("闹钟功能没有开启".to_string());
+    };
+    // Alarms are kept as wall clock time so they survive a restart
+    let Some(now) = wall_clock_secs() else {
+        return Err("时间还没有同步，暂时不能定时".to_string());
+    };
+    f(alarms, now)
+}
+
+fn ring_loop(transcription_tx: Sender<TranscriptionMessage>) {
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+        let Some(now) = wall_clock_secs() else {
+            continue;
+        };
+
+        let (due, utc_offset_mins) = {
+            let mut alarms = ALARMS.lock().unwrap();
+            let Some(alarms) = alarms.as_mut() else {
+                continue;
+            };
+            let due = take_due(&mut alarms.pending, now);
+            if !due.is_empty() {
+                alarms.save();
+            }
+            (due, alarms.utc_offset_mins)
+        };
+
+        for alarm in due {
+            if now - alarm.due > MAX_LATENESS_SECS {
+                log::warn!("Dropping {:?}, it was due {} s ago", alarm, now - alarm.due);
+                continue;
+            }
+            log::info!("Ringing {:?}", alarm);
+            let text = alarm.announcement(utc_offset_mins);
+            for _ in 0..RING_REPEATS {
+                if playback::announce(Earcon::Alarm, &text) {
+                    continue;
+                }
+                // The playback thread isn't up yet right after boot, the worker queues it
+                let _ = transcription_tx.send(TranscriptionMessage::Earcon(Earcon::Alarm));
+                let _ = transcription_tx.send(TranscriptionMessage::Speak { text: text.clone() });
+            }
+        }
+    }
+}
+
+fn load(nvs: &EspNvs<NvsDefault>) -> Vec<Alarm> {
+    let mut buffer = vec![0u8; MAX_JSON_LEN + 1];
+    match nvs.get_str(KEY_PENDING, &mut buffer) {
+        Ok(Some(json)) => serde_json::from_str(json).unwrap_or_else(|e| {
+            log::warn!("Ignoring unreadable alarms: {}", e);
+            Vec::new()
+        }),
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            log::warn!("Failed to read the alarms: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Remove and return the alarms due at `now`, earliest first
+fn take_due(pending: &mut Vec<Alarm>, now: u64) -> Vec<Alarm> {
+    let (mut due, waiting): (Vec<Alarm>, Vec<Alarm>) =
+        pending.drain(..).partition(|alarm| alarm.due <= now);
+    *pending = waiting;
+    due.sort_by_key(|alarm| alarm.due);
+    due
+}
+
+/// Unix seconds of the next `hour`:`minute` local time after `now`
+fn next_occurrence(now: u64, utc_offset_mins: i32, hour: u32, minute: u32) -> u64 {
+    let offset_secs = utc_offset_mins as i64 * 60;
+    let local_now = now as i64 + offset_secs;
+    let local_midnight = local_now - local_now.rem_euclid(86_400);
+    let mut local_due = local_midnight + (hour * 3600 + minute * 60) as i64;
+    if local_due <= local_now {
+        local_due += 86_400;
+    }
+    (local_due - offset_secs) as u64
+}
+
+/// Unix seconds of `hour`:`minute` local time, `days` after the day of `now`
+fn day_time(now: u64, utc_offset_mins: i32, days: u64, hour: u32, minute: u32) -> u64 {
+    let local_midnight = now - local_secs_of_day(now, utc_offset_mins);
+    local_midnight + days * 86_400 + (hour * 3600 + minute * 60) as u64
+}
+
+/// Seconds since local midnight
+fn local_secs_of_day(secs: u64, utc_offset_mins: i32) -> u64 {
+    (secs as i64 + utc_offset_mins as i64 * 60).rem_euclid(86_400) as u64
+}
+
+/// Hour and minute of `secs` in local time
+fn local_time_of_day(secs: u64, utc_offset_mins: i32) -> (u32, u32) {
+    let local_secs = (secs as i64 + utc_offset_mins as i64 * 60).rem_euclid(86_400) as u32;
+    (local_secs / 3600, local_secs % 3600 / 60)
+}
+
+/// Spoken time of day, e.g. "7点30分" or "7点整"
+fn format_time_of_day(hour: u32, minute: u32) -> String {
+    if minute == 0 {
+        format!("{}点整", hour)
+    } else {
+        format!("{}点{}分", hour, minute)
+    }
+}
+
+/// Spoken length of a timer, e.g. "1小时30分钟" or "45秒"
+fn format_duration(secs: u64) -> String {
+    let hours = secs / 3600;
+    let minutes = secs % 3600 / 60;
+    let seconds = secs % 60;
+
+    let mut text = String::new();
+    if hours > 0 {
+        text.push_str(&format!("{}小时", hours));
+    }
+    if minutes > 0 {
+        text.push_str(&format!("{}分钟", minutes));
+    }
+    if seconds > 0 || text.is_empty() {
+        text.push_str(&format!("{}秒", seconds));
+    }
+    text
+}
+
+/// Length of a set_timer intent in seconds, None when it doesn't even fit a u64
+fn timer_secs(slots: &Map<String, Value>) -> Option<u64> {
+    let part = |name, unit: u64| slot_u64(slots, name).unwrap_or(0).checked_mul(unit);
+    part("hours", 3600)?
+        .checked_add(part("minutes", 60)?)?
+        .checked_add(part("seconds", 1)?)
+}
+
+/// Slot as a whole number, LLMs send numbers as strings as often as not
+fn slot_u64(slots: &Map<String, Value>, name: &str) -> Option<u64> {
+    match slots.get(name)? {
+        Value::Number(number) => number.as_u64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_occurrence() {
+        // 2024-02-29 12:34:56 UTC, 20:34:56 in China
+        let now = 1_709_210_096;
+        let due = next_occurrence(now, 480, 21, 0);
+        assert_eq!(due - now, 25 * 60 + 4);
+        assert_eq!(local_time_of_day(due, 480), (21, 0));
+        // Earlier in the day rings tomorrow
+        let due = next_occurrence(now, 480, 7, 30);
+        assert_eq!(local_time_of_day(due, 480), (7, 30));
+        assert!(due > now && due - now < 86_400);
+    }
+
+    #[test]
+    fn test_day_time() {
+        // 2024-02-29 20:34:56 in China
+        let now = 1_709_210_096;
+        let tomorrow = day_time(now, 480, 1, 8, 0);
+        assert_eq!(local_time_of_day(tomorrow, 480), (8, 0));
+        assert_eq!(tomorrow - now, 11 * 3600 + 25 * 60 + 4);
+        // Earlier today is in the past
+        assert!(day_time(now, 480, 0, 8, 0) < now);
+    }
+
+    #[test]
+    fn test_take_due() {
+        let alarm = |due| Alarm {
+            due,
+            timer_secs: Some(60),
+            label: String::new(),
+            reminder: false,
+        };
+        let mut pending = vec![alarm(300), alarm(100), alarm(200)];
+        let due = take_due(&mut pending, 200);
+        assert_eq!(due, vec![alarm(100), alarm(200)]);
+        assert_eq!(pending, vec![alarm(300)]);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut alarms = Alarms {
+            nvs: None,
+            pending: Vec::new(),
+            utc_offset_mins: 480,
+        };
+        let alarm = |label: String| Alarm {
+            due: 100,
+            timer_secs: Some(60),
+            label,
+            reminder: false,
+        };
+        assert!(alarms.add(alarm("药".repeat(MAX_LABEL_CHARS))).is_ok());
+        assert!(alarms.add(alarm("药".repeat(MAX_LABEL_CHARS + 1))).is_err());
+        assert_eq!(alarms.pending.len(), 1);
+    }
+
+    #[test]
+    fn test_announcement() {
+        let timer = Alarm {
+            due: 0,
+            timer_secs: Some(180),
+            label: String::new(),
+            reminder: false,
+        };
+        assert_eq!(timer.announcement(480), "3分钟计时结束");
+        assert_eq!(format_duration(5400), "1小时30分钟");
+        assert_eq!(format_duration(45), "45秒");
+
+        let alarm = Alarm {
+            due: next_occurrence(1_709_210_096, 480, 7, 0),
+            timer_secs: None,
+            label: "开会".to_string(),
+            reminder: false,
+        };
+        assert_eq!(alarm.announcement(480), "现在是7点整，闹钟响了，提醒你开会");
+
+        let reminder = Alarm {
+            label: "吃药".to_string(),
+            reminder: true,
+            ..alarm
+        };
+        assert_eq!(reminder.announcement(480), "现在是7点整，提醒你吃药");
+    }
+
+    #[test]
+    fn test_slots() {
+        let slots: Map<String, Value> =
+            serde_json::from_str(r#"{"minutes": "5", "seconds": 30, "hour": "x"}"#).unwrap();
+        assert_eq!(slot_u64(&slots, "minutes"), Some(5));
+        assert_eq!(slot_u64(&slots, "seconds"), Some(30));
+        assert_eq!(slot_u64(&slots, "hour"), None);
+        assert!(handle_intent("chat", &slots).is_none());
+        assert_eq!(timer_secs(&slots), Some(330));
+
+        let huge: Map<String, Value> =
+            serde_json::from_str(r#"{"hours": 18446744073709551615}"#).unwrap();
+        assert_eq!(timer_secs(&huge), None);
+        assert!(set_timer(u64::MAX, "").unwrap_err().starts_with("计时最长"));
+        assert!(set_reminder(Some(u64::MAX), 8, 0, "吃药").is_err());
+    }
+}
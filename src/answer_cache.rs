@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::CacheConfig;
+use crate::voice_commands::normalize_transcript;
+
+/// File keeping cached answers across reboots
+pub const CACHE_FILE_PATH: &str = "/vfat/answer_cache.json";
+
+/// Questions whose answer changes over time are never cached
+const VOLATILE_WORDS: [&str; 12] = [
+    "几点", "时间", "现在", "今天", "明天", "昨天", "日期", "星期", "天气", "最新", "新闻", "价格",
+];
+/// Questions referring to earlier turns depend on the conversation and are never cached
+const CONTEXT_WORDS: [&str; 8] = ["它", "他", "她", "这个", "那个", "刚才", "上面", "继续"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    key: String,
+    answer: String,
+    /// Value of the use counter when the entry was last read or written
+    last_used: u64,
+}
+
+/// Bounded map from normalized question to answer, evicting the least recently used entry
+pub struct AnswerCache {
+    enabled: bool,
+    max_entries: usize,
+    entries: Vec<CacheEntry>,
+    use_counter: u64,
+    /// File the cache is persisted to
+    path: String,
+}
+
+impl AnswerCache {
+    /// Restore cached answers from the SD card when caching is enabled
+    pub fn load(config: &CacheConfig) -> Self {
+        Self::load_from(config, CACHE_FILE_PATH)
+    }
+
+    fn load_from(config: &CacheConfig, path: &str) -> Self {
+        let entries: Vec<CacheEntry> = if config.enabled {
+            match std::fs::read_to_string(path) {
+                Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                    log::warn!("Failed to parse {}: {}", path, e);
+                    Vec::new()
+                }),
+                Err(_) => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+
+        let use_counter = entries.iter().map(|e| e.last_used).max().unwrap_or(0);
+
+        Self {
+            enabled: config.enabled,
+            max_entries: config.max_entries,
+            entries,
+            use_counter,
+            path: path.to_string(),
+        }
+    }
+
+    /// Cache key for a question, None when caching is off or the answer may change
+    pub fn key(&self, persona: Option<&str>, question: &str) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+
+        let question = normalize_transcript(question);
+        if question.is_empty()
+            || VOLATILE_WORDS.iter().any(|w| question.contains(w))
+            || CONTEXT_WORDS.iter().any(|w| question.contains(w))
+        {
+            return None;
+        }
+
+        // Personas answer the same question differently
+        Some(format!("{}|{}", persona.unwrap_or(""), question.to_lowercase()))
+    }
+
+    /// Look up a cached answer
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        self.use_counter += 1;
+        let counter = self.use_counter;
+
+        self.entries.iter_mut().find(|e| e.key == key).map(|entry| {
+            entry.last_used = counter;
+            entry.answer.clone()
+        })
+    }
+
+    /// Remember an answer and persist the cache
+    pub fn insert(&mut self, key: String, answer: String) {
+        if self.max_entries == 0 {
+            return;
+        }
+
+        self.use_counter += 1;
+        self.entries.retain(|e| e.key != key);
+
+        while self.entries.len() >= self.max_entries {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(index, _)| index)
+            {
+                self.entries.swap_remove(oldest);
+            }
+        }
+
+        self.entries.push(CacheEntry {
+            key,
+            answer,
+            last_used: self.use_counter,
+        });
+
+        self.persist();
+    }
+
+    fn persist(&self) {
+        match serde_json::to_string(&self.entries) {
+            Ok(text) => {
+                if let Err(e) = std::fs::write(&self.path, text) {
+                    log::warn!("Failed to write {}: {}", self.path, e);
+                }
+            }
+            Err(e) => log::warn!("Failed to serialize answer cache: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("answer-cache-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn cache(max_entries: usize, path: &str) -> AnswerCache {
+        let config = CacheConfig {
+            enabled: true,
+            max_entries,
+        };
+        AnswerCache::load_from(&config, path)
+    }
+
+    #[test]
+    fn test_key() {
+        let cache = cache(4, &temp_path("key"));
+        assert_eq!(
+            cache.key(None, "What is Rust?"),
+            Some("|whatisrust".to_string())
+        );
+        assert_eq!(
+            cache.key(Some("老师"), "长城有多长？"),
+            Some("老师|长城有多长".to_string())
+        );
+        assert_eq!(cache.key(None, "今天天气怎么样"), None);
+        assert_eq!(cache.key(None, "它有多高"), None);
+        assert_eq!(cache.key(None, "。"), None);
+
+        let disabled = AnswerCache {
+            enabled: false,
+            ..cache
+        };
+        assert_eq!(disabled.key(None, "长城有多长"), None);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let path = temp_path("evict");
+        let mut cache = cache(2, &path);
+        cache.insert("a".to_string(), "1".to_string());
+        cache.insert("b".to_string(), "2".to_string());
+        assert_eq!(cache.get("a"), Some("1".to_string()));
+
+        cache.insert("c".to_string(), "3".to_string());
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some("1".to_string()));
+        assert_eq!(cache.get("c"), Some("3".to_string()));
+
+        // Replacing an answer doesn't take a second slot
+        cache.insert("c".to_string(), "4".to_string());
+        assert_eq!(cache.get("c"), Some("4".to_string()));
+        assert_eq!(cache.get("a"), Some("1".to_string()));
+
+        // Answers survive a reboot
+        let config = CacheConfig {
+            enabled: true,
+            max_entries: 2,
+        };
+        let mut restored = AnswerCache::load_from(&config, &path);
+        assert_eq!(restored.get("b"), None);
+        assert_eq!(restored.get("c"), Some("4".to_string()));
+        let _ = std::fs::remove_file(&path);
+    }
+}
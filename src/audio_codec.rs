@@ -0,0 +1,197 @@
+use anyhow;
+use flacenc::component::BitRepr;
+use flacenc::error::Verify;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+use crate::http_client::MultipartData;
+
+/// Format recordings are uploaded in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadFormat {
+    /// Uncompressed 16 bit PCM, about 32 KB per second of speech
+    #[default]
+    Wav,
+    /// Lossless, roughly half the size of WAV for speech
+    Flac,
+}
+
+impl UploadFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            UploadFormat::Wav => "audio/wav",
+            UploadFormat::Flac => "audio/flac",
+        }
+    }
+
+    /// Some services, Whisper among them, detect the format from the file name
+    pub fn extension(&self) -> &'static str {
+        match self {
+            UploadFormat::Wav => "wav",
+            UploadFormat::Flac => "flac",
+        }
+    }
+}
+
+/// Format of the utterances recorded by the fetch task
+pub fn recording_spec() -> hound::WavSpec {
+    hound::WavSpec {
+        channels: 1,
+        sample_rate: 16000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    }
+}
+
+/// Where the bytes of an upload come from
+pub enum UploadData {
+    Memory(Vec<u8>),
+    /// WAV recording sent as is, streamed from the SD card instead of read into RAM
+    File(String),
+}
+
+/// A recording ready to be sent to a speech to text service
+pub struct AudioUpload {
+    pub file_name: String,
+    pub content_type: &'static str,
+    pub data: UploadData,
+}
+
+impl AudioUpload {
+    /// Prepare a WAV recording on the SD card for upload, only reading it when it has to be encoded
+    pub fn from_wav_file(path: &str, format: UploadFormat) -> anyhow::Result<Self> {
+        if format == UploadFormat::Wav {
+            return Ok(Self {
+                file_name: path.rsplit('/').next().unwrap_or(path).to_string(),
+                content_type: UploadFormat::Wav.content_type(),
+                data: UploadData::File(path.to_string()),
+            });
+        }
+
+        let wav = std::fs::read(path)?;
+        log::info!("Read {} bytes from WAV file", wav.len());
+        Ok(Self::from_wav(path, wav, format))
+    }
+
+    /// Body of the file part of the upload request
+    pub fn multipart_data(&self) -> MultipartData<'_> {
+        match &self.data {
+            UploadData::Memory(data) => MultipartData::Bytes(data),
+            UploadData::File(path) => MultipartData::File(path),
+        }
+    }
+
+    /// Prepare a WAV recording for upload, sending it as is if encoding fails
+    pub fn from_wav(path: &str, wav: Vec<u8>, format: UploadFormat) -> Self {
+        let stem = path
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.rsplit_once('.').map(|(stem, _)| stem).or(Some(name)))
+            .unwrap_or("audio");
+
+        let encoded = match format {
+            UploadFormat::Wav => None,
+            UploadFormat::Flac => {
+                match decode_wav(&wav).and_then(|(spec, samples)| encode_flac(&samples, spec)) {
+                    Ok(flac) => {
+                        log::info!(
+                            "Compressed {} bytes of WAV to {} bytes of FLAC",
+                            wav.len(),
+                            flac.len()
+                        );
+                        Some(flac)
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to encode {} as FLAC, uploading WAV: {}", path, e);
+                        None
+                    }
+                }
+            }
+        };
+
+        let (format, data) = match encoded {
+            Some(data) => (format, data),
+            None => (UploadFormat::Wav, wav),
+        };
+
+        Self {
+            file_name: format!("{}.{}", stem, format.extension()),
+            content_type: format.content_type(),
+            data: UploadData::Memory(data),
+        }
+    }
+
+    /// Prepare samples recorded in RAM for upload, without going through a file
+    pub fn from_samples(name: &str, samples: &[i16], format: UploadFormat) -> anyhow::Result<Self> {
+        if format == UploadFormat::Flac {
+            let samples: Vec<i32> = samples.iter().map(|&s| s as i32).collect();
+            match encode_flac(&samples, recording_spec()) {
+                Ok(data) => {
+                    return Ok(Self {
+                        file_name: format!("{}.{}", name, format.extension()),
+                        content_type: format.content_type(),
+                        data: UploadData::Memory(data),
+                    })
+                }
+                Err(e) => log::warn!("Failed to encode {} as FLAC, uploading WAV: {}", name, e),
+            }
+        }
+
+        Ok(Self {
+            file_name: format!("{}.{}", name, UploadFormat::Wav.extension()),
+            content_type: UploadFormat::Wav.content_type(),
+            data: UploadData::Memory(encode_wav(samples)?),
+        })
+    }
+}
+
+/// Build a WAV file in memory from samples in the recording format
+pub fn encode_wav(samples: &[i16]) -> anyhow::Result<Vec<u8>> {
+    let mut cursor = Cursor::new(Vec::new());
+    {
+        let mut writer = hound::WavWriter::new(&mut cursor, recording_spec())?;
+        for &sample in samples {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()?;
+    }
+
+    Ok(cursor.into_inner())
+}
+
+fn decode_wav(wav: &[u8]) -> anyhow::Result<(hound::WavSpec, Vec<i32>)> {
+    let reader = hound::WavReader::new(Cursor::new(wav))?;
+    let spec = reader.spec();
+    if spec.sample_format != hound::SampleFormat::Int {
+        return Err(anyhow::anyhow!("Only integer PCM can be encoded as FLAC"));
+    }
+
+    let samples = reader
+        .into_samples::<i32>()
+        .collect::<Result<Vec<i32>, _>>()?;
+
+    Ok((spec, samples))
+}
+
+/// Encode integer PCM as FLAC
+fn encode_flac(samples: &[i32], spec: hound::WavSpec) -> anyhow::Result<Vec<u8>> {
+    let config = flacenc::config::Encoder::default()
+        .into_verified()
+        .map_err(|(_, e)| anyhow::anyhow!("Invalid FLAC encoder configuration: {:?}", e))?;
+    let source = flacenc::source::MemSource::from_samples(
+        samples,
+        spec.channels as usize,
+        spec.bits_per_sample as usize,
+        spec.sample_rate as usize,
+    );
+    let stream = flacenc::encode_with_fixed_block_size(&config, source, config.block_size)
+        .map_err(|e| anyhow::anyhow!("FLAC encoding failed: {:?}", e))?;
+
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    stream
+        .write(&mut sink)
+        .map_err(|e| anyhow::anyhow!("Failed to write FLAC stream: {:?}", e))?;
+
+    Ok(sink.as_slice().to_vec())
+}
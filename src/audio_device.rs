@@ -13,13 +13,18 @@ use esp_idf_svc::hal::{
 };
 
 /// Initialize microphone with PDM configuration
+///
+/// `dma_buffer_count` buffers of 240 frames (15 ms) each hold the samples until the feed task reads them.
 pub fn init_mic<'d>(
     i2s_slot: impl Peripheral<P = impl I2s> + 'd,
     clk: impl Peripheral<P = impl OutputPin> + 'd,
     din: impl Peripheral<P = impl InputPin> + 'd,
+    dma_buffer_count: u32,
 ) -> anyhow::Result<I2sDriver<'d, I2sRx>> {
     let pdm_rx_cfg = PdmRxConfig::new(
-        Config::default(),
+        Config::default()
+            .dma_buffer_count(dma_buffer_count)
+            .frames_per_buffer(240),
         PdmRxClkConfig::from_sample_rate_hz(16000)
             .clk_src(ClockSource::Pll160M)
             .mclk_multiple(MclkMultiple::M256)
@@ -1,15 +1,55 @@
 use anyhow;
 use esp_idf_svc::{hal::{
-    gpio::{Gpio41, Gpio42},
+    gpio::AnyIOPin,
     i2s::I2S0,
 }, sys::daddr_t};
 use esp_idf_svc::sys;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
 use std::{ffi::c_void, os::raw::c_void as raw_c_void};
 use sys::esp_sr;
 
 use crate::audio_device::init_mic;
-use crate::transcription::TranscriptionMessage;
+use crate::config::PowerSaveMode;
+use crate::dashboard::{self, LiveEvent};
+use crate::metrics;
+use crate::offline_commands::OfflineCommand;
+use crate::playback;
+use crate::sd_card;
+use crate::self_test::MicProbe;
+use crate::session::Session;
+use crate::stacks;
+use crate::stt::AudioStreamMessage;
+use crate::supervisor;
+use crate::transcription::{TranscriptionMessage, TranscriptionEvent};
+use crate::usage;
+use crate::watchdog;
+
+/// Stack sizes of the tasks, their peak use is reported by the stacks module
+const FEED_TASK_STACK_SIZE: usize = 8 * 1024;
+const FETCH_TASK_STACK_SIZE: usize = 8 * 1024;
+
+/// Set while the fetch task is in State::Recording, read by the web dashboard
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+/// Set by the talk button, the fetch task starts recording as if the wake word was heard
+static TALK_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// State the fetch task is in right now
+pub fn current_state() -> State {
+    if RECORDING.load(Ordering::Relaxed) {
+        State::Recording
+    } else {
+        State::WakeWordDetecting
+    }
+}
+
+/// Start listening for a question without the wake word, does nothing while already listening
+pub fn start_listening() {
+    if !RECORDING.load(Ordering::Relaxed) {
+        TALK_REQUESTED.store(true, Ordering::Relaxed);
+    }
+}
 
 /// Define the State enum
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -57,8 +97,33 @@ pub struct FeedTaskArg {
     pub afe_data: *mut esp_sr::esp_afe_sr_data_t,
     // Add fields for the peripherals needed for the microphone
     pub i2s0: I2S0,
-    pub gpio_clk: Gpio42,
-    pub gpio_din: Gpio41,
+    pub gpio_clk: AnyIOPin,
+    pub gpio_din: AnyIOPin,
+    /// Modem sleep makes the WiFi driver wake in bursts, the feed task has to ride them out
+    pub power_save: PowerSaveMode,
+}
+
+/// How the microphone is read under a WiFi power save mode
+struct MicBuffering {
+    dma_buffer_count: u32,
+    read_timeout_ms: u32,
+}
+
+impl MicBuffering {
+    fn for_power_save(power_save: PowerSaveMode) -> Self {
+        match power_save {
+            // 90 ms of audio, the driver's default
+            PowerSaveMode::None | PowerSaveMode::Min => Self {
+                dma_buffer_count: 6,
+                read_timeout_ms: 100,
+            },
+            // 240 ms, a late read no longer drops samples
+            PowerSaveMode::Max => Self {
+                dma_buffer_count: 16,
+                read_timeout_ms: 300,
+            },
+        }
+    }
 }
 
 pub struct FetchTaskArg {
@@ -67,7 +132,9 @@ pub struct FetchTaskArg {
     pub multinet: *mut esp_sr::esp_mn_iface_t,
     pub model_data: *mut esp_sr::model_iface_data_t,
     pub transcription_tx: Sender<TranscriptionMessage>,
-    pub transcription_response_rx: Receiver<String>,
+    pub transcription_event_rx: Receiver<TranscriptionEvent>,
+    /// Set when utterances are streamed to a websocket recognizer while recording
+    pub audio_stream_tx: Option<Sender<AudioStreamMessage>>,
 }
 
 macro_rules! call_c_method {
@@ -97,11 +164,14 @@ macro_rules! call_c_method {
 
 /// Modify inner_feed_proc to use peripherals from FeedTaskArg
 fn inner_feed_proc(feed_arg: &mut Box<FeedTaskArg>) -> anyhow::Result<()> {
+    let buffering = MicBuffering::for_power_save(feed_arg.power_save);
+
     // Get peripherals from the FeedTaskArg
     let mut mic = init_mic(
         &mut feed_arg.i2s0,
         &mut feed_arg.gpio_clk,
         &mut feed_arg.gpio_din,
+        buffering.dma_buffer_count,
     )?;
 
     let chunk_size = call_c_method!(feed_arg.afe_handle, get_feed_chunksize, feed_arg.afe_data)?;
@@ -115,8 +185,28 @@ fn inner_feed_proc(feed_arg: &mut Box<FeedTaskArg>) -> anyhow::Result<()> {
 
     let mut chunk = vec![0u8; 2 * chunk_size as usize * channel_num as usize];
 
+    // Only successful reads count as progress, a microphone that stopped delivering resets the device
+    let _watch = watchdog::watch();
+    let mut timeouts = 0u32;
+    // Tells the boot self-test whether the microphone picks up anything
+    let mut mic_probe = MicProbe::default();
     loop {
-        mic.read(chunk.as_mut_slice(), 100)?;
+        match mic.read(chunk.as_mut_slice(), buffering.read_timeout_ms) {
+            Ok(_) => {
+                watchdog::feed();
+                supervisor::heartbeat("feed_task");
+                mic_probe.check(&chunk);
+            }
+            // A late chunk is not worth stopping wake word detection for
+            Err(e) if e.code() == sys::ESP_ERR_TIMEOUT => {
+                timeouts += 1;
+                if timeouts.is_power_of_two() {
+                    log::warn!("Microphone read timed out ({} times so far)", timeouts);
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        }
         let _ = call_c_method!(
             feed_arg.afe_handle,
             feed,
@@ -128,11 +218,10 @@ fn inner_feed_proc(feed_arg: &mut Box<FeedTaskArg>) -> anyhow::Result<()> {
 
 extern "C" fn feed_proc(arg: *mut raw_c_void) {
     let mut feed_arg = unsafe { Box::from_raw(arg as *mut FeedTaskArg) };
+    let _stack = stacks::register("feed_task", FEED_TASK_STACK_SIZE);
 
-    match inner_feed_proc(&mut feed_arg) {
-        Ok(_) => log::info!("Feed task completed successfully"),
-        Err(e) => log::error!("Feed task failed: {}", e),
-    };
+    // The microphone driver is set up again from the peripherals kept in the argument
+    supervisor::run("feed_task", || inner_feed_proc(&mut feed_arg))
 }
 
 /// Helper function to flush FatFs filesystem with improved error handling
@@ -193,9 +282,77 @@ fn print_fetch_result(res: *const esp_sr::afe_fetch_result_t) {
     }
 }
 
+/// Samples kept in RAM per utterance without an SD card, 15 seconds at 16 kHz
+const MAX_MEMORY_SAMPLES: usize = 16000 * 15;
+
+/// The utterance being recorded
+enum Recording {
+    /// WAV file in the session's directory on the SD card
+    File {
+        writer: hound::WavWriter<std::io::BufWriter<std::fs::File>>,
+        path: String,
+    },
+    /// Kept in RAM when only the small internal flash is mounted, longer utterances are cut off
+    Memory(Vec<i16>),
+}
+
+impl Recording {
+    fn is_empty(&self) -> bool {
+        match self {
+            Recording::File { writer, .. } => writer.duration() == 0,
+            Recording::Memory(samples) => samples.is_empty(),
+        }
+    }
+
+    fn write(&mut self, frame: &[i16]) -> Result<(), hound::Error> {
+        match self {
+            Recording::File { writer, .. } => frame.iter().try_for_each(|&sample| writer.write_sample(sample)),
+            Recording::Memory(samples) => {
+                let room = MAX_MEMORY_SAMPLES.saturating_sub(samples.len());
+                samples.extend_from_slice(&frame[..frame.len().min(room)]);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Start recording the next utterance, into the session's directory or into RAM without an SD card
+///
+/// None while the SD card is unavailable.
+fn start_recording(session: &mut Option<Session>) -> Option<Recording> {
+    if sd_card::is_internal_flash() {
+        return Some(Recording::Memory(Vec::new()));
+    }
+
+    if !sd_card::is_available() {
+        return None;
+    }
+
+    // The card may have been unavailable when the wake word was heard
+    if session.is_none() {
+        *session = Session::start();
+    }
+    let path = session.as_mut()?.next_recording_path();
+
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 16000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+
+    log::info!("Creating WAV file: {}", path);
+    match hound::WavWriter::create(&path, spec) {
+        Ok(writer) => Some(Recording::File { writer, path }),
+        Err(e) => {
+            sd_card::report_io_error(&format!("creating {}", path), &e);
+            None
+        }
+    }
+}
+
 /// Modify the RECORDING state code to flush data after finalizing WAV file
 fn inner_fetch_proc(arg: &Box<FetchTaskArg>) -> anyhow::Result<()> {
-    use hound::{WavSpec, WavWriter};
     use std::sync::mpsc::TryRecvError;
 
     let afe_handle = arg.afe_handle;
@@ -223,9 +380,16 @@ fn inner_fetch_proc(arg: &Box<FetchTaskArg>) -> anyhow::Result<()> {
     // Initialize state
     let mut state = State::WakeWordDetecting;
 
-    // For recording WAV files
-    let mut file_idx = 0;
-    let mut wav_writer: Option<WavWriter<std::io::BufWriter<std::fs::File>>> = None;
+    // For recording WAV files, each conversation records into its own directory
+    let mut session: Option<Session> = None;
+    let mut recording: Option<Recording> = None;
+
+    // Set by the worker while the network services are down, MultiNet listens for commands then
+    let mut offline = false;
+    // The current utterance was an offline command, so it isn't uploaded
+    let mut offline_command_heard = false;
+    // The user started the current utterance while a reply was playing, cutting it short
+    let mut barged_in = false;
 
     // For tracking silence duration
     let mut silence_frames = 0;
@@ -233,10 +397,15 @@ fn inner_fetch_proc(arg: &Box<FetchTaskArg>) -> anyhow::Result<()> {
 
     log::info!("Starting detection loop with initial state: {:?}", state);
 
+    // Fetching blocks while the AFE gets no audio, which the watchdog catches as well
+    let _watch = watchdog::watch();
+
     // Infinite loop for the state machine - this function never returns normally
     loop {
         // Always fetch data from AFE
         let res = call_c_method!(afe_handle, fetch, afe_data)?;
+        watchdog::feed();
+        supervisor::heartbeat("fetch_task");
 
         if res.is_null() {
             log::error!("Fetch returned null result");
@@ -253,15 +422,36 @@ fn inner_fetch_proc(arg: &Box<FetchTaskArg>) -> anyhow::Result<()> {
         // Handle the data based on current state
         match state {
             State::WakeWordDetecting => {
-                if unsafe { (*res).wakeup_state } == esp_sr::wakenet_state_t_WAKENET_DETECTED {
+                let wake_word_heard =
+                    unsafe { (*res).wakeup_state } == esp_sr::wakenet_state_t_WAKENET_DETECTED;
+                if wake_word_heard || TALK_REQUESTED.swap(false, Ordering::Relaxed) {
                     let next_state = State::Recording;
                     State::log_transition(
                         state,
                         next_state,
-                        "Wake word detected, starting continuous recording",
+                        if wake_word_heard {
+                            "Wake word detected, starting continuous recording"
+                        } else {
+                            "Talk button pressed, starting continuous recording"
+                        },
                     );
 
                     call_c_method!(afe_handle, disable_wakenet, afe_data)?;
+                    dashboard::publish(LiveEvent::WakeDetected);
+                    if wake_word_heard {
+                        metrics::increment("wake_words");
+                        usage::record_wake_word();
+                    }
+
+                    // Waking the device while it answers interrupts the answer
+                    if playback::is_playing() {
+                        if let Err(e) = arg.transcription_tx.send(TranscriptionMessage::CancelPending) {
+                            log::error!("Failed to send cancel message: {}", e);
+                        }
+                    }
+
+                    // The worker keeps the transcript and history in the new session's directory
+                    session = Session::start();
 
                     // Send restart session message to clear LLM history
                     if let Err(e) = arg
@@ -274,61 +464,103 @@ fn inner_fetch_proc(arg: &Box<FetchTaskArg>) -> anyhow::Result<()> {
                     }
 
                     // Initialize WAV recording
-                    let spec = WavSpec {
-                        channels: 1,
-                        sample_rate: 16000,
-                        bits_per_sample: 16,
-                        sample_format: hound::SampleFormat::Int,
-                    };
-
-                    let current_file_idx = file_idx;
-                    file_idx += 1;
-
-                    log::info!("Creating WAV file: /vfat/audio{}.wav", current_file_idx);
-                    let writer = WavWriter::create(
-                        std::format!("/vfat/audio{}.wav", current_file_idx),
-                        spec,
-                    )?;
-                    wav_writer = Some(writer);
+                    recording = start_recording(&mut session);
                     silence_frames = 0;
 
                     state = next_state;
+                    RECORDING.store(true, Ordering::Relaxed);
                 }
             }
 
             State::Recording => {
                 // Check for transcription responses non-blockingly from the fixed channel
-                match arg.transcription_response_rx.try_recv() {
-                    Ok(transcription) => {
+                match arg.transcription_event_rx.try_recv() {
+                    Ok(TranscriptionEvent::NotUnderstood) => {
+                        // The worker asked the user to repeat, keep listening
+                        log::info!("Utterance was not understood, staying in recording");
+                        offline = false;
+                        silence_frames = 0;
+                    }
+                    Ok(TranscriptionEvent::Error(error)) => {
+                        log::warn!("Transcription failed: {}", error);
+                    }
+                    Ok(TranscriptionEvent::Offline) => {
+                        log::info!("Network is down, listening for offline commands");
+                        let _ = call_c_method!(multinet, clean, model_data);
+                        offline = true;
+                    }
+                    Ok(TranscriptionEvent::Transcript(transcription)) => {
                         log::info!("Received transcription response: {}", transcription);
-
-                        // Check if the transcription contains the exit command
-                        if transcription == "再见" {
-                            let next_state = State::WakeWordDetecting;
-                            State::log_transition(state, next_state, "Exit command detected");
-
-                            // Finalize current recording if active
-                            if let Some(writer) = wav_writer.take() {
-                                writer.finalize()?;
-                                log::info!("Finalized current recording due to exit command");
+                        offline = false;
+                    }
+                    Ok(TranscriptionEvent::Online) => {
+                        log::info!("Network is back, listening for questions again");
+                        offline = false;
+                    }
+                    Ok(TranscriptionEvent::LlmReplyStarted) => {
+                        log::debug!("LLM reply is being spoken");
+                    }
+                    Ok(TranscriptionEvent::PlaybackFinished) => {
+                        // Silence while the reply played doesn't count towards the end of the next utterance
+                        silence_frames = 0;
+                    }
+                    Ok(TranscriptionEvent::ExitCommand) => {
+                        offline = false;
+                        barged_in = false;
+                        let next_state = State::WakeWordDetecting;
+                        State::log_transition(state, next_state, "Exit command detected");
+
+                        // Finalize current recording if active
+                        if let Some(Recording::File { writer, .. }) = recording.take() {
+                            match writer.finalize() {
+                                Ok(()) => log::info!("Finalized current recording due to exit command"),
+                                Err(e) => sd_card::report_io_error("finalizing the recording", &e),
                             }
+                        }
 
-                            file_idx += 1;
-
-                            // Return to wake word detection
-                            call_c_method!(afe_handle, enable_wakenet, afe_data)?;
-                            state = next_state;
-                            continue;
+                        if let Some(stream_tx) = &arg.audio_stream_tx {
+                            let _ = stream_tx.send(AudioStreamMessage::Discard);
                         }
+
+                        session = None;
+
+                        // Return to wake word detection
+                        call_c_method!(afe_handle, enable_wakenet, afe_data)?;
+                        state = next_state;
+                        RECORDING.store(false, Ordering::Relaxed);
+                        continue;
                     }
                     Err(TryRecvError::Disconnected) => {
-                        log::warn!("Transcription response channel was closed");
+                        log::warn!("Transcription event channel was closed");
                     }
                     Err(TryRecvError::Empty) => {
                         // No response yet, continue with recording
                     }
                 }
 
+                // Without network only the commands MultiNet knows can be answered
+                if offline {
+                    let data = unsafe { (*res).data };
+                    let mn_state = call_c_method!(multinet, detect, model_data, data)?;
+                    if mn_state == esp_sr::esp_mn_state_t_ESP_MN_STATE_DETECTED {
+                        let results = call_c_method!(multinet, get_results, model_data)?;
+                        let command_id = unsafe { (*results).command_id[0] };
+
+                        if let Some(command) = OfflineCommand::from_command_id(command_id) {
+                            log::info!("Offline command detected: {:?}", command);
+                            offline_command_heard = true;
+                            if let Err(e) = arg
+                                .transcription_tx
+                                .send(TranscriptionMessage::OfflineCommand(command))
+                            {
+                                log::error!("Failed to send offline command: {}", e);
+                            }
+                        }
+
+                        let _ = call_c_method!(multinet, clean, model_data);
+                    }
+                }
+
                 // Check VAD state
                 let vad_state = unsafe { (*res).vad_state };
 
@@ -339,85 +571,132 @@ fn inner_fetch_proc(arg: &Box<FetchTaskArg>) -> anyhow::Result<()> {
                     if silence_frames >= frames_per_second * 2 {
                         // 1 second of silence
                         // Finalize current WAV file and start transcription
-                        if let Some(writer) = wav_writer.take() {
+                        if let Some(current) = recording.take() {
+                            barged_in = false;
                             log::info!(
-                                "Finalizing WAV file after {} silent frames for transcription",
+                                "Finalizing recording after {} silent frames for transcription",
                                 silence_frames
                             );
 
-                            let has_data = writer.duration() > 0;
-
-                            if has_data {
-                                writer.finalize()?;
-
-                                // Flush the filesystem to ensure all data is written
-                                if let Err(e) = flush_filesystem("/vfat") {
-                                    log::warn!("Failed to flush filesystem: {}", e);
-                                } else {
-                                    log::info!("Filesystem flushed successfully");
-                                }
+                            if !current.is_empty() {
+                                // Files are finalized and uploaded from the card, RAM recordings are sent as they are
+                                let finished = match current {
+                                    Recording::File { writer, path } => match writer.finalize() {
+                                        Ok(()) => {
+                                            // Flush the filesystem to ensure all data is written
+                                            if let Err(e) = flush_filesystem("/vfat") {
+                                                log::warn!("Failed to flush filesystem: {}", e);
+                                            } else {
+                                                log::info!("Filesystem flushed successfully");
+                                            }
+                                            Some(TranscriptionMessage::TranscribeFile { path })
+                                        }
+                                        Err(e) => {
+                                            sd_card::report_io_error("finalizing the recording", &e);
+                                            None
+                                        }
+                                    },
+                                    Recording::Memory(samples) => {
+                                        Some(TranscriptionMessage::TranscribeSamples { samples })
+                                    }
+                                };
 
-                                // Send transcription request
-                                let file_path = format!("/vfat/audio{}.wav", file_idx - 1);
+                                let Some(message) = finished else {
+                                    // The utterance is lost, recording resumes once the card is remounted
+                                    if let Some(stream_tx) = &arg.audio_stream_tx {
+                                        let _ = stream_tx.send(AudioStreamMessage::Discard);
+                                    }
+                                    silence_frames = 0;
+                                    continue;
+                                };
 
-                                if let Err(e) = arg.transcription_tx.send(
-                                    TranscriptionMessage::TranscribeFile {
-                                        path: file_path.clone(),
-                                    },
-                                ) {
-                                    log::error!("Failed to send transcription message: {}", e);
+                                if offline_command_heard {
+                                    // The command was already handled on the device
+                                    log::info!("Not uploading the utterance, it was an offline command");
+                                    if let Some(stream_tx) = &arg.audio_stream_tx {
+                                        let _ = stream_tx.send(AudioStreamMessage::Discard);
+                                    }
+                                    offline_command_heard = false;
                                 } else {
-                                    log::info!("Sent audio file for transcription: {}", file_path);
+                                    // The streaming thread falls back to transcribing the recording itself
+                                    let sent = match &arg.audio_stream_tx {
+                                        Some(stream_tx) => stream_tx
+                                            .send(AudioStreamMessage::End { fallback: message })
+                                            .map_err(|e| anyhow::anyhow!("{}", e)),
+                                        None => arg
+                                            .transcription_tx
+                                            .send(message)
+                                            .map_err(|e| anyhow::anyhow!("{}", e)),
+                                    };
+
+                                    if let Err(e) = sent {
+                                        log::error!("Failed to send transcription message: {}", e);
+                                    } else {
+                                        log::info!("Sent utterance for transcription");
+                                        metrics::start_turn();
+                                    }
                                 }
 
                                 // Start a new recording immediately for continuous conversation
-                                let spec = WavSpec {
-                                    channels: 1,
-                                    sample_rate: 16000,
-                                    bits_per_sample: 16,
-                                    sample_format: hound::SampleFormat::Int,
-                                };
-
-                                let current_file_idx = file_idx;
-                                file_idx += 1;
-
-                                log::info!(
-                                    "Creating new WAV file for continuous recording: /vfat/audio{}.wav",
-                                    current_file_idx
-                                );
-                                let writer = WavWriter::create(
-                                    std::format!("/vfat/audio{}.wav", current_file_idx),
-                                    spec,
-                                )?;
-                                wav_writer = Some(writer);
+                                recording = start_recording(&mut session);
                             } else {
-                                log::warn!("WAV file duration is zero, skipping transcription");
-                                wav_writer = Some(writer);
+                                log::warn!("Recording is empty, skipping transcription");
+                                recording = Some(current);
                             }
                         }
 
                         silence_frames = 0;
                     }
                 } else {
-                    // Write audio data to WAV file
-                    if let Some(writer) = &mut wav_writer {
+                    // Recording was paused while the SD card was unavailable
+                    if recording.is_none() {
+                        recording = start_recording(&mut session);
+                    }
+
+                    // Write audio data to the recording
+                    if let Some(current) = &mut recording {
+                        let mut frame = Vec::new();
                         let cache_size = unsafe { (*res).vad_cache_size };
 
                         if cache_size > 0 {
                             let data_ptr = unsafe { (*res).vad_cache };
                             let data_size = cache_size / 2; // Convert bytes to samples (16-bit samples)
                             for i in 0..data_size {
-                                let sample = unsafe { *data_ptr.offset(i as isize) };
-                                writer.write_sample(sample)?;
+                                frame.push(unsafe { *data_ptr.offset(i as isize) });
                             }
                         }
 
-                                                let data_ptr = unsafe { (*res).data };
+                        let data_ptr = unsafe { (*res).data };
                         let data_size = unsafe { (*res).data_size / 2 }; // Convert bytes to samples (16-bit samples)
                         // Assuming data is an array of i16 samples
                         for i in 0..data_size {
-                            let sample = unsafe { *data_ptr.offset(i as isize) };
-                            writer.write_sample(sample)?;
+                            frame.push(unsafe { *data_ptr.offset(i as isize) });
+                        }
+
+                        if let Err(e) = current.write(&frame) {
+                            sd_card::report_io_error("writing the recording", &e);
+                            recording = None;
+                            if let Some(stream_tx) = &arg.audio_stream_tx {
+                                let _ = stream_tx.send(AudioStreamMessage::Discard);
+                            }
+                            continue;
+                        }
+
+                        // The recognizer listens along so the transcript is ready when the user stops
+                        if let Some(stream_tx) = &arg.audio_stream_tx {
+                            let _ = stream_tx.send(AudioStreamMessage::Audio(frame));
+                        }
+                    }
+
+                    // Talking over the reply interrupts it, a question asked after it doesn't
+                    if !barged_in && !offline && playback::is_playing() {
+                        barged_in = true;
+                        log::info!("The user spoke over the reply, cancelling it");
+                        if let Err(e) = arg
+                            .transcription_tx
+                            .send(TranscriptionMessage::CancelPending)
+                        {
+                            log::error!("Failed to send cancel message: {}", e);
                         }
                     }
 
@@ -437,20 +716,19 @@ fn inner_fetch_proc(arg: &Box<FetchTaskArg>) -> anyhow::Result<()> {
 
 extern "C" fn fetch_proc(arg: *mut raw_c_void) {
     let feed_arg = unsafe { Box::from_raw(arg as *mut FetchTaskArg) };
+    let _stack = stacks::register("fetch_task", FETCH_TASK_STACK_SIZE);
 
-    let res = inner_fetch_proc(&feed_arg);
-    match res {
-        Ok(_) => log::info!("Fetch task completed successfully"),
-        Err(e) => log::error!("Fetch task failed: {}", e),
-    };
+    // Starts over waiting for the wake word, an utterance being recorded is lost
+    supervisor::run("fetch_task", || inner_fetch_proc(&feed_arg))
 }
 
 pub fn create_feed_task(
     afe_handle: *mut esp_sr::esp_afe_sr_iface_t,
     afe_data: *mut esp_sr::esp_afe_sr_data_t,
     i2s0: I2S0,
-    gpio_clk: Gpio42,
-    gpio_din: Gpio41,
+    gpio_clk: AnyIOPin,
+    gpio_din: AnyIOPin,
+    power_save: PowerSaveMode,
 ) -> anyhow::Result<esp_idf_svc::sys::TaskHandle_t> {
     use esp_idf_svc::hal;
     use std::ffi::CString;
@@ -462,6 +740,7 @@ pub fn create_feed_task(
         i2s0,
         gpio_clk,
         gpio_din,
+        power_save,
     });
 
     // Create the feed task
@@ -469,7 +748,7 @@ pub fn create_feed_task(
         hal::task::create(
             feed_proc,
             &*CString::new("feed_task").unwrap(),
-            8 * 1024,
+            FEED_TASK_STACK_SIZE,
             Box::into_raw(feed_task_arg) as *mut c_void,
             5,
             None,
@@ -486,7 +765,8 @@ pub fn create_fetch_task(
     multinet: *mut esp_sr::esp_mn_iface_t,
     model_data: *mut esp_sr::model_iface_data_t,
     transcription_tx: Sender<TranscriptionMessage>,
-    transcription_response_rx: Receiver<String>,
+    transcription_event_rx: Receiver<TranscriptionEvent>,
+    audio_stream_tx: Option<Sender<AudioStreamMessage>>,
 ) -> anyhow::Result<esp_idf_svc::sys::TaskHandle_t> {
     use esp_idf_svc::hal;
     use std::ffi::CString;
@@ -498,7 +778,8 @@ pub fn create_fetch_task(
         multinet,
         model_data,
         transcription_tx,
-        transcription_response_rx,
+        transcription_event_rx,
+        audio_stream_tx,
     });
 
     // Create the fetch task
@@ -506,7 +787,7 @@ pub fn create_fetch_task(
         hal::task::create(
             fetch_proc,
             &*CString::new("fetch_task").unwrap(),
-            8 * 1024,
+            FETCH_TASK_STACK_SIZE,
             Box::into_raw(fetch_task_arg) as *mut c_void,
             5,
             None,
@@ -0,0 +1,200 @@
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_svc::hal::task::notification::{Notification, Notifier};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio_processing;
+use crate::config::{ButtonConfig, EncoderConfig};
+use crate::dashboard::{self, LiveEvent};
+use crate::offline_commands::OfflineCommand;
+use crate::playback;
+use crate::transcription::TranscriptionMessage;
+
+/// How often a held button is checked for being released
+const HOLD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a button does when pressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonAction {
+    #[default]
+    ToggleMute,
+    VolumeUp,
+    VolumeDown,
+    StopSpeaking,
+    PushToTalk,
+}
+
+/// A configured button, `bit` marks it in the notification its interrupt sends
+struct Button {
+    action: ButtonAction,
+    bit: u32,
+    driver: PinDriver<'static, AnyIOPin, Input>,
+}
+
+/// Watch the buttons in `config` and the encoder's push switch, does nothing when none has a pin
+///
+/// Each press is published as a live event and then handled: mute toggles the speaker, the
+/// volume buttons work like the offline volume commands, stop cuts the reply short and
+/// cancels the one on its way, and talk starts listening without the wake word. Holding any
+/// button for `config.factory_reset_hold_secs` asks for a factory reset instead, so while that
+/// is set presses are handled when the button is released.
+pub fn start(
+    config: &ButtonConfig,
+    encoder: &EncoderConfig,
+    transcription_tx: Sender<TranscriptionMessage>,
+) -> anyhow::Result<()> {
+    let pins: Vec<(ButtonAction, u8)> = [
+        (ButtonAction::ToggleMute, config.mute),
+        (ButtonAction::VolumeUp, config.volume_up),
+        (ButtonAction::VolumeDown, config.volume_down),
+        (ButtonAction::StopSpeaking, config.stop),
+        (ButtonAction::PushToTalk, config.talk),
+        (encoder.switch_action, encoder.switch),
+    ]
+    .into_iter()
+    .filter_map(|(action, pin)| pin.map(|pin| (action, pin)))
+    .collect();
+    if pins.is_empty() {
+        return Ok(());
+    }
+
+    let active_high = config.active_high;
+    let debounce = Duration::from_millis(config.debounce_ms);
+    let reset_hold = Duration::from_secs(config.factory_reset_hold_secs as u64);
+    thread::Builder::new()
+        .name("buttons".to_string())
+        .stack_size(4 * 1024)
+        .spawn(move || button_loop(pins, active_high, debounce, reset_hold, transcription_tx))?;
+
+    Ok(())
+}
+
+fn button_loop(
+    pins: Vec<(ButtonAction, u8)>,
+    active_high: bool,
+    debounce: Duration,
+    reset_hold: Duration,
+    transcription_tx: Sender<TranscriptionMessage>,
+) {
+    // Belongs to this thread, the interrupts wake it up
+    let notification = Notification::new();
+
+    let mut buttons = Vec::new();
+    for (bit, (action, pin)) in pins.into_iter().enumerate() {
+        let bit = bit as u32;
+        match watch_pin(pin, active_high, bit, notification.notifier()) {
+            Ok(driver) => {
+                log::info!("{:?} button on GPIO{}", action, pin);
+                buttons.push(Button {
+                    action,
+                    bit,
+                    driver,
+                });
+            }
+            Err(e) => log::warn!("Failed to set up the {:?} button: {}", action, e),
+        }
+    }
+
+    loop {
+        let Some(fired) = notification.wait(BLOCK) else {
+            continue;
+        };
+        // A bounce or a glitch is over by then, a press isn't
+        thread::sleep(debounce);
+
+        for button in buttons.iter_mut() {
+            if fired.get() & (1 << button.bit) == 0 {
+                continue;
+            }
+            if button.driver.is_high() == active_high {
+                // Decided once it's released, a hold for the reset mustn't mute or talk first
+                if !reset_hold.is_zero() && held_for(&button.driver, active_high, reset_hold) {
+                    log::warn!("{:?} button held, factory reset", button.action);
+                    let _ = transcription_tx.send(TranscriptionMessage::FactoryReset);
+                } else {
+                    handle_press(button.action, &transcription_tx);
+                }
+            }
+            // The interrupt is disabled each time it fires
+            if let Err(e) = button.driver.enable_interrupt() {
+                log::warn!("Failed to re-arm the {:?} button: {}", button.action, e);
+            }
+        }
+    }
+}
+
+/// Wait while the button stays pressed, true once it was held for `hold`
+fn held_for(
+    driver: &PinDriver<'static, AnyIOPin, Input>,
+    active_high: bool,
+    hold: Duration,
+) -> bool {
+    let pressed = Instant::now();
+    while driver.is_high() == active_high {
+        if pressed.elapsed() >= hold {
+            return true;
+        }
+        thread::sleep(HOLD_POLL_INTERVAL);
+    }
+    false
+}
+
+/// Interrupt on the edge of a press, with the pin pulled to the released level
+fn watch_pin(
+    pin: u8,
+    active_high: bool,
+    bit: u32,
+    notifier: Arc<Notifier>,
+) -> anyhow::Result<PinDriver<'static, AnyIOPin, Input>> {
+    let mut driver = PinDriver::input(unsafe { AnyIOPin::new(pin as i32) })?;
+    if active_high {
+        driver.set_pull(Pull::Down)?;
+        driver.set_interrupt_type(InterruptType::PosEdge)?;
+    } else {
+        driver.set_pull(Pull::Up)?;
+        driver.set_interrupt_type(InterruptType::NegEdge)?;
+    }
+
+    let value = NonZeroU32::new(1 << bit).unwrap();
+    // Runs in the interrupt handler, which can do no more than wake the thread
+    unsafe {
+        driver.subscribe(move || {
+            notifier.notify_and_yield(value);
+        })?;
+    }
+    driver.enable_interrupt()?;
+    Ok(driver)
+}
+
+fn handle_press(action: ButtonAction, transcription_tx: &Sender<TranscriptionMessage>) {
+    log::info!("{:?} button pressed", action);
+    dashboard::publish(LiveEvent::ButtonPressed { action });
+
+    let message = match action {
+        ButtonAction::ToggleMute => {
+            playback::set_muted(!playback::is_muted());
+            return;
+        }
+        ButtonAction::VolumeUp => TranscriptionMessage::OfflineCommand(OfflineCommand::VolumeUp),
+        ButtonAction::VolumeDown => {
+            TranscriptionMessage::OfflineCommand(OfflineCommand::VolumeDown)
+        }
+        ButtonAction::StopSpeaking => {
+            playback::stop_speaking();
+            TranscriptionMessage::CancelPending
+        }
+        ButtonAction::PushToTalk => {
+            audio_processing::start_listening();
+            return;
+        }
+    };
+    if let Err(e) = transcription_tx.send(message) {
+        log::error!("Failed to send the {:?} button press: {}", action, e);
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::anyhow;
+use esp_idf_svc::hal::i2c::I2C1;
+use esp_idf_svc::hal::ledc::{CHANNEL0, TIMER0};
+use esp_idf_svc::sys::{esp, esp_camera};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::{CameraConfig, CameraFrameSize, ProviderConfig, BUILT_IN_API_KEY};
+use crate::llm_intf::{
+    create_provider, ChatImage, ChatRequestBuilder, Completion, LlmError, LlmHelper,
+};
+
+/// Clock of the OV2640 in Espressif's examples
+const XCLK_FREQ_HZ: i32 = 20_000_000;
+/// Replies about a picture are a few sentences
+const DESCRIBE_MAX_TOKENS: u32 = 512;
+
+const DESCRIBE_INSTRUCTION: &str = "用户用摄像头拍了一张照片给你看，并用语音问了关于它的问题。\
+请根据照片用简短的中文口语回答，两三句话，不要使用列表，不要包含*。\
+用户的话来自语音识别，可能有同音字的错误，请按最合理的意思理解。";
+
+/// Set once the sensor answered at boot
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Set up the sensor in `config`, does nothing while the camera is disabled
+///
+/// The driver takes I2C1 for the sensor's SCCB bus and LEDC timer 0 and channel 0 for its clock.
+pub fn start(
+    config: &CameraConfig,
+    _i2c: I2C1,
+    _timer: TIMER0,
+    _channel: CHANNEL0,
+) -> anyhow::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let pin = |pin: Option<u8>| pin.map_or(-1, |pin| pin as i32);
+    let data = config.data.map(|pin| pin as i32);
+    let mut camera_config: esp_camera::camera_config_t = unsafe { std::mem::zeroed() };
+    camera_config.pin_pwdn = pin(config.pwdn);
+    camera_config.pin_reset = pin(config.reset);
+    camera_config.pin_xclk = config.xclk as i32;
+    camera_config.__bindgen_anon_1.pin_sccb_sda = config.sda as i32;
+    camera_config.__bindgen_anon_2.pin_sccb_scl = config.scl as i32;
+    camera_config.pin_d0 = data[0];
+    camera_config.pin_d1 = data[1];
+    camera_config.pin_d2 = data[2];
+    camera_config.pin_d3 = data[3];
+    camera_config.pin_d4 = data[4];
+    camera_config.pin_d5 = data[5];
+    camera_config.pin_d6 = data[6];
+    camera_config.pin_d7 = data[7];
+    camera_config.pin_vsync = config.vsync as i32;
+    camera_config.pin_href = config.href as i32;
+    camera_config.pin_pclk = config.pclk as i32;
+    camera_config.xclk_freq_hz = XCLK_FREQ_HZ;
+    camera_config.ledc_timer = esp_camera::ledc_timer_t_LEDC_TIMER_0;
+    camera_config.ledc_channel = esp_camera::ledc_channel_t_LEDC_CHANNEL_0;
+    camera_config.pixel_format = esp_camera::pixformat_t_PIXFORMAT_JPEG;
+    camera_config.frame_size = frame_size(config.frame_size);
+    camera_config.jpeg_quality = config.jpeg_quality as i32;
+    // One buffer in PSRAM, filled only when it's empty so the sensor isn't streaming for nothing
+    camera_config.fb_count = 1;
+    camera_config.fb_location = esp_camera::camera_fb_location_t_CAMERA_FB_IN_PSRAM;
+    camera_config.grab_mode = esp_camera::camera_grab_mode_t_CAMERA_GRAB_WHEN_EMPTY;
+    camera_config.sccb_i2c_port = 1;
+
+    esp!(unsafe { esp_camera::esp_camera_init(&camera_config) })
+        .map_err(|e| anyhow!("Failed to initialize the camera: {}", e))?;
+    READY.store(true, Ordering::Relaxed);
+    log::info!("Camera ready at {:?}", config.frame_size);
+    Ok(())
+}
+
+/// Whether pictures can be taken
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Relaxed)
+}
+
+/// Take a picture, as JPEG
+pub fn capture() -> anyhow::Result<Vec<u8>> {
+    if !is_ready() {
+        return Err(anyhow!("The camera is not set up"));
+    }
+    // The buffer was filled right after the last picture was returned, maybe hours ago
+    release(grab()?);
+    let frame = grab()?;
+    let jpeg = unsafe { std::slice::from_raw_parts((*frame).buf, (*frame).len) }.to_vec();
+    release(frame);
+    log::info!("Captured a picture of {} bytes", jpeg.len());
+    Ok(jpeg)
+}
+
+/// Ask the vision service `question` about the picture in `jpeg`, outside the conversation
+/// history
+///
+/// The service in `vision` is used when set, the conversation's services otherwise.
+pub fn describe(
+    llm: &LlmHelper,
+    vision: Option<&ProviderConfig>,
+    question: &str,
+    jpeg: &[u8],
+) -> Result<Completion, LlmError> {
+    let request = ChatRequestBuilder::new(llm.generation_params())
+        .system(DESCRIBE_INSTRUCTION)
+        .user_with_image(question, ChatImage::jpeg(jpeg))
+        .max_tokens(DESCRIBE_MAX_TOKENS)
+        .json_output(false)
+        .build();
+    match vision {
+        Some(vision) => LlmHelper::with_provider(create_provider(vision, BUILT_IN_API_KEY))
+            .complete_request(&request),
+        None => llm.complete_request(&request),
+    }
+}
+
+fn grab() -> anyhow::Result<*mut esp_camera::camera_fb_t> {
+    let frame = unsafe { esp_camera::esp_camera_fb_get() };
+    if frame.is_null() {
+        return Err(anyhow!("The camera returned no picture"));
+    }
+    Ok(frame)
+}
+
+fn release(frame: *mut esp_camera::camera_fb_t) {
+    unsafe { esp_camera::esp_camera_fb_return(frame) };
+}
+
+fn frame_size(size: CameraFrameSize) -> esp_camera::framesize_t {
+    match size {
+        CameraFrameSize::Qvga => esp_camera::framesize_t_FRAMESIZE_QVGA,
+        CameraFrameSize::Vga => esp_camera::framesize_t_FRAMESIZE_VGA,
+        CameraFrameSize::Svga => esp_camera::framesize_t_FRAMESIZE_SVGA,
+        CameraFrameSize::Xga => esp_camera::framesize_t_FRAMESIZE_XGA,
+    }
+}
@@ -0,0 +1,274 @@
+use anyhow;
+use esp_idf_svc::http::client::Configuration as HttpConfiguration;
+use esp_idf_svc::http::Method;
+use serde::{Deserialize, Serialize};
+use std::io::{Cursor, Read};
+
+use crate::http_client::{
+    read_response_bytes, AttemptError, HttpError, PooledConnection, RetryPolicy,
+};
+
+const DEFAULT_URL: &str = "https://api.openai.com/v1/audio/speech";
+const DEFAULT_MODEL: &str = "tts-1";
+const DEFAULT_VOICE: &str = "alloy";
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+/// The user is waiting for the answer, a second attempt is all that's worth it
+const DEFAULT_ATTEMPTS: u32 = 2;
+/// Rate the I2S output runs at, see init_i2s_tx
+pub const OUTPUT_SAMPLE_RATE: u32 = 16000;
+/// Rates accepted for playing, a chunk of a tenth of a second has to be at least a sample
+const WAV_SAMPLE_RATES: std::ops::RangeInclusive<u32> = 8000..=48000;
+
+/// Settings of an OpenAI compatible /v1/audio/speech service, used for languages the
+/// on-device voice can't speak
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CloudTtsConfig {
+    pub url: String,
+    /// Sent as a bearer token; without one the cloud voice is disabled
+    pub api_key: Option<String>,
+    pub model: String,
+    pub voice: String,
+    pub timeout_secs: u64,
+    pub retry: RetryPolicy,
+}
+
+impl Default for CloudTtsConfig {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_URL.to_string(),
+            api_key: None,
+            model: DEFAULT_MODEL.to_string(),
+            voice: DEFAULT_VOICE.to_string(),
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+            retry: RetryPolicy {
+                max_attempts: DEFAULT_ATTEMPTS,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SpeechRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+    voice: &'a str,
+    /// WAV carries the sample rate, so the audio can be resampled whatever the service sends
+    response_format: &'static str,
+}
+
+/// Speech synthesis through a cloud service
+pub struct CloudTts {
+    config: CloudTtsConfig,
+    authorization: String,
+}
+
+impl CloudTts {
+    /// None when no API key is configured
+    pub fn from_config(config: &CloudTtsConfig) -> Option<Self> {
+        let api_key = config.api_key.as_deref()?;
+
+        Some(Self {
+            config: config.clone(),
+            authorization: format!("Bearer {}", api_key),
+        })
+    }
+
+    /// Synthesize text into 16 kHz mono samples
+    pub fn synthesize(&self, text: &str) -> anyhow::Result<Vec<i16>> {
+        log::info!("Synthesizing text with the cloud voice: {}", text);
+        let request = SpeechRequest {
+            model: &self.config.model,
+            input: text,
+            voice: &self.config.voice,
+            response_format: "wav",
+        };
+        let body = serde_json::to_vec(&request)?;
+
+        let http_config = HttpConfiguration {
+            timeout: Some(std::time::Duration::from_secs(self.config.timeout_secs)),
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            ..Default::default()
+        };
+        let content_length = body.len().to_string();
+        let headers = [
+            ("Content-Type", "application/json"),
+            ("Content-Length", content_length.as_str()),
+            ("Authorization", self.authorization.as_str()),
+        ];
+
+        let send_request = |_attempt: u32| -> Result<Vec<u8>, AttemptError> {
+            let mut client = PooledConnection::open(&self.config.url, &http_config)
+                .map_err(AttemptError::Transient)?;
+
+            client
+                .send(Method::Post, |client| {
+                    client
+                        .initiate_request(Method::Post, &self.config.url, &headers)
+                        .map_err(|e| anyhow::anyhow!("Failed to initiate TTS request: {}", e))?;
+                    client
+                        .write(&body)
+                        .map_err(|e| anyhow::anyhow!("Failed to write TTS request: {}", e))?;
+                    client
+                        .initiate_response()
+                        .map_err(|e| anyhow::anyhow!("Failed to get TTS response: {}", e))
+                })
+                .map_err(AttemptError::Transient)?;
+
+            if client.status() != 200 {
+                let error = HttpError::from_response(&mut client);
+                return Err(self.config.retry.status_error(error));
+            }
+
+            let wav = read_response_bytes(&mut client).map_err(AttemptError::Transient)?;
+            client.release();
+            Ok(wav)
+        };
+
+        let wav = self
+            .config
+            .retry
+            .run("Cloud TTS request", std::thread::sleep, send_request)
+            .map_err(AttemptError::into_inner)?;
+        decode_to_output_format(&wav)
+    }
+}
+
+/// Decode 16 bit WAV audio and convert it to the mono 16 kHz the speaker is driven with
+pub fn decode_to_output_format(wav: &[u8]) -> anyhow::Result<Vec<i16>> {
+    let reader = open_wav(Cursor::new(wav))?;
+    let spec = reader.spec();
+    let samples = reader
+        .into_samples::<i16>()
+        .collect::<Result<Vec<i16>, _>>()?;
+
+    Ok(to_output_format(&samples, spec.channels, spec.sample_rate))
+}
+
+/// Whether `to_output_format` can convert 16-bit audio with this many channels at this rate
+pub fn is_playable_format(channels: u16, sample_rate: u32) -> bool {
+    (1..=2).contains(&channels) && WAV_SAMPLE_RATES.contains(&sample_rate)
+}
+
+/// Read the header of a 16 bit WAV, the samples follow from `reader` as they are decoded
+pub fn open_wav<R: Read>(reader: R) -> anyhow::Result<hound::WavReader<R>> {
+    let reader = hound::WavReader::new(reader)?;
+    let spec = reader.spec();
+    if spec.sample_format != hound::SampleFormat::Int || spec.bits_per_sample != 16 {
+        return Err(anyhow::anyhow!(
+            "Unsupported audio format: {:?}, {} bits",
+            spec.sample_format,
+            spec.bits_per_sample
+        ));
+    }
+    if !is_playable_format(spec.channels, spec.sample_rate) {
+        return Err(anyhow::anyhow!(
+            "Unsupported audio format: {} channels at {} Hz",
+            spec.channels,
+            spec.sample_rate
+        ));
+    }
+    Ok(reader)
+}
+
+/// Decode `wav` a tenth of a second at a time in the output format, handing each chunk to
+/// `sink` until it returns false
+pub fn decode_chunks<R: Read>(
+    mut wav: hound::WavReader<R>,
+    mut sink: impl FnMut(Vec<i16>) -> bool,
+) -> anyhow::Result<()> {
+    let spec = wav.spec();
+    let chunk_samples = (spec.sample_rate / 10) as usize * spec.channels as usize;
+    let mut samples = wav.samples::<i16>();
+    loop {
+        let chunk = samples
+            .by_ref()
+            .take(chunk_samples)
+            .collect::<Result<Vec<i16>, _>>()?;
+        if chunk.is_empty() || !sink(to_output_format(&chunk, spec.channels, spec.sample_rate)) {
+            return Ok(());
+        }
+    }
+}
+
+/// Mix interleaved 16 bit audio down to mono and resample it to the output rate
+pub fn to_output_format(samples: &[i16], channels: u16, sample_rate: u32) -> Vec<i16> {
+    let channels = channels.max(1) as usize;
+    let mono: Vec<i16> = samples
+        .chunks(channels)
+        .map(|frame| (frame.iter().map(|&s| s as i32).sum::<i32>() / frame.len() as i32) as i16)
+        .collect();
+
+    resample(&mono, sample_rate, OUTPUT_SAMPLE_RATE)
+}
+
+/// Linear interpolation, good enough for speech
+fn resample(samples: &[i16], from_rate: u32, to_rate: u32) -> Vec<i16> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let output_len = (samples.len() as u64 * to_rate as u64 / from_rate as u64) as usize;
+    let step = from_rate as f32 / to_rate as f32;
+
+    (0..output_len)
+        .map(|i| {
+            let position = i as f32 * step;
+            let index = position as usize;
+            let fraction = position - index as f32;
+            let current = samples[index.min(samples.len() - 1)] as f32;
+            let next = samples[(index + 1).min(samples.len() - 1)] as f32;
+            (current + (next - current) * fraction) as i16
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resample_length() {
+        let samples = vec![0i16; 24000];
+        assert_eq!(resample(&samples, 24000, 16000).len(), 16000);
+        assert_eq!(resample(&samples, 16000, 16000).len(), 24000);
+    }
+
+    #[test]
+    fn test_decode_chunks() {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 32000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+        for _ in 0..2 * 8000 {
+            writer.write_sample(100i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        let wav = wav.into_inner();
+
+        let mut chunks = Vec::new();
+        decode_chunks(open_wav(Cursor::new(&wav)).unwrap(), |chunk| {
+            chunks.push(chunk);
+            true
+        })
+        .unwrap();
+        let lengths: Vec<usize> = chunks.iter().map(Vec::len).collect();
+        assert_eq!(lengths, vec![1600, 1600, 800]);
+        assert!(chunks.iter().flatten().all(|&sample| sample == 100));
+        assert_eq!(decode_to_output_format(&wav).unwrap().len(), 4000);
+
+        // Stops as soon as the sink has had enough
+        let mut calls = 0;
+        decode_chunks(open_wav(Cursor::new(&wav)).unwrap(), |_| {
+            calls += 1;
+            false
+        })
+        .unwrap();
+        assert_eq!(calls, 1);
+    }
+}
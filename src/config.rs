@@ -0,0 +1,2196 @@
+use anyhow;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+
+use crate::buttons::ButtonAction;
+use crate::cloud_tts::CloudTtsConfig;
+use crate::http_client::RetryPolicy;
+use crate::knowledge::KnowledgeConfig;
+use crate::language::Language;
+use crate::llm_intf::HistoryBudget;
+use crate::settings::Settings;
+use crate::stt::SttConfig;
+
+/// Location of the user editable configuration file on the SD card
+pub const CONFIG_FILE_PATH: &str = "/vfat/config.toml";
+/// Keys whose values the web API never hands out
+const SECRET_KEYS: [&str; 4] = ["api_key", "password", "token", "ble_pop"];
+/// Shown instead of a secret, saving a file with it keeps the secret as it was
+const REDACTED: &str = "\"<redacted>\"";
+
+/// NVS namespace and key used to keep a copy of the last good configuration
+const CONFIG_NVS_NAMESPACE: &str = "config";
+const CONFIG_NVS_KEY: &str = "toml";
+/// NVS strings are limited to 4000 bytes including the terminating NUL
+const CONFIG_NVS_MAX_LEN: usize = 4000;
+
+const DEFAULT_SYSTEM_PROMPT: &str = "接下来的请求来自一个语音转文字服务，请小心中间可能有一些字词被识别成同音的字词。请不要使用列表，不要包含*，回答保持一个段落。";
+const DEFAULT_GREETING: &str = "你好，乐鑫";
+const DEFAULT_EXIT_PHRASES: [&str; 3] = ["再见", "退下", "stop"];
+const DEFAULT_BLACKLIST_PATH: &str = "/vfat/blacklist.txt";
+const DEFAULT_FILTER_REPLACEMENT: &str = "哔";
+const DEFAULT_CACHE_ENTRIES: usize = 100;
+const DEFAULT_TRANSCRIPT_FILE_KB: u64 = 256;
+const DEFAULT_KEEP_RECORDINGS: usize = 20;
+const DEFAULT_MIN_SPEAKER_SIMILARITY: f32 = 0.6;
+const DEFAULT_THINKING_PHRASE: &str = "让我想想";
+const DEFAULT_THINKING_DELAY_MS: u64 = 1500;
+const DEFAULT_THINKING_INTERVAL_MS: u64 = 4000;
+const DEFAULT_MAX_CARD_USAGE_PERCENT: u8 = 90;
+const DEFAULT_LOW_SPACE_WARNING_MB: u64 = 20;
+const DEFAULT_SPACE_CHECK_INTERVAL_SECS: u64 = 60;
+const DEFAULT_LOG_FILE_KB: u64 = 256;
+const DEFAULT_PORTAL_TIMEOUT_SECS: u32 = 300;
+const DEFAULT_HOSTNAME: &str = "ai-chatbox";
+/// Longest hostname the DHCP client sends
+const MAX_HOSTNAME_LEN: usize = 30;
+const DEFAULT_MODEL_PARTITION: &str = "model";
+const DEFAULT_MODEL_PATH: &str = "/vfat";
+const DEFAULT_KEEP_BOOT_LOGS: u32 = 10;
+/// Standard syslog port
+const DEFAULT_REMOTE_LOG_PORT: u16 = 514;
+const DEFAULT_WEB_PORT: u16 = 80;
+const DEFAULT_MQTT_TOPIC_PREFIX: &str = "ai-chatbox";
+const DEFAULT_MQTT_STATUS_INTERVAL_SECS: u64 = 60;
+/// Port Home Assistant's Wyoming integration expects satellites on
+const DEFAULT_SATELLITE_PORT: u16 = 10700;
+/// Long enough for the contacts of a cheap push button to settle
+const DEFAULT_DEBOUNCE_MS: u64 = 50;
+/// Long enough that nobody does it by accident
+const DEFAULT_FACTORY_RESET_HOLD_SECS: u32 = 10;
+const DEFAULT_ENCODER_VOLUME_STEP: u8 = 5;
+/// Bright enough to see across a room without lighting it up
+const DEFAULT_LED_BRIGHTNESS: u8 = 30;
+/// Size of the common 1.3" ST7789 modules
+const DEFAULT_DISPLAY_WIDTH: u16 = 240;
+const DEFAULT_DISPLAY_HEIGHT: u16 = 240;
+/// Sharp enough to read a label, small enough to upload in a second or two
+const DEFAULT_CAMERA_JPEG_QUALITY: u8 = 12;
+/// Lowest quality esp32-camera accepts
+const MAX_CAMERA_JPEG_QUALITY: u8 = 63;
+/// Lowest clock the CPU runs at besides the crystal's, wake word detection has to keep up
+const DEFAULT_IDLE_CPU_MHZ: u32 = 80;
+/// Twice the default LLM timeout, a single read never blocks longer than a request may take
+const DEFAULT_WATCHDOG_TIMEOUT_SECS: u32 = 60;
+/// The audio tasks make progress many times a second, this long without any means they hang
+const DEFAULT_SUPERVISOR_STALL_SECS: u32 = 30;
+const DEFAULT_SUPERVISOR_MAX_RESTARTS: u32 = 3;
+const DEFAULT_MEMORY_INTERVAL_SECS: u32 = 60;
+/// TLS handshakes need around 40 KB of internal RAM, below this requests start failing
+const DEFAULT_MEMORY_FLOOR_KB: u32 = 48;
+const DEFAULT_METRICS_LOG_INTERVAL_MINS: u32 = 10;
+/// China Standard Time
+const DEFAULT_UTC_OFFSET_MINS: i32 = 8 * 60;
+/// Loud enough to keep listening, quiet enough for the microphone to make out the user
+const DEFAULT_DUCK_PERCENT: u8 = 20;
+/// Room for a story of several minutes
+const DEFAULT_STORY_MAX_TOKENS: u32 = 2048;
+/// A notch slower than the normal voice
+const DEFAULT_STORY_TTS_SPEED: u32 = 2;
+const DEFAULT_STORY_VOLUME_PERCENT: u8 = 60;
+const DEFAULT_STORY_MAX_MINUTES: u32 = 15;
+const DEFAULT_STORY_END_MARKER: &str = "讲完了";
+
+/// Credentials the firmware was built with, used until the configuration file provides them
+const BUILT_IN_WIFI_SSID: Option<&str> = option_env!("WIFI_SSID");
+const BUILT_IN_WIFI_PASS: Option<&str> = option_env!("WIFI_PASS");
+/// API key of the LLM service the firmware was built with, empty when none was given
+pub const BUILT_IN_API_KEY: &str = match option_env!("LLM_AUTH_TOKEN") {
+    Some(key) => key,
+    None => "",
+};
+/// Secret each device's BLE proof of possession is derived from, together with its MAC
+pub const BUILT_IN_BLE_POP_SECRET: Option<&str> = option_env!("BLE_POP_SECRET");
+
+/// Limits of the WiFi driver's configuration
+const MAX_SSID_LEN: usize = 32;
+const MAX_WIFI_PASS_LEN: usize = 64;
+const MIN_WIFI_PASS_LEN: usize = 8;
+/// Highest GPIO number of the ESP32-S3
+const MAX_GPIO: u8 = 48;
+/// GPIO numbers the ESP32-S3 doesn't have
+const MISSING_GPIOS: RangeInclusive<u8> = 22..=25;
+/// Wired to the SPI flash and the PSRAM
+const FLASH_GPIOS: RangeInclusive<u8> = 26..=32;
+/// Also wired to the PSRAM when it is octal, as on the N16R8 modules
+const OCTAL_PSRAM_GPIOS: RangeInclusive<u8> = 33..=37;
+/// MOSI, MISO, SCLK and CS of the SD card, fixed because the configuration lives on the card
+const SD_CARD_PINS: [u8; 4] = [9, 8, 7, 21];
+
+/// Settings describing who the assistant is and how it should answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AssistantConfig {
+    /// Name the assistant introduces itself with, empty to leave it unnamed
+    pub name: String,
+    /// Free form description of the assistant's personality
+    pub persona: String,
+    /// Base instructions sent as the system message of every session
+    pub system_prompt: String,
+    /// Phrase spoken once the device is ready
+    pub greeting: String,
+    /// Saying one of these ends the conversation, compared ignoring case, spaces and punctuation
+    pub exit_phrases: Vec<String>,
+}
+
+impl Default for AssistantConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            persona: String::new(),
+            system_prompt: DEFAULT_SYSTEM_PROMPT.to_string(),
+            greeting: DEFAULT_GREETING.to_string(),
+            exit_phrases: DEFAULT_EXIT_PHRASES.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+impl AssistantConfig {
+    /// Combine the name, persona and base prompt into the system message sent to the LLM
+    pub fn full_system_prompt(&self) -> String {
+        self.compose_prompt(&self.persona, &self.system_prompt)
+    }
+
+    /// System message for a switchable persona, falling back to the base prompt
+    pub fn persona_system_prompt(&self, persona: &PersonaConfig) -> String {
+        let base_prompt = persona
+            .system_prompt
+            .as_deref()
+            .unwrap_or(&self.system_prompt);
+        self.compose_prompt(&persona.persona, base_prompt)
+    }
+
+    fn compose_prompt(&self, persona: &str, base_prompt: &str) -> String {
+        let mut prompt = String::new();
+
+        if !self.name.is_empty() {
+            prompt.push_str(&format!("你的名字是{}。", self.name));
+        }
+
+        if !persona.is_empty() {
+            prompt.push_str(persona);
+            if !persona.ends_with('。') {
+                prompt.push('。');
+            }
+        }
+
+        prompt.push_str(base_prompt);
+        prompt
+    }
+}
+
+/// A named profile the user can switch to by voice ("切换到英语老师模式")
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PersonaConfig {
+    /// Name used in the switch command
+    pub name: String,
+    /// Description of the personality
+    pub persona: String,
+    /// Replaces the assistant's base system prompt when set
+    pub system_prompt: Option<String>,
+    /// Sampling temperature used while this persona is active
+    pub temperature: Option<f32>,
+    /// TTS speed (0-5) used while this persona is active
+    pub tts_speed: Option<u32>,
+}
+
+/// LLM services the assistant can talk to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmProviderKind {
+    #[default]
+    DeepSeek,
+    Anthropic,
+    Gemini,
+}
+
+/// One LLM service the conversation can be sent to
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProviderConfig {
+    /// Which API the conversation is sent to
+    pub provider: LlmProviderKind,
+    /// API key, defaults to the LLM_AUTH_TOKEN the firmware was built with
+    pub api_key: Option<String>,
+    /// Model name, defaults to a model suitable for the provider
+    pub model: Option<String>,
+    /// Endpoint URL, defaults to the provider's public API
+    pub endpoint: Option<String>,
+    /// Gemini safety threshold applied to all harm categories, e.g. "BLOCK_ONLY_HIGH"
+    pub safety_threshold: Option<String>,
+    /// Time allowed for one request, defaults to 30 seconds
+    pub timeout_secs: Option<u64>,
+    /// Stream replies so a timeout keeps the text received so far
+    pub stream: bool,
+}
+
+/// Settings for talking to the LLM service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LlmConfig {
+    /// Primary service, its fields sit directly in the [llm] table
+    #[serde(flatten)]
+    pub primary: ProviderConfig,
+    /// Services tried in order when the primary keeps failing
+    pub fallbacks: Vec<ProviderConfig>,
+    /// Ask for `{intent, slots, reply_text}` JSON replies so the device can act on requests
+    pub structured_output: bool,
+    /// Retry policy for rate limits, server errors and dropped connections
+    pub retry: RetryPolicy,
+    /// How much conversation is kept before old turns are summarized
+    pub history: HistoryBudget,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            primary: ProviderConfig::default(),
+            fallbacks: Vec::new(),
+            structured_output: false,
+            retry: RetryPolicy::llm(),
+            history: HistoryBudget::default(),
+        }
+    }
+}
+
+/// Filter applied to replies before they are spoken
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Add instructions for talking to children to the system prompt
+    pub kids_mode: bool,
+    /// File with one blocked word per line, lines starting with '#' are comments
+    pub blacklist_path: String,
+    /// Spoken in place of a blocked word
+    pub replacement: String,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            kids_mode: false,
+            blacklist_path: DEFAULT_BLACKLIST_PATH.to_string(),
+            replacement: DEFAULT_FILTER_REPLACEMENT.to_string(),
+        }
+    }
+}
+
+/// Cache of answers to repeated questions, kept on the SD card
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Answer repeated questions from the cache instead of asking the LLM
+    pub enabled: bool,
+    /// Least recently used answers are dropped beyond this many entries
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_entries: DEFAULT_CACHE_ENTRIES,
+        }
+    }
+}
+
+/// Log of what the device heard and answered, kept on the SD card
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TranscriptConfig {
+    /// Append every utterance and reply to /vfat/transcripts/YYYYMMDD.txt
+    pub enabled: bool,
+    /// A day's file is rotated once it grows beyond this size
+    pub max_file_kb: u64,
+}
+
+impl Default for TranscriptConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_file_kb: DEFAULT_TRANSCRIPT_FILE_KB,
+        }
+    }
+}
+
+/// What happens to a recording on the SD card once it was transcribed
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionPolicy {
+    /// Leave every recording on the card
+    Keep,
+    #[default]
+    Delete,
+    /// Keep the most recent `keep_last` recordings
+    KeepLast,
+    /// Move recordings to /vfat/archive/
+    Archive,
+}
+
+/// Retention of the utterance recordings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    pub retention: RetentionPolicy,
+    /// Recordings kept by the keep_last policy
+    pub keep_last: usize,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            retention: RetentionPolicy::default(),
+            keep_last: DEFAULT_KEEP_RECORDINGS,
+        }
+    }
+}
+
+/// What the device plays while waiting for the LLM to answer
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThinkingSound {
+    /// Stay silent until the reply is spoken
+    Off,
+    /// A soft tone, repeated until the reply arrives
+    #[default]
+    Earcon,
+    /// Say the filler phrase once, then repeat the soft tone
+    Phrase,
+}
+
+/// Feedback while a reply is on its way, so the silence doesn't sound like a hang
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThinkingConfig {
+    pub sound: ThinkingSound,
+    pub phrase: String,
+    /// Quick replies shouldn't be preceded by a sound, nothing is played before this delay
+    pub delay_ms: u64,
+    pub interval_ms: u64,
+}
+
+impl Default for ThinkingConfig {
+    fn default() -> Self {
+        Self {
+            sound: ThinkingSound::default(),
+            phrase: DEFAULT_THINKING_PHRASE.to_string(),
+            delay_ms: DEFAULT_THINKING_DELAY_MS,
+            interval_ms: DEFAULT_THINKING_INTERVAL_MS,
+        }
+    }
+}
+
+/// Keeps the SD card from filling up with recordings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Oldest recordings are deleted once more of the card than this is in use
+    pub max_usage_percent: u8,
+    /// Warn the user when less than this is left after cleaning up
+    pub low_space_warning_mb: u64,
+    pub check_interval_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            max_usage_percent: DEFAULT_MAX_CARD_USAGE_PERCENT,
+            low_space_warning_mb: DEFAULT_LOW_SPACE_WARNING_MB,
+            check_interval_secs: DEFAULT_SPACE_CHECK_INTERVAL_SECS,
+        }
+    }
+}
+
+/// Where the wake word and command models are loaded from
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelLocation {
+    /// The flash partition when the partition table has one and the firmware was built with
+    /// CONFIG_MODEL_IN_FLASH, otherwise the SD card
+    #[default]
+    Auto,
+    Flash,
+    SdCard,
+}
+
+/// ESP-SR models, read at boot only
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeechModelConfig {
+    pub location: ModelLocation,
+    /// Flash partition holding srmodels.bin
+    pub partition: String,
+    /// Directory with one subdirectory per model
+    pub path: String,
+}
+
+impl Default for SpeechModelConfig {
+    fn default() -> Self {
+        Self {
+            location: ModelLocation::default(),
+            partition: DEFAULT_MODEL_PARTITION.to_string(),
+            path: DEFAULT_MODEL_PATH.to_string(),
+        }
+    }
+}
+
+/// Copy of the log on the SD card, for diagnosing devices without a serial console attached
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Mirror the log to /vfat/logs/boot-N.log
+    pub enabled: bool,
+    /// A boot's log is rotated to boot-N.1.log once it grows beyond this size
+    pub max_file_kb: u64,
+    /// Logs of older boots are deleted
+    pub keep_boots: u32,
+    /// Read at boot only
+    pub remote: RemoteLogConfig,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_file_kb: DEFAULT_LOG_FILE_KB,
+            keep_boots: DEFAULT_KEEP_BOOT_LOGS,
+            remote: RemoteLogConfig::default(),
+        }
+    }
+}
+
+/// How log lines are sent to a collector on the network
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteLogProtocol {
+    /// RFC 5424 messages over UDP
+    #[default]
+    Syslog,
+    /// Plain lines over a TCP connection, e.g. to `nc -lk 9000`
+    Tcp,
+}
+
+/// Copy of the log sent over the network, for devices out of reach of a cable or card reader
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteLogConfig {
+    /// Collector to send to, empty turns remote logging off
+    pub host: String,
+    pub port: u16,
+    pub protocol: RemoteLogProtocol,
+}
+
+impl Default for RemoteLogConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: DEFAULT_REMOTE_LOG_PORT,
+            protocol: RemoteLogProtocol::default(),
+        }
+    }
+}
+
+/// Firmware updates over HTTPS
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtaConfig {
+    /// JSON manifest describing the latest firmware, empty turns updates off
+    pub manifest_url: String,
+    /// Hours between automatic checks, 0 only checks when asked
+    pub check_interval_hours: u32,
+    /// Install updates found by the automatic check instead of only logging them
+    pub auto_install: bool,
+}
+
+/// Status page and control API served on the local network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// Required as `Authorization: Bearer <token>` by the API, which refuses everything without one
+    pub token: String,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: DEFAULT_WEB_PORT,
+            token: String::new(),
+        }
+    }
+}
+
+/// Telemetry and remote control through an MQTT broker, e.g. the one of a home automation system
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// Broker URL like "mqtt://192.168.1.2:1883" or "mqtts://...", empty turns MQTT off
+    pub url: String,
+    /// Defaults to network.hostname
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Every topic starts with it, e.g. "ai-chatbox/transcript"
+    pub topic_prefix: String,
+    /// How often status and metrics are published
+    pub status_interval_secs: u64,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            client_id: None,
+            username: None,
+            password: None,
+            topic_prefix: DEFAULT_MQTT_TOPIC_PREFIX.to_string(),
+            status_interval_secs: DEFAULT_MQTT_STATUS_INTERVAL_SECS,
+        }
+    }
+}
+
+/// Wyoming satellite for Home Assistant: wake word and recording stay on the device, Home
+/// Assistant's voice pipeline transcribes, answers and speaks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SatelliteConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// Shown in Home Assistant, defaults to network.hostname
+    pub name: Option<String>,
+}
+
+impl Default for SatelliteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: DEFAULT_SATELLITE_PORT,
+            name: None,
+        }
+    }
+}
+
+/// Saving power while nobody talks to the device, for battery-powered builds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PowerConfig {
+    /// Minutes without a wake word, a button press or a reply before idling, 0 never idles
+    pub idle_minutes: u32,
+    /// CPU clock while idle, 80 or 160; WiFi sleeps as with `wifi.power_save = "max"` too
+    pub idle_cpu_mhz: u32,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            idle_minutes: 0,
+            idle_cpu_mhz: DEFAULT_IDLE_CPU_MHZ,
+        }
+    }
+}
+
+/// Task watchdog that resets the device when the audio or transcription threads hang
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// Time a watched thread may go without progress, 0 turns the watchdog off
+    pub timeout_secs: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: DEFAULT_WATCHDOG_TIMEOUT_SECS,
+        }
+    }
+}
+
+/// Restarting the pipeline tasks when they fail, and the device when that doesn't help
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SupervisorConfig {
+    /// Time the audio tasks may go without a heartbeat before the device restarts, 0 never
+    pub stall_secs: u32,
+    /// Failures of a task within ten minutes that are restarted, one more restarts the device
+    pub max_restarts: u32,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            stall_secs: DEFAULT_SUPERVISOR_STALL_SECS,
+            max_restarts: DEFAULT_SUPERVISOR_MAX_RESTARTS,
+        }
+    }
+}
+
+/// Periodic sampling of free memory, to notice leaks before they crash the device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryConfig {
+    /// Time between samples, 0 turns sampling off
+    pub interval_secs: u32,
+    /// Warn when free internal RAM averaged over the last samples falls below this
+    pub floor_kb: u32,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: DEFAULT_MEMORY_INTERVAL_SECS,
+            floor_kb: DEFAULT_MEMORY_FLOOR_KB,
+        }
+    }
+}
+
+/// Summary of the counters, gauges and timers in the log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Time between summaries, 0 only logs them on request
+    pub log_interval_mins: u32,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            log_interval_mins: DEFAULT_METRICS_LOG_INTERVAL_MINS,
+        }
+    }
+}
+
+/// Timers and alarms set by voice, kept across restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlarmsConfig {
+    pub enabled: bool,
+    /// Local time zone, alarms at a time of day ring by it
+    pub utc_offset_mins: i32,
+}
+
+impl Default for AlarmsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            utc_offset_mins: DEFAULT_UTC_OFFSET_MINS,
+        }
+    }
+}
+
+/// An internet radio station, played by voice ("播放新闻电台")
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StationConfig {
+    /// Name used in the play command
+    pub name: String,
+    /// HTTP stream of MP3 or AAC audio
+    pub url: String,
+}
+
+/// Internet radio station presets
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RadioConfig {
+    pub stations: Vec<StationConfig>,
+}
+
+impl RadioConfig {
+    /// Station asked for by `name`, the first one when no name was given
+    pub fn find_station(&self, name: &str) -> Option<&StationConfig> {
+        if name.is_empty() {
+            return self.stations.first();
+        }
+        // "播放新闻" and "播放新闻电台" both mean the station named "新闻"
+        self.stations
+            .iter()
+            .find(|station| name.contains(station.name.as_str()) || station.name.contains(name))
+    }
+}
+
+/// Music from the card and radio stations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MusicConfig {
+    /// Volume while the user talks after the wake word, in percent of the normal volume, 0
+    /// pauses the stream instead
+    pub duck_percent: u8,
+}
+
+impl Default for MusicConfig {
+    fn default() -> Self {
+        Self {
+            duck_percent: DEFAULT_DUCK_PERCENT,
+        }
+    }
+}
+
+/// A device switched by publishing to MQTT, e.g. a zigbee2mqtt light
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SmartHomeDeviceConfig {
+    /// Name used in commands, "打开客厅灯" for "客厅灯"
+    pub name: String,
+    /// Topic the payloads are published to
+    pub topic: String,
+    pub on_payload: Option<String>,
+    pub off_payload: Option<String>,
+    /// For "把客厅灯调到50", `{value}` is replaced by the number
+    pub set_payload: Option<String>,
+    /// MultiNet phrases in pinyin like "da kai ke ting deng", so the device can be switched offline
+    pub on_pinyin: Option<String>,
+    pub off_pinyin: Option<String>,
+}
+
+/// Devices controlled by voice through the MQTT broker
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SmartHomeConfig {
+    pub devices: Vec<SmartHomeDeviceConfig>,
+}
+
+impl SmartHomeConfig {
+    /// Device named `name`, the LLM may leave out or add words, "灯" or "客厅灯光"
+    ///
+    /// Such a name has to fit a single device, the error lists the names of the devices it
+    /// could mean, none when it fits no device.
+    pub fn find_device(&self, name: &str) -> Result<&SmartHomeDeviceConfig, Vec<&str>> {
+        if name.is_empty() {
+            return Err(Vec::new());
+        }
+        if let Some(device) = self.devices.iter().find(|device| device.name == name) {
+            return Ok(device);
+        }
+        let matches: Vec<&SmartHomeDeviceConfig> = self
+            .devices
+            .iter()
+            .filter(|device| name.contains(device.name.as_str()) || device.name.contains(name))
+            .collect();
+        match matches.as_slice() {
+            [device] => Ok(device),
+            _ => Err(matches.iter().map(|device| device.name.as_str()).collect()),
+        }
+    }
+}
+
+/// Bedtime stories told by "讲个睡前故事"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoryConfig {
+    /// Reply tokens of a story, instead of the usual limit
+    pub max_tokens: u32,
+    /// TTS speed (0-5) while telling a story
+    pub tts_speed: u32,
+    /// Volume while telling a story, in percent of the normal volume
+    pub volume_percent: u8,
+    /// The story is cut off after this long, whether or not it ended
+    pub max_minutes: u32,
+    /// The LLM ends the story with this, anything after it isn't spoken
+    pub end_marker: String,
+}
+
+impl Default for StoryConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: DEFAULT_STORY_MAX_TOKENS,
+            tts_speed: DEFAULT_STORY_TTS_SPEED,
+            volume_percent: DEFAULT_STORY_VOLUME_PERCENT,
+            max_minutes: DEFAULT_STORY_MAX_MINUTES,
+            end_marker: DEFAULT_STORY_END_MARKER.to_string(),
+        }
+    }
+}
+
+/// Check the microphone, speaker, storage and network once at boot and say what is broken
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SelfTestConfig {
+    pub enabled: bool,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Recognizing who is talking from the voice print the speech to text service computes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeakerConfig {
+    /// Personalize replies with the profiles in /vfat/speakers/
+    pub enabled: bool,
+    /// Cosine similarity a voice print needs to be attributed to a profile
+    pub min_similarity: f32,
+}
+
+impl Default for SpeakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_similarity: DEFAULT_MIN_SPEAKER_SIMILARITY,
+        }
+    }
+}
+
+/// Diagnostics of the requests sent to the cloud services
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Log method, URL, duration and status of every request, tagged with the turn it belongs to
+    pub trace: bool,
+}
+
+/// Network the device joins at boot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WifiConfig {
+    pub ssid: String,
+    /// Empty for an open network
+    pub password: String,
+    /// How the network is set up when none of the known ones connects
+    pub provisioning: ProvisioningMode,
+    /// How long provisioning waits for someone to pick a network, 0 turns it off
+    pub portal_timeout_secs: u32,
+    /// Proof of possession the BLE provisioning app asks for, derived from the MAC and
+    /// BLE_POP_SECRET when empty
+    pub ble_pop: String,
+    /// How much the modem sleeps between beacons while connected
+    pub power_save: PowerSaveMode,
+}
+
+/// Modem sleep while connected, saving power at the cost of slower reactions to incoming traffic
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerSaveMode {
+    /// Radio always on, lowest latency
+    None,
+    /// Wake for every DTIM beacon, the ESP-IDF default
+    #[default]
+    Min,
+    /// Wake only every few beacons, for battery-powered builds
+    Max,
+}
+
+/// Way of entering WiFi credentials on a device that can't connect
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProvisioningMode {
+    /// Temporary access point with a setup page
+    #[default]
+    #[serde(rename = "softap")]
+    SoftAp,
+    /// Espressif's BLE provisioning, set up from their phone apps
+    Ble,
+}
+
+impl Default for WifiConfig {
+    fn default() -> Self {
+        Self {
+            ssid: BUILT_IN_WIFI_SSID.unwrap_or_default().to_string(),
+            password: BUILT_IN_WIFI_PASS.unwrap_or_default().to_string(),
+            provisioning: ProvisioningMode::default(),
+            portal_timeout_secs: DEFAULT_PORTAL_TIMEOUT_SECS,
+            ble_pop: String::new(),
+            power_save: PowerSaveMode::default(),
+        }
+    }
+}
+
+/// Addressing of the station interface, read at boot only
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// Name sent to the DHCP server, shown in the router's client list
+    pub hostname: String,
+    /// Fixed address instead of DHCP, for networks where DHCP doesn't work
+    pub static_ip: Option<Ipv4Addr>,
+    /// Network prefix length of `static_ip`, 24 is 255.255.255.0
+    pub prefix_len: u8,
+    /// Required with `static_ip`
+    pub gateway: Option<Ipv4Addr>,
+    /// Up to two DNS servers, used instead of the ones from DHCP
+    pub dns: Vec<Ipv4Addr>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            hostname: DEFAULT_HOSTNAME.to_string(),
+            static_ip: None,
+            prefix_len: 24,
+            gateway: None,
+            dns: Vec::new(),
+        }
+    }
+}
+
+/// GPIO numbers of the microphone and the amplifier, the defaults match the XIAO ESP32S3 Sense
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PinConfig {
+    /// PDM microphone clock
+    pub mic_clk: u8,
+    /// PDM microphone data
+    pub mic_data: u8,
+    /// MAX98357 bit clock
+    pub speaker_bclk: u8,
+    /// MAX98357 word select (LRCLK)
+    pub speaker_ws: u8,
+    /// MAX98357 data in
+    pub speaker_dout: u8,
+    /// MAX98357 SD pin, pulled low to shut the amplifier down
+    pub speaker_enable: u8,
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        Self {
+            mic_clk: 42,
+            mic_data: 41,
+            speaker_bclk: 2,
+            speaker_ws: 1,
+            speaker_dout: 3,
+            speaker_enable: 5,
+        }
+    }
+}
+
+impl PinConfig {
+    fn named_pins(&self) -> [(&'static str, u8); 6] {
+        [
+            ("pins.mic_clk", self.mic_clk),
+            ("pins.mic_data", self.mic_data),
+            ("pins.speaker_bclk", self.speaker_bclk),
+            ("pins.speaker_ws", self.speaker_ws),
+            ("pins.speaker_dout", self.speaker_dout),
+            ("pins.speaker_enable", self.speaker_enable),
+        ]
+    }
+}
+
+/// Push buttons, each wired between its GPIO and ground; buttons without a pin aren't used
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ButtonConfig {
+    pub mute: Option<u8>,
+    pub volume_up: Option<u8>,
+    pub volume_down: Option<u8>,
+    /// Stops the reply being spoken, and the one still on its way
+    pub stop: Option<u8>,
+    /// Starts listening as if the wake word was heard
+    pub talk: Option<u8>,
+    /// The buttons connect their pins to 3.3V instead of ground
+    pub active_high: bool,
+    /// How long a press must last, shorter pulses are contact bounce
+    pub debounce_ms: u64,
+    /// Holding any button this long wipes settings and conversations, 0 turns that off
+    pub factory_reset_hold_secs: u32,
+}
+
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        Self {
+            mute: None,
+            volume_up: None,
+            volume_down: None,
+            stop: None,
+            talk: None,
+            active_high: false,
+            debounce_ms: DEFAULT_DEBOUNCE_MS,
+            factory_reset_hold_secs: DEFAULT_FACTORY_RESET_HOLD_SECS,
+        }
+    }
+}
+
+/// Quadrature rotary encoder, turning it changes the volume
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncoderConfig {
+    /// A and B outputs, the encoder is only used with both set
+    pub pin_a: Option<u8>,
+    pub pin_b: Option<u8>,
+    /// Push switch of the knob, wired like the buttons
+    pub switch: Option<u8>,
+    /// Same actions as the buttons
+    pub switch_action: ButtonAction,
+    /// Volume change per detent, in percent
+    pub volume_step: u8,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            pin_a: None,
+            pin_b: None,
+            switch: None,
+            switch_action: ButtonAction::default(),
+            volume_step: DEFAULT_ENCODER_VOLUME_STEP,
+        }
+    }
+}
+
+impl EncoderConfig {
+    fn named_pins(&self) -> Vec<(&'static str, u8)> {
+        [
+            ("encoder.pin_a", self.pin_a),
+            ("encoder.pin_b", self.pin_b),
+            ("encoder.switch", self.switch),
+        ]
+        .into_iter()
+        .filter_map(|(name, pin)| pin.map(|pin| (name, pin)))
+        .collect()
+    }
+}
+
+/// WS2812 or SK68xx RGB LED showing what the device is doing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LedConfig {
+    /// Data pin, no LED is driven when unset
+    pub pin: Option<u8>,
+    /// In percent of full brightness
+    pub brightness: u8,
+}
+
+impl Default for LedConfig {
+    fn default() -> Self {
+        Self {
+            pin: None,
+            brightness: DEFAULT_LED_BRIGHTNESS,
+        }
+    }
+}
+
+/// Controller of the display
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisplayDriver {
+    /// 128x64 monochrome OLED on I2C
+    Ssd1306,
+    /// Color LCD on SPI
+    St7789,
+}
+
+/// Small screen showing the status, what was heard and the reply
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    /// No display is driven when unset
+    pub driver: Option<DisplayDriver>,
+    /// I2C pins of the SSD1306
+    pub sda: Option<u8>,
+    pub scl: Option<u8>,
+    /// SPI pins of the ST7789, `cs` and `backlight` are optional
+    pub sclk: Option<u8>,
+    pub mosi: Option<u8>,
+    pub cs: Option<u8>,
+    pub dc: Option<u8>,
+    pub rst: Option<u8>,
+    pub backlight: Option<u8>,
+    /// Size of the ST7789 panel in pixels
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            driver: None,
+            sda: None,
+            scl: None,
+            sclk: None,
+            mosi: None,
+            cs: None,
+            dc: None,
+            rst: None,
+            backlight: None,
+            width: DEFAULT_DISPLAY_WIDTH,
+            height: DEFAULT_DISPLAY_HEIGHT,
+        }
+    }
+}
+
+impl DisplayConfig {
+    fn named_pins(&self) -> Vec<(&'static str, u8)> {
+        [
+            ("display.sda", self.sda),
+            ("display.scl", self.scl),
+            ("display.sclk", self.sclk),
+            ("display.mosi", self.mosi),
+            ("display.cs", self.cs),
+            ("display.dc", self.dc),
+            ("display.rst", self.rst),
+            ("display.backlight", self.backlight),
+        ]
+        .into_iter()
+        .filter_map(|(name, pin)| pin.map(|pin| (name, pin)))
+        .collect()
+    }
+
+    /// Names of the pins `driver` can't do without that are unset
+    fn missing_pins(&self) -> Vec<&'static str> {
+        let required = match self.driver {
+            None => vec![],
+            Some(DisplayDriver::Ssd1306) => {
+                vec![("display.sda", self.sda), ("display.scl", self.scl)]
+            }
+            Some(DisplayDriver::St7789) => vec![
+                ("display.sclk", self.sclk),
+                ("display.mosi", self.mosi),
+                ("display.dc", self.dc),
+                ("display.rst", self.rst),
+            ],
+        };
+        required
+            .into_iter()
+            .filter(|(_, pin)| pin.is_none())
+            .map(|(name, _)| name)
+            .collect()
+    }
+}
+
+/// Resolution of the pictures taken
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CameraFrameSize {
+    /// 320x240
+    Qvga,
+    /// 640x480
+    Vga,
+    /// 800x600
+    Svga,
+    /// 1024x768
+    Xga,
+}
+
+/// Camera for "看看这是什么", the default pins match the OV2640 of the XIAO ESP32S3 Sense
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraConfig {
+    /// Off unless a sensor is connected, its pins are free for other uses then
+    pub enabled: bool,
+    /// Power down and reset pins, unset on boards that tie them off
+    pub pwdn: Option<u8>,
+    pub reset: Option<u8>,
+    /// Clock driven to the sensor
+    pub xclk: u8,
+    /// SCCB (I2C) pins of the sensor
+    pub sda: u8,
+    pub scl: u8,
+    /// D0 to D7
+    pub data: [u8; 8],
+    pub vsync: u8,
+    pub href: u8,
+    pub pclk: u8,
+    pub frame_size: CameraFrameSize,
+    /// JPEG quality from 0 to 63, lower is better and larger
+    pub jpeg_quality: u8,
+    /// Vision capable service the pictures go to, the conversation's services when unset
+    pub vision: Option<ProviderConfig>,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pwdn: None,
+            reset: None,
+            xclk: 10,
+            sda: 40,
+            scl: 39,
+            data: [15, 17, 18, 16, 14, 12, 11, 48],
+            vsync: 38,
+            href: 47,
+            pclk: 13,
+            frame_size: CameraFrameSize::Vga,
+            jpeg_quality: DEFAULT_CAMERA_JPEG_QUALITY,
+            vision: None,
+        }
+    }
+}
+
+impl CameraConfig {
+    /// Pins in use, none while the camera is disabled
+    fn named_pins(&self) -> Vec<(&'static str, u8)> {
+        if !self.enabled {
+            return Vec::new();
+        }
+        let mut pins = vec![
+            ("camera.xclk", self.xclk),
+            ("camera.sda", self.sda),
+            ("camera.scl", self.scl),
+            ("camera.vsync", self.vsync),
+            ("camera.href", self.href),
+            ("camera.pclk", self.pclk),
+        ];
+        pins.extend(self.pwdn.map(|pin| ("camera.pwdn", pin)));
+        pins.extend(self.reset.map(|pin| ("camera.reset", pin)));
+        pins.extend(self.data.iter().map(|&pin| ("camera.data", pin)));
+        pins
+    }
+}
+
+impl ButtonConfig {
+    fn named_pins(&self) -> Vec<(&'static str, u8)> {
+        [
+            ("buttons.mute", self.mute),
+            ("buttons.volume_up", self.volume_up),
+            ("buttons.volume_down", self.volume_down),
+            ("buttons.stop", self.stop),
+            ("buttons.talk", self.talk),
+        ]
+        .into_iter()
+        .filter_map(|(name, pin)| pin.map(|pin| (name, pin)))
+        .collect()
+    }
+}
+
+/// How utterances in one language are answered, e.g. the `[languages.en]` table
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LanguageConfig {
+    /// Replaces the assistant's or persona's prompt while the user speaks this language
+    pub system_prompt: Option<String>,
+    /// Speak replies with the cloud voice, the on-device voice only speaks Chinese
+    pub cloud_tts: bool,
+}
+
+/// Root of the configuration file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub wifi: WifiConfig,
+    pub network: NetworkConfig,
+    /// Read at boot only, changing them takes a reboot
+    pub pins: PinConfig,
+    /// Read at boot only
+    pub buttons: ButtonConfig,
+    /// Read at boot only
+    pub encoder: EncoderConfig,
+    /// Read at boot only
+    pub led: LedConfig,
+    /// Read at boot only
+    pub display: DisplayConfig,
+    /// The sensor is set up at boot only
+    pub camera: CameraConfig,
+    pub speech_models: SpeechModelConfig,
+    pub assistant: AssistantConfig,
+    pub llm: LlmConfig,
+    pub personas: Vec<PersonaConfig>,
+    pub filter: FilterConfig,
+    pub cache: CacheConfig,
+    pub transcripts: TranscriptConfig,
+    pub recordings: RecordingConfig,
+    pub thinking: ThinkingConfig,
+    pub speakers: SpeakerConfig,
+    pub stt: SttConfig,
+    /// Per language overrides keyed by language code ("zh", "en")
+    pub languages: BTreeMap<String, LanguageConfig>,
+    pub cloud_tts: CloudTtsConfig,
+    pub http: HttpConfig,
+    pub storage: StorageConfig,
+    pub log: LogConfig,
+    pub ota: OtaConfig,
+    pub web: WebConfig,
+    pub mqtt: MqttConfig,
+    /// Read at boot only
+    pub satellite: SatelliteConfig,
+    /// Read at boot only
+    pub power: PowerConfig,
+    /// Read at boot only
+    pub watchdog: WatchdogConfig,
+    /// Read at boot only
+    pub supervisor: SupervisorConfig,
+    /// Read at boot only
+    pub memory: MemoryConfig,
+    /// Read at boot only
+    pub metrics: MetricsConfig,
+    /// Read at boot only
+    pub self_test: SelfTestConfig,
+    /// Read at boot only
+    pub alarms: AlarmsConfig,
+    pub radio: RadioConfig,
+    /// Read at boot only
+    pub music: MusicConfig,
+    /// The MultiNet phrases are read at boot only
+    pub smart_home: SmartHomeConfig,
+    pub story: StoryConfig,
+    pub knowledge: KnowledgeConfig,
+}
+
+impl AppConfig {
+    /// Look up a persona profile by name
+    pub fn find_persona(&self, name: &str) -> Option<&PersonaConfig> {
+        self.personas.iter().find(|p| p.name == name)
+    }
+
+    /// Overrides for a language, if any are configured
+    pub fn language(&self, language: Language) -> Option<&LanguageConfig> {
+        self.languages.get(language.code())
+    }
+
+    /// Features this configuration turns on, advertised to companion apps over mDNS
+    pub fn capabilities(&self) -> Vec<&'static str> {
+        let mut capabilities = vec!["wake-word", "offline-commands"];
+        if self.stt.streaming.is_some() {
+            capabilities.push("streaming-stt");
+        }
+        if self.cloud_tts.api_key.is_some() {
+            capabilities.push("cloud-tts");
+        }
+        if self.llm.structured_output {
+            capabilities.push("intents");
+        }
+        if self.speakers.enabled {
+            capabilities.push("speakers");
+        }
+        if !self.personas.is_empty() {
+            capabilities.push("personas");
+        }
+        if self.knowledge.enabled {
+            capabilities.push("knowledge");
+        }
+        if self.camera.enabled {
+            capabilities.push("camera");
+        }
+        if !self.ota.manifest_url.is_empty() {
+            capabilities.push("ota");
+        }
+        if self.web.enabled {
+            capabilities.push("web");
+        }
+        if !self.mqtt.url.is_empty() {
+            capabilities.push("mqtt");
+        }
+        if self.satellite.enabled {
+            capabilities.push("wyoming");
+        }
+        if !self.radio.stations.is_empty() {
+            capabilities.push("radio");
+        }
+        if !self.smart_home.devices.is_empty() {
+            capabilities.push("smart-home");
+        }
+        capabilities
+    }
+
+    /// Parse configuration from TOML text, missing fields take their default values
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let config: Self =
+            toml::from_str(text).map_err(|e| anyhow::anyhow!("Invalid configuration: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the values the types alone don't rule out, reporting every problem at once
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+
+        if self.wifi.ssid.len() > MAX_SSID_LEN {
+            problems.push(format!("wifi.ssid is longer than {} bytes", MAX_SSID_LEN));
+        }
+        let pass_len = self.wifi.password.len();
+        if pass_len > MAX_WIFI_PASS_LEN || (pass_len > 0 && pass_len < MIN_WIFI_PASS_LEN) {
+            problems.push(format!(
+                "wifi.password must be empty or {} to {} bytes long",
+                MIN_WIFI_PASS_LEN, MAX_WIFI_PASS_LEN
+            ));
+        }
+
+        if self.wifi.provisioning == ProvisioningMode::Ble
+            && self.wifi.ble_pop.is_empty()
+            && BUILT_IN_BLE_POP_SECRET.is_none()
+        {
+            problems.push(
+                "wifi.ble_pop must be set when the firmware is built without BLE_POP_SECRET"
+                    .to_string(),
+            );
+        }
+        // WiFi and Bluetooth take turns on the radio, which needs the modem to sleep
+        if self.wifi.provisioning == ProvisioningMode::Ble
+            && self.wifi.power_save == PowerSaveMode::None
+        {
+            problems.push(
+                "wifi.power_save = \"none\" doesn't work with provisioning = \"ble\"".to_string(),
+            );
+        }
+
+        let hostname = &self.network.hostname;
+        if hostname.is_empty()
+            || hostname.len() > MAX_HOSTNAME_LEN
+            || !hostname.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            problems.push(format!(
+                "network.hostname must be 1 to {} letters, digits or '-'",
+                MAX_HOSTNAME_LEN
+            ));
+        }
+        if self.network.static_ip.is_some() && self.network.gateway.is_none() {
+            problems.push("network.gateway is required with network.static_ip".to_string());
+        }
+        if !(1..=32).contains(&self.network.prefix_len) {
+            problems.push("network.prefix_len must be between 1 and 32".to_string());
+        }
+        if self.network.dns.len() > 2 {
+            problems.push("network.dns takes at most two servers".to_string());
+        }
+
+        // The drivers take their pins by number, which is only sound because every pin in use is
+        // checked here: one the module leaves free, off the flash, the PSRAM and the SD card, and
+        // not taken twice. The display's required pins are checked with its other settings.
+        let mut pins = self.pins.named_pins().to_vec();
+        pins.extend(self.buttons.named_pins());
+        pins.extend(self.encoder.named_pins());
+        pins.extend(self.led.pin.map(|pin| ("led.pin", pin)));
+        pins.extend(self.display.named_pins());
+        pins.extend(self.camera.named_pins());
+        for (i, (name, pin)) in pins.iter().enumerate() {
+            if !is_usable_gpio(*pin) {
+                problems.push(format!("{} = {} is not a usable GPIO", name, pin));
+            } else if SD_CARD_PINS.contains(pin) {
+                problems.push(format!("{} = {} is used by the SD card", name, pin));
+            } else if let Some((other, _)) = pins[..i].iter().find(|(_, p)| p == pin) {
+                problems.push(format!("{} and {} are both {}", other, name, pin));
+            }
+        }
+
+        if self.encoder.pin_a.is_some() != self.encoder.pin_b.is_some() {
+            problems.push("encoder.pin_a and encoder.pin_b must be set together".to_string());
+        }
+        if !(1..=50).contains(&self.encoder.volume_step) {
+            problems.push("encoder.volume_step must be between 1 and 50".to_string());
+        }
+        if self.led.brightness > 100 {
+            problems.push("led.brightness must not be above 100".to_string());
+        }
+        if ![80, 160].contains(&self.power.idle_cpu_mhz) {
+            problems.push("power.idle_cpu_mhz must be 80 or 160".to_string());
+        }
+        for name in self.display.missing_pins() {
+            problems.push(format!("{} is required by display.driver", name));
+        }
+        if self.display.driver == Some(DisplayDriver::St7789)
+            && (self.display.width == 0 || self.display.height == 0)
+        {
+            problems.push("display.width and display.height must not be 0".to_string());
+        }
+        if self.camera.jpeg_quality > MAX_CAMERA_JPEG_QUALITY {
+            problems.push(format!(
+                "camera.jpeg_quality must not be above {}",
+                MAX_CAMERA_JPEG_QUALITY
+            ));
+        }
+
+        let providers = std::iter::once(&self.llm.primary)
+            .chain(&self.llm.fallbacks)
+            .chain(&self.camera.vision);
+        for endpoint in providers.filter_map(|p| p.endpoint.as_deref()) {
+            if !is_http_url(endpoint) {
+                problems.push(format!("LLM endpoint '{}' is not an http(s) URL", endpoint));
+            }
+        }
+        // A single blocking request mustn't outlast the watchdog
+        let watchdog_timeout = self.watchdog.timeout_secs as u64;
+        let providers = std::iter::once(&self.llm.primary)
+            .chain(&self.llm.fallbacks)
+            .chain(&self.camera.vision);
+        let request_timeouts = providers
+            .filter_map(|p| p.timeout_secs)
+            .chain([self.stt.timeout_secs, self.cloud_tts.timeout_secs]);
+        if watchdog_timeout > 0 && request_timeouts.max().unwrap_or(0) >= watchdog_timeout {
+            problems.push(
+                "watchdog.timeout_secs must be longer than the LLM, stt and cloud_tts timeouts"
+                    .to_string(),
+            );
+        }
+        if let Some(url) = self.stt.url.as_deref().filter(|url| !is_http_url(url)) {
+            problems.push(format!("stt.url '{}' is not an http(s) URL", url));
+        }
+
+        for station in &self.radio.stations {
+            if station.name.is_empty() {
+                problems.push("a radio station has no name".to_string());
+            }
+            if !is_http_url(&station.url) {
+                problems.push(format!(
+                    "radio station '{}' has no http(s) URL",
+                    station.name
+                ));
+            }
+        }
+
+        if !self.smart_home.devices.is_empty() && self.mqtt.url.is_empty() {
+            problems.push("smart_home devices need mqtt.url".to_string());
+        }
+        for device in &self.smart_home.devices {
+            if device.name.is_empty() {
+                problems.push("a smart_home device has no name".to_string());
+            }
+            if device.topic.is_empty() || device.topic.contains(['+', '#']) {
+                problems.push(format!(
+                    "smart_home device '{}' needs a topic without wildcards",
+                    device.name
+                ));
+            }
+            let pinyin = [&device.on_pinyin, &device.off_pinyin];
+            if pinyin.into_iter().flatten().any(|phrase| !is_pinyin(phrase)) {
+                problems.push(format!(
+                    "smart_home device '{}' pinyin must be lowercase letters and spaces",
+                    device.name
+                ));
+            }
+        }
+
+        let mut persona_names = std::collections::BTreeSet::new();
+        for persona in &self.personas {
+            if persona.name.is_empty() {
+                problems.push("a persona has no name".to_string());
+            } else if !persona_names.insert(persona.name.as_str()) {
+                problems.push(format!("persona '{}' is defined twice", persona.name));
+            }
+            if let Some(temperature) = persona.temperature.filter(|t| !(0.0..=2.0).contains(t)) {
+                problems.push(format!(
+                    "persona '{}' has temperature {}, expected 0 to 2",
+                    persona.name, temperature
+                ));
+            }
+        }
+
+        if self.speech_models.location != ModelLocation::SdCard
+            && self.speech_models.partition.is_empty()
+        {
+            problems.push("speech_models.partition must not be empty".to_string());
+        }
+        if self.speech_models.location != ModelLocation::Flash
+            && !self.speech_models.path.starts_with('/')
+        {
+            problems.push("speech_models.path must be an absolute path".to_string());
+        }
+
+        if !(1..=100).contains(&self.storage.max_usage_percent) {
+            problems.push("storage.max_usage_percent must be between 1 and 100".to_string());
+        }
+        if self.music.duck_percent > 100 {
+            problems.push("music.duck_percent must be at most 100".to_string());
+        }
+        if self.story.max_tokens == 0 || self.story.max_minutes == 0 {
+            problems.push("story.max_tokens and story.max_minutes must not be 0".to_string());
+        }
+        if self.story.tts_speed > 5 {
+            problems.push("story.tts_speed must be between 0 and 5".to_string());
+        }
+        if !(1..=100).contains(&self.story.volume_percent) {
+            problems.push("story.volume_percent must be between 1 and 100".to_string());
+        }
+        if self.story.end_marker.trim().is_empty() {
+            problems.push("story.end_marker must not be empty".to_string());
+        }
+        if self.knowledge.chunk_chars == 0 || self.knowledge.max_snippets == 0 {
+            problems.push(
+                "knowledge.chunk_chars and knowledge.max_snippets must not be 0".to_string(),
+            );
+        }
+        if let Some(embeddings) = &self.knowledge.embeddings {
+            if !is_http_url(&embeddings.url) {
+                problems.push(format!(
+                    "knowledge.embeddings.url '{}' is not an http(s) URL",
+                    embeddings.url
+                ));
+            }
+            if embeddings.api_key.is_empty() {
+                problems.push("knowledge.embeddings.api_key must not be empty".to_string());
+            }
+        }
+        if !self.ota.manifest_url.is_empty() && !is_http_url(&self.ota.manifest_url) {
+            problems.push(format!(
+                "ota.manifest_url '{}' is not an http(s) URL",
+                self.ota.manifest_url
+            ));
+        }
+
+        if self.web.enabled && self.web.port == 0 {
+            problems.push("web.port must not be 0".to_string());
+        }
+        if !self.mqtt.url.is_empty() {
+            let schemes = ["mqtt://", "mqtts://", "ws://", "wss://"];
+            if !schemes.iter().any(|scheme| self.mqtt.url.starts_with(scheme)) {
+                problems.push(format!("mqtt.url '{}' is not an MQTT URL", self.mqtt.url));
+            }
+            let prefix = &self.mqtt.topic_prefix;
+            if prefix.is_empty() || prefix.contains(['+', '#']) || prefix.ends_with('/') {
+                problems.push(
+                    "mqtt.topic_prefix must not be empty, contain wildcards or end with '/'"
+                        .to_string(),
+                );
+            }
+            if self.mqtt.status_interval_secs == 0 {
+                problems.push("mqtt.status_interval_secs must not be 0".to_string());
+            }
+        }
+
+        if self.satellite.enabled {
+            if self.satellite.port == 0 {
+                problems.push("satellite.port must not be 0".to_string());
+            }
+            // Both take the utterance audio, only one of them can
+            if self.stt.streaming.is_some() {
+                problems.push("satellite and stt.streaming can't be used together".to_string());
+            }
+        }
+
+        if self.storage.check_interval_secs == 0 {
+            problems.push("storage.check_interval_secs must not be 0".to_string());
+        }
+        if self.log.max_file_kb == 0 {
+            problems.push("log.max_file_kb must not be 0".to_string());
+        }
+        if !self.log.remote.host.is_empty() && self.log.remote.port == 0 {
+            problems.push("log.remote.port must not be 0".to_string());
+        }
+        if self.thinking.interval_ms == 0 {
+            problems.push("thinking.interval_ms must not be 0".to_string());
+        }
+        if !(-12 * 60..=14 * 60).contains(&self.alarms.utc_offset_mins) {
+            problems.push("alarms.utc_offset_mins must be between -720 and 840".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Invalid configuration: {}",
+                problems.join("; ")
+            ))
+        }
+    }
+}
+
+/// Replace the configuration file with `text` if it is a valid configuration
+///
+/// Takes effect with the next session, like edits made on the card directly.
+pub fn save_config_file(text: &str) -> anyhow::Result<()> {
+    AppConfig::from_toml(text)?;
+
+    // A half written file would leave the device with the defaults after a reset
+    let staging = format!("{}.new", CONFIG_FILE_PATH);
+    std::fs::write(&staging, text)?;
+    if std::path::Path::new(CONFIG_FILE_PATH).exists() {
+        std::fs::remove_file(CONFIG_FILE_PATH)?;
+    }
+    std::fs::rename(&staging, CONFIG_FILE_PATH)?;
+    log::info!("Saved a new {}", CONFIG_FILE_PATH);
+    Ok(())
+}
+
+/// `text` with the value of every non-empty secret replaced, comments and layout are kept
+///
+/// Secrets are found line by line, so one in an inline table or a multi-line string is missed.
+/// The result is parsed to make sure none is left, and refused when one is.
+pub fn redact_secrets(text: &str) -> anyhow::Result<String> {
+    let redacted = replace_secrets(text, |secret| {
+        (secret.value.len() > 2).then(|| Ok(REDACTED.to_string()))
+    })?;
+
+    let document: toml::Value = toml::from_str(&redacted)
+        .map_err(|_| anyhow::anyhow!("The file isn't valid TOML, its secrets can't be hidden"))?;
+    if let Some(key) = unredacted_secret(&document) {
+        return Err(anyhow::anyhow!(
+            "{} can't be hidden, write it as {} = \"...\" on a line of its own",
+            key,
+            key
+        ));
+    }
+    Ok(redacted)
+}
+
+/// Name of a secret in `value` or below it that still has a value
+fn unredacted_secret(value: &toml::Value) -> Option<&str> {
+    match value {
+        toml::Value::Table(table) => table.iter().find_map(|(key, value)| match value {
+            toml::Value::String(secret)
+                if SECRET_KEYS.contains(&key.as_str())
+                    && !secret.is_empty()
+                    && *secret != REDACTED.trim_matches('"') =>
+            {
+                Some(key.as_str())
+            }
+            value => unredacted_secret(value),
+        }),
+        toml::Value::Array(values) => values.iter().find_map(unredacted_secret),
+        _ => None,
+    }
+}
+
+/// `text` with the redacted secrets put back from `current`, the file it replaces
+///
+/// Fails when a redacted secret isn't in `current`, its value is unknown then.
+pub fn restore_secrets(text: &str, current: &str) -> anyhow::Result<String> {
+    let old = secret_lines(current);
+    replace_secrets(text, |secret| {
+        (secret.value == REDACTED).then(|| {
+            old.iter()
+                .find(|old| old.table == secret.table && old.key == secret.key)
+                .map(|old| old.value.to_string())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("{} in {} has no value to keep", secret.key, secret.table)
+                })
+        })
+    })
+}
+
+/// A secret's value in a configuration file
+struct SecretLine<'a> {
+    /// Table header and how many of the same came before, "[[llm.fallbacks]]#1"
+    table: String,
+    key: &'a str,
+    line: usize,
+    /// Byte offset of the value in the line
+    start: usize,
+    /// Quoted value as written
+    value: &'a str,
+}
+
+fn secret_lines(text: &str) -> Vec<SecretLine<'_>> {
+    let mut seen: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut table = String::new();
+    let mut secrets = Vec::new();
+    for (line, content) in text.lines().enumerate() {
+        let trimmed = content.trim_start();
+        if trimmed.starts_with('[') {
+            let header = trimmed.split('#').next().unwrap_or_default().trim_end();
+            let count = seen.entry(header).or_insert(0);
+            table = format!("{}#{}", header, count);
+            *count += 1;
+            continue;
+        }
+        let Some((key, rest)) = trimmed.split_once('=') else {
+            continue;
+        };
+        let key = key.trim();
+        let name = key.rsplit('.').next().unwrap_or(key).trim().trim_matches('"');
+        if !SECRET_KEYS.contains(&name) {
+            continue;
+        }
+        let rest = rest.trim_start();
+        if let Some(value) = quoted_prefix(rest) {
+            secrets.push(SecretLine {
+                table: table.clone(),
+                key,
+                line,
+                start: content.len() - rest.len(),
+                value,
+            });
+        }
+    }
+    secrets
+}
+
+/// The quoted string `text` starts with, quotes included
+fn quoted_prefix(text: &str) -> Option<&str> {
+    let quote = text.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        if c == quote && !escaped {
+            return Some(&text[..=i]);
+        }
+        escaped = quote == '"' && c == '\\' && !escaped;
+    }
+    None
+}
+
+/// `text` with the values `replacement` returns for its secrets
+fn replace_secrets(
+    text: &str,
+    replacement: impl Fn(&SecretLine) -> Option<anyhow::Result<String>>,
+) -> anyhow::Result<String> {
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    for secret in secret_lines(text) {
+        if let Some(value) = replacement(&secret) {
+            let end = secret.start + secret.value.len();
+            lines[secret.line].replace_range(secret.start..end, &value?);
+        }
+    }
+    let mut replaced = lines.join("\n");
+    if text.ends_with('\n') {
+        replaced.push('\n');
+    }
+    Ok(replaced)
+}
+
+/// Loads the configuration from the SD card and mirrors it into NVS so the
+/// device keeps its personality even when the card is missing
+pub struct ConfigStore {
+    nvs: Option<EspNvs<NvsDefault>>,
+    /// Values changed from the console, they replace those of the file
+    settings: Option<Settings>,
+}
+
+impl ConfigStore {
+    pub fn new(nvs_partition: EspDefaultNvsPartition) -> Self {
+        let nvs = match EspNvs::new(nvs_partition.clone(), CONFIG_NVS_NAMESPACE, true) {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                log::warn!("Failed to open NVS namespace '{}': {}", CONFIG_NVS_NAMESPACE, e);
+                None
+            }
+        };
+
+        let settings = match Settings::new(nvs_partition) {
+            Ok(settings) => Some(settings),
+            Err(e) => {
+                log::warn!("Failed to open the settings, config.toml applies unchanged: {}", e);
+                None
+            }
+        };
+
+        Self { nvs, settings }
+    }
+
+    /// Load the configuration with the values changed from the console applied
+    pub fn load(&mut self) -> AppConfig {
+        let config = self.load_stored();
+        let Some(settings) = &self.settings else {
+            return config;
+        };
+
+        let mut changed = config.clone();
+        settings.apply_overrides(&mut changed);
+        match changed.validate() {
+            Ok(()) => changed,
+            Err(e) => {
+                log::error!("Ignoring the settings changed from the console: {}", e);
+                config
+            }
+        }
+    }
+
+    /// Load the configuration, preferring the SD card copy, then NVS, then built-in defaults
+    fn load_stored(&mut self) -> AppConfig {
+        match std::fs::read_to_string(CONFIG_FILE_PATH) {
+            Ok(text) => match AppConfig::from_toml(&text) {
+                Ok(config) => {
+                    log::info!("Loaded configuration from {}", CONFIG_FILE_PATH);
+                    self.store_to_nvs(&text);
+                    return config;
+                }
+                Err(e) => log::error!("Failed to parse {}: {}", CONFIG_FILE_PATH, e),
+            },
+            Err(e) => log::info!("No configuration file at {}: {}", CONFIG_FILE_PATH, e),
+        }
+
+        if let Some(text) = self.read_from_nvs() {
+            match AppConfig::from_toml(&text) {
+                Ok(config) => {
+                    log::info!("Loaded configuration from NVS");
+                    return config;
+                }
+                Err(e) => log::error!("Failed to parse configuration stored in NVS: {}", e),
+            }
+        }
+
+        log::info!("Using default configuration");
+        AppConfig::default()
+    }
+
+    fn read_from_nvs(&self) -> Option<String> {
+        let nvs = self.nvs.as_ref()?;
+        let mut buffer = vec![0u8; CONFIG_NVS_MAX_LEN];
+
+        match nvs.get_str(CONFIG_NVS_KEY, &mut buffer) {
+            Ok(value) => value.map(|s| s.to_string()),
+            Err(e) => {
+                log::warn!("Failed to read configuration from NVS: {}", e);
+                None
+            }
+        }
+    }
+
+    fn store_to_nvs(&mut self, text: &str) {
+        if text.len() >= CONFIG_NVS_MAX_LEN {
+            log::warn!(
+                "Configuration is {} bytes, too large to mirror into NVS",
+                text.len()
+            );
+            return;
+        }
+
+        // Avoid wearing the flash when nothing changed
+        if self.read_from_nvs().as_deref() == Some(text) {
+            return;
+        }
+
+        if let Some(nvs) = self.nvs.as_mut() {
+            match nvs.set_str(CONFIG_NVS_KEY, text) {
+                Ok(_) => log::info!("Configuration mirrored into NVS"),
+                Err(e) => log::warn!("Failed to store configuration in NVS: {}", e),
+            }
+        }
+    }
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// MultiNet's notation, "da kai ke ting deng"
+fn is_pinyin(phrase: &str) -> bool {
+    !phrase.trim().is_empty() && phrase.chars().all(|c| c.is_ascii_lowercase() || c == ' ')
+}
+
+/// A GPIO the chip has and that isn't wired to the flash or the PSRAM inside the module
+fn is_usable_gpio(pin: u8) -> bool {
+    pin <= MAX_GPIO
+        && !MISSING_GPIOS.contains(&pin)
+        && !FLASH_GPIOS.contains(&pin)
+        && !(cfg!(esp_idf_spiram_mode_oct) && OCTAL_PSRAM_GPIOS.contains(&pin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(AppConfig::from_toml("").is_ok());
+
+        let config = AppConfig::from_toml(
+            r#"
+            [wifi]
+            ssid = "home"
+            password = "secret"
+
+            [pins]
+            mic_clk = 2
+            "#,
+        );
+        let message = config.unwrap_err().to_string();
+        assert!(message.contains("wifi.password"));
+        assert!(message.contains("pins.mic_clk and pins.speaker_bclk"));
+    }
+
+    #[test]
+    fn test_validate_pins() {
+        assert!(AppConfig::from_toml("[pins]\nspeaker_enable = 21").is_err());
+        assert!(AppConfig::from_toml("[pins]\nspeaker_enable = 38").is_ok());
+        assert!(AppConfig::from_toml("[pins]\nspeaker_enable = 23").is_err());
+        assert!(AppConfig::from_toml("[pins]\nspeaker_enable = 30").is_err());
+        assert!(AppConfig::from_toml("[pins]\nspeaker_enable = 49").is_err());
+        let octal_psram_pin = AppConfig::from_toml("[pins]\nspeaker_enable = 35");
+        assert_eq!(octal_psram_pin.is_err(), cfg!(esp_idf_spiram_mode_oct));
+    }
+
+    #[test]
+    fn test_validate_buttons() {
+        assert!(AppConfig::from_toml("[buttons]\nmute = 0\ntalk = 4").is_ok());
+        assert!(AppConfig::from_toml("[buttons]\nmute = 5").is_err());
+        assert!(AppConfig::from_toml("[buttons]\nmute = 4\nstop = 4").is_err());
+    }
+
+    #[test]
+    fn test_validate_encoder() {
+        let config = AppConfig::from_toml(
+            "[encoder]\npin_a = 10\npin_b = 11\nswitch = 12\nswitch_action = \"push_to_talk\"",
+        )
+        .unwrap();
+        assert_eq!(config.encoder.switch_action, ButtonAction::PushToTalk);
+        assert!(AppConfig::from_toml("[encoder]\npin_a = 10").is_err());
+        assert!(AppConfig::from_toml("[encoder]\npin_a = 10\npin_b = 42").is_err());
+    }
+
+    #[test]
+    fn test_validate_led() {
+        assert!(AppConfig::from_toml("[led]\npin = 48").is_ok());
+        assert!(AppConfig::from_toml("[led]\npin = 48\nbrightness = 120").is_err());
+    }
+
+    #[test]
+    fn test_validate_display() {
+        let config = AppConfig::from_toml("[display]\ndriver = \"ssd1306\"\nsda = 15\nscl = 16");
+        assert_eq!(config.unwrap().display.driver, Some(DisplayDriver::Ssd1306));
+        assert!(AppConfig::from_toml("[display]\ndriver = \"ssd1306\"\nsda = 15").is_err());
+        let st7789 = "[display]\ndriver = \"st7789\"\nsclk = 17\nmosi = 18\ndc = 38\nrst = 39";
+        assert!(AppConfig::from_toml(st7789).is_ok());
+        assert!(AppConfig::from_toml(&format!("{}\nwidth = 0", st7789)).is_err());
+        assert!(AppConfig::from_toml("[display]\ndriver = \"st7789\"\nsclk = 17").is_err());
+        assert!(AppConfig::from_toml("[display]\nsda = 15\nscl = 41").is_err());
+    }
+
+    #[test]
+    fn test_validate_camera() {
+        let config = AppConfig::from_toml("[camera]\nenabled = true\nframe_size = \"svga\"");
+        assert_eq!(config.unwrap().camera.frame_size, CameraFrameSize::Svga);
+        assert!(AppConfig::from_toml("[camera]\nenabled = true\njpeg_quality = 70").is_err());
+        assert!(AppConfig::from_toml("[camera]\nenabled = true\n[led]\npin = 48").is_err());
+        let vision = "[camera.vision]\nprovider = \"gemini\"\nendpoint = \"example.com\"";
+        assert!(AppConfig::from_toml(vision).is_err());
+    }
+
+    #[test]
+    fn test_validate_power() {
+        assert!(AppConfig::from_toml("[power]\nidle_minutes = 10\nidle_cpu_mhz = 160").is_ok());
+        assert!(AppConfig::from_toml("[power]\nidle_cpu_mhz = 100").is_err());
+    }
+
+    #[test]
+    fn test_validate_llm() {
+        assert!(AppConfig::from_toml("[llm]\nendpoint = \"api.example.com\"").is_err());
+        assert!(AppConfig::from_toml("[llm]\ntimeout_secs = 90").is_err());
+        let no_watchdog = "[llm]\ntimeout_secs = 90\n[watchdog]\ntimeout_secs = 0";
+        assert!(AppConfig::from_toml(no_watchdog).is_ok());
+    }
+
+    #[test]
+    fn test_validate_remote_log() {
+        let remote_log = "[log.remote]\nhost = \"192.168.1.2\"";
+        let config = AppConfig::from_toml(&format!("{}\nprotocol = \"tcp\"", remote_log));
+        assert_eq!(config.unwrap().log.remote.protocol, RemoteLogProtocol::Tcp);
+        assert!(AppConfig::from_toml(&format!("{}\nport = 0", remote_log)).is_err());
+    }
+
+    #[test]
+    fn test_validate_alarms() {
+        assert!(AppConfig::from_toml("[alarms]\nutc_offset_mins = -300").is_ok());
+        assert!(AppConfig::from_toml("[alarms]\nutc_offset_mins = 900").is_err());
+    }
+
+    #[test]
+    fn test_validate_radio() {
+        let radio = "[[radio.stations]]\nname = \"新闻\"\nurl = \"http://example.com/news.mp3\"";
+        let config = AppConfig::from_toml(radio).unwrap();
+        assert_eq!(config.radio.find_station("新闻电台").unwrap().name, "新闻");
+        assert_eq!(config.radio.find_station("").unwrap().name, "新闻");
+        assert!(config.radio.find_station("音乐").is_none());
+        assert!(AppConfig::from_toml("[[radio.stations]]\nname = \"新闻\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_music() {
+        assert!(AppConfig::from_toml("[music]\nduck_percent = 0").is_ok());
+        assert!(AppConfig::from_toml("[music]\nduck_percent = 150").is_err());
+    }
+
+    #[test]
+    fn test_validate_story() {
+        assert!(AppConfig::from_toml("[story]\ntts_speed = 1\nvolume_percent = 40").is_ok());
+        assert!(AppConfig::from_toml("[story]\nvolume_percent = 0").is_err());
+        assert!(AppConfig::from_toml("[story]\nend_marker = \"\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_knowledge() {
+        assert!(AppConfig::from_toml("[knowledge]\nenabled = true").is_ok());
+        assert!(AppConfig::from_toml("[knowledge]\nchunk_chars = 0").is_err());
+        assert!(AppConfig::from_toml("[knowledge.embeddings]\nmodel = \"bge-m3\"").is_err());
+        let embeddings = "[knowledge.embeddings]\napi_key = \"sk-test\"";
+        assert!(AppConfig::from_toml(embeddings).is_ok());
+    }
+
+    #[test]
+    fn test_validate_smart_home() {
+        let light = "[[smart_home.devices]]\nname = \"客厅灯\"\ntopic = \"home/light/set\"";
+        assert!(AppConfig::from_toml(light).is_err());
+        let mqtt = "[mqtt]\nurl = \"mqtt://192.168.1.2\"";
+        let config = AppConfig::from_toml(&format!("{}\n{}", mqtt, light)).unwrap();
+        assert!(config.smart_home.find_device("卧室灯").unwrap_err().is_empty());
+        assert_eq!(config.smart_home.find_device("客厅灯").unwrap().name, "客厅灯");
+        assert_eq!(config.smart_home.find_device("灯").unwrap().name, "客厅灯");
+        let lights = format!("{}\n{}\n{}", mqtt, light, light.replace("客厅", "卧室"));
+        let config = AppConfig::from_toml(&lights).unwrap();
+        assert_eq!(
+            config.smart_home.find_device("卧室灯光").unwrap().name,
+            "卧室灯"
+        );
+        assert_eq!(
+            config.smart_home.find_device("灯").unwrap_err(),
+            vec!["客厅灯", "卧室灯"]
+        );
+        let bad_pinyin = format!("{}\n{}\non_pinyin = \"打开\"", mqtt, light);
+        assert!(AppConfig::from_toml(&bad_pinyin).is_err());
+    }
+
+    #[test]
+    fn test_validate_speech_models() {
+        assert!(AppConfig::from_toml("[speech_models]\nlocation = \"flash\"").is_ok());
+        assert!(AppConfig::from_toml("[speech_models]\npath = \"vfat\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_network() {
+        assert!(AppConfig::from_toml("[network]\nstatic_ip = \"192.168.1.50\"").is_err());
+        assert!(AppConfig::from_toml("[network]\nhostname = \"my chatbox\"").is_err());
+        let config = AppConfig::from_toml(
+            r#"
+            [network]
+            static_ip = "192.168.1.50"
+            gateway = "192.168.1.1"
+            dns = ["1.1.1.1"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.network.dns, [Ipv4Addr::new(1, 1, 1, 1)]);
+    }
+
+    #[test]
+    fn test_validate_wifi() {
+        let config = AppConfig::from_toml("[wifi]\npower_save = \"max\"").unwrap();
+        assert_eq!(config.wifi.power_save, PowerSaveMode::Max);
+        assert!(
+            AppConfig::from_toml("[wifi]\nprovisioning = \"ble\"\npower_save = \"none\"").is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_web() {
+        assert!(AppConfig::from_toml("[web]\nenabled = true\nport = 0").is_err());
+        assert!(AppConfig::from_toml("[web]\nport = 0").is_ok());
+    }
+
+    #[test]
+    fn test_validate_mqtt() {
+        assert!(AppConfig::from_toml("[mqtt]\nurl = \"mqtt://broker:1883\"").is_ok());
+        assert!(AppConfig::from_toml("[mqtt]\nurl = \"broker:1883\"").is_err());
+        assert!(
+            AppConfig::from_toml("[mqtt]\nurl = \"mqtt://broker\"\ntopic_prefix = \"home/#\"").is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_satellite() {
+        assert!(AppConfig::from_toml("[satellite]\nenabled = true").is_ok());
+        assert!(AppConfig::from_toml(
+            "[satellite]\nenabled = true\n[stt.streaming]\nurl = \"ws://asr:2700\""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_secrets() {
+        let current = "[llm]\napi_key = \"sk-1\" # key\n\n[[llm.fallbacks]]\napi_key = \"\"\n\
+                       [[llm.fallbacks]]\napi_key = 'sk-3'\n[web]\ntoken = \"t\\\"x\"\n";
+        let redacted = redact_secrets(current).unwrap();
+        assert_eq!(
+            redacted,
+            "[llm]\napi_key = \"<redacted>\" # key\n\n[[llm.fallbacks]]\napi_key = \"\"\n\
+             [[llm.fallbacks]]\napi_key = \"<redacted>\"\n[web]\ntoken = \"<redacted>\"\n"
+        );
+        assert_eq!(restore_secrets(&redacted, current).unwrap(), current);
+
+        let edited = redacted.replace("[web]", "[web]\nport = 8080");
+        assert!(restore_secrets(&edited, current).unwrap().contains("token = \"t\\\"x\""));
+        assert!(restore_secrets(&redacted, "").is_err());
+
+        let redacted = redact_secrets("[wifi]\nble_pop = \"abcd1234\"\n").unwrap();
+        assert_eq!(redacted, "[wifi]\nble_pop = \"<redacted>\"\n");
+        // Missed by the line by line search, so the file isn't handed out
+        assert!(redact_secrets("llm = { api_key = \"sk-1\" }\n").is_err());
+        assert!(redact_secrets("[web]\ntoken = \"\"\"\nt\"\"\"\n").is_err());
+        assert!(redact_secrets("[web\ntoken = \"t\"\n").is_err());
+        assert_eq!(redact_secrets("").unwrap(), "");
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::wifi;
+
+/// Set while the device is in offline mode: it told the user so and MultiNet handles commands
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the speech to text and LLM services can be used
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connectivity {
+    Online,
+    /// No WiFi connection, requests aren't even attempted
+    NoWifi,
+    /// WiFi is up but the last request to a service failed, the next one tells if it's back
+    ServicesUnreachable,
+}
+
+pub fn state() -> Connectivity {
+    if !wifi::is_connected() {
+        Connectivity::NoWifi
+    } else if OFFLINE.load(Ordering::Relaxed) {
+        Connectivity::ServicesUnreachable
+    } else {
+        Connectivity::Online
+    }
+}
+
+/// Requests have a chance to get through, they'd only run into a timeout without WiFi
+pub fn has_wifi() -> bool {
+    wifi::is_connected()
+}
+
+/// Enter offline mode, true when the device was online until now
+pub fn go_offline() -> bool {
+    !OFFLINE.swap(true, Ordering::Relaxed)
+}
+
+/// Leave offline mode, true when the device was offline until now
+pub fn go_online() -> bool {
+    OFFLINE.swap(false, Ordering::Relaxed)
+}
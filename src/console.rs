@@ -0,0 +1,352 @@
+use anyhow;
+use std::io::BufRead;
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use crate::config::RetentionPolicy;
+use crate::known_networks::{Credentials, KnownNetworks};
+use crate::log_file;
+use crate::metrics::{self, LogMetricsSink, MetricsSink};
+use crate::notes;
+use crate::sd_card;
+use crate::settings::{
+    Settings, KEY_EXIT_PHRASES, KEY_KEEP_RECORDINGS, KEY_LLM_ENDPOINT, KEY_RETENTION, KEY_STT_URL,
+};
+use crate::stacks;
+use crate::transcription::TranscriptionMessage;
+use crate::wifi;
+
+const HELP_TEXT: &str = "Commands:
+  set max_tokens <n>     maximum number of tokens in a reply
+  set temperature <x>    sampling temperature (0.0 - 2.0)
+  set top_p <x>          nucleus sampling threshold (0.0 - 1.0)
+  set llm_endpoint <url> use another LLM endpoint than config.toml
+  set stt_url <url>      use another speech to text service than config.toml
+  set exit_phrases <a,b> phrases ending the conversation, separated by commas
+  set retention <policy> keep, delete, keep_last or archive the recordings
+  set keep_recordings <n> recordings kept by the keep_last policy
+  unset <name>           drop one of the five settings above, config.toml applies again
+  params                 log the current generation parameters
+  storage                log capacity, error counters and write speed of the storage
+  network                log WiFi signal strength, disconnects and the last LLM request time
+  metrics                log the counters, gauges and timers, e.g. STT and LLM durations
+  stacks                 log the peak stack use of the audio and transcription threads
+  diagnose               check DNS, connection and a request to the STT and LLM services
+  ota                    check for new firmware and install it
+  dictation on|off       write what is said to the notes on the card instead of answering
+  wifi list              list the known WiFi networks, highest priority first
+  wifi add <ssid> [pass] add a network or update its password, quote names with spaces
+  wifi remove <ssid>     forget a network
+  reboot                 unmount the SD card and restart
+  factory-reset          wipe settings, known networks and conversations, then restart
+  help                   show this help";
+
+/// Parse one console line into a message for the transcription worker
+fn parse_command(
+    line: &str,
+    known_networks: &mut KnownNetworks,
+    settings: &mut Settings,
+) -> anyhow::Result<Option<TranscriptionMessage>> {
+    let words = split_words(line);
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+
+    match words.as_slice() {
+        [] => Ok(None),
+        ["help"] => {
+            println!("{}", HELP_TEXT);
+            Ok(None)
+        }
+        ["params"] => Ok(Some(TranscriptionMessage::SetGenerationParams {
+            max_tokens: None,
+            temperature: None,
+            top_p: None,
+        })),
+        ["set", "max_tokens", value] => Ok(Some(TranscriptionMessage::SetGenerationParams {
+            max_tokens: Some(value.parse()?),
+            temperature: None,
+            top_p: None,
+        })),
+        ["set", "temperature", value] => Ok(Some(TranscriptionMessage::SetGenerationParams {
+            max_tokens: None,
+            temperature: Some(parse_in_range("temperature", value, 0.0, 2.0)?),
+            top_p: None,
+        })),
+        ["set", "top_p", value] => Ok(Some(TranscriptionMessage::SetGenerationParams {
+            max_tokens: None,
+            temperature: None,
+            top_p: Some(parse_in_range("top_p", value, 0.0, 1.0)?),
+        })),
+        ["set", name, value] => {
+            set_override(settings, name, value)?;
+            println!("Saved {}, used from the next conversation", name);
+            Ok(None)
+        }
+        ["unset", name] => {
+            unset_override(settings, name)?;
+            println!(
+                "Removed {}, config.toml applies from the next conversation",
+                name
+            );
+            Ok(None)
+        }
+        ["storage"] => {
+            LogMetricsSink.record_storage(&sd_card::storage_metrics("/vfat", true));
+            Ok(None)
+        }
+        ["network"] => {
+            LogMetricsSink.record_network(&wifi::network_metrics());
+            Ok(None)
+        }
+        ["metrics"] => {
+            LogMetricsSink.record_snapshot(&metrics::snapshot());
+            Ok(None)
+        }
+        ["stacks"] => {
+            LogMetricsSink.record_stacks(&stacks::stack_metrics());
+            Ok(None)
+        }
+        ["diagnose"] => Ok(Some(TranscriptionMessage::RunDiagnostics)),
+        ["ota"] => Ok(Some(TranscriptionMessage::UpdateFirmware)),
+        ["dictation", "on"] => {
+            notes::set_dictating(true);
+            Ok(None)
+        }
+        ["dictation", "off"] => {
+            notes::set_dictating(false);
+            Ok(None)
+        }
+        ["wifi", "list"] => {
+            for (i, network) in known_networks.list().iter().enumerate() {
+                let security = if network.password.is_empty() {
+                    "open"
+                } else {
+                    "password"
+                };
+                println!("{}. {} ({})", i + 1, network.ssid, security);
+            }
+            Ok(None)
+        }
+        ["wifi", "add", ssid, password @ ..] if password.len() <= 1 => {
+            known_networks.add(&Credentials {
+                ssid: ssid.to_string(),
+                password: password.first().unwrap_or(&"").to_string(),
+            })?;
+            println!("Saved '{}', used from the next connection attempt", ssid);
+            Ok(None)
+        }
+        ["wifi", "remove", ssid] => {
+            if !known_networks.remove(ssid)? {
+                println!("'{}' is not a known network", ssid);
+            }
+            Ok(None)
+        }
+        ["reboot"] => reboot(),
+        ["factory-reset"] => Ok(Some(TranscriptionMessage::FactoryReset)),
+        _ => Err(anyhow::anyhow!("Unknown command '{}', type 'help'", line.trim())),
+    }
+}
+
+fn parse_in_range(name: &str, value: &str, min: f32, max: f32) -> anyhow::Result<f32> {
+    let number: f32 = value.parse()?;
+    if !(min..=max).contains(&number) {
+        return Err(anyhow::anyhow!(
+            "{} must be between {:.1} and {:.1}",
+            name,
+            min,
+            max
+        ));
+    }
+    Ok(number)
+}
+
+/// Store a setting that replaces a value of config.toml
+fn set_override(settings: &mut Settings, name: &str, value: &str) -> anyhow::Result<()> {
+    match name {
+        "llm_endpoint" => settings.set(KEY_LLM_ENDPOINT, parse_url(value)?),
+        "stt_url" => settings.set(KEY_STT_URL, parse_url(value)?),
+        "exit_phrases" => settings.set(KEY_EXIT_PHRASES, parse_phrases(value)?),
+        "retention" => settings.set(KEY_RETENTION, parse_retention(value)?),
+        "keep_recordings" => settings.set(KEY_KEEP_RECORDINGS, value.parse()?),
+        _ => Err(anyhow::anyhow!("Unknown setting '{}', type 'help'", name)),
+    }
+}
+
+fn unset_override(settings: &mut Settings, name: &str) -> anyhow::Result<()> {
+    match name {
+        "llm_endpoint" => settings.remove(KEY_LLM_ENDPOINT),
+        "stt_url" => settings.remove(KEY_STT_URL),
+        "exit_phrases" => settings.remove(KEY_EXIT_PHRASES),
+        "retention" => settings.remove(KEY_RETENTION),
+        "keep_recordings" => settings.remove(KEY_KEEP_RECORDINGS),
+        _ => Err(anyhow::anyhow!("Unknown setting '{}', type 'help'", name)),
+    }
+}
+
+fn parse_url(value: &str) -> anyhow::Result<String> {
+    if !value.starts_with("http://") && !value.starts_with("https://") {
+        return Err(anyhow::anyhow!(
+            "'{}' is not an http:// or https:// URL",
+            value
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Comma separated phrases, e.g. "再见,拜拜"
+fn parse_phrases(value: &str) -> anyhow::Result<Vec<String>> {
+    let phrases: Vec<String> = value
+        .split([',', '，'])
+        .map(str::trim)
+        .filter(|phrase| !phrase.is_empty())
+        .map(str::to_string)
+        .collect();
+    if phrases.is_empty() {
+        return Err(anyhow::anyhow!("Give at least one phrase"));
+    }
+    Ok(phrases)
+}
+
+fn parse_retention(value: &str) -> anyhow::Result<RetentionPolicy> {
+    serde_json::from_value(serde_json::Value::String(value.to_string())).map_err(|_| {
+        anyhow::anyhow!(
+            "Unknown policy '{}', use keep, delete, keep_last or archive",
+            value
+        )
+    })
+}
+
+/// Split a line into words, double quotes keep spaces inside a word
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// Restart once the SD card is unmounted, so no file is left half written
+pub fn reboot() -> ! {
+    log::info!("Rebooting");
+    log_file::close();
+    // Held until the restart, the card must not be mounted again
+    let _card = sd_card::release().map_err(|e| log::warn!("Failed to release the SD card: {}", e));
+    unsafe { esp_idf_svc::sys::esp_restart() }
+}
+
+fn console_loop(
+    transcription_tx: Sender<TranscriptionMessage>,
+    mut known_networks: KnownNetworks,
+    mut settings: Settings,
+) {
+    let stdin = std::io::stdin();
+    let mut line = String::new();
+
+    loop {
+        // Without a UART driver stdin is non-blocking and returns no data, so poll it
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                continue;
+            }
+            Ok(_) if !line.ends_with('\n') => continue,
+            Ok(_) => {}
+            Err(e) => {
+                log::debug!("Console read error: {}", e);
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                continue;
+            }
+        }
+
+        match parse_command(&line, &mut known_networks, &mut settings) {
+            Ok(Some(message)) => {
+                if let Err(e) = transcription_tx.send(message) {
+                    log::error!("Failed to forward console command: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => println!("{}", e),
+        }
+
+        line.clear();
+    }
+}
+
+/// Start a thread reading commands from the serial console
+pub fn start_console(
+    transcription_tx: Sender<TranscriptionMessage>,
+    known_networks: KnownNetworks,
+    settings: Settings,
+) -> anyhow::Result<()> {
+    thread::Builder::new()
+        .name("console".to_string())
+        .stack_size(4 * 1024)
+        .spawn(move || console_loop(transcription_tx, known_networks, settings))?;
+
+    log::info!("Serial console started, type 'help' for commands");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            split_words("wifi add \"My Home\" secret"),
+            ["wifi", "add", "My Home", "secret"]
+        );
+        assert_eq!(split_words("  params \n"), ["params"]);
+        assert_eq!(split_words("wifi add \"\""), ["wifi", "add", ""]);
+    }
+
+    #[test]
+    fn test_parse_in_range() {
+        assert_eq!(parse_in_range("top_p", "0.9", 0.0, 1.0).unwrap(), 0.9);
+        assert_eq!(
+            parse_in_range("top_p", "1.5", 0.0, 1.0)
+                .unwrap_err()
+                .to_string(),
+            "top_p must be between 0.0 and 1.0"
+        );
+        assert!(parse_in_range("temperature", "warm", 0.0, 2.0).is_err());
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        assert_eq!(
+            parse_phrases("再见, 拜拜，,stop").unwrap(),
+            ["再见", "拜拜", "stop"]
+        );
+        assert!(parse_phrases(" , ").is_err());
+        assert_eq!(
+            parse_retention("keep_last").unwrap(),
+            RetentionPolicy::KeepLast
+        );
+        assert!(parse_retention("forever").is_err());
+        assert!(parse_url("https://example.com/v1").is_ok());
+        assert!(parse_url("example.com").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::config::FilterConfig;
+
+/// Appended to the system prompt in kids mode
+pub const KIDS_MODE_PROMPT: &str = "你正在和小朋友聊天。请使用简单易懂、积极友善的语言，\
+不要谈论暴力、恐怖、成人或其他不适合儿童的话题；遇到这类问题请温和地拒绝，并建议小朋友去问爸爸妈妈。";
+
+/// Word blacklist applied to LLM replies before they are spoken
+pub struct ContentFilter {
+    /// Blocked words, lowercased so ASCII words match regardless of case
+    words: Vec<String>,
+    replacement: String,
+}
+
+impl ContentFilter {
+    /// Load the blacklist named in the configuration, a missing file means no words are blocked
+    pub fn load(config: &FilterConfig) -> Self {
+        let words = match std::fs::read_to_string(&config.blacklist_path) {
+            Ok(text) => parse_blacklist(&text),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Failed to read {}: {}", config.blacklist_path, e);
+                }
+                Vec::new()
+            }
+        };
+
+        if !words.is_empty() {
+            log::info!("Loaded {} blocked words from {}", words.len(), config.blacklist_path);
+        }
+
+        Self {
+            words,
+            replacement: config.replacement.clone(),
+        }
+    }
+
+    /// Replace every blocked word in the reply
+    pub fn apply(&self, text: &str) -> String {
+        let mut filtered = text.to_string();
+
+        for word in &self.words {
+            // ASCII lowercasing keeps byte offsets identical between the two strings
+            let mut start = 0;
+            while let Some(offset) = filtered.to_ascii_lowercase()[start..].find(word.as_str()) {
+                let pos = start + offset;
+                filtered.replace_range(pos..pos + word.len(), &self.replacement);
+                // Continue after the replacement in case it contains the word itself
+                start = pos + self.replacement.len();
+            }
+        }
+
+        if filtered != text {
+            log::info!("Content filter replaced blocked words in the reply");
+        }
+
+        filtered
+    }
+}
+
+fn parse_blacklist(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(blacklist: &str) -> ContentFilter {
+        ContentFilter {
+            words: parse_blacklist(blacklist),
+            replacement: "**".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_blacklist() {
+        assert_eq!(
+            parse_blacklist("# comment\n  笨蛋 \n\nStupid\n"),
+            ["笨蛋", "stupid"]
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let filter = filter("笨蛋\nstupid\n");
+        assert_eq!(filter.apply("你这个笨蛋，笨蛋！"), "你这个**，**！");
+        assert_eq!(filter.apply("That's STUPID, stupid."), "That's **, **.");
+        assert_eq!(filter.apply("没有问题"), "没有问题");
+    }
+
+    #[test]
+    fn test_apply_replacement_containing_word() {
+        let filter = ContentFilter {
+            words: parse_blacklist("bad"),
+            replacement: "[bad]".to_string(),
+        };
+        assert_eq!(filter.apply("bad bad"), "[bad] [bad]");
+    }
+}
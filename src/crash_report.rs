@@ -0,0 +1,213 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::panic;
+use std::sync::Mutex;
+
+/// NVS namespace with the panic of the previous boot
+const CRASH_NVS_NAMESPACE: &str = "crash";
+const KEY_PANIC: &str = "panic";
+/// Longer panic messages are cut, the log file on the card has them in full
+const MAX_MESSAGE_CHARS: usize = 200;
+const MAX_JSON_LEN: usize = 512;
+
+/// Written to by the panic hook, None until `install`
+static NVS: Mutex<Option<EspNvs<NvsDefault>>> = Mutex::new(None);
+/// How the previous boot ended, set by `check_previous_boot`
+static PREVIOUS: Mutex<Option<CrashReport>> = Mutex::new(None);
+
+/// What the panic hook saves
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SavedPanic {
+    message: String,
+    task: String,
+}
+
+/// Why the previous boot ended, reported once on the next one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CrashReport {
+    /// "panic", "watchdog" or "brownout"
+    pub reason: &'static str,
+    /// Panic message with its source location, only for Rust panics
+    pub message: Option<String>,
+    /// FreeRTOS task that crashed
+    pub task: Option<String>,
+    /// Program counters of the crashed task from the core dump, for addr2line
+    pub backtrace: Option<String>,
+}
+
+impl CrashReport {
+    /// Spoken on the boot after the crash
+    pub fn announcement(&self) -> String {
+        let reason = match self.reason {
+            "watchdog" => "程序卡住了",
+            "brownout" => "供电电压不足",
+            _ => "程序出错",
+        };
+        format!("我刚刚异常重启了，原因是{}", reason)
+    }
+}
+
+/// Why the previous boot ended, if it didn't end on purpose
+pub fn reset_reason() -> Option<&'static str> {
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_PANIC => Some("panic"),
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT
+        | sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+        | sys::esp_reset_reason_t_ESP_RST_WDT => Some("watchdog"),
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => Some("brownout"),
+        _ => None,
+    }
+}
+
+/// Save the message and task of a panic to NVS before the device resets
+pub fn install(nvs_partition: EspDefaultNvsPartition) {
+    match EspNvs::new(nvs_partition, CRASH_NVS_NAMESPACE, true) {
+        Ok(nvs) => *NVS.lock().unwrap() = Some(nvs),
+        Err(e) => {
+            log::warn!("Failed to open NVS namespace for crash reports: {}", e);
+            return;
+        }
+    }
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        save_panic(&info.to_string());
+        default_hook(info);
+    }));
+}
+
+/// Look at how the previous boot ended and log it, the report is returned after a crash
+///
+/// What was saved for it is cleared either way, so a crash is only reported once.
+pub fn check_previous_boot() -> Option<CrashReport> {
+    let saved = take_saved_panic();
+    let core_dump = take_core_dump();
+
+    let reason = reset_reason()?;
+    let report = CrashReport {
+        reason,
+        message: saved.as_ref().map(|saved| saved.message.clone()),
+        task: saved
+            .map(|saved| saved.task)
+            .or_else(|| core_dump.as_ref().map(|(task, _)| task.clone())),
+        backtrace: core_dump.map(|(_, backtrace)| backtrace),
+    };
+
+    log::error!(
+        "Previous boot crashed: {}, task {}, {}",
+        report.reason,
+        report.task.as_deref().unwrap_or("unknown"),
+        report.message.as_deref().unwrap_or("no panic message")
+    );
+    if let Some(backtrace) = &report.backtrace {
+        log::error!("Backtrace: {}", backtrace);
+    }
+
+    *PREVIOUS.lock().unwrap() = Some(report.clone());
+    Some(report)
+}
+
+/// How the previous boot ended, None when it didn't crash
+pub fn previous() -> Option<CrashReport> {
+    PREVIOUS.lock().unwrap().clone()
+}
+
+fn save_panic(text: &str) {
+    // Another panic while saving would otherwise deadlock here instead of resetting
+    let Ok(nvs) = NVS.try_lock() else {
+        return;
+    };
+    let Some(nvs) = nvs.as_ref() else {
+        return;
+    };
+
+    let saved = SavedPanic {
+        message: truncate(&text.replace('\n', " "), MAX_MESSAGE_CHARS),
+        task: current_task_name(),
+    };
+    if let Ok(json) = serde_json::to_string(&saved) {
+        let _ = nvs.set_str(KEY_PANIC, &json);
+    }
+}
+
+fn take_saved_panic() -> Option<SavedPanic> {
+    let nvs = NVS.lock().unwrap();
+    let nvs = nvs.as_ref()?;
+
+    let mut buffer = vec![0u8; MAX_JSON_LEN];
+    let saved = match nvs.get_str(KEY_PANIC, &mut buffer) {
+        Ok(Some(json)) => serde_json::from_str(json).ok(),
+        Ok(None) => None,
+        Err(e) => {
+            log::warn!("Failed to read the saved panic: {}", e);
+            None
+        }
+    };
+    if saved.is_some() {
+        if let Err(e) = nvs.remove(KEY_PANIC) {
+            log::warn!("Failed to clear the saved panic: {}", e);
+        }
+    }
+    saved
+}
+
+/// Task name and backtrace from the core dump partition, which is erased afterwards
+///
+/// None without a `coredump` partition or when the previous boot didn't write one.
+fn take_core_dump() -> Option<(String, String)> {
+    let mut summary = sys::esp_core_dump_summary_t::default();
+    if unsafe { sys::esp_core_dump_get_summary(&mut summary) } != sys::ESP_OK {
+        return None;
+    }
+    unsafe { sys::esp_core_dump_image_erase() };
+
+    let task = unsafe { CStr::from_ptr(summary.exc_task.as_ptr()) }
+        .to_string_lossy()
+        .into_owned();
+    let depth = (summary.exc_bt_info.depth as usize).min(summary.exc_bt_info.bt.len());
+    let mut backtrace: Vec<String> = summary.exc_bt_info.bt[..depth]
+        .iter()
+        .map(|pc| format!("{:#010x}", pc))
+        .collect();
+    if summary.exc_bt_info.corrupted {
+        backtrace.push("|<-CORRUPTED".to_string());
+    }
+    Some((task, backtrace.join(" ")))
+}
+
+fn current_task_name() -> String {
+    let name = unsafe { sys::pcTaskGetName(std::ptr::null_mut()) };
+    if name.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => text[..end].to_string(),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announcement() {
+        let report = CrashReport {
+            reason: "watchdog",
+            message: None,
+            task: Some("fetch_task".to_string()),
+            backtrace: None,
+        };
+        assert_eq!(report.announcement(), "我刚刚异常重启了，原因是程序卡住了");
+        assert_eq!(truncate("异常重启", 2), "异常");
+        assert_eq!(truncate("panic", 10), "panic");
+    }
+}
@@ -0,0 +1,665 @@
+use esp_idf_svc::http::server::{
+    Configuration as HttpServerConfig, EspHttpConnection, EspHttpServer, Request,
+};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::sys;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+
+use crate::audio_processing;
+use crate::cloud_tts;
+use crate::config::{self, WebConfig, CONFIG_FILE_PATH};
+use crate::connectivity;
+use crate::crash_report::{self, CrashReport};
+use crate::earcon::Earcon;
+use crate::metrics::{self, NetworkMetrics};
+use crate::notes;
+use crate::ota;
+use crate::playback;
+use crate::transcription::{self, TranscriptionMessage};
+use crate::wifi;
+
+mod live;
+
+pub use live::{publish, LiveEvent};
+
+/// Largest request body accepted, enough for a configuration file
+const MAX_BODY_BYTES: usize = 16 * 1024;
+/// Longest WAV announcement accepted, it's played while it arrives and holds up the server
+const MAX_ANNOUNCE_SECS: u32 = 30;
+/// Decoded chunks of a WAV announcement waiting for the speaker, a tenth of a second each
+const ANNOUNCE_CHUNKS_QUEUED: usize = 4;
+/// Longest text the speak action takes, in characters
+const MAX_SPEAK_CHARS: usize = 500;
+/// Longest question the chat endpoint takes, in characters
+const MAX_CHAT_CHARS: usize = 2000;
+/// Chat outcomes kept for fetching, older ones are forgotten
+const MAX_CHAT_RESULTS: usize = 8;
+
+/// Recent chat requests by id, None while the assistant is still working on the reply
+static CHATS: Mutex<VecDeque<(u32, Option<Result<String, String>>)>> = Mutex::new(VecDeque::new());
+static NEXT_CHAT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Everything the status endpoint reports, also published over MQTT
+#[derive(Debug, Serialize)]
+pub struct Status {
+    version: &'static str,
+    /// State of the fetch task, "WakeWordDetecting" or "Recording"
+    state: String,
+    /// "Online", "NoWifi" or "ServicesUnreachable"
+    connectivity: String,
+    muted: bool,
+    last_transcript: Option<String>,
+    uptime_secs: u64,
+    free_heap: u32,
+    /// Lowest free heap since boot
+    min_free_heap: u32,
+    wifi: NetworkMetrics,
+    /// Why the previous boot ended, when it crashed
+    last_crash: Option<CrashReport>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpeakRequest {
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatRequest {
+    text: String,
+    /// Speak the reply as well, it's only returned by default
+    #[serde(default)]
+    speak: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatResponse {
+    reply: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatAccepted {
+    id: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct MuteRequest {
+    muted: bool,
+}
+
+/// Serve the dashboard page, its JSON API and the live events on `config.port`
+///
+/// The server stops when the returned value is dropped.
+pub fn start(
+    config: &WebConfig,
+    transcription_tx: Sender<TranscriptionMessage>,
+) -> anyhow::Result<EspHttpServer<'static>> {
+    if config.token.is_empty() {
+        log::warn!("web.token is not set, the API refuses every request");
+    }
+    let mut server = EspHttpServer::new(&HttpServerConfig {
+        http_port: config.port,
+        // Serializing the configuration needs more than the default
+        stack_size: 10 * 1024,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Get, |req| -> anyhow::Result<()> {
+        respond(
+            req,
+            200,
+            "text/html; charset=utf-8",
+            DASHBOARD_PAGE.as_bytes(),
+        )
+    })?;
+
+    let token = config.token.clone();
+    server.fn_handler(
+        "/api/status",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            respond_json(req, 200, &status())
+        },
+    )?;
+
+    // Prometheus text format, for scraping with a bearer token when one is configured
+    let token = config.token.clone();
+    server.fn_handler(
+        "/metrics",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            let text = metrics::snapshot().prometheus_text();
+            respond(req, 200, "text/plain; version=0.0.4", text.as_bytes())
+        },
+    )?;
+
+    let token = config.token.clone();
+    server.fn_handler(
+        "/api/config",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            // No file means the device runs on the defaults, an empty file means the same
+            let text = std::fs::read_to_string(CONFIG_FILE_PATH).unwrap_or_default();
+            match config::redact_secrets(&text) {
+                Ok(text) => respond(req, 200, "text/plain; charset=utf-8", text.as_bytes()),
+                Err(e) => respond_error(req, 409, &e.to_string()),
+            }
+        },
+    )?;
+
+    let token = config.token.clone();
+    let tx = transcription_tx.clone();
+    server.fn_handler(
+        "/api/config",
+        Method::Put,
+        move |mut req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            let Some(body) = read_body(&mut req)? else {
+                return respond_error(req, 413, "Configuration is too large");
+            };
+            // The keys were redacted when the file was fetched, the ones left so are kept
+            let current = std::fs::read_to_string(CONFIG_FILE_PATH).unwrap_or_default();
+            let text = match config::restore_secrets(&String::from_utf8_lossy(&body), &current) {
+                Ok(text) => text,
+                Err(e) => return respond_error(req, 400, &e.to_string()),
+            };
+            if let Err(e) = config::save_config_file(&text) {
+                return respond_error(req, 400, &e.to_string());
+            }
+            // Reloaded from the file, the same as when the wake word starts a session
+            let _ = tx.send(TranscriptionMessage::RestartSession);
+            respond_ok(req)
+        },
+    )?;
+
+    let token = config.token.clone();
+    let tx = transcription_tx.clone();
+    server.fn_handler(
+        "/api/speak",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            let request: SpeakRequest = match parse_json(&mut req) {
+                Ok(request) => request,
+                Err(e) => return respond_error(req, 400, &e.to_string()),
+            };
+            let text = request.text.trim();
+            if text.is_empty() || text.chars().count() > MAX_SPEAK_CHARS {
+                let message = format!("text must be 1 to {} characters", MAX_SPEAK_CHARS);
+                return respond_error(req, 400, &message);
+            }
+            let _ = tx.send(TranscriptionMessage::Speak {
+                text: text.to_string(),
+            });
+            respond_ok(req)
+        },
+    )?;
+
+    // Played ahead of whatever the worker is doing, a body of type audio/wav is played as it arrives
+    let token = config.token.clone();
+    server.fn_handler(
+        "/api/announce",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            let is_audio = req
+                .header("Content-Type")
+                .is_some_and(|content_type| content_type.starts_with("audio/"));
+            if is_audio {
+                return announce_wav(req);
+            }
+
+            let request: SpeakRequest = match parse_json(&mut req) {
+                Ok(request) => request,
+                Err(e) => return respond_error(req, 400, &e.to_string()),
+            };
+            let text = request.text.trim();
+            if text.is_empty() || text.chars().count() > MAX_SPEAK_CHARS {
+                let message = format!("text must be 1 to {} characters", MAX_SPEAK_CHARS);
+                return respond_error(req, 400, &message);
+            }
+            if !playback::announce(Earcon::Announcement, text) {
+                return respond_error(req, 503, "Playback is not running");
+            }
+            respond_ok(req)
+        },
+    )?;
+
+    // Blocks the server until the LLM answers, like a voice question blocks the worker
+    let token = config.token.clone();
+    let tx = transcription_tx.clone();
+    server.fn_handler(
+        "/api/chat",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            let request: ChatRequest = match parse_json(&mut req) {
+                Ok(request) => request,
+                Err(e) => return respond_error(req, 400, &e.to_string()),
+            };
+            let text = request.text.trim();
+            if text.is_empty() || text.chars().count() > MAX_CHAT_CHARS {
+                let message = format!("text must be 1 to {} characters", MAX_CHAT_CHARS);
+                return respond_error(req, 400, &message);
+            }
+
+            // The LLM may take minutes with retries and fallbacks, the server can't wait for it
+            let id = chat_started();
+            let message = TranscriptionMessage::Chat {
+                id,
+                text: text.to_string(),
+                speak: request.speak,
+            };
+            if tx.send(message).is_err() {
+                chat_answered(id, Err("Assistant is not running".to_string()));
+                return respond_error(req, 503, "Assistant is not running");
+            }
+            respond_json(req, 202, &ChatAccepted { id })
+        },
+    )?;
+
+    let token = config.token.clone();
+    server.fn_handler(
+        "/api/chat",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            let id = req
+                .uri()
+                .split_once('?')
+                .and_then(|(_, query)| query.split('&').find_map(|p| p.strip_prefix("id=")))
+                .and_then(|id| id.parse().ok());
+            let Some(id) = id else {
+                return respond_error(req, 400, "id is missing");
+            };
+            match chat_result(id) {
+                None => respond_error(req, 404, "No such chat request"),
+                Some(None) => respond_json(req, 202, &ChatAccepted { id }),
+                Some(Some(Ok(reply))) => respond_json(req, 200, &ChatResponse { reply }),
+                Some(Some(Err(error))) => respond_error(req, 502, &error),
+            }
+        },
+    )?;
+
+    let token = config.token.clone();
+    let tx = transcription_tx.clone();
+    server.fn_handler(
+        "/api/factory_reset",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            let _ = tx.send(TranscriptionMessage::FactoryReset);
+            respond_ok(req)
+        },
+    )?;
+
+    let token = config.token.clone();
+    let tx = transcription_tx;
+    server.fn_handler(
+        "/api/restart",
+        Method::Post,
+        move |req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            let _ = tx.send(TranscriptionMessage::RestartSession);
+            respond_ok(req)
+        },
+    )?;
+
+    let token = config.token.clone();
+    server.fn_handler(
+        "/api/mute",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            match parse_json::<MuteRequest>(&mut req) {
+                Ok(request) => {
+                    playback::set_muted(request.muted);
+                    respond_ok(req)
+                }
+                Err(e) => respond_error(req, 400, &e.to_string()),
+            }
+        },
+    )?;
+
+    // The list of note files, or the notes of one day with ?date=YYYYMMDD
+    let token = config.token.clone();
+    server.fn_handler(
+        "/api/notes",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            if !is_authorized(req.header("Authorization"), &token) {
+                return respond_error(req, 401, "Missing or wrong token");
+            }
+            let date = req
+                .uri()
+                .split_once('?')
+                .and_then(|(_, query)| query.split('&').find_map(|p| p.strip_prefix("date=")))
+                .map(str::to_string);
+            match date {
+                None => respond_json(req, 200, &notes::list()),
+                Some(date) => match notes::read(&date) {
+                    Some(text) => {
+                        respond(req, 200, "text/plain; charset=utf-8", text.as_bytes())
+                    }
+                    None => respond_error(req, 404, "No notes for this date"),
+                },
+            }
+        },
+    )?;
+
+    live::register(&mut server, config.token.clone())?;
+
+    log::info!("Web dashboard listening on port {}", config.port);
+    Ok(server)
+}
+
+pub fn status() -> Status {
+    let uptime_us = unsafe { sys::esp_timer_get_time() };
+    Status {
+        version: ota::current_version(),
+        state: format!("{:?}", audio_processing::current_state()),
+        connectivity: format!("{:?}", connectivity::state()),
+        muted: playback::is_muted(),
+        last_transcript: transcription::last_transcript(),
+        uptime_secs: uptime_us as u64 / 1_000_000,
+        free_heap: unsafe { sys::esp_get_free_heap_size() },
+        min_free_heap: unsafe { sys::esp_get_minimum_free_heap_size() },
+        wifi: wifi::network_metrics(),
+        last_crash: crash_report::previous(),
+    }
+}
+
+/// Nobody is let in without a token configured, the API reads and changes the API keys
+fn is_authorized(header: Option<&str>, token: &str) -> bool {
+    let Some(given) = header.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    !token.is_empty() && constant_time_eq(given.as_bytes(), token.as_bytes())
+}
+
+/// Compares every byte, so the time taken doesn't tell how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The whole body, None when it is larger than MAX_BODY_BYTES
+fn read_body(req: &mut Request<&mut EspHttpConnection<'_>>) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    let mut buffer = [0u8; 512];
+    loop {
+        let n = req.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(Some(body));
+        }
+        if body.len() + n > MAX_BODY_BYTES {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buffer[..n]);
+    }
+}
+
+/// Play the WAV in the body as an announcement while it's read, the response waits until the
+/// last chunk was handed to the speaker
+fn announce_wav(mut req: Request<&mut EspHttpConnection<'_>>) -> anyhow::Result<()> {
+    let wav = match cloud_tts::open_wav(StdBody(&mut req)) {
+        Ok(wav) => wav,
+        Err(e) => return respond_error(req, 400, &format!("Invalid WAV: {}", e)),
+    };
+    if wav.duration() > MAX_ANNOUNCE_SECS * wav.spec().sample_rate {
+        drop(wav);
+        let message = format!("Announcement is longer than {} s", MAX_ANNOUNCE_SECS);
+        return respond_error(req, 413, &message);
+    }
+
+    let (tx, rx) = mpsc::sync_channel(ANNOUNCE_CHUNKS_QUEUED);
+    if !playback::announce_audio(Earcon::Announcement, rx) {
+        drop(wav);
+        return respond_error(req, 503, "Playback is not running");
+    }
+    // Reading stops early when the speaker was stopped and dropped the receiver
+    let result = cloud_tts::decode_chunks(wav, |chunk| tx.send(chunk).is_ok());
+    if let Err(e) = result {
+        return respond_error(req, 400, &format!("Invalid WAV: {}", e));
+    }
+    respond_ok(req)
+}
+
+/// A request body as std::io::Read, for decoders that take one
+struct StdBody<R>(R);
+
+impl<R: Read> std::io::Read for StdBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0
+            .read(buf)
+            .map_err(|e| std::io::Error::other(format!("{:?}", e)))
+    }
+}
+
+fn parse_json<T: for<'de> Deserialize<'de>>(
+    req: &mut Request<&mut EspHttpConnection<'_>>,
+) -> anyhow::Result<T> {
+    let body = read_body(req)?.ok_or_else(|| anyhow::anyhow!("Request is too large"))?;
+    serde_json::from_slice(&body).map_err(|e| anyhow::anyhow!("Invalid request: {}", e))
+}
+
+/// Remember a new chat request, returning its id
+fn chat_started() -> u32 {
+    let id = NEXT_CHAT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut chats = CHATS.lock().unwrap();
+    if chats.len() >= MAX_CHAT_RESULTS {
+        chats.pop_front();
+    }
+    chats.push_back((id, None));
+    id
+}
+
+/// Keep the reply text or the error of chat request `id` for `GET /api/chat?id=`
+pub fn chat_answered(id: u32, result: Result<String, String>) {
+    let mut chats = CHATS.lock().unwrap();
+    if let Some((_, outcome)) = chats.iter_mut().find(|(other, _)| *other == id) {
+        *outcome = Some(result);
+    }
+}
+
+/// Outcome of chat request `id`, None once it has been forgotten
+fn chat_result(id: u32) -> Option<Option<Result<String, String>>> {
+    let chats = CHATS.lock().unwrap();
+    chats
+        .iter()
+        .find(|(other, _)| *other == id)
+        .map(|(_, outcome)| outcome.clone())
+}
+
+fn respond(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    req.into_response(status, None, &[("Content-Type", content_type)])?
+        .write_all(body)?;
+    Ok(())
+}
+
+fn respond_json(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    value: &impl Serialize,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(value)?;
+    respond(req, status, "application/json", &body)
+}
+
+fn respond_ok(req: Request<&mut EspHttpConnection<'_>>) -> anyhow::Result<()> {
+    respond_json(req, 200, &serde_json::json!({ "ok": true }))
+}
+
+fn respond_error(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    message: &str,
+) -> anyhow::Result<()> {
+    log::warn!("Web request failed with {}: {}", status, message);
+    respond_json(req, status, &serde_json::json!({ "error": message }))
+}
+
+/// Polls the status every few seconds, the token is kept in the browser's local storage
+const DASHBOARD_PAGE: &str = r#"<!DOCTYPE html><html><head><meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>AI Chatbox</title><style>body{font-family:sans-serif;margin:2em;max-width:40em}
+textarea,input{width:100%;padding:.5em;margin:.3em 0 1em;box-sizing:border-box}
+textarea{height:20em;font-family:monospace}pre{background:#f4f4f4;padding:1em}
+.error{color:#c00}#live p{margin:.3em 0}.partial{color:#888}</style></head><body>
+<h2>AI Chatbox</h2>
+<label>访问令牌（配置文件中的 web.token）</label><input id="token" type="password">
+<h3>状态</h3><pre id="status">…</pre>
+<h3>操作</h3>
+<input id="text" placeholder="要说的话"><button onclick="speak()">播放</button>
+<input id="question" placeholder="问助手"><label><input id="speak-reply" type="checkbox"
+style="width:auto">朗读回答</label><button onclick="chat()">发送</button>
+<button onclick="post('/api/restart')">重新开始对话</button>
+<button onclick="mute(true)">静音</button><button onclick="mute(false)">取消静音</button>
+<button onclick="confirm('清除所有设置和对话记录并重启？') && post('/api/factory_reset')">恢复出厂设置</button>
+<h3>对话</h3><div id="live"></div><p id="speech"></p>
+<h3>配置</h3>
+<textarea id="config"></textarea>
+<button onclick="loadConfig()">重新读取</button><button onclick="saveConfig()">保存</button>
+<p id="message"></p>
+<script>
+const token = document.getElementById('token');
+token.value = localStorage.getItem('token') || '';
+token.onchange = () => { localStorage.setItem('token', token.value); refresh(); loadConfig(); };
+function headers() { return token.value ? {'Authorization': 'Bearer ' + token.value} : {}; }
+async function call(method, path, body) {
+  const response = await fetch(path, {method, headers: headers(), body});
+  const message = document.getElementById('message');
+  if (!response.ok) {
+    const error = await response.json().catch(() => ({error: response.statusText}));
+    message.className = 'error';
+    message.textContent = error.error;
+    throw new Error(error.error);
+  }
+  message.className = '';
+  message.textContent = method === 'GET' ? '' : '完成';
+  return response;
+}
+function post(path, value) { return call('POST', path, value && JSON.stringify(value)); }
+function speak() { post('/api/speak', {text: document.getElementById('text').value}); }
+function chat() {
+  const question = document.getElementById('question');
+  const speak = document.getElementById('speak-reply').checked;
+  post('/api/chat', {text: question.value, speak}).then(() => { question.value = ''; });
+}
+function mute(muted) { post('/api/mute', {muted}).then(refresh); }
+async function refresh() {
+  const status = await (await call('GET', '/api/status')).json();
+  document.getElementById('status').textContent = JSON.stringify(status, null, 2);
+}
+async function loadConfig() {
+  document.getElementById('config').value = await (await call('GET', '/api/config')).text();
+}
+function saveConfig() { call('PUT', '/api/config', document.getElementById('config').value); }
+function line(text, className) {
+  const p = document.createElement('p');
+  p.textContent = text;
+  if (className) p.className = className;
+  document.getElementById('live').appendChild(p);
+  return p;
+}
+let partial = null;
+function connect() {
+  const ws = new WebSocket('ws://' + location.host + '/ws');
+  ws.onopen = () => { if (token.value) ws.send(token.value); };
+  ws.onclose = () => setTimeout(connect, 3000);
+  ws.onmessage = (message) => {
+    const event = JSON.parse(message.data);
+    const speech = document.getElementById('speech');
+    if (event.type === 'wake_detected') line('— 已唤醒 —');
+    if (event.type === 'partial_transcript') {
+      partial = partial || line('', 'partial');
+      partial.textContent = '你：' + event.text;
+    }
+    if (event.type === 'transcript') {
+      if (partial) partial.remove();
+      partial = null;
+      line('你：' + event.text);
+    }
+    if (event.type === 'reply') line('助手：' + event.text);
+    if (event.type === 'speech_started') speech.textContent = '正在播放：' + event.text;
+    if (event.type === 'speech_progress') speech.textContent = '正在播放 ' + event.chunk + '/' + event.chunks;
+    if (event.type === 'speech_finished') speech.textContent = '';
+    if (event.type === 'low_memory') line('— 内存不足：剩余 ' + Math.round(event.free_internal / 1024) + ' KB —');
+  };
+}
+refresh(); loadConfig(); connect(); setInterval(refresh, 5000);
+</script></body></html>"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_authorized() {
+        assert!(!is_authorized(None, ""));
+        assert!(!is_authorized(Some("Bearer "), ""));
+        assert!(is_authorized(Some("Bearer secret"), "secret"));
+        assert!(!is_authorized(Some("Bearer wrong"), "secret"));
+        assert!(!is_authorized(Some("secret"), "secret"));
+        assert!(!is_authorized(None, "secret"));
+        assert!(!is_authorized(Some("Bearer secreT"), "secret"));
+        assert!(!is_authorized(Some("Bearer secret2"), "secret"));
+    }
+
+    #[test]
+    fn test_chat_request() {
+        let request: ChatRequest = serde_json::from_str(r#"{"text": "你好"}"#).unwrap();
+        assert!(!request.speak);
+        let request: ChatRequest =
+            serde_json::from_str(r#"{"text": "你好", "speak": true}"#).unwrap();
+        assert!(request.speak);
+    }
+
+    #[test]
+    fn test_chat_results() {
+        let id = chat_started();
+        assert_eq!(chat_result(id), Some(None));
+        chat_answered(id, Ok("你好".to_string()));
+        assert_eq!(chat_result(id), Some(Some(Ok("你好".to_string()))));
+
+        for _ in 0..MAX_CHAT_RESULTS {
+            chat_started();
+        }
+        assert_eq!(chat_result(id), None);
+        chat_answered(id, Err("late".to_string()));
+        assert_eq!(chat_result(id), None);
+    }
+}
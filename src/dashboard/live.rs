@@ -0,0 +1,209 @@
+use esp_idf_svc::http::server::ws::{EspHttpWsConnection, EspHttpWsDetachedSender};
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::ws::FrameType;
+use serde::Serialize;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+use crate::buttons::ButtonAction;
+use crate::display;
+use crate::mqtt;
+use crate::power;
+
+/// Browsers receiving the live events, with the socket each one is connected on
+static SUBSCRIBERS: Mutex<Vec<(i32, EspHttpWsDetachedSender)>> = Mutex::new(Vec::new());
+/// Each subscriber costs a socket of the server, which has only a few
+const MAX_SUBSCRIBERS: usize = 4;
+/// Clients only ever send the token
+const MAX_FRAME_BYTES: usize = 256;
+/// Events waiting for the browsers and the broker, further ones are dropped while they lag
+const QUEUE_LEN: usize = 16;
+
+/// Set up by the first event, None when the sending thread couldn't be started
+static QUEUE: OnceLock<Option<SyncSender<LiveEvent>>> = OnceLock::new();
+
+/// What happens in the conversation, sent to every subscriber as JSON, e.g.
+/// `{"type": "transcript", "text": "今天天气怎么样"}`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveEvent {
+    WakeDetected,
+    /// Hypothesis of the streaming recognizer while the user is still speaking
+    PartialTranscript {
+        text: String,
+    },
+    /// What the user said, final
+    Transcript {
+        text: String,
+    },
+    /// Answer to the last utterance, from the LLM or a voice command
+    Reply {
+        text: String,
+    },
+    SpeechStarted {
+        text: String,
+    },
+    /// The on-device voice is about to speak chunk `chunk` of `chunks`, counted from 1
+    SpeechProgress {
+        chunk: usize,
+        chunks: usize,
+    },
+    SpeechFinished,
+    ButtonPressed {
+        action: ButtonAction,
+    },
+    /// Free internal RAM averaged over the last samples fell below the configured floor, in bytes
+    LowMemory {
+        free_internal: u32,
+        floor: u32,
+    },
+}
+
+/// Send an event to every connected browser, the MQTT broker and the display
+///
+/// Each one but a warning means somebody is using the device, which keeps it out of idle.
+/// Browsers and the broker are served from a thread of their own, a slow connection drops
+/// events instead of holding up the conversation.
+pub fn publish(event: LiveEvent) {
+    if !matches!(event, LiveEvent::LowMemory { .. }) {
+        power::activity();
+    }
+    display::show_event(&event);
+
+    let Some(queue) = QUEUE.get_or_init(start_sending) else {
+        return;
+    };
+    if let Err(TrySendError::Full(event)) = queue.try_send(event) {
+        log::debug!("Live event queue is full, dropping {:?}", event);
+    }
+}
+
+fn start_sending() -> Option<SyncSender<LiveEvent>> {
+    let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+    let spawned = thread::Builder::new()
+        .name("live_events".to_string())
+        .stack_size(6 * 1024)
+        .spawn(move || {
+            for event in rx {
+                send(&event);
+            }
+        });
+    match spawned {
+        Ok(_) => Some(tx),
+        Err(e) => {
+            log::error!("Failed to start sending live events: {}", e);
+            None
+        }
+    }
+}
+
+fn send(event: &LiveEvent) {
+    mqtt::publish_event(event);
+
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    if subscribers.is_empty() {
+        return;
+    }
+    let Ok(json) = serde_json::to_string(event) else {
+        return;
+    };
+
+    subscribers.retain_mut(|(session, sender)| {
+        match sender.send(FrameType::Text(false), json.as_bytes()) {
+            Ok(()) => true,
+            Err(e) => {
+                log::info!("Live event client {} is gone: {}", session, e);
+                false
+            }
+        }
+    });
+}
+
+/// Accept WebSocket clients on `/ws`
+///
+/// Browsers can't set headers on a WebSocket, so the client sends the token as its first message
+/// and only receives events once it matched. Without a token configured nobody does.
+pub(super) fn register(server: &mut EspHttpServer<'static>, token: String) -> anyhow::Result<()> {
+    server.ws_handler(
+        "/ws",
+        move |ws: &mut EspHttpWsConnection| -> Result<(), EspError> {
+            if ws.is_new() {
+                log::info!("Live event client {} connected", ws.session());
+                return Ok(());
+            }
+            if ws.is_closed() {
+                unsubscribe(ws.session());
+                return Ok(());
+            }
+
+            let (_frame_type, len) = ws.recv(&mut [])?;
+            if len > MAX_FRAME_BYTES {
+                ws.send(FrameType::Close, &[])?;
+                return Ok(());
+            }
+            let mut buffer = [0u8; MAX_FRAME_BYTES];
+            ws.recv(&mut buffer[..len])?;
+
+            // Text frames arrive NUL terminated
+            let message = std::str::from_utf8(&buffer[..len])
+                .unwrap_or("")
+                .trim_end_matches('\0');
+            if !token.is_empty() && message == token {
+                subscribe(ws)?;
+            }
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
+
+fn subscribe(ws: &mut EspHttpWsConnection) -> Result<(), EspError> {
+    let session = ws.session();
+    let sender = ws.create_detached_sender()?;
+
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    subscribers.retain(|(other, _)| *other != session);
+    if subscribers.len() >= MAX_SUBSCRIBERS {
+        // Most likely a tab that was closed without saying goodbye
+        let (oldest, _) = subscribers.remove(0);
+        log::info!("Too many live event clients, dropping {}", oldest);
+    }
+    subscribers.push((session, sender));
+    log::info!("Live event client {} subscribed", session);
+    Ok(())
+}
+
+fn unsubscribe(session: i32) {
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|(other, _)| *other != session);
+    log::info!("Live event client {} disconnected", session);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_json() {
+        let json = serde_json::to_string(&LiveEvent::Transcript {
+            text: "你好".to_string(),
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"type":"transcript","text":"你好"}"#);
+        assert_eq!(
+            serde_json::to_string(&LiveEvent::WakeDetected).unwrap(),
+            r#"{"type":"wake_detected"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&LiveEvent::ButtonPressed {
+                action: ButtonAction::VolumeUp
+            })
+            .unwrap(),
+            r#"{"type":"button_pressed","action":"volume_up"}"#
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::config::AppConfig;
+use crate::http_client::{pinned_certificate, url_host_port};
+use crate::llm_intf::endpoint_url;
+use crate::stt::stt_url;
+use crate::wifi;
+
+/// Time allowed for each step, a working service answers well within it
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Steps between the device and a service, checked in this order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hop {
+    /// The device has no WiFi connection
+    Wifi,
+    /// The host name doesn't resolve
+    Dns,
+    /// Nothing accepts connections on the host's port
+    Connect,
+    /// The TLS handshake or the request itself failed
+    Request,
+}
+
+impl Hop {
+    fn describe(self) -> &'static str {
+        match self {
+            Hop::Wifi => "WiFi",
+            Hop::Dns => "DNS lookup",
+            Hop::Connect => "TCP connect",
+            Hop::Request => "TLS/HTTP request",
+        }
+    }
+
+    fn spoken(self) -> &'static str {
+        match self {
+            Hop::Wifi => "无线网络没有连上",
+            Hop::Dns => "域名解析失败",
+            Hop::Connect => "服务器连不上",
+            Hop::Request => "加密连接或请求失败",
+        }
+    }
+}
+
+/// A service the device talks to
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// Used in logs, e.g. "LLM fallback 1"
+    pub name: String,
+    /// Used in the spoken summary, e.g. "备用大模型1"
+    pub spoken_name: String,
+    pub url: String,
+}
+
+/// How far the device got with one service
+#[derive(Debug)]
+pub struct Report {
+    pub target: Target,
+    /// HTTP status the service answered with, or the hop that failed and why
+    pub result: Result<u16, (Hop, String)>,
+    pub elapsed: Duration,
+}
+
+/// The speech to text service and every configured LLM service
+pub fn targets(config: &AppConfig) -> Vec<Target> {
+    let mut targets = vec![
+        Target {
+            name: "STT".to_string(),
+            spoken_name: "语音识别".to_string(),
+            url: stt_url(&config.stt).to_string(),
+        },
+        Target {
+            name: "LLM".to_string(),
+            spoken_name: "大模型".to_string(),
+            url: endpoint_url(&config.llm.primary).to_string(),
+        },
+    ];
+    for (i, fallback) in config.llm.fallbacks.iter().enumerate() {
+        targets.push(Target {
+            name: format!("LLM fallback {}", i + 1),
+            spoken_name: format!("备用大模型{}", i + 1),
+            url: endpoint_url(fallback).to_string(),
+        });
+    }
+    targets
+}
+
+/// Check every hop to each target, logging where each one fails
+pub fn run(targets: &[Target]) -> Vec<Report> {
+    log::info!("Running network diagnostics");
+    let reports: Vec<Report> = targets.iter().map(check).collect();
+    for report in &reports {
+        match &report.result {
+            Ok(status) => log::info!(
+                "{} ({}): reachable, HTTP status {} in {} ms",
+                report.target.name,
+                report.target.url,
+                status,
+                report.elapsed.as_millis()
+            ),
+            Err((hop, error)) => log::warn!(
+                "{} ({}): {} failed: {}",
+                report.target.name,
+                report.target.url,
+                hop.describe(),
+                error
+            ),
+        }
+    }
+    reports
+}
+
+/// What failed for a service, e.g. "大模型：服务器连不上", None when it answered
+pub fn spoken_failure(report: &Report) -> Option<String> {
+    match &report.result {
+        Ok(_) => None,
+        Err((hop, _)) => Some(format!("{}：{}", report.target.spoken_name, hop.spoken())),
+    }
+}
+
+/// One sentence per failing service, or that all of them answered
+pub fn spoken_summary(reports: &[Report]) -> String {
+    // Every service fails at the first hop then, no need to list them
+    if reports
+        .iter()
+        .any(|report| matches!(report.result, Err((Hop::Wifi, _))))
+    {
+        return "网络检查发现无线网络没有连上。".to_string();
+    }
+
+    let failures: Vec<String> = reports.iter().filter_map(spoken_failure).collect();
+
+    if failures.is_empty() {
+        "网络检查完成，所有服务都能连上。".to_string()
+    } else {
+        format!("网络检查发现问题。{}。", failures.join("；"))
+    }
+}
+
+fn check(target: &Target) -> Report {
+    let started = Instant::now();
+    let result = check_hops(&target.url);
+    Report {
+        target: target.clone(),
+        result,
+        elapsed: started.elapsed(),
+    }
+}
+
+fn check_hops(url: &str) -> Result<u16, (Hop, String)> {
+    if !wifi::is_connected() {
+        return Err((Hop::Wifi, "not connected".to_string()));
+    }
+
+    let (host, port) =
+        host_and_port(url).ok_or_else(|| (Hop::Dns, format!("'{}' is not an http(s) URL", url)))?;
+    let address = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| (Hop::Dns, e.to_string()))?
+        .next()
+        .ok_or_else(|| (Hop::Dns, format!("no address for {}", host)))?;
+
+    TcpStream::connect_timeout(&address, STEP_TIMEOUT)
+        .map_err(|e| (Hop::Connect, format!("{}: {}", address, e)))?;
+
+    head(url).map_err(|e| (Hop::Request, e.to_string()))
+}
+
+/// Status of a HEAD request on a new connection, so the TLS handshake is part of the check
+fn head(url: &str) -> anyhow::Result<u16> {
+    let mut config = HttpConfiguration {
+        timeout: Some(STEP_TIMEOUT),
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+    if let Some(certificate) = pinned_certificate(url) {
+        config.server_certificate = Some(certificate);
+        config.crt_bundle_attach = None;
+        config.use_global_ca_store = false;
+    }
+
+    let mut client = EspHttpConnection::new(&config)?;
+    client.initiate_request(Method::Head, url, &[])?;
+    client.initiate_response()?;
+    // Any status means the service is there, 401 or 405 are expected without credentials
+    Ok(client.status())
+}
+
+/// Host and port a URL connects to, the scheme's default port unless one is given
+fn host_and_port(url: &str) -> Option<(&str, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let default_port = match scheme {
+        "http" => 80,
+        "https" => 443,
+        _ => return None,
+    };
+
+    let (host, port) = url_host_port(rest)?;
+    Some((host, port.unwrap_or(default_port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_and_port() {
+        assert_eq!(
+            host_and_port("https://api.deepseek.com/chat/completions"),
+            Some(("api.deepseek.com", 443))
+        );
+        assert_eq!(
+            host_and_port("http://192.168.1.10:5000?x=1"),
+            Some(("192.168.1.10", 5000))
+        );
+        assert_eq!(host_and_port("http://vosk"), Some(("vosk", 80)));
+        assert_eq!(
+            host_and_port("http://[fd00::10]:5000/"),
+            Some(("fd00::10", 5000))
+        );
+        assert_eq!(host_and_port("ws://vosk:2700"), None);
+        assert_eq!(host_and_port(""), None);
+    }
+
+    #[test]
+    fn test_spoken_summary() {
+        let report = |spoken_name: &str, result| Report {
+            target: Target {
+                name: String::new(),
+                spoken_name: spoken_name.to_string(),
+                url: String::new(),
+            },
+            result,
+            elapsed: Duration::ZERO,
+        };
+
+        assert_eq!(
+            spoken_summary(&[report("语音识别", Ok(200)), report("大模型", Ok(401))]),
+            "网络检查完成，所有服务都能连上。"
+        );
+        assert_eq!(
+            spoken_summary(&[
+                report("语音识别", Err((Hop::Connect, String::new()))),
+                report("大模型", Err((Hop::Dns, String::new()))),
+            ]),
+            "网络检查发现问题。语音识别：服务器连不上；大模型：域名解析失败。"
+        );
+        assert_eq!(
+            spoken_summary(&[report("语音识别", Err((Hop::Wifi, String::new())))]),
+            "网络检查发现无线网络没有连上。"
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use anyhow::anyhow;
+use display_interface_spi::SPIInterface;
+use embedded_graphics::pixelcolor::{BinaryColor, Rgb565};
+use embedded_graphics::prelude::*;
+use esp_idf_svc::hal::delay::Ets;
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyOutputPin, Output, PinDriver};
+use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver, I2C0};
+use esp_idf_svc::hal::spi::config::Config as SpiConfig;
+use esp_idf_svc::hal::spi::{SpiDeviceDriver, SpiDriver, SpiDriverConfig, SPI3};
+use esp_idf_svc::hal::units::FromValueType;
+use mipidsi::models::ST7789;
+use mipidsi::options::ColorInversion;
+use ssd1306::mode::BufferedGraphicsMode;
+use ssd1306::prelude::*;
+use ssd1306::{I2CDisplayInterface, Ssd1306};
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use u8g2_fonts::types::{FontColor, VerticalPosition};
+use u8g2_fonts::{fonts, FontRenderer};
+
+use crate::config::{DisplayConfig, DisplayDriver};
+use crate::dashboard::LiveEvent;
+use crate::led::Status;
+
+/// How often the status is checked, the same one the LED shows
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+/// A reply longer than the screen moves up one line this often
+const SCROLL_INTERVAL: Duration = Duration::from_millis(1500);
+/// Scroll steps the reply rests at its start and at its end
+const SCROLL_PAUSE: usize = 2;
+/// Rows of the 12 pixel font, with a pixel between them
+const LINE_HEIGHT: u32 = 13;
+/// Most rows what the user said takes, its end is kept since that is what changes
+const HEARD_ROWS: usize = 2;
+
+/// Last utterance and reply, kept by `show_event` whether a display is attached or not
+static TEXTS: Mutex<Texts> = Mutex::new(Texts::new());
+
+#[derive(Debug, Clone, PartialEq)]
+struct Texts {
+    heard: String,
+    reply: String,
+    /// Counts the changes so the reply starts over at the top when a new one comes
+    version: u32,
+}
+
+impl Texts {
+    const fn new() -> Self {
+        Self {
+            heard: String::new(),
+            reply: String::new(),
+            version: 0,
+        }
+    }
+}
+
+/// What is on the screen, the status on top
+#[derive(Debug, Clone, PartialEq)]
+struct View {
+    lines: Vec<String>,
+    /// Reply lines that don't fit below the rest
+    overflow: usize,
+}
+
+type Oled = Ssd1306<
+    I2CInterface<I2cDriver<'static>>,
+    DisplaySize128x64,
+    BufferedGraphicsMode<DisplaySize128x64>,
+>;
+type Lcd = mipidsi::Display<
+    SPIInterface<
+        SpiDeviceDriver<'static, SpiDriver<'static>>,
+        PinDriver<'static, AnyOutputPin, Output>,
+    >,
+    ST7789,
+    PinDriver<'static, AnyOutputPin, Output>,
+>;
+
+enum Screen {
+    Oled(Oled),
+    Lcd(Lcd),
+}
+
+impl Screen {
+    fn size(&self) -> Size {
+        match self {
+            Screen::Oled(oled) => oled.bounding_box().size,
+            Screen::Lcd(lcd) => lcd.bounding_box().size,
+        }
+    }
+
+    fn show(&mut self, lines: &[String]) -> anyhow::Result<()> {
+        match self {
+            Screen::Oled(oled) => {
+                draw_lines(
+                    oled,
+                    lines,
+                    BinaryColor::On,
+                    BinaryColor::On,
+                    BinaryColor::Off,
+                )?;
+                oled.flush().map_err(|e| anyhow!("{:?}", e))
+            }
+            Screen::Lcd(lcd) => draw_lines(lcd, lines, Rgb565::CYAN, Rgb565::WHITE, Rgb565::BLACK),
+        }
+    }
+}
+
+/// Keep what was heard and answered for the display, called with every live event
+pub fn show_event(event: &LiveEvent) {
+    let mut texts = TEXTS.lock().unwrap();
+    match event {
+        LiveEvent::WakeDetected => {
+            texts.heard.clear();
+            texts.reply.clear();
+        }
+        LiveEvent::PartialTranscript { text } | LiveEvent::Transcript { text } => {
+            texts.heard = text.clone();
+            texts.reply.clear();
+        }
+        LiveEvent::Reply { text } => texts.reply = text.clone(),
+        _ => return,
+    }
+    texts.version = texts.version.wrapping_add(1);
+}
+
+/// Drive the display in `config` from its own thread, does nothing when it has no driver
+///
+/// The SSD1306 is on I2C0 and the ST7789 on SPI3, the SD card has SPI2.
+pub fn start(config: &DisplayConfig, i2c: I2C0, spi: SPI3) -> anyhow::Result<()> {
+    let Some(driver) = config.driver else {
+        return Ok(());
+    };
+
+    let pin = |pin: Option<u8>| pin.map(|pin| unsafe { AnyIOPin::new(pin as i32) });
+    let output = |pin: Option<u8>| pin.map(|pin| unsafe { AnyOutputPin::new(pin as i32) });
+    let screen = match driver {
+        DisplayDriver::Ssd1306 => {
+            let i2c = I2cDriver::new(
+                i2c,
+                pin(config.sda).unwrap(),
+                pin(config.scl).unwrap(),
+                &I2cConfig::new().baudrate(400.kHz().into()),
+            )?;
+            let mut oled = Ssd1306::new(
+                I2CDisplayInterface::new(i2c),
+                DisplaySize128x64,
+                DisplayRotation::Rotate0,
+            )
+            .into_buffered_graphics_mode();
+            oled.init().map_err(|e| anyhow!("{:?}", e))?;
+            Screen::Oled(oled)
+        }
+        DisplayDriver::St7789 => {
+            let bus = SpiDriver::new(
+                spi,
+                pin(config.sclk).unwrap(),
+                pin(config.mosi).unwrap(),
+                Option::<AnyIOPin>::None,
+                &SpiDriverConfig::new(),
+            )?;
+            let device = SpiDeviceDriver::new(
+                bus,
+                output(config.cs),
+                &SpiConfig::new().baudrate(40.MHz().into()),
+            )?;
+            let dc = PinDriver::output(output(config.dc).unwrap())?;
+            let rst = PinDriver::output(output(config.rst).unwrap())?;
+            let lcd = mipidsi::Builder::new(ST7789, SPIInterface::new(device, dc))
+                .display_size(config.width, config.height)
+                .invert_colors(ColorInversion::Inverted)
+                .reset_pin(rst)
+                .init(&mut Ets)
+                .map_err(|e| anyhow!("{:?}", e))?;
+            if let Some(backlight) = output(config.backlight) {
+                let mut backlight = PinDriver::output(backlight)?;
+                backlight.set_high()?;
+                // Stays on for as long as the device runs
+                std::mem::forget(backlight);
+            }
+            Screen::Lcd(lcd)
+        }
+    };
+
+    thread::Builder::new()
+        .name("display".to_string())
+        .stack_size(6 * 1024)
+        .spawn(move || display_loop(screen))?;
+
+    log::info!("{:?} display started", driver);
+    Ok(())
+}
+
+fn display_loop(mut screen: Screen) {
+    let size = screen.size();
+    let rows = (size.height / LINE_HEIGHT) as usize;
+    // Built once, the texts are measured character by character on every pass
+    let font = font();
+    let mut shown: Option<Vec<String>> = None;
+    let mut version = None;
+    let mut scroll = 0;
+    let mut scrolled = Instant::now();
+
+    loop {
+        let texts = TEXTS.lock().unwrap().clone();
+        if version != Some(texts.version) {
+            version = Some(texts.version);
+            scroll = 0;
+            scrolled = Instant::now();
+        }
+
+        let view = layout(Status::current(), &texts, rows, scroll, |text| {
+            wrap(text, size.width, |c| char_width(&font, c))
+        });
+        if shown.as_ref() != Some(&view.lines) {
+            match screen.show(&view.lines) {
+                Ok(()) => shown = Some(view.lines),
+                Err(e) => log::warn!("Failed to update the display: {}", e),
+            }
+        }
+
+        if view.overflow > 0 && scrolled.elapsed() >= SCROLL_INTERVAL {
+            scrolled = Instant::now();
+            scroll = if scroll >= view.overflow + 2 * SCROLL_PAUSE {
+                0
+            } else {
+                scroll + 1
+            };
+        }
+
+        thread::sleep(FRAME_INTERVAL);
+    }
+}
+
+/// Fit the status, what was heard and the reply into `rows` lines with the reply `scroll`
+/// steps down, `wrap` breaks a text into lines as wide as the screen
+fn layout(
+    status: Status,
+    texts: &Texts,
+    rows: usize,
+    scroll: usize,
+    wrap: impl Fn(&str) -> Vec<String>,
+) -> View {
+    let mut lines = vec![status_label(status).to_string()];
+
+    if !texts.heard.is_empty() {
+        // Leave at least a row for the reply
+        let heard_rows = HEARD_ROWS.min(rows.saturating_sub(2));
+        let heard = wrap(&format!("你：{}", texts.heard));
+        lines.extend(
+            heard[heard.len().saturating_sub(heard_rows)..]
+                .iter()
+                .cloned(),
+        );
+    }
+
+    let reply = wrap(&texts.reply);
+    let reply_rows = rows.saturating_sub(lines.len());
+    let overflow = reply.len().saturating_sub(reply_rows);
+    let start = scroll.saturating_sub(SCROLL_PAUSE).min(overflow);
+    lines.extend(reply.into_iter().skip(start).take(reply_rows));
+
+    View { lines, overflow }
+}
+
+fn status_label(status: Status) -> &'static str {
+    match status {
+        Status::Muted => "已静音",
+        Status::Error => "网络不可用",
+        Status::Speaking => "正在回答",
+        Status::Thinking => "思考中",
+        Status::Recording => "正在听",
+        Status::Listening => "等待唤醒",
+    }
+}
+
+/// Break `text` into lines no wider than `max_width`, between any two characters since
+/// Chinese has no spaces
+fn wrap(text: &str, max_width: u32, width_of: impl Fn(char) -> u32) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut width = 0;
+
+    for c in text.chars() {
+        if c == '\n' {
+            lines.push(std::mem::take(&mut line));
+            width = 0;
+            continue;
+        }
+        if c.is_control() {
+            continue;
+        }
+        let char_width = width_of(c);
+        if width + char_width > max_width && !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+            width = 0;
+        }
+        line.push(c);
+        width += char_width;
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Covers the GB2312 characters, others are left out
+fn font() -> FontRenderer {
+    FontRenderer::new::<fonts::u8g2_font_wqy12_t_gb2312>().with_ignore_unknown_chars(true)
+}
+
+fn char_width(font: &FontRenderer, c: char) -> u32 {
+    let mut buffer = [0u8; 4];
+    font.get_rendered_dimensions(
+        &*c.encode_utf8(&mut buffer),
+        Point::zero(),
+        VerticalPosition::Top,
+    )
+    .map(|dimensions| dimensions.advance.x.max(0) as u32)
+    .unwrap_or(0)
+}
+
+/// Clear the screen and write `lines` from the top, the first in `accent`
+fn draw_lines<D>(
+    target: &mut D,
+    lines: &[String],
+    accent: D::Color,
+    color: D::Color,
+    background: D::Color,
+) -> anyhow::Result<()>
+where
+    D: DrawTarget,
+    D::Error: Debug,
+{
+    target.clear(background).map_err(|e| anyhow!("{:?}", e))?;
+
+    let font = font();
+    for (row, line) in lines.iter().enumerate() {
+        let position = Point::new(0, (row as u32 * LINE_HEIGHT) as i32);
+        let color = if row == 0 { accent } else { color };
+        font.render(
+            line.as_str(),
+            position,
+            VerticalPosition::Top,
+            FontColor::Transparent(color),
+            target,
+        )
+        .map_err(|e| anyhow!("{:?}", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Half as wide for ASCII, like the real font
+    fn width_of(c: char) -> u32 {
+        if c.is_ascii() {
+            6
+        } else {
+            12
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(wrap("你好abc", 24, width_of), vec!["你好", "abc"]);
+        assert_eq!(wrap("ab\ncd", 30, width_of), vec!["ab", "cd"]);
+
+        let texts = Texts {
+            heard: "今天天气怎么样".to_string(),
+            reply: "晴天，气温二十度，适合出门散步".to_string(),
+            version: 1,
+        };
+        let wrap = |text: &str| wrap(text, 48, width_of);
+
+        let view = layout(Status::Speaking, &texts, 6, 0, wrap);
+        assert_eq!(
+            view.lines,
+            vec![
+                "正在回答",
+                "天气怎么",
+                "样",
+                "晴天，气",
+                "温二十度",
+                "，适合出"
+            ]
+        );
+        assert_eq!(view.overflow, 1);
+
+        let view = layout(Status::Speaking, &texts, 6, SCROLL_PAUSE, wrap);
+        assert_eq!(view.lines[3..], ["晴天，气", "温二十度", "，适合出"]);
+        let view = layout(Status::Speaking, &texts, 6, 100, wrap);
+        assert_eq!(view.lines[3..], ["温二十度", "，适合出", "门散步"]);
+    }
+}
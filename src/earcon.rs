@@ -0,0 +1,109 @@
+use esp_idf_svc::hal::i2s::{I2sDriver, I2sTx};
+
+use crate::tts::apply_volume;
+
+/// Rate the I2S output runs at, see init_i2s_tx
+const SAMPLE_RATE: u32 = 16000;
+/// Peak amplitude of the tones, well below full scale so they don't startle
+const AMPLITUDE: f32 = 6000.0;
+/// Fade in and out of each tone to avoid clicks
+const FADE_MS: u32 = 5;
+
+/// Short sounds played instead of speech
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Earcon {
+    /// The user spoke again while the last question was still being answered
+    StillThinking,
+    /// Waiting for the LLM, repeated until the reply arrives so it has to stay unobtrusive
+    Thinking,
+    /// Played at the new volume for each step of the volume knob
+    VolumeTick,
+    /// Played by the boot self-test, nobody hearing it means the speaker is broken
+    SelfTest,
+    /// A timer ran out or an alarm is due, meant to be heard across the room
+    Alarm,
+    /// An utterance was written to the notes in dictation mode
+    NoteSaved,
+    /// Ding-dong before an announcement pushed from the network
+    Announcement,
+}
+
+impl Earcon {
+    /// Frequency in Hz and duration in ms of each tone, a frequency of 0 is a pause
+    fn tones(&self) -> &'static [(u32, u32)] {
+        match self {
+            Earcon::StillThinking => &[(660, 80), (0, 60), (660, 80)],
+            Earcon::Thinking => &[(440, 150)],
+            Earcon::VolumeTick => &[(880, 40)],
+            Earcon::SelfTest => &[(523, 120), (0, 40), (784, 160)],
+            Earcon::Alarm => &[(988, 200), (0, 100), (988, 200), (0, 100), (988, 200)],
+            Earcon::NoteSaved => &[(784, 60), (0, 30), (1047, 80)],
+            Earcon::Announcement => &[(659, 250), (0, 50), (523, 400)],
+        }
+    }
+
+    fn amplitude(&self) -> f32 {
+        match self {
+            Earcon::StillThinking | Earcon::Alarm | Earcon::Announcement => AMPLITUDE,
+            Earcon::Thinking | Earcon::VolumeTick | Earcon::SelfTest | Earcon::NoteSaved => {
+                AMPLITUDE / 3.0
+            }
+        }
+    }
+
+    /// 16 kHz mono PCM of the earcon
+    pub fn samples(&self) -> Vec<i16> {
+        let mut samples = Vec::new();
+        let fade_len = (SAMPLE_RATE * FADE_MS / 1000) as usize;
+        let amplitude = self.amplitude();
+
+        for &(frequency, duration_ms) in self.tones() {
+            let len = (SAMPLE_RATE * duration_ms / 1000) as usize;
+
+            for i in 0..len {
+                if frequency == 0 {
+                    samples.push(0);
+                    continue;
+                }
+
+                let fade = (i.min(len - 1 - i) as f32 / fade_len as f32).min(1.0);
+                let phase =
+                    2.0 * std::f32::consts::PI * frequency as f32 * i as f32 / SAMPLE_RATE as f32;
+                samples.push((phase.sin() * amplitude * fade) as i16);
+            }
+        }
+
+        samples
+    }
+
+    /// Play the earcon at a volume in percent
+    pub fn play(&self, volume: u8, i2s_driver: &mut I2sDriver<I2sTx>) -> anyhow::Result<()> {
+        let mut samples = self.samples();
+        apply_volume(&mut samples, volume);
+
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        i2s_driver.write_all(&bytes, 1000)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_still_thinking_length() {
+        let samples = Earcon::StillThinking.samples();
+        assert_eq!(samples.len(), (SAMPLE_RATE * 220 / 1000) as usize);
+        // Tones start and end silent
+        assert_eq!(samples[0], 0);
+        assert_eq!(*samples.last().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_thinking_is_quieter() {
+        let peak = |earcon: Earcon| earcon.samples().iter().map(|s| s.unsigned_abs()).max();
+        assert!(peak(Earcon::Thinking) < peak(Earcon::StillThinking));
+    }
+}
@@ -0,0 +1,117 @@
+use esp_idf_svc::hal::gpio::AnyInputPin;
+use esp_idf_svc::hal::pcnt::{
+    PcntChannel, PcntChannelConfig, PcntControlMode, PcntCountMode, PcntDriver, PinIndex, PCNT0,
+};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::EncoderConfig;
+use crate::transcription::TranscriptionMessage;
+
+/// Counts of the quadrature decoding below per detent of a typical encoder
+const COUNTS_PER_DETENT: i16 = 4;
+/// The counter is cleared long before it gets near these
+const COUNTER_LIMIT: i16 = 1000;
+/// Pulses shorter than this many APB cycles (80 MHz) are contact noise
+const FILTER_CYCLES: u16 = 1000;
+/// How often the counter is read, fast enough that the knob feels direct
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Count the turns of the encoder in `config` with the PCNT unit, does nothing when it has no pins
+///
+/// Each detent changes the volume by `volume_step` percent. The push switch is handled by the
+/// buttons module.
+pub fn start(
+    config: &EncoderConfig,
+    pcnt: PCNT0,
+    transcription_tx: Sender<TranscriptionMessage>,
+) -> anyhow::Result<()> {
+    let (Some(pin_a), Some(pin_b)) = (config.pin_a, config.pin_b) else {
+        return Ok(());
+    };
+
+    let mut counter = PcntDriver::new(
+        pcnt,
+        Some(unsafe { AnyInputPin::new(pin_a as i32) }),
+        Some(unsafe { AnyInputPin::new(pin_b as i32) }),
+        Option::<AnyInputPin>::None,
+        Option::<AnyInputPin>::None,
+    )?;
+
+    // Both edges of both pins count, each channel reversed by the level of the other pin
+    counter.channel_config(
+        PcntChannel::Channel0,
+        PinIndex::Pin0,
+        PinIndex::Pin1,
+        &PcntChannelConfig {
+            lctrl_mode: PcntControlMode::Reverse,
+            hctrl_mode: PcntControlMode::Keep,
+            pos_mode: PcntCountMode::Decrement,
+            neg_mode: PcntCountMode::Increment,
+            counter_h_lim: COUNTER_LIMIT,
+            counter_l_lim: -COUNTER_LIMIT,
+        },
+    )?;
+    counter.channel_config(
+        PcntChannel::Channel1,
+        PinIndex::Pin1,
+        PinIndex::Pin0,
+        &PcntChannelConfig {
+            lctrl_mode: PcntControlMode::Reverse,
+            hctrl_mode: PcntControlMode::Keep,
+            pos_mode: PcntCountMode::Increment,
+            neg_mode: PcntCountMode::Decrement,
+            counter_h_lim: COUNTER_LIMIT,
+            counter_l_lim: -COUNTER_LIMIT,
+        },
+    )?;
+    counter.set_filter_value(FILTER_CYCLES)?;
+    counter.filter_enable()?;
+    counter.counter_clear()?;
+    counter.counter_resume()?;
+
+    let volume_step = config.volume_step as i32;
+    thread::Builder::new()
+        .name("encoder".to_string())
+        .stack_size(3 * 1024)
+        .spawn(move || encoder_loop(counter, volume_step, transcription_tx))?;
+
+    log::info!("Rotary encoder on GPIO{} and GPIO{}", pin_a, pin_b);
+    Ok(())
+}
+
+fn encoder_loop(
+    counter: PcntDriver<'static>,
+    volume_step: i32,
+    transcription_tx: Sender<TranscriptionMessage>,
+) {
+    loop {
+        thread::sleep(POLL_INTERVAL);
+
+        let count = match counter.get_counter_value() {
+            Ok(count) => count,
+            Err(e) => {
+                log::warn!("Failed to read the rotary encoder: {}", e);
+                continue;
+            }
+        };
+        let detents = count / COUNTS_PER_DETENT;
+        if detents == 0 {
+            continue;
+        }
+        // A partial detent left over is dropped, the knob rests on a detent anyway
+        if let Err(e) = counter.counter_clear() {
+            log::warn!("Failed to clear the rotary encoder: {}", e);
+        }
+
+        let delta = detents as i32 * volume_step;
+        if transcription_tx
+            .send(TranscriptionMessage::ChangeVolume { delta })
+            .is_err()
+        {
+            log::warn!("Transcription worker has exited, stop reading the encoder");
+            break;
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{self, esp};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::answer_cache::CACHE_FILE_PATH;
+use crate::facts::FACTS_FILE_PATH;
+use crate::knowledge::INDEX_FILE_PATH;
+use crate::recordings::ARCHIVE_DIR;
+use crate::session::SESSIONS_DIR;
+use crate::speakers::PROFILES_DIR;
+use crate::transcript_log::TRANSCRIPT_DIR;
+use crate::upload_queue::QUEUE_DIR;
+use crate::usage::USAGE_FILE_PATH;
+
+/// NVS namespace with the reset request, the only one surviving the reset
+const FACTORY_NVS_NAMESPACE: &str = "factory";
+/// Set by `request`, everything is wiped on the next boot
+const KEY_RESET: &str = "reset";
+/// Set by the wipe, WiFi provisioning starts right away on that boot
+const KEY_PROVISION: &str = "provision";
+
+/// What the device recorded and learned on the card, config.toml and the models are kept
+const USER_DATA: [&str; 9] = [
+    SESSIONS_DIR,
+    TRANSCRIPT_DIR,
+    ARCHIVE_DIR,
+    QUEUE_DIR,
+    PROFILES_DIR,
+    CACHE_FILE_PATH,
+    USAGE_FILE_PATH,
+    INDEX_FILE_PATH,
+    FACTS_FILE_PATH,
+];
+
+/// Opened by `reset_nvs_if_requested`
+static NVS: Mutex<Option<EspNvs<NvsDefault>>> = Mutex::new(None);
+
+/// Erase NVS if the previous boot asked for a factory reset, before anything else opens it
+///
+/// Returns true when it did, the card is wiped by `delete_user_data` once it is mounted.
+pub fn reset_nvs_if_requested(nvs_partition: &EspDefaultNvsPartition) -> bool {
+    let requested =
+        open(nvs_partition).is_some_and(|nvs| matches!(nvs.get_u8(KEY_RESET), Ok(Some(_))));
+    if requested {
+        log::warn!("Factory reset, erasing NVS");
+        // Settings, known networks, the WiFi driver's own data and the rest alike
+        let erased = esp!(unsafe { sys::nvs_flash_erase() })
+            .and_then(|_| esp!(unsafe { sys::nvs_flash_init() }));
+        if let Err(e) = erased {
+            log::error!("Failed to erase NVS: {}", e);
+        }
+    }
+
+    let nvs = open(nvs_partition);
+    if let (true, Some(nvs)) = (requested, nvs.as_ref()) {
+        // Also when erasing failed, a reset must not repeat on every boot
+        let _ = nvs.remove(KEY_RESET);
+        if let Err(e) = nvs.set_u8(KEY_PROVISION, 1) {
+            log::warn!("Failed to ask for WiFi provisioning: {}", e);
+        }
+    }
+    *NVS.lock().unwrap() = nvs;
+    requested
+}
+
+/// Delete the conversations, recordings and voice prints on the card, after a factory reset
+pub fn delete_user_data() {
+    for path in USER_DATA {
+        let path = Path::new(path);
+        let result = if path.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else if path.exists() {
+            std::fs::remove_file(path)
+        } else {
+            continue;
+        };
+        match result {
+            Ok(()) => log::info!("Deleted {}", path.display()),
+            Err(e) => log::warn!("Failed to delete {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Wipe settings and user data on the next boot, the caller restarts the device
+pub fn request() -> anyhow::Result<()> {
+    let nvs = NVS.lock().unwrap();
+    let nvs = nvs
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("NVS is not available"))?;
+    nvs.set_u8(KEY_RESET, 1)?;
+    log::warn!("Factory reset requested, wiping on the next boot");
+    Ok(())
+}
+
+/// Whether this is the first boot after a factory reset, only true once
+pub fn take_provisioning_request() -> bool {
+    let nvs = NVS.lock().unwrap();
+    let Some(nvs) = nvs.as_ref() else {
+        return false;
+    };
+    match nvs.get_u8(KEY_PROVISION) {
+        Ok(Some(_)) => {
+            let _ = nvs.remove(KEY_PROVISION);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn open(nvs_partition: &EspDefaultNvsPartition) -> Option<EspNvs<NvsDefault>> {
+    match EspNvs::new(nvs_partition.clone(), FACTORY_NVS_NAMESPACE, true) {
+        Ok(nvs) => Some(nvs),
+        Err(e) => {
+            log::warn!("Failed to open NVS namespace for factory reset: {}", e);
+            None
+        }
+    }
+}
@@ -1,28 +1,284 @@
 use anyhow;
-use esp_idf_svc::http::client::{EspHttpConnection};
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
 use esp_idf_svc::http::Method;
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-/// Helper function to send a multipart request with a file
+use crate::watchdog;
+
+mod certs;
+mod encoding;
+mod error;
+mod retry;
+mod trace;
+mod url;
+mod websocket;
+
+pub use certs::pinned_certificate;
+pub use encoding::{decode_body, ACCEPT_ENCODING};
+pub use error::HttpError;
+pub use retry::{AttemptError, RetryPolicy, RetryableError};
+pub use trace::{enter_turn, new_turn_id, set_tracing};
+pub use url::url_host_port;
+pub use websocket::{WebSocket, WebSocketMessage};
+
+/// Size of the pieces a file is read from the SD card and sent in
+const UPLOAD_CHUNK_SIZE: usize = 4096;
+/// Downloads can be large, the timeout only applies to each read
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+/// Servers close idle connections after a minute or so, older ones aren't worth keeping
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+/// Connections kept open between requests, at most one per server
+static IDLE_CONNECTIONS: Mutex<Vec<IdleConnection>> = Mutex::new(Vec::new());
+
+struct IdleConnection {
+    key: String,
+    connection: EspHttpConnection,
+    since: Instant,
+}
+
+/// A connection to one server, reused by later requests to it so only the first pays for the TLS handshake
+pub struct PooledConnection {
+    key: String,
+    /// Without the query, which may carry an API key, for tracing
+    url: String,
+    config: HttpConfiguration,
+    connection: EspHttpConnection,
+    /// Kept alive from an earlier request, the server may have closed it meanwhile
+    reused: bool,
+}
+
+impl PooledConnection {
+    /// Take the idle connection to the server of the URL, or open a new one
+    pub fn open(url: &str, config: &HttpConfiguration) -> anyhow::Result<Self> {
+        // Connections differ in timeout and certificate checks, only identical ones are shared
+        let key = format!(
+            "{}|{:?}|{}",
+            url_origin(url),
+            config.timeout,
+            config.use_global_ca_store
+        );
+        let mut config = config.clone();
+        if let Some(certificate) = pinned_certificate(url) {
+            // Self-hosted servers aren't signed by a CA of the bundle
+            config.server_certificate = Some(certificate);
+            config.crt_bundle_attach = None;
+            config.use_global_ca_store = false;
+        }
+
+        let idle = {
+            let mut idle_connections = IDLE_CONNECTIONS.lock().unwrap();
+            idle_connections.retain(|idle| idle.since.elapsed() < MAX_IDLE);
+            idle_connections
+                .iter()
+                .position(|idle| idle.key == key)
+                .map(|index| idle_connections.swap_remove(index).connection)
+        };
+
+        let (connection, reused) = match idle {
+            Some(connection) => (connection, true),
+            None => (EspHttpConnection::new(&config)?, false),
+        };
+
+        Ok(Self {
+            key,
+            url: url.split('?').next().unwrap_or(url).to_string(),
+            config,
+            connection,
+            reused,
+        })
+    }
+
+    /// Send a request, once more on a new connection if a kept-alive one turned out to be closed
+    ///
+    /// `method` is only used for tracing, `request` has to initiate the request itself.
+    pub fn send<T, E: std::fmt::Display>(
+        &mut self,
+        method: Method,
+        request: impl FnMut(&mut EspHttpConnection) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let started = Instant::now();
+        let result = self.send_with_reconnect(request);
+        let outcome = match &result {
+            Ok(_) => Ok(self.connection.status()),
+            Err(e) => Err(e as &dyn std::fmt::Display),
+        };
+        trace::trace_request(method, &self.url, started, outcome);
+        result
+    }
+
+    fn send_with_reconnect<T, E: std::fmt::Display>(
+        &mut self,
+        mut request: impl FnMut(&mut EspHttpConnection) -> Result<T, E>,
+    ) -> Result<T, E> {
+        match request(&mut self.connection) {
+            Err(e) if self.reused => {
+                log::info!("Kept-alive connection failed ({}), reconnecting", e);
+                self.reused = false;
+                match EspHttpConnection::new(&self.config) {
+                    Ok(connection) => {
+                        self.connection = connection;
+                        request(&mut self.connection)
+                    }
+                    Err(connect_error) => {
+                        log::warn!("Failed to reconnect: {}", connect_error);
+                        Err(e)
+                    }
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Keep the connection for the next request, only call this once the response was read to the end
+    pub fn release(self) {
+        let mut idle_connections = IDLE_CONNECTIONS.lock().unwrap();
+        idle_connections.retain(|idle| idle.key != self.key);
+        idle_connections.push(IdleConnection {
+            key: self.key,
+            connection: self.connection,
+            since: Instant::now(),
+        });
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = EspHttpConnection;
+
+    fn deref(&self) -> &EspHttpConnection {
+        &self.connection
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut EspHttpConnection {
+        &mut self.connection
+    }
+}
+
+/// Scheme, host and port of a URL, the part a connection is bound to
+fn url_origin(url: &str) -> &str {
+    let path_start = url.find("://").map_or(0, |scheme_end| scheme_end + 3);
+    match url[path_start..].find(['/', '?']) {
+        Some(index) => &url[..path_start + index],
+        None => url,
+    }
+}
+
+/// Content of a multipart file
+pub enum MultipartData<'a> {
+    /// Already in memory, e.g. an encoded recording
+    Bytes(&'a [u8]),
+    /// File on the SD card, read in chunks while it is sent
+    File(&'a str),
+    /// Produced while it is sent, e.g. audio still being captured; ends when the sender is dropped.
+    /// The length isn't known up front, so the request uses chunked transfer encoding.
+    #[allow(dead_code)] // For the live streaming capture path
+    Stream(&'a Receiver<Vec<u8>>),
+}
+
+impl MultipartData<'_> {
+    /// Size of the data, None when it is only known once everything was sent
+    fn len(&self) -> anyhow::Result<Option<usize>> {
+        match self {
+            MultipartData::Bytes(data) => Ok(Some(data.len())),
+            MultipartData::File(path) => Ok(Some(std::fs::metadata(path)?.len() as usize)),
+            MultipartData::Stream(_) => Ok(None),
+        }
+    }
+
+    /// Whether the byte sequence occurs in the data; a stream can't be checked before it is sent
+    fn contains(&self, needle: &[u8]) -> anyhow::Result<bool> {
+        match self {
+            MultipartData::Bytes(data) => Ok(contains_subslice(data, needle)),
+            MultipartData::File(path) => {
+                let mut reader = std::fs::File::open(path)?;
+                // Keep the end of the previous chunk so a match across chunks is found
+                let mut window = Vec::with_capacity(UPLOAD_CHUNK_SIZE + needle.len());
+                let mut buffer = vec![0u8; UPLOAD_CHUNK_SIZE];
+                loop {
+                    let bytes_read = std::io::Read::read(&mut reader, &mut buffer)?;
+                    if bytes_read == 0 {
+                        return Ok(false);
+                    }
+                    window.extend_from_slice(&buffer[..bytes_read]);
+                    if contains_subslice(&window, needle) {
+                        return Ok(true);
+                    }
+                    let keep = window.len().min(needle.len().saturating_sub(1));
+                    window.drain(..window.len() - keep);
+                }
+            }
+            MultipartData::Stream(_) => Ok(false),
+        }
+    }
+}
+
+fn contains_subslice(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// Random boundary that occurs neither in the fields nor in the file
+fn multipart_boundary(fields: &[(&str, &str)], file: &MultipartFile) -> anyhow::Result<String> {
+    loop {
+        let random: String = (0..4)
+            .map(|_| format!("{:08x}", unsafe { esp_idf_svc::sys::esp_random() }))
+            .collect();
+        let boundary = format!("----------------esp32{}", random);
+
+        let in_fields = fields
+            .iter()
+            .any(|(name, value)| name.contains(&boundary) || value.contains(&boundary));
+        if !in_fields && !file.data.contains(boundary.as_bytes())? {
+            return Ok(boundary);
+        }
+        log::warn!("Multipart boundary {} occurs in the payload, picking another", boundary);
+    }
+}
+
+/// File sent as one part of a multipart form
+pub struct MultipartFile<'a> {
+    /// Form field name, "file" for most transcription servers
+    pub field: &'a str,
+    pub path: &'a str,
+    /// MIME type of the data, e.g. "audio/wav"
+    pub content_type: &'a str,
+    pub data: MultipartData<'a>,
+}
+
+/// Helper function to send a multipart request with a file, extra text fields and headers
 pub fn send_multipart_request(
     client: &mut EspHttpConnection,
     url: &str,
-    file_path: &str,
-    file_data: &[u8],
+    extra_headers: &[(&str, &str)],
+    fields: &[(&str, &str)],
+    file: &MultipartFile,
 ) -> anyhow::Result<()> {
-    // Create multipart form data boundary
-    let boundary = "------------------------boundary";
+    // A fixed boundary would corrupt the request whenever the audio happens to contain it
+    let boundary = multipart_boundary(fields, file)?;
 
-    // Create request body
-    let request_body = create_multipart_body(boundary, file_path, file_data);
+    // The body is sent in pieces so the file is never copied into a second buffer
+    let prologue = multipart_prologue(&boundary, fields, file);
+    let epilogue = multipart_epilogue(&boundary);
 
     // Set up headers
     let content_type = format!("multipart/form-data; boundary={}", boundary);
-    let content_length = request_body.len().to_string();
+    let content_length = file
+        .data
+        .len()?
+        .map(|len| (prologue.len() + len + epilogue.len()).to_string());
+    let chunked = content_length.is_none();
 
-    let headers = [
-        ("Content-Type", content_type.as_str()),
-        ("Content-Length", content_length.as_str()),
-    ];
+    let mut headers = vec![("Content-Type", content_type.as_str()), ACCEPT_ENCODING];
+    match &content_length {
+        Some(content_length) => headers.push(("Content-Length", content_length.as_str())),
+        None => headers.push(("Transfer-Encoding", "chunked")),
+    }
+    headers.extend_from_slice(extra_headers);
 
     // Send the request
     if let Err(e) = client.initiate_request(Method::Post, url, &headers) {
@@ -30,8 +286,36 @@ pub fn send_multipart_request(
     }
 
     // Write the request body
-    if let Err(e) = client.write(&request_body) {
-        return Err(anyhow::anyhow!("Failed to write request body: {}", e));
+    write_body(client, prologue.as_bytes(), chunked)?;
+    match &file.data {
+        MultipartData::Bytes(data) => {
+            for chunk in data.chunks(UPLOAD_CHUNK_SIZE) {
+                write_body(client, chunk, chunked)?;
+            }
+        }
+        MultipartData::File(path) => {
+            let mut reader = std::fs::File::open(path)?;
+            let mut buffer = vec![0u8; UPLOAD_CHUNK_SIZE];
+            loop {
+                let bytes_read = std::io::Read::read(&mut reader, &mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                write_body(client, &buffer[..bytes_read], chunked)?;
+                watchdog::feed();
+            }
+        }
+        MultipartData::Stream(rx) => {
+            for data in rx.iter() {
+                write_body(client, &data, chunked)?;
+                watchdog::feed();
+            }
+        }
+    }
+    write_body(client, epilogue.as_bytes(), chunked)?;
+    if chunked {
+        // Zero length chunk ends the body
+        write_all(client, b"0\r\n\r\n")?;
     }
 
     // Finalize the request
@@ -42,38 +326,182 @@ pub fn send_multipart_request(
     Ok(())
 }
 
-/// Helper function to create a multipart request body
-fn create_multipart_body(boundary: &str, file_path: &str, file_data: &[u8]) -> Vec<u8> {
-    let filename = file_path.split('/').last().unwrap_or("audio.wav");
-    let content_disposition = format!(
-        "Content-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n",
-        filename
-    );
-    let content_type = "Content-Type: audio/wav\r\n\r\n";
+/// Write a piece of the request body, framed as one chunk when using chunked transfer encoding
+fn write_body(client: &mut EspHttpConnection, data: &[u8], chunked: bool) -> anyhow::Result<()> {
+    if !chunked {
+        return write_all(client, data);
+    }
+    // An empty chunk would end the body early
+    if data.is_empty() {
+        return Ok(());
+    }
 
-    let mut request_body = Vec::new();
+    write_all(client, format!("{:x}\r\n", data.len()).as_bytes())?;
+    write_all(client, data)?;
+    write_all(client, b"\r\n")
+}
 
-    // Add boundary start
-    request_body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+/// Write a piece of the request body, the connection may accept less than asked for at once
+fn write_all(client: &mut EspHttpConnection, mut data: &[u8]) -> anyhow::Result<()> {
+    while !data.is_empty() {
+        match client.write(data) {
+            Ok(0) => return Err(anyhow::anyhow!("Connection closed while writing request body")),
+            Ok(written) => data = &data[written..],
+            Err(e) => return Err(anyhow::anyhow!("Failed to write request body: {}", e)),
+        }
+    }
+    Ok(())
+}
 
-    // Add content disposition
-    request_body.extend_from_slice(content_disposition.as_bytes());
+/// Text fields and the header of the file part, everything sent before the file data
+fn multipart_prologue(boundary: &str, fields: &[(&str, &str)], file: &MultipartFile) -> String {
+    let filename = file.path.split('/').last().unwrap_or("audio.wav");
+    let mut prologue = String::new();
 
-    // Add content type
-    request_body.extend_from_slice(content_type.as_bytes());
+    // Add text fields before the file
+    for (name, value) in fields {
+        prologue.push_str(&format!("--{}\r\n", boundary));
+        prologue.push_str(&format!(
+            "Content-Disposition: form-data; name=\"{}\"\r\n\r\n",
+            name
+        ));
+        prologue.push_str(value);
+        prologue.push_str("\r\n");
+    }
 
-    // Add file data
-    request_body.extend_from_slice(file_data);
-    request_body.extend_from_slice(b"\r\n");
+    // Add boundary start, content disposition and content type of the file
+    prologue.push_str(&format!("--{}\r\n", boundary));
+    prologue.push_str(&format!(
+        "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+        file.field, filename
+    ));
+    prologue.push_str(&format!("Content-Type: {}\r\n\r\n", file.content_type));
 
-    // Add boundary end
-    request_body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    prologue
+}
 
-    request_body
+/// End of the file part and the closing boundary
+fn multipart_epilogue(boundary: &str) -> String {
+    format!("\r\n--{}--\r\n", boundary)
+}
+
+/// Download a file to the SD card, resuming a download interrupted earlier
+///
+/// The data goes to `<path>.part` until it is complete, so a later call picks up where
+/// the last one stopped. `progress` is called with the bytes received so far and the
+/// total size, when the server reports it. Returns the size of the file.
+pub fn download_to_file(
+    url: &str,
+    path: &str,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> anyhow::Result<u64> {
+    let part_path = format!("{}.part", path);
+    let offset = std::fs::metadata(&part_path).map_or(0, |meta| meta.len());
+
+    let http_config = HttpConfiguration {
+        timeout: Some(DOWNLOAD_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+    let mut client = PooledConnection::open(url, &http_config)?;
+
+    let range = format!("bytes={}-", offset);
+    let headers: Vec<(&str, &str)> = if offset > 0 {
+        log::info!("Resuming download of {} at {} bytes", url, offset);
+        vec![("Range", range.as_str())]
+    } else {
+        Vec::new()
+    };
+    client.send(Method::Get, |client| {
+        client
+            .initiate_request(Method::Get, url, &headers)
+            .map_err(|e| anyhow::anyhow!("Failed to initiate download: {}", e))?;
+        client
+            .initiate_response()
+            .map_err(|e| anyhow::anyhow!("Failed to get download response: {}", e))
+    })?;
+
+    let status = client.status();
+    let content_length = client
+        .header("Content-Length")
+        .and_then(|value| value.parse::<u64>().ok());
+    let (mut received, total, append) = match status {
+        // The server continues where the partial file ends
+        206 => {
+            let total = client.header("Content-Range").and_then(content_range_total);
+            (offset, total.or(content_length.map(|len| offset + len)), true)
+        }
+        // The server ignored the range, start over
+        200 => (0, content_length, false),
+        // Nothing left to fetch, the partial file is complete
+        416 if offset > 0 => {
+            replace_file(&part_path, path)?;
+            progress(offset, Some(offset));
+            return Ok(offset);
+        }
+        _ => return Err(HttpError::from_response(&mut client).into()),
+    };
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(&part_path)?;
+
+    let mut buffer = vec![0u8; UPLOAD_CHUNK_SIZE];
+    loop {
+        let bytes_read = client
+            .read(&mut buffer)
+            .map_err(|e| anyhow::anyhow!("Error reading download: {}", e))?;
+        if bytes_read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..bytes_read])?;
+        received += bytes_read as u64;
+        watchdog::feed();
+        progress(received, total);
+    }
+    file.flush()?;
+    drop(file);
+    client.release();
+
+    if let Some(total) = total {
+        if received != total {
+            return Err(anyhow::anyhow!(
+                "Download ended after {} of {} bytes",
+                received,
+                total
+            ));
+        }
+    }
+
+    replace_file(&part_path, path)?;
+    log::info!("Downloaded {} to {} ({} bytes)", url, path, received);
+    Ok(received)
+}
+
+/// Move a finished download into place, FAT can't rename over an existing file
+fn replace_file(from: &str, to: &str) -> std::io::Result<()> {
+    if std::path::Path::new(to).exists() {
+        std::fs::remove_file(to)?;
+    }
+    std::fs::rename(from, to)
+}
+
+/// Total size from a Content-Range header such as "bytes 100-999/1000"
+fn content_range_total(value: &str) -> Option<u64> {
+    value.rsplit_once('/')?.1.trim().parse().ok()
 }
 
 /// Helper function to read response body
 pub fn read_response_body(client: &mut EspHttpConnection) -> anyhow::Result<String> {
+    let response_body = read_response_bytes(client)?;
+    Ok(String::from_utf8_lossy(&response_body).to_string())
+}
+
+/// Read a binary response body, e.g. audio, decompressing it if the server compressed it
+pub fn read_response_bytes(client: &mut EspHttpConnection) -> anyhow::Result<Vec<u8>> {
     let mut response_body = Vec::new();
     let mut buffer = [0u8; 1024];
 
@@ -84,6 +512,7 @@ pub fn read_response_body(client: &mut EspHttpConnection) -> anyhow::Result<Stri
                     break;
                 }
                 response_body.extend_from_slice(&buffer[..bytes_read]);
+                watchdog::feed();
             }
             Err(e) => {
                 return Err(anyhow::anyhow!("Error reading response: {}", e));
@@ -91,21 +520,56 @@ pub fn read_response_body(client: &mut EspHttpConnection) -> anyhow::Result<Stri
         }
     }
 
-    Ok(String::from_utf8_lossy(&response_body).to_string())
+    decode_body(client.header("Content-Encoding"), response_body)
 }
 
-/// Helper function to read and process HTTP response
-pub fn read_response(client: &mut EspHttpConnection) -> anyhow::Result<String> {
-    // Get status code
-    let status = client.status();
-    log::info!("Response status: {}", status);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if status != 200 {
-        // Handle error response
-        let error_text = read_response_body(client)?;
-        return Err(anyhow::anyhow!("API error ({}): {}", status, error_text));
+    #[test]
+    fn test_url_origin() {
+        assert_eq!(
+            url_origin("https://api.deepseek.com/chat/completions"),
+            "https://api.deepseek.com"
+        );
+        assert_eq!(url_origin("http://192.168.1.10:9000?x=1"), "http://192.168.1.10:9000");
+        assert_eq!(url_origin("http://vosk:5000"), "http://vosk:5000");
     }
 
-    // Read successful response
-    read_response_body(client)
+    #[test]
+    fn test_content_range_total() {
+        assert_eq!(content_range_total("bytes 100-999/1000"), Some(1000));
+        // The total may be unknown
+        assert_eq!(content_range_total("bytes 100-999/*"), None);
+    }
+
+    #[test]
+    fn test_payload_contains_boundary() {
+        let data = MultipartData::Bytes(b"RIFF--b0undary--data");
+        assert!(data.contains(b"b0undary").unwrap());
+        assert!(!data.contains(b"boundary").unwrap());
+    }
+
+    #[test]
+    fn test_multipart_framing() {
+        let file = MultipartFile {
+            field: "file",
+            path: "/vfat/rec0.wav",
+            content_type: "audio/wav",
+            data: MultipartData::Bytes(b"RIFF"),
+        };
+        let body = format!(
+            "{}RIFF{}",
+            multipart_prologue("b", &[("language", "zh")], &file),
+            multipart_epilogue("b")
+        );
+
+        assert_eq!(
+            body,
+            "--b\r\nContent-Disposition: form-data; name=\"language\"\r\n\r\nzh\r\n\
+             --b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"rec0.wav\"\r\n\
+             Content-Type: audio/wav\r\n\r\nRIFF\r\n--b--\r\n"
+        );
+    }
 }
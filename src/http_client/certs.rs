@@ -0,0 +1,46 @@
+use esp_idf_svc::tls::X509;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use super::url_host_port;
+
+/// Certificates for self-hosted servers, one PEM file per host, e.g. /vfat/certs/192.168.1.10.pem
+const CERTS_DIR: &str = "/vfat/certs";
+
+/// Certificate of each host looked up so far, None when the host has none on the card
+static PINNED_CERTS: Mutex<BTreeMap<String, Option<X509<'static>>>> = Mutex::new(BTreeMap::new());
+
+/// Certificate the server at the URL has to present, instead of one signed by a public CA
+///
+/// The file can hold the server's own (self-signed) certificate to pin it, or the private
+/// CA that signed it. It is read once per host, new files are picked up after a reboot.
+pub fn pinned_certificate(url: &str) -> Option<X509<'static>> {
+    let host = url_host(url);
+    let mut pinned_certs = PINNED_CERTS.lock().unwrap();
+
+    *pinned_certs
+        .entry(host.to_string())
+        .or_insert_with(|| load_certificate(host))
+}
+
+fn load_certificate(host: &str) -> Option<X509<'static>> {
+    // FAT doesn't allow ':' in names, fd00::10 becomes fd00--10.pem
+    let path = format!("{}/{}.pem", CERTS_DIR, host.replace(':', "-"));
+    let mut pem = std::fs::read(&path).ok()?;
+
+    if !pem.starts_with(b"-----BEGIN CERTIFICATE-----") {
+        log::warn!("{} is not a PEM certificate, ignoring it", path);
+        return None;
+    }
+
+    log::info!("Verifying {} with the certificate in {}", host, path);
+    // mbedTLS expects PEM data to end with a NUL, and the configuration borrows it for good
+    pem.push(0);
+    Some(X509::pem_until_nul(Box::leak(pem.into_boxed_slice())))
+}
+
+/// Host part of a URL, without scheme, port and path
+fn url_host(url: &str) -> &str {
+    url_host_port(url).map_or("", |(host, _)| host)
+}
+
@@ -0,0 +1,106 @@
+use anyhow;
+use miniz_oxide::inflate::{decompress_to_vec_with_limit, decompress_to_vec_zlib_with_limit};
+
+/// Sent with requests whose responses are read in one piece; streamed ones stay uncompressed
+pub const ACCEPT_ENCODING: (&str, &str) = ("Accept-Encoding", "gzip, deflate");
+
+/// Upper bound for a decompressed body, a broken or hostile response must not exhaust the heap
+const MAX_DECODED_SIZE: usize = 256 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_HEADER_LEN: usize = 10;
+/// CRC32 and size of the uncompressed data
+const GZIP_TRAILER_LEN: usize = 8;
+const FLAG_HCRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+
+/// Undo the Content-Encoding of a response body, bodies without one are returned as they are
+pub fn decode_body(content_encoding: Option<&str>, body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match content_encoding.map(|encoding| encoding.trim().to_ascii_lowercase()) {
+        None => Ok(body),
+        Some(encoding) => match encoding.as_str() {
+            "" | "identity" => Ok(body),
+            "gzip" | "x-gzip" => gunzip(&body),
+            // Servers disagree on whether "deflate" means zlib framed or raw data
+            "deflate" => decompress_to_vec_zlib_with_limit(&body, MAX_DECODED_SIZE)
+                .or_else(|_| decompress_to_vec_with_limit(&body, MAX_DECODED_SIZE))
+                .map_err(|e| anyhow::anyhow!("Failed to inflate response: {}", e)),
+            other => Err(anyhow::anyhow!("Unsupported content encoding: {}", other)),
+        },
+    }
+}
+
+/// Decompress a gzip member (RFC 1952)
+fn gunzip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if data.len() < GZIP_HEADER_LEN + GZIP_TRAILER_LEN || data[..2] != GZIP_MAGIC || data[2] != 8 {
+        return Err(anyhow::anyhow!("Response is not gzip data"));
+    }
+
+    let flags = data[3];
+    let mut pos = GZIP_HEADER_LEN;
+    if flags & FLAG_EXTRA != 0 {
+        let extra_len = u16::from_le_bytes([data[pos], data[pos + 1]]) as usize;
+        pos += 2 + extra_len;
+    }
+    if flags & FLAG_NAME != 0 {
+        pos += skip_zero_terminated(data.get(pos..).unwrap_or_default());
+    }
+    if flags & FLAG_COMMENT != 0 {
+        pos += skip_zero_terminated(data.get(pos..).unwrap_or_default());
+    }
+    if flags & FLAG_HCRC != 0 {
+        pos += 2;
+    }
+
+    let trailer_start = data.len() - GZIP_TRAILER_LEN;
+    let deflated = data
+        .get(pos..trailer_start)
+        .ok_or_else(|| anyhow::anyhow!("Truncated gzip header"))?;
+    let decoded = decompress_to_vec_with_limit(deflated, MAX_DECODED_SIZE)
+        .map_err(|e| anyhow::anyhow!("Failed to gunzip response: {}", e))?;
+
+    let size = u32::from_le_bytes(data[trailer_start + 4..].try_into()?);
+    if decoded.len() as u32 != size {
+        return Err(anyhow::anyhow!("Gzip response is corrupt"));
+    }
+
+    Ok(decoded)
+}
+
+/// Length of a zero-terminated header field including the terminator
+fn skip_zero_terminated(data: &[u8]) -> usize {
+    data.iter()
+        .position(|&byte| byte == 0)
+        .map_or(data.len(), |end| end + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_body() {
+        let gzip = vec![
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xab, 0x56, 0x2a, 0x49,
+            0xad, 0x28, 0x51, 0xb2, 0x52, 0xca, 0xc8, 0x54, 0xaa, 0x05, 0x00, 0xd8, 0xc4, 0x47,
+            0xca, 0x0d, 0x00, 0x00, 0x00,
+        ];
+        let zlib = vec![
+            0x78, 0x9c, 0xab, 0x56, 0x2a, 0x49, 0xad, 0x28, 0x51, 0xb2, 0x52, 0xca, 0xc8, 0x54,
+            0xaa, 0x05, 0x00, 0x1f, 0x3a, 0x04, 0x51,
+        ];
+
+        assert_eq!(
+            decode_body(Some("gzip"), gzip).unwrap(),
+            br#"{"text":"hi"}"#
+        );
+        assert_eq!(
+            decode_body(Some("deflate"), zlib).unwrap(),
+            br#"{"text":"hi"}"#
+        );
+        assert_eq!(decode_body(None, b"plain".to_vec()).unwrap(), b"plain");
+        assert!(decode_body(Some("br"), b"x".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use esp_idf_svc::http::client::EspHttpConnection;
+use std::time::Duration;
+
+use super::read_response_body;
+
+/// Characters of the response body kept in the error, enough for the service's message
+const BODY_EXCERPT_CHARS: usize = 300;
+
+/// A response with an unexpected status, so callers can tell e.g. 401 from 429 from 503
+///
+/// Travels inside `anyhow::Error`, get it back with `downcast_ref::<HttpError>()`.
+#[derive(Debug, Clone)]
+pub struct HttpError {
+    pub status: u16,
+    /// How long the server asked to wait before trying again, from the Retry-After header
+    pub retry_after: Option<Duration>,
+    /// Start of the response body, usually the service's error message
+    pub body: String,
+}
+
+impl HttpError {
+    /// Take the status and headers of the response and read its body
+    pub fn from_response(client: &mut EspHttpConnection) -> Self {
+        let status = client.status();
+        let retry_after = client.header("Retry-After").and_then(parse_retry_after);
+        let body = read_response_body(client).unwrap_or_else(|e| format!("<{}>", e));
+
+        Self {
+            status,
+            retry_after,
+            body: excerpt(&body),
+        }
+    }
+
+    /// Wrong or missing credentials, asking again won't help
+    pub fn is_unauthorized(&self) -> bool {
+        self.status == 401 || self.status == 403
+    }
+
+    /// The request itself was refused, as opposed to the server being busy or broken
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.status) && self.status != 408 && self.status != 429
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP status {}", self.status)?;
+        if !self.body.is_empty() {
+            write!(f, ": {}", self.body)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// Retry-After in seconds; the HTTP date form is not supported, the clock may not be set
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+fn excerpt(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(BODY_EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_details() {
+        assert_eq!(parse_retry_after(" 30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+
+        let long_body = "错".repeat(BODY_EXCERPT_CHARS + 10);
+        assert_eq!(excerpt(&long_body).chars().count(), BODY_EXCERPT_CHARS + 3);
+
+        let error = HttpError {
+            status: 429,
+            retry_after: None,
+            body: String::new(),
+        };
+        assert!(!error.is_client_error());
+        assert!(!error.is_unauthorized());
+    }
+}
@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::HttpError;
+use crate::metrics;
+use crate::watchdog;
+
+/// Status codes that signal a temporary condition, 529 is Anthropic's "overloaded"
+const DEFAULT_RETRYABLE_STATUSES: [u16; 7] = [408, 429, 500, 502, 503, 504, 529];
+/// LLM services report overload and upstream failures with all kinds of 5xx codes
+const LLM_RETRYABLE_STATUSES: [u16; 2] = [408, 429];
+const LLM_RETRYABLE_SERVER_ERRORS: std::ops::RangeInclusive<u16> = 500..=599;
+
+/// Retry policy with exponential backoff and random jitter, chosen per request
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total number of attempts including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry in milliseconds
+    pub initial_backoff_ms: u64,
+    /// Upper bound for the delay between attempts in milliseconds
+    pub max_backoff_ms: u64,
+    /// HTTP status codes worth another attempt, anything else other than 200 fails right away
+    pub retryable_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 8000,
+            retryable_statuses: DEFAULT_RETRYABLE_STATUSES.to_vec(),
+        }
+    }
+}
+
+/// Failure of one attempt, split by whether another attempt can help
+#[derive(Debug)]
+pub enum AttemptError {
+    /// Dropped connection, timeout, rate limit or server error
+    Transient(anyhow::Error),
+    /// Bad request, wrong credentials or a response that can't be used
+    Permanent(anyhow::Error),
+}
+
+impl AttemptError {
+    pub fn into_inner(self) -> anyhow::Error {
+        match self {
+            AttemptError::Transient(e) | AttemptError::Permanent(e) => e,
+        }
+    }
+}
+
+impl std::fmt::Display for AttemptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttemptError::Transient(e) | AttemptError::Permanent(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Errors that know whether the request failing with them is worth repeating
+pub trait RetryableError: std::fmt::Display {
+    fn is_retryable(&self) -> bool;
+
+    /// Delay the server asked for, waited instead of a shorter backoff
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl RetryableError for AttemptError {
+    fn is_retryable(&self) -> bool {
+        matches!(self, AttemptError::Transient(_))
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            AttemptError::Transient(e) => e.downcast_ref::<HttpError>()?.retry_after,
+            AttemptError::Permanent(_) => None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A single attempt, for requests whose body can't be sent twice
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// The default policy for LLM requests, which retries every server error
+    pub fn llm() -> Self {
+        Self {
+            retryable_statuses: LLM_RETRYABLE_STATUSES
+                .into_iter()
+                .chain(LLM_RETRYABLE_SERVER_ERRORS)
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether an HTTP status signals a temporary condition worth retrying
+    pub fn is_retryable_status(&self, status: u16) -> bool {
+        self.retryable_statuses.contains(&status)
+    }
+
+    /// Classify the error of a response with an unexpected status
+    pub fn status_error(&self, error: HttpError) -> AttemptError {
+        if self.is_retryable_status(error.status) {
+            AttemptError::Transient(error.into())
+        } else {
+            AttemptError::Permanent(error.into())
+        }
+    }
+
+    /// Delay to wait after the given failed attempt (1-based)
+    pub fn backoff_delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(1u64 << exponent)
+            .min(self.max_backoff_ms);
+
+        // Keep half of the backoff and randomize the rest so devices don't retry in lockstep
+        let half = backoff / 2;
+        let jitter = if half > 0 {
+            (unsafe { esp_idf_svc::sys::esp_random() } as u64) % (half + 1)
+        } else {
+            0
+        };
+
+        Duration::from_millis(half + jitter)
+    }
+
+    /// Run `attempt` until it succeeds, fails for good or runs out of attempts
+    ///
+    /// `attempt` gets the 1-based attempt number, `wait` sleeps between attempts and is
+    /// where callers that can be cancelled cut the wait short.
+    pub fn run<T, E: RetryableError>(
+        &self,
+        what: &str,
+        mut wait: impl FnMut(Duration),
+        mut attempt: impl FnMut(u32) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let max_attempts = self.max_attempts.max(1);
+        let mut number = 1;
+
+        loop {
+            // Each attempt may take as long as its timeout, but no longer
+            watchdog::feed();
+            match attempt(number) {
+                Err(e) if e.is_retryable() && number < max_attempts => {
+                    // Honor Retry-After, but never wait longer than the policy allows
+                    let max_backoff = Duration::from_millis(self.max_backoff_ms);
+                    let delay = match e.retry_after() {
+                        Some(retry_after) => retry_after.min(max_backoff),
+                        None => self.backoff_delay(number),
+                    };
+                    log::warn!(
+                        "{} attempt {}/{} failed: {}. Retrying in {} ms",
+                        what,
+                        number,
+                        max_attempts,
+                        e,
+                        delay.as_millis()
+                    );
+                    metrics::increment("http_retries");
+                    wait(delay);
+                    number += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_stops_at_permanent_error() {
+        let policy = RetryPolicy {
+            initial_backoff_ms: 0,
+            ..Default::default()
+        };
+
+        let mut attempts = 0;
+        let result: Result<(), AttemptError> = policy.run(
+            "Test",
+            |_| {},
+            |number| {
+                attempts = number;
+                if number < 2 {
+                    Err(AttemptError::Transient(anyhow::anyhow!("timeout")))
+                } else {
+                    Err(AttemptError::Permanent(anyhow::anyhow!("unauthorized")))
+                }
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+
+        let result: Result<(), AttemptError> = policy.run(
+            "Test",
+            |_| {},
+            |number| {
+                attempts = number;
+                Err(AttemptError::Transient(anyhow::anyhow!("timeout")))
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_retryable_statuses() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_retryable_status(429));
+        assert!(policy.is_retryable_status(503));
+        assert!(!policy.is_retryable_status(401));
+        assert!(!policy.is_retryable_status(501));
+
+        let policy = RetryPolicy::llm();
+        assert!(policy.is_retryable_status(408));
+        assert!(policy.is_retryable_status(501));
+        assert!(policy.is_retryable_status(529));
+        assert!(!policy.is_retryable_status(400));
+        assert!(!policy.is_retryable_status(401));
+    }
+}
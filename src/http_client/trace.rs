@@ -0,0 +1,74 @@
+use esp_idf_svc::http::Method;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Instant;
+
+/// Off by default, a line per request is noisy on the serial console
+static TRACING: AtomicBool = AtomicBool::new(false);
+static NEXT_TURN_ID: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    /// Turn the requests made by this thread belong to
+    static CURRENT_TURN: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Log every outbound request with its duration, status and turn
+pub fn set_tracing(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+/// ID tying together the requests made for one question, from speech to text to the reply
+pub fn new_turn_id() -> u32 {
+    NEXT_TURN_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Attribute the requests this thread makes to a turn until the guard is dropped
+pub fn enter_turn(turn_id: u32) -> TurnGuard {
+    let previous = CURRENT_TURN.with(|turn| turn.replace(Some(turn_id)));
+    TurnGuard { previous }
+}
+
+pub struct TurnGuard {
+    previous: Option<u32>,
+}
+
+impl Drop for TurnGuard {
+    fn drop(&mut self) {
+        CURRENT_TURN.with(|turn| turn.set(self.previous));
+    }
+}
+
+/// Log one finished request if tracing is enabled, `outcome` is the status or the error
+pub(super) fn trace_request(
+    method: Method,
+    url: &str,
+    started: Instant,
+    outcome: Result<u16, &dyn std::fmt::Display>,
+) {
+    if !TRACING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let turn = CURRENT_TURN
+        .with(Cell::get)
+        .map_or_else(|| "-".to_string(), |id| id.to_string());
+    let elapsed_ms = started.elapsed().as_millis();
+    match outcome {
+        Ok(status) => log::info!(
+            "[turn {}] {:?} {} -> {} in {} ms",
+            turn,
+            method,
+            url,
+            status,
+            elapsed_ms
+        ),
+        Err(e) => log::warn!(
+            "[turn {}] {:?} {} failed after {} ms: {}",
+            turn,
+            method,
+            url,
+            elapsed_ms,
+            e
+        ),
+    }
+}
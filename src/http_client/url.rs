@@ -0,0 +1,53 @@
+/// Host and port of a URL, the port None unless the URL gives one
+///
+/// An IPv6 host is written in brackets, `http://[fd00::10]:8080/`, and returned without them.
+/// None when the host is empty or the port is not a number.
+pub fn url_host_port(url: &str) -> Option<(&str, Option<u16>)> {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or(rest);
+
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']')?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':')?)),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+
+    let port = match port {
+        Some(port) => Some(port.parse().ok()?),
+        None => None,
+    };
+    Some((host, port)).filter(|(host, _)| !host.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_host_port() {
+        assert_eq!(
+            url_host_port("https://192.168.1.10:8443/v1/chat"),
+            Some(("192.168.1.10", Some(8443)))
+        );
+        assert_eq!(url_host_port("wss://asr.lan/ws"), Some(("asr.lan", None)));
+        assert_eq!(url_host_port("http://vosk?x=1"), Some(("vosk", None)));
+        assert_eq!(
+            url_host_port("http://[fd00::10]:2700/ws"),
+            Some(("fd00::10", Some(2700)))
+        );
+        assert_eq!(url_host_port("https://[::1]/v1"), Some(("::1", None)));
+        assert_eq!(url_host_port("http://[fd00::10]2700"), None);
+        assert_eq!(url_host_port("http://[fd00::10"), None);
+        assert_eq!(url_host_port("http://vosk:port"), None);
+        assert_eq!(url_host_port("http://:80"), None);
+        assert_eq!(url_host_port(""), None);
+    }
+}
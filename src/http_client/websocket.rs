@@ -0,0 +1,134 @@
+use anyhow;
+use esp_idf_svc::io::EspIOError;
+use esp_idf_svc::ws::client::{
+    EspWebSocketClient, EspWebSocketClientConfig, FrameType, WebSocketEvent, WebSocketEventType,
+};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use super::pinned_certificate;
+
+/// What arrives on a websocket connection
+#[derive(Debug)]
+pub enum WebSocketMessage {
+    Text(String),
+    /// Not used by the streaming recognizer, kept for streaming TTS
+    #[allow(dead_code)]
+    Binary(Vec<u8>),
+    /// The server closed the connection or it broke, nothing follows
+    Closed,
+}
+
+/// Events handed over from the websocket client's task
+enum Event {
+    Connected,
+    Message(WebSocketMessage),
+}
+
+/// Blocking websocket client on top of esp_websocket_client, for the streaming
+/// recognizer, streaming TTS and the companion app
+pub struct WebSocket {
+    client: EspWebSocketClient<'static>,
+    events: Receiver<Event>,
+}
+
+impl WebSocket {
+    /// Connect to a ws:// or wss:// URL and wait until the handshake completed
+    pub fn connect(url: &str, timeout: Duration) -> anyhow::Result<Self> {
+        let (event_tx, event_rx) = mpsc::channel();
+        let config = match pinned_certificate(url) {
+            Some(certificate) => EspWebSocketClientConfig {
+                server_cert: Some(certificate),
+                ..Default::default()
+            },
+            None => EspWebSocketClientConfig {
+                // Only used for wss endpoints
+                crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+                ..Default::default()
+            },
+        };
+
+        let client = EspWebSocketClient::new(url, &config, timeout, move |event| {
+            forward_event(&event_tx, event)
+        })?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match event_rx.recv_timeout(remaining) {
+                Ok(Event::Connected) => break,
+                Ok(Event::Message(WebSocketMessage::Closed)) => {
+                    return Err(anyhow::anyhow!("Connection to {} was refused", url))
+                }
+                Ok(Event::Message(_)) => {}
+                Err(_) => return Err(anyhow::anyhow!("Timed out connecting to {}", url)),
+            }
+        }
+
+        Ok(Self {
+            client,
+            events: event_rx,
+        })
+    }
+
+    pub fn send_text(&mut self, text: &str) -> anyhow::Result<()> {
+        self.client.send(FrameType::Text(false), text.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.client.send(FrameType::Binary(false), data)?;
+        Ok(())
+    }
+
+    /// Next message if one already arrived
+    pub fn try_recv(&self) -> Option<WebSocketMessage> {
+        loop {
+            match self.events.try_recv() {
+                Ok(Event::Message(message)) => return Some(message),
+                Ok(Event::Connected) => {}
+                Err(mpsc::TryRecvError::Empty) => return None,
+                Err(mpsc::TryRecvError::Disconnected) => return Some(WebSocketMessage::Closed),
+            }
+        }
+    }
+
+    /// Wait for the next message, None when the timeout expired first
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<WebSocketMessage> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.events.recv_timeout(remaining) {
+                Ok(Event::Message(message)) => return Some(message),
+                Ok(Event::Connected) => {}
+                Err(RecvTimeoutError::Timeout) => return None,
+                Err(RecvTimeoutError::Disconnected) => return Some(WebSocketMessage::Closed),
+            }
+        }
+    }
+}
+
+/// Runs in the websocket client's task, so only hand the event over
+fn forward_event(tx: &Sender<Event>, event: &Result<WebSocketEvent, EspIOError>) {
+    let event = match event {
+        Ok(event) => match event.event_type {
+            WebSocketEventType::Connected => Event::Connected,
+            WebSocketEventType::Text(text) => {
+                Event::Message(WebSocketMessage::Text(text.to_string()))
+            }
+            WebSocketEventType::Binary(data) => {
+                Event::Message(WebSocketMessage::Binary(data.to_vec()))
+            }
+            WebSocketEventType::Disconnected
+            | WebSocketEventType::Close(_)
+            | WebSocketEventType::Closed => Event::Message(WebSocketMessage::Closed),
+            _ => return,
+        },
+        Err(e) => {
+            log::warn!("Websocket connection error: {}", e);
+            Event::Message(WebSocketMessage::Closed)
+        }
+    };
+
+    let _ = tx.send(event);
+}
@@ -0,0 +1,116 @@
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// Intent used for ordinary conversation and for replies that aren't valid JSON
+pub const CHAT_INTENT: &str = "chat";
+
+/// Appended to the system prompt in structured output mode. It has to mention JSON,
+/// DeepSeek rejects json_object requests whose prompt doesn't.
+pub const INTENT_INSTRUCTION: &str = "请只输出一个JSON对象，格式为 {\"intent\": \"...\", \"slots\": {...}, \"reply_text\": \"...\"}。\
+intent 是用户意图，例如 chat、set_timer、set_alarm、cancel_alarms、smart_home，普通聊天用 chat；\
+slots 是执行意图需要的参数，值只能是字符串、数字或布尔值，例如 {\"minutes\": 5}；\
+set_timer 的 slots 是 hours、minutes、seconds，set_alarm 的是 hour（0到23）和 minute，\
+两者都可以带 label 说明要提醒什么，cancel_alarms 取消所有计时、闹钟和提醒；\
+set_reminder 在某个时间提醒用户做某事，slots 是 label（要提醒的事）、hour、minute 和 days（0今天、1明天、2后天，不确定就不填）；\
+smart_home 控制家里的设备，slots 是 device（设备名）、action（on、off 或 set）和 set 时的 value；\
+remember 长期记住用户的名字、过敏、喜好等以后用得上的事，slots 是 fact（一句简短的陈述，如“用户对花生过敏”）；\
+forget 忘掉记住的事，slots 是 fact（要忘掉的事里的关键词）；\
+reply_text 是要朗读给用户听的回答。不要输出JSON以外的任何内容。";
+
+/// Reply of the LLM in structured output mode: something to do and something to say
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct IntentReply {
+    pub intent: String,
+    #[serde(default)]
+    pub slots: Map<String, Value>,
+    #[serde(default)]
+    pub reply_text: String,
+}
+
+impl IntentReply {
+    /// Treat a free text reply as plain conversation
+    pub fn plain(text: &str) -> Self {
+        Self {
+            intent: CHAT_INTENT.to_string(),
+            slots: Map::new(),
+            reply_text: text.to_string(),
+        }
+    }
+
+    /// Parse and validate a JSON reply. Providers without a JSON mode sometimes wrap
+    /// the object in prose or a code fence, so only the outermost braces are parsed.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let start = text.find('{');
+        let end = text.rfind('}');
+        let json = match (start, end) {
+            (Some(start), Some(end)) if start < end => &text[start..=end],
+            _ => return Err(anyhow::anyhow!("Reply contains no JSON object")),
+        };
+
+        let reply: IntentReply = serde_json::from_str(json)?;
+        reply.validate()?;
+        Ok(reply)
+    }
+
+    /// Parse a JSON reply, falling back to speaking the raw text when it doesn't fit the schema
+    pub fn parse_or_plain(text: &str) -> Self {
+        match Self::parse(text) {
+            Ok(reply) => reply,
+            Err(e) => {
+                log::warn!("Falling back to plain text reply: {}", e);
+                Self::plain(text)
+            }
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let valid_name = !self.intent.is_empty()
+            && self
+                .intent
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_name {
+            return Err(anyhow::anyhow!("Invalid intent name '{}'", self.intent));
+        }
+
+        if let Some((name, _)) = self
+            .slots
+            .iter()
+            .find(|(_, value)| value.is_object() || value.is_array())
+        {
+            return Err(anyhow::anyhow!("Slot '{}' is not a plain value", name));
+        }
+
+        if self.intent == CHAT_INTENT && self.reply_text.trim().is_empty() {
+            return Err(anyhow::anyhow!("Chat reply has no text"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_intent_reply() {
+        let reply = IntentReply::parse(
+            "```json\n{\"intent\": \"set_timer\", \"slots\": {\"minutes\": \"5\"}, \"reply_text\": \"好的\"}\n```",
+        )
+        .unwrap();
+        assert_eq!(reply.intent, "set_timer");
+        assert_eq!(reply.slots.get("minutes"), Some(&Value::from("5")));
+        assert_eq!(reply.reply_text, "好的");
+    }
+
+    #[test]
+    fn test_invalid_reply_falls_back() {
+        assert!(IntentReply::parse("{\"intent\": \"chat\", \"reply_text\": \"\"}").is_err());
+        assert!(IntentReply::parse("{\"intent\": \"x\", \"slots\": {\"a\": [1]}}").is_err());
+        assert_eq!(
+            IntentReply::parse_or_plain("今天天气不错"),
+            IntentReply::plain("今天天气不错")
+        );
+    }
+}
@@ -0,0 +1,500 @@
+use esp_idf_svc::http::client::Configuration as HttpConfiguration;
+use esp_idf_svc::http::Method;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::http_client::{read_response_bytes, HttpError, PooledConnection};
+use crate::notes::NOTES_DIR;
+use crate::speakers::cosine_similarity;
+
+/// Embeddings of the chunks, so only new or changed text is sent to the service
+pub const INDEX_FILE_PATH: &str = "/vfat/knowledge_index.json";
+/// Files that are read, the dictation notes and documents copied onto the card
+const EXTENSIONS: [&str; 2] = ["txt", "md"];
+const DEFAULT_CHUNK_CHARS: usize = 300;
+const DEFAULT_MAX_SNIPPETS: usize = 3;
+const DEFAULT_EMBEDDING_URL: &str = "https://api.openai.com/v1/embeddings";
+const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+const DEFAULT_MIN_SIMILARITY: f32 = 0.3;
+/// The user is waiting for the answer, the keywords are used when the service is slower
+const EMBEDDING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Chunks embedded per request
+const EMBEDDING_BATCH: usize = 16;
+/// New chunks embedded while the user waits for an answer, the rest follow with later questions
+const MAX_EMBEDDED_PER_QUESTION: usize = 4 * EMBEDDING_BATCH;
+/// Chunks with an embedding, about 2.5 MB at 1536 dimensions; later ones are matched by keywords
+const MAX_INDEXED_CHUNKS: usize = 400;
+/// Share of the question's character pairs a chunk needs to contain
+const MIN_KEYWORD_SCORE: f32 = 0.3;
+const CONTEXT_INTRO: &str =
+    "以下是用户存储卡上的笔记和文档中可能与问题有关的内容，回答时可以参考，\
+不相关就忽略，不要提到这段说明：";
+
+/// Answering questions about the text files in /vfat/notes with snippets of them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KnowledgeConfig {
+    pub enabled: bool,
+    /// Length the files are cut into, in characters
+    pub chunk_chars: usize,
+    /// Most snippets sent with a question
+    pub max_snippets: usize,
+    /// Rank the snippets by meaning instead of shared words
+    pub embeddings: Option<EmbeddingConfig>,
+}
+
+impl Default for KnowledgeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            chunk_chars: DEFAULT_CHUNK_CHARS,
+            max_snippets: DEFAULT_MAX_SNIPPETS,
+            embeddings: None,
+        }
+    }
+}
+
+/// Settings of an OpenAI compatible /v1/embeddings service
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmbeddingConfig {
+    pub url: String,
+    /// Sent as a bearer token
+    pub api_key: String,
+    pub model: String,
+    /// Cosine similarity a snippet needs to the question
+    pub min_similarity: f32,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_EMBEDDING_URL.to_string(),
+            api_key: String::new(),
+            model: DEFAULT_EMBEDDING_MODEL.to_string(),
+            min_similarity: DEFAULT_MIN_SIMILARITY,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// Embeddings by hash of the chunk text, for one model
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct EmbeddingIndex {
+    model: String,
+    vectors: BTreeMap<String, Vec<f32>>,
+}
+
+/// Part of a file, what a question is matched against
+#[derive(Debug, Clone, PartialEq)]
+struct Chunk {
+    /// File name, tells the LLM where the snippet is from
+    source: String,
+    text: String,
+}
+
+/// The chunks of the files in /vfat/notes, cut again whenever a file changes
+pub struct KnowledgeBase {
+    config: KnowledgeConfig,
+    chunks: Vec<Chunk>,
+    /// Name, size and modification time of every file the chunks were cut from
+    files: Vec<(String, u64, Option<SystemTime>)>,
+    /// Read from the card on first use
+    index: Option<EmbeddingIndex>,
+}
+
+impl KnowledgeBase {
+    pub fn new(config: &KnowledgeConfig) -> Self {
+        Self {
+            config: config.clone(),
+            chunks: Vec::new(),
+            files: Vec::new(),
+            index: None,
+        }
+    }
+
+    /// Take a reloaded configuration, keeping the chunks and embeddings that are still valid
+    pub fn reconfigure(&mut self, config: &KnowledgeConfig) {
+        if config.chunk_chars != self.config.chunk_chars {
+            self.files.clear();
+        }
+        if config.embeddings != self.config.embeddings {
+            self.index = None;
+        }
+        self.config = config.clone();
+    }
+
+    /// Snippets relevant to `question` for the LLM, None when nothing is
+    pub fn context(&mut self, question: &str) -> Option<String> {
+        if !self.config.enabled {
+            return None;
+        }
+        self.refresh();
+        if self.chunks.is_empty() {
+            return None;
+        }
+
+        let ranked = match self.config.embeddings.clone() {
+            Some(embeddings) => self
+                .rank_by_embedding(&embeddings, question)
+                .unwrap_or_else(|e| {
+                    log::warn!(
+                        "Failed to rank the notes by embedding, using keywords: {:#}",
+                        e
+                    );
+                    rank_by_keywords(&self.chunks, question)
+                }),
+            None => rank_by_keywords(&self.chunks, question),
+        };
+        if ranked.is_empty() {
+            return None;
+        }
+
+        let snippets: Vec<String> = ranked
+            .iter()
+            .take(self.config.max_snippets)
+            .map(|&index| {
+                let chunk = &self.chunks[index];
+                format!("【{}】{}", chunk.source, chunk.text)
+            })
+            .collect();
+        log::info!(
+            "Adding {} snippets of the notes to the question",
+            snippets.len()
+        );
+        Some(format!("{}\n{}", CONTEXT_INTRO, snippets.join("\n")))
+    }
+
+    /// Cut the files again if any of them was added, changed or removed
+    fn refresh(&mut self) {
+        let mut paths: Vec<_> = match std::fs::read_dir(NOTES_DIR) {
+            Ok(entries) => entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| is_document(path))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        paths.sort();
+
+        let files: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                let metadata = std::fs::metadata(path).ok();
+                (
+                    file_name(path),
+                    metadata.as_ref().map_or(0, |m| m.len()),
+                    metadata.and_then(|m| m.modified().ok()),
+                )
+            })
+            .collect();
+        if files == self.files {
+            return;
+        }
+
+        self.chunks = paths
+            .iter()
+            .filter_map(|path| match std::fs::read_to_string(path) {
+                Ok(text) => Some((file_name(path), text)),
+                Err(e) => {
+                    log::warn!("Failed to read {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .flat_map(|(source, text)| {
+                split(&text, self.config.chunk_chars)
+                    .into_iter()
+                    .map(move |text| Chunk {
+                        source: source.clone(),
+                        text,
+                    })
+            })
+            .collect();
+        self.files = files;
+        log::info!(
+            "Cut {} files in {} into {} chunks",
+            self.files.len(),
+            NOTES_DIR,
+            self.chunks.len()
+        );
+    }
+
+    /// Chunks at least `min_similarity` alike the question, most alike first, followed by the
+    /// chunks without an embedding yet that share enough words with it
+    fn rank_by_embedding(
+        &mut self,
+        config: &EmbeddingConfig,
+        question: &str,
+    ) -> anyhow::Result<Vec<usize>> {
+        let index = self.index.get_or_insert_with(|| load_index(&config.model));
+        let indexed = &self.chunks[..self.chunks.len().min(MAX_INDEXED_CHUNKS)];
+
+        // Chunks of changed or removed files aren't needed anymore
+        let current: HashSet<String> = indexed.iter().map(|chunk| text_hash(&chunk.text)).collect();
+        let before = index.vectors.len();
+        index.vectors.retain(|hash, _| current.contains(hash));
+        if index.vectors.len() != before {
+            save_index(index);
+        }
+
+        let missing: Vec<&str> = indexed
+            .iter()
+            .map(|chunk| chunk.text.as_str())
+            .filter(|text| !index.vectors.contains_key(&text_hash(text)))
+            .take(MAX_EMBEDDED_PER_QUESTION)
+            .collect();
+        if !missing.is_empty() {
+            log::info!("Embedding {} new chunks of the notes", missing.len());
+        }
+        for batch in missing.chunks(EMBEDDING_BATCH) {
+            for (text, vector) in batch.iter().zip(embed(config, batch)?) {
+                index.vectors.insert(text_hash(text), vector);
+            }
+            // What was embedded so far is kept when a later batch fails
+            save_index(index);
+        }
+
+        let unembedded = rank_unembedded(&self.chunks, index, question);
+        let question = embed(config, &[question])?.pop().unwrap_or_default();
+        let mut scored: Vec<(usize, f32)> = self
+            .chunks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, chunk)| {
+                let vector = index.vectors.get(&text_hash(&chunk.text))?;
+                let similarity = cosine_similarity(&question, vector);
+                (similarity >= config.min_similarity).then_some((i, similarity))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(scored
+            .into_iter()
+            .map(|(i, _)| i)
+            .chain(unembedded)
+            .collect())
+    }
+}
+
+/// Chunks sharing enough pairs of characters with the question, best match first
+///
+/// Pairs of characters stand in for words, Chinese isn't written with spaces between them.
+fn rank_by_keywords(chunks: &[Chunk], question: &str) -> Vec<usize> {
+    let question = bigrams(question);
+    if question.is_empty() {
+        return Vec::new();
+    }
+    let mut scored: Vec<(usize, f32)> = chunks
+        .iter()
+        .enumerate()
+        .filter_map(|(i, chunk)| {
+            let shared = bigrams(&chunk.text).intersection(&question).count();
+            let score = shared as f32 / question.len() as f32;
+            (score >= MIN_KEYWORD_SCORE).then_some((i, score))
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.into_iter().map(|(i, _)| i).collect()
+}
+
+/// Chunks `index` has no embedding of, ranked by keywords
+fn rank_unembedded(chunks: &[Chunk], index: &EmbeddingIndex, question: &str) -> Vec<usize> {
+    rank_by_keywords(chunks, question)
+        .into_iter()
+        .filter(|&i| !index.vectors.contains_key(&text_hash(&chunks[i].text)))
+        .collect()
+}
+
+fn bigrams(text: &str) -> HashSet<(char, char)> {
+    let chars: Vec<char> = text
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect();
+    chars.windows(2).map(|pair| (pair[0], pair[1])).collect()
+}
+
+/// Cut `text` at line ends into pieces of at most `max_chars`, longer lines are cut anywhere
+fn split(text: &str, max_chars: usize) -> Vec<String> {
+    let max_chars = max_chars.max(1);
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_chars = 0;
+
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let line: Vec<char> = line.chars().collect();
+        for piece in line.chunks(max_chars) {
+            if current_chars > 0 && current_chars + 1 + piece.len() > max_chars {
+                chunks.push(std::mem::take(&mut current));
+                current_chars = 0;
+            }
+            if current_chars > 0 {
+                current.push('\n');
+                current_chars += 1;
+            }
+            current.extend(piece);
+            current_chars += piece.len();
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+fn embed(config: &EmbeddingConfig, input: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+    let body = serde_json::to_vec(&EmbeddingRequest {
+        model: &config.model,
+        input,
+    })?;
+    let http_config = HttpConfiguration {
+        timeout: Some(EMBEDDING_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+    let content_length = body.len().to_string();
+    let authorization = format!("Bearer {}", config.api_key);
+    let headers = [
+        ("Content-Type", "application/json"),
+        ("Content-Length", content_length.as_str()),
+        ("Authorization", authorization.as_str()),
+    ];
+
+    let mut client = PooledConnection::open(&config.url, &http_config)?;
+    client.send(Method::Post, |client| {
+        client
+            .initiate_request(Method::Post, &config.url, &headers)
+            .map_err(|e| anyhow::anyhow!("Failed to initiate embedding request: {}", e))?;
+        client
+            .write(&body)
+            .map_err(|e| anyhow::anyhow!("Failed to write embedding request: {}", e))?;
+        client
+            .initiate_response()
+            .map_err(|e| anyhow::anyhow!("Failed to get embedding response: {}", e))
+    })?;
+    if client.status() != 200 {
+        return Err(HttpError::from_response(&mut client).into());
+    }
+    let response = read_response_bytes(&mut client)?;
+    client.release();
+
+    let mut data = serde_json::from_slice::<EmbeddingResponse>(&response)?.data;
+    if data.len() != input.len() {
+        return Err(anyhow::anyhow!(
+            "Got {} embeddings for {} texts",
+            data.len(),
+            input.len()
+        ));
+    }
+    data.sort_by_key(|d| d.index);
+    Ok(data.into_iter().map(|d| d.embedding).collect())
+}
+
+fn load_index(model: &str) -> EmbeddingIndex {
+    let index = std::fs::read_to_string(INDEX_FILE_PATH)
+        .ok()
+        .and_then(|text| serde_json::from_str::<EmbeddingIndex>(&text).ok());
+    match index {
+        // Vectors of another model can't be compared with the question's
+        Some(index) if index.model == model => index,
+        _ => EmbeddingIndex {
+            model: model.to_string(),
+            vectors: BTreeMap::new(),
+        },
+    }
+}
+
+fn save_index(index: &EmbeddingIndex) {
+    let result = serde_json::to_string(index)
+        .map_err(anyhow::Error::from)
+        .and_then(|text| std::fs::write(INDEX_FILE_PATH, text).map_err(anyhow::Error::from));
+    if let Err(e) = result {
+        log::warn!("Failed to save {}: {}", INDEX_FILE_PATH, e);
+    }
+}
+
+/// FNV-1a, stable across builds unlike the standard library's hasher
+fn text_hash(text: &str) -> String {
+    let hash = text.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+fn is_document(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(text: &str) -> Chunk {
+        Chunk {
+            source: "家电.txt".to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("第一行\n\n第二行\n", 10), vec!["第一行\n第二行"]);
+        assert_eq!(split("第一行\n第二行", 5), vec!["第一行", "第二行"]);
+        assert_eq!(split("一二三四五六七", 3), vec!["一二三", "四五六", "七"]);
+        assert!(split("", 10).is_empty());
+    }
+
+    #[test]
+    fn test_rank_by_keywords() {
+        let chunks = vec![
+            chunk("冰箱保修到2026年3月，售后电话400-123"),
+            chunk("洗衣机的滤网每个月清洗一次"),
+        ];
+        assert_eq!(rank_by_keywords(&chunks, "洗衣机滤网多久清洗？"), vec![1]);
+        assert_eq!(rank_by_keywords(&chunks, "冰箱保修到什么时候"), vec![0]);
+        assert!(rank_by_keywords(&chunks, "今天天气怎么样").is_empty());
+    }
+
+    #[test]
+    fn test_rank_unembedded() {
+        let chunks = vec![
+            chunk("洗衣机的滤网每个月清洗一次"),
+            chunk("洗衣机滤网坏了要换新的"),
+        ];
+        let mut index = EmbeddingIndex::default();
+        assert_eq!(rank_unembedded(&chunks, &index, "洗衣机滤网"), vec![1, 0]);
+        index.vectors.insert(text_hash(&chunks[1].text), vec![1.0]);
+        assert_eq!(rank_unembedded(&chunks, &index, "洗衣机滤网"), vec![0]);
+    }
+
+    #[test]
+    fn test_text_hash() {
+        assert_eq!(text_hash(""), "cbf29ce484222325");
+        assert_ne!(text_hash("冰箱"), text_hash("洗衣机"));
+    }
+}
@@ -0,0 +1,153 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+/// NVS namespace with the networks the device knows
+const WIFI_NVS_NAMESPACE: &str = "wifi";
+const KEY_NETWORKS: &str = "networks";
+/// Oldest networks are forgotten beyond this
+const MAX_NETWORKS: usize = 8;
+/// JSON of MAX_NETWORKS networks with the longest SSIDs and passwords
+const MAX_JSON_LEN: usize = 1536;
+
+/// SSID and password of a network
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    pub ssid: String,
+    /// Empty for an open network
+    pub password: String,
+}
+
+/// Networks added by provisioning or from the console, highest priority first
+pub struct KnownNetworks {
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl KnownNetworks {
+    pub fn new(nvs_partition: EspDefaultNvsPartition) -> Self {
+        let nvs = match EspNvs::new(nvs_partition, WIFI_NVS_NAMESPACE, true) {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                log::warn!("Failed to open NVS namespace for WiFi networks: {}", e);
+                None
+            }
+        };
+        Self { nvs }
+    }
+
+    pub fn list(&self) -> Vec<Credentials> {
+        let Some(nvs) = self.nvs.as_ref() else {
+            return Vec::new();
+        };
+
+        let mut buffer = vec![0u8; MAX_JSON_LEN];
+        match nvs.get_str(KEY_NETWORKS, &mut buffer) {
+            Ok(Some(text)) => serde_json::from_str(text).unwrap_or_else(|e| {
+                log::warn!("Ignoring unreadable list of WiFi networks: {}", e);
+                Vec::new()
+            }),
+            Ok(None) => Vec::new(),
+            Err(e) => {
+                log::warn!("Failed to read WiFi networks: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Add a network or update its password, either way it gets the highest priority
+    pub fn add(&mut self, credentials: &Credentials) -> anyhow::Result<()> {
+        let mut networks = self.list();
+        networks.retain(|known| known.ssid != credentials.ssid);
+        networks.insert(0, credentials.clone());
+        networks.truncate(MAX_NETWORKS);
+        self.store(&networks)?;
+        log::info!("Saved WiFi network '{}'", credentials.ssid);
+        Ok(())
+    }
+
+    /// Forget a network, false if it wasn't known
+    pub fn remove(&mut self, ssid: &str) -> anyhow::Result<bool> {
+        let mut networks = self.list();
+        let count = networks.len();
+        networks.retain(|known| known.ssid != ssid);
+        if networks.len() == count {
+            return Ok(false);
+        }
+        self.store(&networks)?;
+        log::info!("Removed WiFi network '{}'", ssid);
+        Ok(true)
+    }
+
+    fn store(&mut self, networks: &[Credentials]) -> anyhow::Result<()> {
+        let nvs = self
+            .nvs
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("NVS is not available"))?;
+        nvs.set_str(KEY_NETWORKS, &serde_json::to_string(networks)?)?;
+        Ok(())
+    }
+}
+
+/// Order in which to try the candidates, given the networks a scan found with their RSSI
+///
+/// Networks in range come first, strongest first; candidates the scan didn't see, e.g.
+/// hidden networks, follow in their original order.
+pub fn connection_order(candidates: &[Credentials], seen: &[(String, i8)]) -> Vec<Credentials> {
+    let rssi = |ssid: &str| {
+        seen.iter()
+            .filter(|(seen_ssid, _)| seen_ssid == ssid)
+            .map(|(_, rssi)| *rssi)
+            .max()
+    };
+
+    let mut in_range: Vec<(i8, &Credentials)> = candidates
+        .iter()
+        .filter_map(|c| rssi(&c.ssid).map(|rssi| (rssi, c)))
+        .collect();
+    // Stable, so equally strong networks keep their priority
+    in_range.sort_by(|a, b| b.0.cmp(&a.0));
+
+    in_range
+        .into_iter()
+        .map(|(_, c)| c.clone())
+        .chain(
+            candidates
+                .iter()
+                .filter(|c| rssi(&c.ssid).is_none())
+                .cloned(),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(ssid: &str) -> Credentials {
+        Credentials {
+            ssid: ssid.to_string(),
+            password: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_connection_order() {
+        let candidates = [
+            network("home"),
+            network("office"),
+            network("hidden"),
+            network("phone"),
+        ];
+        let seen = [
+            ("neighbor".to_string(), -40),
+            ("phone".to_string(), -50),
+            ("home".to_string(), -70),
+            ("office".to_string(), -50),
+        ];
+
+        let order: Vec<String> = connection_order(&candidates, &seen)
+            .into_iter()
+            .map(|c| c.ssid)
+            .collect();
+        assert_eq!(order, ["office", "phone", "home", "hidden"]);
+    }
+}
@@ -0,0 +1,72 @@
+/// Languages the assistant can be spoken to in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Language {
+    #[default]
+    Chinese,
+    English,
+}
+
+impl Language {
+    /// ISO 639-1 code, also the key of the language's table in the configuration
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::Chinese => "zh",
+            Language::English => "en",
+        }
+    }
+
+    /// Parse a language code or the English name some services report, e.g. "chinese"
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "zh" | "zh-cn" | "cmn" | "chinese" | "mandarin" => Some(Language::Chinese),
+            "en" | "en-us" | "en-gb" | "english" => Some(Language::English),
+            _ => None,
+        }
+    }
+
+    /// Guess the language from the script. Chinese speakers mix in English words,
+    /// so the text counts as Chinese unless Latin words clearly outnumber Han characters.
+    pub fn detect(text: &str) -> Self {
+        let han_chars = text.chars().filter(|&c| is_han(c)).count();
+        let latin_words = text
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .filter(|word| !word.is_empty())
+            .count();
+
+        if latin_words > han_chars {
+            Language::English
+        } else {
+            Language::Chinese
+        }
+    }
+}
+
+fn is_han(c: char) -> bool {
+    matches!(c, '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' | '\u{F900}'..='\u{FAFF}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_language() {
+        assert_eq!(Language::detect("今天天气怎么样"), Language::Chinese);
+        assert_eq!(
+            Language::detect("帮我查一下iPhone的价格"),
+            Language::Chinese
+        );
+        assert_eq!(
+            Language::detect("What's the weather like today?"),
+            Language::English
+        );
+        assert_eq!(Language::detect(""), Language::Chinese);
+    }
+
+    #[test]
+    fn test_language_from_name() {
+        assert_eq!(Language::from_name("english"), Some(Language::English));
+        assert_eq!(Language::from_name("ZH"), Some(Language::Chinese));
+        assert_eq!(Language::from_name("french"), None);
+    }
+}
@@ -0,0 +1,170 @@
+use esp_idf_svc::hal::gpio::AnyOutputPin;
+use esp_idf_svc::hal::rmt::config::TransmitConfig;
+use esp_idf_svc::hal::rmt::{FixedLengthSignal, PinState, Pulse, TxRmtDriver, CHANNEL0};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio_processing::{self, State};
+use crate::config::LedConfig;
+use crate::connectivity::{self, Connectivity};
+use crate::playback;
+use crate::self_test;
+
+/// How often the status is checked and animations advance
+const FRAME_INTERVAL: Duration = Duration::from_millis(30);
+/// WS2812 bit timings
+const T0H: Duration = Duration::from_nanos(350);
+const T0L: Duration = Duration::from_nanos(800);
+const T1H: Duration = Duration::from_nanos(700);
+const T1L: Duration = Duration::from_nanos(600);
+
+/// What the device is doing as the LED and the display show it, in the order they take precedence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Muted,
+    /// The network services are unreachable, or starting up failed
+    Error,
+    Speaking,
+    Thinking,
+    Recording,
+    /// Waiting for the wake word
+    Listening,
+}
+
+impl Status {
+    pub fn current() -> Self {
+        if playback::is_muted() {
+            Status::Muted
+        } else if connectivity::state() != Connectivity::Online || self_test::has_boot_failed() {
+            Status::Error
+        } else if playback::is_playing() || playback::is_streaming() {
+            Status::Speaking
+        } else if playback::is_thinking() {
+            Status::Thinking
+        } else if audio_processing::current_state() == State::Recording {
+            Status::Recording
+        } else {
+            Status::Listening
+        }
+    }
+
+    /// Color at full brightness, `elapsed` into the status
+    fn color_at(&self, elapsed: Duration) -> (u8, u8, u8) {
+        let millis = elapsed.as_millis() as u32;
+        match self {
+            Status::Muted => (160, 0, 160),
+            Status::Error => {
+                if millis % 1000 < 500 {
+                    (255, 0, 0)
+                } else {
+                    (0, 0, 0)
+                }
+            }
+            Status::Speaking => (0, 200, 200),
+            Status::Thinking => dim((255, 160, 0), breathe(millis, 1500)),
+            Status::Recording => (0, 255, 0),
+            Status::Listening => (0, 0, 40),
+        }
+    }
+}
+
+/// Triangle wave between 10 and 100 percent with a period of `period_ms`
+fn breathe(millis: u32, period_ms: u32) -> u8 {
+    let phase = millis % period_ms;
+    let half = period_ms / 2;
+    let rising = if phase < half {
+        phase
+    } else {
+        period_ms - phase
+    };
+    (10 + rising * 90 / half) as u8
+}
+
+fn dim((red, green, blue): (u8, u8, u8), percent: u8) -> (u8, u8, u8) {
+    let scale = |value: u8| (value as u32 * percent as u32 / 100) as u8;
+    (scale(red), scale(green), scale(blue))
+}
+
+/// Drive the LED in `config` from its own thread, does nothing when it has no pin
+pub fn start(config: &LedConfig, channel: CHANNEL0) -> anyhow::Result<()> {
+    let Some(pin) = config.pin else {
+        return Ok(());
+    };
+
+    let driver = TxRmtDriver::new(
+        channel,
+        unsafe { AnyOutputPin::new(pin as i32) },
+        &TransmitConfig::new().clock_divider(1),
+    )?;
+
+    let brightness = config.brightness;
+    thread::Builder::new()
+        .name("led".to_string())
+        .stack_size(3 * 1024)
+        .spawn(move || led_loop(driver, brightness))?;
+
+    log::info!("Status LED on GPIO{}", pin);
+    Ok(())
+}
+
+fn led_loop(mut driver: TxRmtDriver<'static>, brightness: u8) {
+    let mut status = Status::current();
+    let mut since = Instant::now();
+    let mut shown = None;
+
+    loop {
+        let current = Status::current();
+        if current != status {
+            status = current;
+            since = Instant::now();
+        }
+
+        let color = dim(status.color_at(since.elapsed()), brightness);
+        if shown != Some(color) {
+            match write_color(&mut driver, color) {
+                Ok(()) => shown = Some(color),
+                Err(e) => log::warn!("Failed to update the status LED: {}", e),
+            }
+        }
+
+        thread::sleep(FRAME_INTERVAL);
+    }
+}
+
+/// Send one color to the LED, which takes its 24 bits in green, red, blue order
+fn write_color(driver: &mut TxRmtDriver, (red, green, blue): (u8, u8, u8)) -> anyhow::Result<()> {
+    let ticks_hz = driver.counter_clock()?;
+    let zero = (
+        Pulse::new_with_duration(ticks_hz, PinState::High, &T0H)?,
+        Pulse::new_with_duration(ticks_hz, PinState::Low, &T0L)?,
+    );
+    let one = (
+        Pulse::new_with_duration(ticks_hz, PinState::High, &T1H)?,
+        Pulse::new_with_duration(ticks_hz, PinState::Low, &T1L)?,
+    );
+
+    let bits = ((green as u32) << 16) | ((red as u32) << 8) | blue as u32;
+    let mut signal = FixedLengthSignal::<24>::new();
+    for i in 0..24 {
+        let bit = bits & (1 << (23 - i)) != 0;
+        signal.set(i, if bit { &one } else { &zero })?;
+    }
+    driver.start_blocking(&signal)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        assert_eq!(dim((255, 100, 0), 50), (127, 50, 0));
+        assert_eq!(breathe(0, 1500), 10);
+        assert_eq!(breathe(750, 1500), 100);
+
+        let error = Status::Error;
+        assert_eq!(error.color_at(Duration::from_millis(200)), (255, 0, 0));
+        assert_eq!(error.color_at(Duration::from_millis(700)), (0, 0, 0));
+    }
+}
@@ -1,14 +1,37 @@
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use std::net::ToSocketAddrs;
 use std::vec::Vec;
 use log::{info, warn, error};
 use esp_idf_svc::{
-    http::client::{EspHttpConnection, Configuration as HttpConfiguration},
+    http::client::Configuration as HttpConfiguration,
     http::Method,
 };
-use anyhow::Result;
+
+use crate::config::{LlmConfig, LlmProviderKind, ProviderConfig};
+use crate::connectivity;
+use crate::http_client::{
+    decode_body, url_host_port, HttpError, PooledConnection, RetryPolicy, RetryableError,
+    ACCEPT_ENCODING,
+};
+use crate::metrics::{self, LogMetricsSink, MetricsSink, RequestMetrics};
+use crate::watchdog;
+
+mod anthropic;
+mod deepseek;
+mod gemini;
+
+pub use anthropic::AnthropicProvider;
+pub use deepseek::DeepSeekProvider;
+pub use gemini::GeminiProvider;
 
 /// Enum representing different roles in a chat conversation
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ChatRole {
     System,
     User,
@@ -16,7 +39,8 @@ pub enum ChatRole {
 }
 
 impl ChatRole {
-    fn as_str(&self) -> &'static str {
+    /// Name of the role in OpenAI style requests
+    pub fn as_str(&self) -> &'static str {
         match self {
             ChatRole::System => "system",
             ChatRole::User => "user",
@@ -25,78 +49,464 @@ impl ChatRole {
     }
 }
 
+impl std::fmt::Display for ChatRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// Structure representing a chat message
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
-    role: String,
-    content: String,
+    pub role: ChatRole,
+    pub content: String,
+    /// Picture the text is about, each provider sends it in its own format
+    #[serde(skip)]
+    pub image: Option<ChatImage>,
 }
 
-/// Request structure for the DeepSeek API
-#[derive(Debug, Serialize)]
-struct DeepSeekRequest {
-    messages: Vec<ChatMessage>,
-    model: String,
-    frequency_penalty: f32,
-    max_tokens: u32,
-    presence_penalty: f32,
-    response_format: ResponseFormat,
-    stop: Option<Vec<String>>,
-    stream: bool,
-    stream_options: Option<String>,
-    temperature: f32,
-    top_p: f32,
-    tools: Option<String>,
-    tool_choice: String,
-    logprobs: bool,
-    top_logprobs: Option<u32>,
+/// Base64 encoded picture attached to a user message, for vision capable models
+#[derive(Clone)]
+pub struct ChatImage {
+    /// MIME type such as "image/jpeg"
+    pub media_type: &'static str,
+    pub data: String,
 }
 
-#[derive(Debug, Serialize)]
-struct ResponseFormat {
-    #[serde(rename = "type")]
-    format_type: String,
+impl ChatImage {
+    pub fn jpeg(bytes: &[u8]) -> Self {
+        Self {
+            media_type: "image/jpeg",
+            data: BASE64_STANDARD.encode(bytes),
+        }
+    }
+
+    /// The picture as a data URL, as OpenAI style APIs take it
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
 }
 
-/// Response structure from the DeepSeek API
-#[derive(Debug, Deserialize)]
-struct DeepSeekResponse {
-    #[allow(dead_code)]
-    id: String,
-    choices: Vec<Choice>,
-    #[allow(dead_code)]
-    created: u64,
-    #[allow(dead_code)]
-    model: String,
-    #[allow(dead_code)]
-    object: String,
-    usage: Usage,
+// Tens of kilobytes of base64 would drown the logs
+impl std::fmt::Debug for ChatImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ChatImage({}, {} bytes)", self.media_type, self.data.len())
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct Choice {
-    #[allow(dead_code)]
-    finish_reason: String,
+impl ChatMessage {
+    pub fn new(role: ChatRole, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            image: None,
+        }
+    }
+
+    /// User message asking about a picture
+    pub fn user_with_image(content: impl Into<String>, image: ChatImage) -> Self {
+        Self {
+            image: Some(image),
+            ..Self::user(content)
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::User, content)
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::Assistant, content)
+    }
+}
+
+/// A one-off request outside the conversation history, built with [`ChatRequestBuilder`]
+#[derive(Debug, Clone)]
+pub struct ChatRequest {
+    pub messages: Vec<ChatMessage>,
+    pub params: GenerationParams,
+}
+
+/// Builds a [`ChatRequest`], starting from the sampling parameters of the conversation
+///
+/// ```ignore
+/// let request = ChatRequestBuilder::new(llm.generation_params())
+///     .system("把用户的话翻译成英文")
+///     .user("今天天气很好")
+///     .max_tokens(128)
+///     .build();
+/// let reply = llm.complete_request(&request)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChatRequestBuilder {
+    messages: Vec<ChatMessage>,
+    params: GenerationParams,
+}
+
+impl ChatRequestBuilder {
+    pub fn new(params: GenerationParams) -> Self {
+        Self {
+            messages: Vec::new(),
+            params,
+        }
+    }
+
+    pub fn message(mut self, message: ChatMessage) -> Self {
+        self.messages.push(message);
+        self
+    }
+
+    pub fn system(self, content: impl Into<String>) -> Self {
+        self.message(ChatMessage::system(content))
+    }
+
+    pub fn user(self, content: impl Into<String>) -> Self {
+        self.message(ChatMessage::user(content))
+    }
+
+    pub fn user_with_image(self, content: impl Into<String>, image: ChatImage) -> Self {
+        self.message(ChatMessage::user_with_image(content, image))
+    }
+
+    /// Example answers for few-shot prompts
     #[allow(dead_code)]
-    index: u32,
-    message: ChatMessage,
+    pub fn assistant(self, content: impl Into<String>) -> Self {
+        self.message(ChatMessage::assistant(content))
+    }
+
+    pub fn max_tokens(mut self, max_tokens: u32) -> Self {
+        self.params.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.params.temperature = temperature;
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.params.top_p = top_p;
+        self
+    }
+
+    pub fn json_output(mut self, enabled: bool) -> Self {
+        self.params.json_output = enabled;
+        self
+    }
+
+    pub fn build(self) -> ChatRequest {
+        ChatRequest {
+            messages: self.messages,
+            params: self.params,
+        }
+    }
+}
+
+/// Token counts reported by the API for one request
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Usage {
+    pub completion_tokens: u32,
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+}
+
+impl Usage {
+    /// Sum of two usages, for requests made on behalf of one turn
+    fn combine(self, other: Usage) -> Usage {
+        Usage {
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            total_tokens: self.total_tokens + other.total_tokens,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct Usage {
-    completion_tokens: u32,
-    prompt_tokens: u32,
-    total_tokens: u32,
+/// Error returned by an LLM request, split by whether retrying can help
+#[derive(Debug)]
+pub enum LlmError {
+    /// Temporary failure such as a rate limit, server error or dropped connection
+    Retryable(anyhow::Error),
+    /// Failure that will not go away by retrying, e.g. bad credentials or a malformed reply
+    Fatal(anyhow::Error),
+    /// The request was aborted through its cancellation token
+    Cancelled,
 }
 
-/// Main structure for interacting with the DeepSeek LLM API
+impl std::fmt::Display for LlmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LlmError::Retryable(e) => write!(f, "{} (retryable)", e),
+            LlmError::Fatal(e) => write!(f, "{}", e),
+            LlmError::Cancelled => write!(f, "Request cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl RetryableError for LlmError {
+    fn is_retryable(&self) -> bool {
+        matches!(self, LlmError::Retryable(_))
+    }
+
+    fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            LlmError::Retryable(e) => e.downcast_ref::<HttpError>()?.retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Shared flag used to abort an in-flight request from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Ask the request currently in flight to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Clear the flag before starting new work
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Sleep for the given duration, returning early with false if cancelled
+    fn sleep(&self, duration: std::time::Duration) -> bool {
+        let step = std::time::Duration::from_millis(50);
+        let deadline = std::time::Instant::now() + duration;
+
+        while std::time::Instant::now() < deadline {
+            if self.is_cancelled() {
+                return false;
+            }
+            std::thread::sleep(step.min(deadline - std::time::Instant::now()));
+        }
+
+        !self.is_cancelled()
+    }
+}
+
+/// Timings filled in by the HTTP helpers while a request runs
+#[derive(Debug, Clone, Copy, Default)]
+struct RequestTimings {
+    dns_ms: Option<u64>,
+    connect_ms: Option<u64>,
+    ttfb_ms: Option<u64>,
+    first_token_ms: Option<u64>,
+    status: Option<u16>,
+}
+
+/// State of one request attempt handed to a provider
+pub struct RequestContext {
+    cancel: CancellationToken,
+    started: Instant,
+    timings: Cell<RequestTimings>,
+    /// Decides which response statuses are worth another attempt
+    retry_policy: RetryPolicy,
+}
+
+impl RequestContext {
+    fn new(cancel: CancellationToken, retry_policy: &RetryPolicy) -> Self {
+        Self {
+            cancel,
+            started: Instant::now(),
+            timings: Cell::new(RequestTimings::default()),
+            retry_policy: retry_policy.clone(),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    fn update_timings(&self, update: impl FnOnce(&mut RequestTimings)) {
+        let mut timings = self.timings.get();
+        update(&mut timings);
+        self.timings.set(timings);
+    }
+}
+
+/// Limits on the conversation kept verbatim; older turns are folded into a summary
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryBudget {
+    /// Characters of conversation (excluding system messages) allowed before summarizing
+    pub max_chars: usize,
+    /// Number of most recent messages that are never summarized
+    pub keep_recent_messages: usize,
+}
+
+impl Default for HistoryBudget {
+    fn default() -> Self {
+        Self {
+            max_chars: 2000,
+            keep_recent_messages: 4,
+        }
+    }
+}
+
+/// Prefix of the system note that replaces summarized turns
+const SUMMARY_PREFIX: &str = "对话摘要: ";
+const SUMMARY_INSTRUCTION: &str = "请把下面的对话总结成一段简短的摘要，保留用户提到的事实、偏好和尚未解决的问题，不超过150字。";
+
+/// Sampling parameters applied to every request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GenerationParams {
+    pub max_tokens: u32,
+    pub temperature: f32,
+    pub top_p: f32,
+    /// Ask the provider for a JSON object instead of free text
+    pub json_output: bool,
+}
+
+/// Default time allowed for a whole request, including reading the reply
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Duration of the last successful request in milliseconds, 0 before the first one
+static LAST_ROUND_TRIP_MS: AtomicU64 = AtomicU64::new(0);
+
+/// How long the last successful LLM request took, for "网络怎么样"
+pub fn last_round_trip_ms() -> Option<u64> {
+    Some(LAST_ROUND_TRIP_MS.load(Ordering::Relaxed)).filter(|&ms| ms > 0)
+}
+
+/// Transport options each provider is created with
+#[derive(Debug, Clone)]
+pub struct RequestOptions {
+    /// Time allowed for the whole request; the connection times out after the same idle time
+    pub timeout: std::time::Duration,
+    /// Receive the reply as server-sent events so a timeout keeps the text received so far
+    pub stream: bool,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            stream: false,
+        }
+    }
+}
+
+/// Piece of a streamed reply decoded from one server-sent event
+#[derive(Debug, Default)]
+struct StreamDelta {
+    text: String,
+    /// Providers report usage in the first and/or last event, the latest value wins
+    usage: Option<Usage>,
+    /// Set on the event that ends the stream
+    done: bool,
+}
+
+/// Assistant reply returned by a provider
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub content: String,
+    pub usage: Option<Usage>,
+}
+
+/// A chat completion backend with its own request and response format
+pub trait LlmProvider: Send {
+    /// Name of the service used in logs
+    fn name(&self) -> &'static str;
+
+    /// Model used for generating responses
+    fn model_name(&self) -> &str;
+
+    /// Send the whole conversation and return the assistant's reply, giving up early
+    /// once the request is cancelled
+    fn complete(
+        &self,
+        messages: &[ChatMessage],
+        params: &GenerationParams,
+        ctx: &RequestContext,
+    ) -> Result<Completion, LlmError>;
+}
+
+/// Create the primary provider followed by the fallbacks, in the order they are tried
+pub fn create_providers(config: &LlmConfig, default_api_key: &str) -> Vec<Box<dyn LlmProvider>> {
+    std::iter::once(&config.primary)
+        .chain(config.fallbacks.iter())
+        .map(|provider| create_provider(provider, default_api_key))
+        .collect()
+}
+
+/// URL requests of the configuration entry go to, the provider's public API unless overridden
+pub fn endpoint_url(config: &ProviderConfig) -> &str {
+    config.endpoint.as_deref().unwrap_or(match config.provider {
+        LlmProviderKind::DeepSeek => deepseek::DEFAULT_ENDPOINT,
+        LlmProviderKind::Anthropic => anthropic::DEFAULT_ENDPOINT,
+        LlmProviderKind::Gemini => gemini::API_BASE,
+    })
+}
+
+/// Create the provider described by one configuration entry
+pub fn create_provider(config: &ProviderConfig, default_api_key: &str) -> Box<dyn LlmProvider> {
+    let api_key = config.api_key.as_deref().unwrap_or(default_api_key);
+    if api_key.is_empty() {
+        log::warn!("No api_key configured for the {:?} LLM service", config.provider);
+    }
+
+    let model = config.model.as_deref();
+    let endpoint = config.endpoint.as_deref();
+
+    let options = RequestOptions {
+        timeout: std::time::Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+        stream: config.stream,
+    };
+
+    let provider: Box<dyn LlmProvider> = match config.provider {
+        LlmProviderKind::DeepSeek => {
+            let mut provider = DeepSeekProvider::new(api_key, model.unwrap_or(deepseek::DEFAULT_MODEL))
+                .with_options(options);
+            if let Some(endpoint) = endpoint {
+                provider = provider.with_endpoint(endpoint);
+            }
+            Box::new(provider)
+        }
+        LlmProviderKind::Anthropic => {
+            let mut provider = AnthropicProvider::new(api_key, model.unwrap_or(anthropic::DEFAULT_MODEL))
+                .with_options(options);
+            if let Some(endpoint) = endpoint {
+                provider = provider.with_endpoint(endpoint);
+            }
+            Box::new(provider)
+        }
+        LlmProviderKind::Gemini => {
+            let mut provider = GeminiProvider::new(api_key, model.unwrap_or(gemini::DEFAULT_MODEL))
+                .with_options(options);
+            if let Some(endpoint) = endpoint {
+                provider = provider.with_endpoint(endpoint);
+            }
+            if let Some(threshold) = &config.safety_threshold {
+                provider = provider.with_safety_threshold(threshold);
+            }
+            Box::new(provider)
+        }
+    };
+
+    info!("Using {} provider with model {}", provider.name(), provider.model_name());
+    provider
+}
+
+/// Main structure for holding a conversation with an LLM provider
 pub struct LlmHelper {
-    /// API endpoint for the DeepSeek service
-    api_endpoint: String,
-    /// API token for authentication
-    api_token: String,
-    /// Model to use for generating responses
-    model_name: String,
+    /// Backends the conversation is sent to, later ones are used when earlier ones fail
+    providers: Vec<Box<dyn LlmProvider>>,
     /// Chat history
     message_history: Vec<ChatMessage>,
     /// Maximum number of tokens to generate
@@ -105,24 +515,57 @@ pub struct LlmHelper {
     temperature: f32,
     /// Top_p parameter for nucleus sampling
     top_p: f32,
+    /// Request JSON replies from providers that support it
+    json_output: bool,
+    /// How failed requests are retried
+    retry_policy: RetryPolicy,
+    /// When to fold old turns into a summary
+    history_budget: HistoryBudget,
+    /// Token usage of the last successful request, not yet collected
+    last_usage: Option<Usage>,
+    /// Lets other threads abort the request in flight
+    cancel_token: CancellationToken,
+    /// Receives timing of every request attempt
+    metrics_sink: Arc<dyn MetricsSink>,
+    /// Instruction appended to every request without being kept in the history
+    style_hint: Option<String>,
+    /// Reference material appended to the next request only, e.g. snippets of the user's notes
+    context: Option<String>,
 }
 
 impl LlmHelper {
-    /// Create a new instance of LlmHelper
+    /// Create a new instance of LlmHelper talking to DeepSeek
     pub fn new(api_token: &str, model_name: &str) -> Self {
+        Self::with_provider(Box::new(DeepSeekProvider::new(api_token, model_name)))
+    }
+
+    /// Create a new instance of LlmHelper using the given provider
+    pub fn with_provider(provider: Box<dyn LlmProvider>) -> Self {
         let helper = LlmHelper {
-            api_endpoint: "https://api.deepseek.com/chat/completions".to_string(),
-            api_token: api_token.to_string(),
-            model_name: model_name.to_string(),
+            providers: vec![provider],
             message_history: Vec::new(),
             max_tokens: 2048,
             temperature: 1.0,
             top_p: 1.0,
+            json_output: false,
+            retry_policy: RetryPolicy::llm(),
+            history_budget: HistoryBudget::default(),
+            last_usage: None,
+            cancel_token: CancellationToken::default(),
+            metrics_sink: Arc::new(LogMetricsSink),
+            style_hint: None,
+            context: None,
         };
 
         helper
     }
 
+    /// Add a question answered without the LLM, e.g. from the cache, so follow-ups have context
+    pub fn record_exchange(&mut self, question: &str, answer: &str) {
+        self.message_history.push(ChatMessage::user(question));
+        self.message_history.push(ChatMessage::assistant(answer));
+    }
+
     /// Get a copy of the message history
     pub fn get_history(&self) -> Vec<String> {
         self.message_history
@@ -131,6 +574,11 @@ impl LlmHelper {
             .collect()
     }
 
+    /// Messages of the conversation so far, starting with the system message
+    pub fn messages(&self) -> &[ChatMessage] {
+        &self.message_history
+    }
+
     /// Clear the message history, keeping only the system message
     #[allow(dead_code)]
     pub fn clear_history(&mut self) {
@@ -154,166 +602,555 @@ impl LlmHelper {
         }
     }
 
+    /// Current sampling parameters
+    pub fn generation_params(&self) -> GenerationParams {
+        GenerationParams {
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            json_output: self.json_output,
+        }
+    }
+
+    /// Swap the system message for another one, keeping the conversation
+    pub fn replace_system_prompt(&mut self, prompt: String) {
+        match self.message_history.first_mut() {
+            Some(first) if first.role == ChatRole::System => first.content = prompt,
+            _ => self.message_history.insert(0, ChatMessage::system(prompt)),
+        }
+    }
+
+    /// Set an instruction such as the preferred reply length, sent with every request
+    pub fn set_style_hint(&mut self, hint: Option<&str>) {
+        self.style_hint = hint.map(str::to_string);
+    }
+
+    /// Send `context` with the next request without keeping it in the history
+    pub fn set_context(&mut self, context: Option<String>) {
+        self.context = context;
+    }
+
+    /// Switch between free text replies and JSON object replies
+    pub fn set_json_output(&mut self, enabled: bool) {
+        self.json_output = enabled;
+    }
+
+    /// Switch to other providers, keeping the conversation history
+    pub fn set_providers(&mut self, providers: Vec<Box<dyn LlmProvider>>) {
+        if providers.is_empty() {
+            warn!("No LLM providers given, keeping the current ones");
+            return;
+        }
+        self.providers = providers;
+    }
+
+    /// Set how failed requests are retried
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Use a token shared with other threads to cancel requests in flight
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel_token = token;
+    }
+
+    /// Send request metrics somewhere other than the log
+    pub fn set_metrics_sink(&mut self, sink: Arc<dyn MetricsSink>) {
+        self.metrics_sink = sink;
+    }
+
+    /// Set how much conversation is kept before old turns get summarized
+    pub fn set_history_budget(&mut self, budget: HistoryBudget) {
+        self.history_budget = budget;
+    }
+
+    /// Take the token usage of the last request, if it hasn't been collected yet
+    pub fn take_last_usage(&mut self) -> Option<Usage> {
+        self.last_usage.take()
+    }
+
     /// Send a message to the LLM and get a response
-    pub fn send_message(&mut self, text: String, role: ChatRole) -> String {
+    ///
+    /// The error tells a dead network (retryable) from a refused request (fatal).
+    pub fn send_message(&mut self, text: String, role: ChatRole) -> Result<String, LlmError> {
         // Create and store the new message
-        let message = ChatMessage {
-            role: role.as_str().to_string(),
-            content: text,
-        };
-
-        self.message_history.push(message);
+        self.message_history.push(ChatMessage::new(role, text));
 
         // Don't make API calls for system messages
-        if matches!(role, ChatRole::System) {
-            return String::new();
+        if role == ChatRole::System {
+            return Ok(String::new());
         }
 
         // Build and send request
-        match self.make_api_request() {
-            Ok(response) => response,
-            Err(e) => {
-                let error_msg = format!("Error: {}", e);
-                error!("{}", error_msg);
-                error_msg
+        let result = self.make_api_request();
+        match &result {
+            Ok(_) => {}
+            Err(LlmError::Cancelled) => {
+                // Drop the unanswered question so the next request stays well formed
+                self.message_history.pop();
+                info!("LLM request cancelled");
             }
+            Err(e) => error!("LLM request failed: {}", e),
         }
+        result
     }
 
-    /// Make the actual API request to DeepSeek using ESP-IDF HTTP client
-    fn make_api_request(&mut self) -> Result<String> {
-        // Prepare request payload
-        let request = DeepSeekRequest {
-            messages: self.message_history.clone(),
-            model: self.model_name.clone(),
-            frequency_penalty: 0.0,
-            max_tokens: self.max_tokens,
-            presence_penalty: 0.0,
-            response_format: ResponseFormat {
-                format_type: "text".to_string(),
-            },
-            stop: None,
-            stream: false,
-            stream_options: None,
-            temperature: self.temperature,
-            top_p: self.top_p,
-            tools: None,
-            tool_choice: "none".to_string(),
-            logprobs: false,
-            top_logprobs: None,
+    /// Make the actual API request through the provider, retrying transient failures
+    fn make_api_request(&mut self) -> Result<String, LlmError> {
+        self.summarize_old_turns();
+
+        let params = self.generation_params();
+        let context = self.context.take();
+        let extra: Vec<&String> = context.iter().chain(&self.style_hint).collect();
+        let completion = if extra.is_empty() {
+            self.complete_with_failover(&self.message_history, &params)?
+        } else {
+            let mut messages = self.message_history.clone();
+            messages.extend(extra.into_iter().map(|text| ChatMessage::system(text.as_str())));
+            self.complete_with_failover(&messages, &params)?
         };
+        Ok(self.handle_completion(completion))
+    }
 
-        let json_payload = serde_json::to_string(&request)?;
+    /// Send a one-off request without touching the conversation history
+    pub fn complete_request(&self, request: &ChatRequest) -> Result<Completion, LlmError> {
+        self.complete_with_failover(&request.messages, &request.params)
+    }
 
-        info!("Sending request to DeepSeek API...");
+    /// Send a request to the first provider that answers, retrying each according to the
+    /// retry policy before failing over to the next one
+    fn complete_with_failover(
+        &self,
+        messages: &[ChatMessage],
+        params: &GenerationParams,
+    ) -> Result<Completion, LlmError> {
+        // Every provider and retry would only wait out its timeout
+        if !connectivity::has_wifi() {
+            return Err(LlmError::Retryable(anyhow::anyhow!("WiFi is not connected")));
+        }
 
-        // Create HTTP client configuration with TLS support
-        let config = HttpConfiguration {
-            timeout: Some(std::time::Duration::from_secs(30)),
-            use_global_ca_store: true,
-            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
-            ..Default::default()
-        };
+        let mut last_error = None;
 
-        let api_url = self.api_endpoint.clone();
+        for (index, provider) in self.providers.iter().enumerate() {
+            if index > 0 {
+                warn!(
+                    "LLM failover: switching from {} to {} ({})",
+                    self.providers[index - 1].name(),
+                    provider.name(),
+                    provider.model_name()
+                );
+            }
 
-        // Create HTTP client
-        let mut client = match EspHttpConnection::new(&config) {
-            Ok(client) => client,
-            Err(e) => {
-                error!("Failed to create HTTP client: {}", e);
-                return Err(anyhow::anyhow!("HTTP client creation failed: {}", e));
+            match self.complete_with_retries(provider.as_ref(), messages, params) {
+                Ok(completion) => {
+                    if index > 0 {
+                        info!("LLM failover: {} answered the request", provider.name());
+                    }
+                    return Ok(completion);
+                }
+                Err(LlmError::Cancelled) => return Err(LlmError::Cancelled),
+                Err(e) => {
+                    error!("{} request failed: {}", provider.name(), e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| LlmError::Fatal(anyhow::anyhow!("No LLM provider configured"))))
+    }
+
+    /// Send a request to one provider, retrying temporary failures with backoff
+    fn complete_with_retries(
+        &self,
+        provider: &dyn LlmProvider,
+        messages: &[ChatMessage],
+        params: &GenerationParams,
+    ) -> Result<Completion, LlmError> {
+        let what = format!("{} request", provider.name());
+        let wait = |delay: std::time::Duration| {
+            // Cut short on cancellation, the next attempt then returns Cancelled
+            self.cancel_token.sleep(delay);
+        };
+
+        self.retry_policy.run(&what, wait, |attempt| {
+            if self.cancel_token.is_cancelled() {
+                return Err(LlmError::Cancelled);
             }
+
+            info!("Sending request to {} API...", provider.name());
+            let ctx = RequestContext::new(self.cancel_token.clone(), &self.retry_policy);
+            let result = provider.complete(messages, params, &ctx);
+            self.report_metrics(provider, attempt, &ctx, result.is_ok());
+            result
+        })
+    }
+
+    fn report_metrics(&self, provider: &dyn LlmProvider, attempt: u32, ctx: &RequestContext, success: bool) {
+        let timings = ctx.timings.get();
+        if success {
+            LAST_ROUND_TRIP_MS.store(ctx.elapsed_ms(), Ordering::Relaxed);
+            // Without streaming the first token arrives with the whole reply
+            let first_token_ms = timings.first_token_ms.unwrap_or(ctx.elapsed_ms());
+            metrics::record_time(
+                "llm_first_token",
+                std::time::Duration::from_millis(first_token_ms),
+            );
+        }
+        self.metrics_sink.record_request(&RequestMetrics {
+            service: provider.name().to_string(),
+            model: provider.model_name().to_string(),
+            attempt,
+            dns_ms: timings.dns_ms,
+            connect_ms: timings.connect_ms,
+            ttfb_ms: timings.ttfb_ms,
+            first_token_ms: timings.first_token_ms,
+            total_ms: ctx.elapsed_ms(),
+            status: timings.status,
+            success,
+        });
+    }
+
+    /// Store the assistant reply in the history and return its text
+    fn handle_completion(&mut self, completion: Completion) -> String {
+        if let Some(usage) = completion.usage {
+            info!(
+                "Response received. Tokens used: {} (prompt) + {} (completion) = {} (total)",
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.total_tokens
+            );
+        }
+        self.record_usage(completion.usage);
+
+        // Add the assistant response to the history
+        self.message_history.push(ChatMessage::assistant(completion.content.clone()));
+
+        completion.content
+    }
+
+    /// Add usage to what hasn't been collected yet
+    fn record_usage(&mut self, usage: Option<Usage>) {
+        self.last_usage = match (self.last_usage.take(), usage) {
+            (Some(previous), Some(usage)) => Some(previous.combine(usage)),
+            (previous, usage) => usage.or(previous),
         };
+    }
+
+    /// Replace the oldest turns with a summary once the history exceeds its budget
+    fn summarize_old_turns(&mut self) {
+        let conversation_chars: usize = self
+            .message_history
+            .iter()
+            .filter(|msg| msg.role != ChatRole::System)
+            .map(|msg| msg.content.chars().count())
+            .sum();
+
+        if conversation_chars <= self.history_budget.max_chars {
+            return;
+        }
+
+        // The leading system prompt stays; a previous summary gets folded into the new one
+        let start = self
+            .message_history
+            .iter()
+            .position(|msg| msg.role != ChatRole::System || msg.content.starts_with(SUMMARY_PREFIX))
+            .unwrap_or(self.message_history.len());
+
+        // Keep the recent turns (at least the pending question) and make sure they still
+        // start with a user message
+        let mut end = self
+            .message_history
+            .len()
+            .saturating_sub(self.history_budget.keep_recent_messages.max(1));
+        while end > start && self.message_history[end].role != ChatRole::User {
+            end -= 1;
+        }
+
+        if end <= start {
+            return;
+        }
+
+        let transcript: String = self.message_history[start..end]
+            .iter()
+            .map(|msg| {
+                let speaker = match msg.role {
+                    ChatRole::User => "用户",
+                    ChatRole::Assistant => "助手",
+                    ChatRole::System => "之前的摘要",
+                };
+                format!("{}: {}\n", speaker, msg.content)
+            })
+            .collect();
+
+        let request = ChatRequestBuilder::new(self.generation_params())
+            .system(SUMMARY_INSTRUCTION)
+            .user(transcript)
+            .max_tokens(256)
+            .temperature(0.3)
+            .top_p(1.0)
+            .json_output(false)
+            .build();
+
+        info!(
+            "Conversation is {} characters, summarizing {} old messages",
+            conversation_chars,
+            end - start
+        );
 
-        // Prepare headers for the request
-        let headers = [
-            ("Content-Type", "application/json"),
-            ("Accept", "application/json"),
-            ("Authorization", &format!("Bearer {}", self.api_token)),
-            ("Content-Length", &json_payload.len().to_string()),
-        ];
-
-        // Send the request with better error handling
-        info!("Initiating HTTP request to {}", &api_url);
-        if let Err(e) = client.initiate_request(Method::Post, &api_url, &headers) {
+        match self.complete_request(&request) {
+            Ok(summary) => {
+                self.record_usage(summary.usage);
+                let note = ChatMessage::system(format!("{}{}", SUMMARY_PREFIX, summary.content.trim()));
+                self.message_history.splice(start..end, std::iter::once(note));
+            }
+            Err(e) => warn!("Failed to summarize conversation, keeping full history: {}", e),
+        }
+    }
+}
+
+/// Send a JSON payload and wait for the response headers, shared by all providers
+fn send_json_request(
+    url: &str,
+    headers: &[(&str, &str)],
+    json_payload: &str,
+    options: &RequestOptions,
+    ctx: &RequestContext,
+) -> Result<PooledConnection, LlmError> {
+    // Resolve the host ourselves to time DNS separately, the client then hits the lwIP cache
+    let dns_started = Instant::now();
+    let host = url_host(url);
+    if let Err(e) = (host, 443).to_socket_addrs() {
+        error!("Failed to resolve {}: {}", host, e);
+        return Err(LlmError::Retryable(anyhow::anyhow!("DNS lookup for {} failed: {}", host, e)));
+    }
+    ctx.update_timings(|t| t.dns_ms = Some(dns_started.elapsed().as_millis() as u64));
+
+    // Create HTTP client configuration with TLS support
+    let config = HttpConfiguration {
+        timeout: Some(options.timeout),
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+
+    // Reuse the connection of the last request to this server when it's still open
+    let mut client = match PooledConnection::open(url, &config) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to create HTTP client: {}", e);
+            return Err(LlmError::Retryable(anyhow::anyhow!("HTTP client creation failed: {}", e)));
+        }
+    };
+
+    // Prepare headers for the request
+    let content_length = json_payload.len().to_string();
+    let accept = if options.stream {
+        "text/event-stream"
+    } else {
+        "application/json"
+    };
+    let mut all_headers = vec![
+        ("Content-Type", "application/json"),
+        ("Accept", accept),
+        ("Content-Length", content_length.as_str()),
+    ];
+    if !options.stream {
+        // Event streams are parsed as they arrive, only whole bodies can be decompressed
+        all_headers.push(ACCEPT_ENCODING);
+    }
+    all_headers.extend_from_slice(headers);
+
+    // Network and TLS failures are usually transient, so they are all retryable
+    let mut sent = Instant::now();
+    client.send(Method::Post, |client| {
+        info!("Initiating HTTP request to {}", url);
+        let connect_started = Instant::now();
+        if let Err(e) = client.initiate_request(Method::Post, url, &all_headers) {
             error!("Failed to initiate HTTP request: {}", e);
-            return Err(anyhow::anyhow!("Failed to initiate HTTP request: {}", e));
+            return Err(LlmError::Retryable(anyhow::anyhow!("Failed to initiate HTTP request: {}", e)));
         }
+        ctx.update_timings(|t| t.connect_ms = Some(connect_started.elapsed().as_millis() as u64));
 
         if let Err(e) = client.write(json_payload.as_bytes()) {
             error!("Failed to write request body: {}", e);
-            return Err(anyhow::anyhow!("Failed to write request body: {}", e));
+            return Err(LlmError::Retryable(anyhow::anyhow!("Failed to write request body: {}", e)));
         }
 
         // Finalize the request
+        sent = Instant::now();
         if let Err(e) = client.initiate_response() {
             error!("Failed to finalize HTTP request: {}", e);
-            return Err(anyhow::anyhow!("Failed to finalize HTTP request: {}", e));
+            return Err(LlmError::Retryable(anyhow::anyhow!("Failed to finalize HTTP request: {}", e)));
         }
-        info!("HTTP request sent successfully.");
+        Ok(())
+    })?;
+    info!("HTTP request sent successfully.");
+
+    if ctx.is_cancelled() {
+        return Err(LlmError::Cancelled);
+    }
+
+    // Get the response status
+    let status = client.status();
+    info!("HTTP response status: {}", status);
+    ctx.update_timings(|t| {
+        t.ttfb_ms = Some(sent.elapsed().as_millis() as u64);
+        t.status = Some(status);
+    });
+
+    if status != 200 {
+        let error = HttpError::from_response(&mut client);
+        return Err(if ctx.retry_policy.is_retryable_status(status) {
+            LlmError::Retryable(error.into())
+        } else {
+            LlmError::Fatal(error.into())
+        });
+    }
+
+    Ok(client)
+}
+
+/// POST a JSON payload and return the response body
+fn post_json(
+    url: &str,
+    headers: &[(&str, &str)],
+    json_payload: &str,
+    options: &RequestOptions,
+    ctx: &RequestContext,
+) -> Result<String, LlmError> {
+    let mut client = send_json_request(url, headers, json_payload, options, ctx)?;
 
-        // Get the response status
-        let status = client.status();
-        info!("HTTP response status: {}", status);
+    // Read response body
+    let mut response_body = Vec::new();
+    let mut buffer = [0u8; 1024];
 
-        if status != 200 {
-            return Err(anyhow::anyhow!("HTTP request failed with status: {}", status));
+    loop {
+        // Dropping the connection aborts the request
+        if ctx.is_cancelled() {
+            return Err(LlmError::Cancelled);
         }
 
-        // Read response body
-        let mut response_body = Vec::new();
-        let mut buffer = [0u8; 1024];
+        if ctx.started.elapsed() > options.timeout {
+            return Err(LlmError::Retryable(anyhow::anyhow!(
+                "Request timed out after {} s",
+                options.timeout.as_secs()
+            )));
+        }
 
-        loop {
-            match client.read(&mut buffer) {
-                Ok(bytes_read) => {
-                    if bytes_read == 0 {
-                        break;
-                    }
-                    response_body.extend_from_slice(&buffer[..bytes_read]);
-                },
-                Err(e) => {
-                    error!("Error reading response: {}", e);
-                    return Err(anyhow::anyhow!("Error reading response: {}", e));
+        match client.read(&mut buffer) {
+            Ok(bytes_read) => {
+                if bytes_read == 0 {
+                    break;
                 }
+                response_body.extend_from_slice(&buffer[..bytes_read]);
+                watchdog::feed();
+            },
+            Err(e) => {
+                error!("Error reading response: {}", e);
+                return Err(LlmError::Retryable(anyhow::anyhow!("Error reading response: {}", e)));
             }
         }
+    }
 
-        // Parse the response
-        let response_str = String::from_utf8(response_body)?;
+    let response_body = decode_body(client.header("Content-Encoding"), response_body)
+        .map_err(LlmError::Fatal)?;
 
-        // Check if the response is valid JSON
-        match serde_json::from_str::<DeepSeekResponse>(&response_str) {
-            Ok(api_response) => {
-                // Extract and store the assistant's response
-                if !api_response.choices.is_empty() {
-                    let assistant_message = api_response.choices[0].message.clone();
+    // The whole response was read, the next request can use the connection
+    client.release();
 
-                    // Add the assistant response to the history
-                    self.message_history.push(assistant_message.clone());
+    String::from_utf8(response_body)
+        .map_err(|e| LlmError::Fatal(anyhow::anyhow!("Response is not valid UTF-8: {}", e)))
+}
 
-                    info!(
-                        "Response received. Tokens used: {} (prompt) + {} (completion) = {} (total)",
-                        api_response.usage.prompt_tokens,
-                        api_response.usage.completion_tokens,
-                        api_response.usage.total_tokens
-                    );
+/// POST a JSON payload and collect a reply streamed as server-sent events.
+///
+/// `parse_event` decodes the data of one event. When the stream breaks off or the
+/// request runs out of time the text received so far is returned instead of an error.
+fn post_sse(
+    url: &str,
+    headers: &[(&str, &str)],
+    json_payload: &str,
+    options: &RequestOptions,
+    ctx: &RequestContext,
+    mut parse_event: impl FnMut(&str) -> Result<StreamDelta, LlmError>,
+) -> Result<Completion, LlmError> {
+    let mut client = send_json_request(url, headers, json_payload, options, ctx)?;
 
-                    Ok(assistant_message.content)
-                } else {
-                    let error_msg = "No response choices returned from API".to_string();
-                    warn!("{}", error_msg);
-                    Ok(error_msg)
+    let mut content = String::new();
+    let mut usage = None;
+    let mut pending = Vec::new();
+    let mut buffer = [0u8; 1024];
+
+    let interruption = 'read: loop {
+        if ctx.is_cancelled() {
+            return Err(LlmError::Cancelled);
+        }
+
+        if ctx.started.elapsed() > options.timeout {
+            break Some(anyhow::anyhow!("Request timed out after {} s", options.timeout.as_secs()));
+        }
+
+        let bytes_read = match client.read(&mut buffer) {
+            Ok(0) => break Some(anyhow::anyhow!("Stream closed before the reply was complete")),
+            Ok(bytes_read) => bytes_read,
+            Err(e) => break Some(anyhow::anyhow!("Error reading response: {}", e)),
+        };
+        watchdog::feed();
+        pending.extend_from_slice(&buffer[..bytes_read]);
+
+        // Only complete lines are decoded so multi-byte characters are never split
+        while let Some(newline) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:") else {
+                continue;
+            };
+
+            let delta = parse_event(data.trim())?;
+            if content.is_empty() && !delta.text.is_empty() {
+                ctx.update_timings(|t| t.first_token_ms = Some(ctx.elapsed_ms()));
+            }
+            content.push_str(&delta.text);
+            if delta.usage.is_some() {
+                usage = delta.usage;
+            }
+
+            if delta.done {
+                // Read what follows the last event so the connection can be reused
+                while let Ok(bytes_read) = client.read(&mut buffer) {
+                    if bytes_read == 0 {
+                        client.release();
+                        break 'read None;
+                    }
                 }
-            },
-            Err(e) => {
-                error!("Failed to parse API response: {}", e);
-                error!("Raw response: {}", response_str);
-                Err(anyhow::anyhow!("Failed to parse API response: {}", e))
+                break 'read None;
             }
         }
+    };
+
+    if let Some(e) = interruption {
+        if content.is_empty() {
+            error!("{}", e);
+            return Err(LlmError::Retryable(e));
+        }
+        warn!("{}, keeping {} characters received so far", e, content.chars().count());
     }
+
+    Ok(Completion { content, usage })
+}
+
+/// Host part of a URL, used for the DNS lookup
+fn url_host(url: &str) -> &str {
+    url_host_port(url).map_or("", |(host, _)| host)
+}
+
+/// Parse a JSON response body, logging the raw text when it doesn't match
+fn parse_json_response<T: serde::de::DeserializeOwned>(response_str: &str) -> Result<T, LlmError> {
+    serde_json::from_str::<T>(response_str).map_err(|e| {
+        error!("Failed to parse API response: {}", e);
+        error!("Raw response: {}", response_str);
+        LlmError::Fatal(anyhow::anyhow!("Failed to parse API response: {}", e))
+    })
 }
 
 // Unit tests
@@ -325,7 +1162,7 @@ mod tests {
     #[test]
     fn test_llm_helper_new() {
         let helper = LlmHelper::new("fake_token", "deepseek-chat");
-        assert_eq!(helper.model_name, "deepseek-chat");
+        assert_eq!(helper.providers[0].model_name(), "deepseek-chat");
         assert_eq!(helper.max_tokens, 2048);
         assert_eq!(helper.temperature, 1.0);
         assert!(!helper.message_history.is_empty()); // Should have system message
@@ -339,6 +1176,14 @@ mod tests {
         assert_eq!(helper.max_tokens, 1024);
         assert_eq!(helper.temperature, 0.7);
         assert_eq!(helper.top_p, 0.9);
+
+        // Unset values are left alone
+        helper.configure(None, Some(1.2), None);
+        let params = helper.generation_params();
+        assert_eq!(
+            (params.max_tokens, params.temperature, params.top_p),
+            (1024, 1.2, 0.9)
+        );
     }
 
     // Test clearing history
@@ -347,7 +1192,7 @@ mod tests {
         let mut helper = LlmHelper::new("fake_token", "deepseek-chat");
 
         // Add a user message
-        helper.send_message("Hello".to_string(), ChatRole::User);
+        let _ = helper.send_message("Hello".to_string(), ChatRole::User);
         assert!(helper.message_history.len() > 1);
 
         // Clear history
@@ -356,8 +1201,19 @@ mod tests {
         // Should keep system message(s)
         let system_count = helper.message_history
             .iter()
-            .filter(|msg| msg.role == "system")
+            .filter(|msg| msg.role == ChatRole::System)
             .count();
         assert_eq!(helper.message_history.len(), system_count);
     }
+
+    #[test]
+    fn test_chat_image() {
+        let image = ChatImage::jpeg(&[0xff, 0xd8, 0xff]);
+        assert_eq!(image.data_url(), "data:image/jpeg;base64,/9j/");
+
+        // Pictures are left out of the saved conversations
+        let message = ChatMessage::user_with_image("这是什么", image);
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(!json.contains("/9j/"));
+    }
 }
\ No newline at end of file
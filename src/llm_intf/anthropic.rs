@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    parse_json_response, post_json, post_sse, ChatMessage, ChatRole, Completion, GenerationParams,
+    LlmError, LlmProvider, RequestContext, RequestOptions, StreamDelta, Usage,
+};
+
+pub const DEFAULT_ENDPOINT: &str = "https://api.anthropic.com/v1/messages";
+const API_VERSION: &str = "2023-06-01";
+pub const DEFAULT_MODEL: &str = "claude-3-5-haiku-latest";
+
+/// Request structure for the Anthropic Messages API
+#[derive(Debug, Serialize)]
+struct AnthropicRequest<'a> {
+    model: &'a str,
+    /// Required by the API, unlike the OpenAI style endpoints
+    max_tokens: u32,
+    /// System prompt is a top level field instead of a message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage<'a>>,
+    /// Only temperature is sent, newer models reject requests that set both it and top_p
+    temperature: f32,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct AnthropicMessage<'a> {
+    role: &'static str,
+    content: MessageContent<'a>,
+}
+
+/// Plain text, or content blocks when the message carries a picture
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum MessageContent<'a> {
+    Text(&'a str),
+    Blocks(Vec<RequestBlock<'a>>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestBlock<'a> {
+    Text { text: &'a str },
+    Image { source: ImageSource<'a> },
+}
+
+#[derive(Debug, Serialize)]
+struct ImageSource<'a> {
+    /// Always "base64", the picture is sent inline
+    #[serde(rename = "type")]
+    source_type: &'static str,
+    media_type: &'static str,
+    data: &'a str,
+}
+
+impl<'a> From<&'a ChatMessage> for AnthropicMessage<'a> {
+    fn from(message: &'a ChatMessage) -> Self {
+        let content = match &message.image {
+            None => MessageContent::Text(&message.content),
+            // The documentation recommends the image before the question about it
+            Some(image) => MessageContent::Blocks(vec![
+                RequestBlock::Image {
+                    source: ImageSource {
+                        source_type: "base64",
+                        media_type: image.media_type,
+                        data: &image.data,
+                    },
+                },
+                RequestBlock::Text {
+                    text: &message.content,
+                },
+            ]),
+        };
+        Self {
+            role: message.role.as_str(),
+            content,
+        }
+    }
+}
+
+/// Response structure from the Anthropic Messages API
+#[derive(Debug, Deserialize)]
+struct AnthropicResponse {
+    content: Vec<ContentBlock>,
+    #[allow(dead_code)]
+    stop_reason: Option<String>,
+    usage: AnthropicUsage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    block_type: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+/// Events of a streamed reply, see the Messages streaming documentation
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: StreamMessage,
+    },
+    ContentBlockDelta {
+        delta: TextDelta,
+    },
+    MessageDelta {
+        usage: OutputUsage,
+    },
+    MessageStop,
+    Error {
+        error: StreamError,
+    },
+    /// Pings and content block boundaries carry no text
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamMessage {
+    usage: AnthropicUsage,
+}
+
+#[derive(Debug, Deserialize)]
+struct TextDelta {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutputUsage {
+    output_tokens: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamError {
+    message: String,
+}
+
+/// Provider for Anthropic's Claude models
+pub struct AnthropicProvider {
+    api_endpoint: String,
+    api_key: String,
+    model_name: String,
+    options: RequestOptions,
+}
+
+impl AnthropicProvider {
+    pub fn new(api_key: &str, model_name: &str) -> Self {
+        Self {
+            api_endpoint: DEFAULT_ENDPOINT.to_string(),
+            api_key: api_key.to_string(),
+            model_name: model_name.to_string(),
+            options: RequestOptions::default(),
+        }
+    }
+
+    /// Set the request timeout and whether replies are streamed
+    pub fn with_options(mut self, options: RequestOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Use another endpoint, e.g. a proxy
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.api_endpoint = endpoint.to_string();
+        self
+    }
+}
+
+impl LlmProvider for AnthropicProvider {
+    fn name(&self) -> &'static str {
+        "Anthropic"
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    fn complete(
+        &self,
+        messages: &[ChatMessage],
+        params: &GenerationParams,
+        ctx: &RequestContext,
+    ) -> Result<Completion, LlmError> {
+        // There is no JSON mode, params.json_output relies on the system prompt asking for JSON
+        // Collect every system message into the single system field
+        let system_prompt: Vec<&str> = messages
+            .iter()
+            .filter(|msg| msg.role == ChatRole::System)
+            .map(|msg| msg.content.as_str())
+            .collect();
+
+        let request = AnthropicRequest {
+            model: &self.model_name,
+            max_tokens: params.max_tokens,
+            system: if system_prompt.is_empty() {
+                None
+            } else {
+                Some(system_prompt.join("\n"))
+            },
+            messages: messages
+                .iter()
+                .filter(|msg| msg.role != ChatRole::System)
+                .map(AnthropicMessage::from)
+                .collect(),
+            temperature: params.temperature.min(1.0),
+            stream: self.options.stream,
+        };
+
+        let json_payload = serde_json::to_string(&request)
+            .map_err(|e| LlmError::Fatal(anyhow::anyhow!("Failed to serialize request: {}", e)))?;
+
+        let headers = [
+            ("x-api-key", self.api_key.as_str()),
+            ("anthropic-version", API_VERSION),
+        ];
+
+        if self.options.stream {
+            // Input tokens arrive at the start, output tokens at the end of the stream
+            let mut input_tokens = 0;
+            return post_sse(
+                &self.api_endpoint,
+                &headers,
+                &json_payload,
+                &self.options,
+                ctx,
+                |data| parse_stream_event(data, &mut input_tokens),
+            );
+        }
+
+        let response_str = post_json(
+            &self.api_endpoint,
+            &headers,
+            &json_payload,
+            &self.options,
+            ctx,
+        )?;
+
+        let api_response: AnthropicResponse = parse_json_response(&response_str)?;
+
+        let content: String = api_response
+            .content
+            .iter()
+            .filter(|block| block.block_type == "text")
+            .map(|block| block.text.as_str())
+            .collect();
+
+        Ok(Completion {
+            content,
+            usage: Some(Usage {
+                prompt_tokens: api_response.usage.input_tokens,
+                completion_tokens: api_response.usage.output_tokens,
+                total_tokens: api_response.usage.input_tokens + api_response.usage.output_tokens,
+            }),
+        })
+    }
+}
+
+/// Decode one server-sent event into text and usage
+fn parse_stream_event(data: &str, input_tokens: &mut u32) -> Result<StreamDelta, LlmError> {
+    let delta = match parse_json_response(data)? {
+        StreamEvent::MessageStart { message } => {
+            *input_tokens = message.usage.input_tokens;
+            StreamDelta::default()
+        }
+        StreamEvent::ContentBlockDelta { delta } => StreamDelta {
+            text: delta.text,
+            ..Default::default()
+        },
+        StreamEvent::MessageDelta { usage } => StreamDelta {
+            usage: Some(Usage {
+                prompt_tokens: *input_tokens,
+                completion_tokens: usage.output_tokens,
+                total_tokens: *input_tokens + usage.output_tokens,
+            }),
+            ..Default::default()
+        },
+        StreamEvent::MessageStop => StreamDelta {
+            done: true,
+            ..Default::default()
+        },
+        // Sent mid-stream e.g. when the API is overloaded
+        StreamEvent::Error { error } => {
+            return Err(LlmError::Retryable(anyhow::anyhow!(
+                "Stream error: {}",
+                error.message
+            )));
+        }
+        StreamEvent::Other => StreamDelta::default(),
+    };
+
+    Ok(delta)
+}
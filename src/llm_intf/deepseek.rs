@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    parse_json_response, post_json, post_sse, ChatMessage, ChatRole, Completion, GenerationParams,
+    LlmError, LlmProvider, RequestContext, RequestOptions, StreamDelta, Usage,
+};
+
+pub const DEFAULT_ENDPOINT: &str = "https://api.deepseek.com/chat/completions";
+pub const DEFAULT_MODEL: &str = "deepseek-chat";
+
+/// Request structure for the DeepSeek API
+#[derive(Debug, Serialize)]
+struct DeepSeekRequest<'a> {
+    messages: Vec<RequestMessage<'a>>,
+    model: &'a str,
+    frequency_penalty: f32,
+    max_tokens: u32,
+    presence_penalty: f32,
+    response_format: ResponseFormat,
+    stop: Option<Vec<String>>,
+    stream: bool,
+    stream_options: Option<StreamOptions>,
+    temperature: f32,
+    top_p: f32,
+    tools: Option<String>,
+    tool_choice: String,
+    logprobs: bool,
+    top_logprobs: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestMessage<'a> {
+    role: ChatRole,
+    content: MessageContent<'a>,
+}
+
+/// Plain text, or a list of parts when the message carries a picture
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum MessageContent<'a> {
+    Text(&'a str),
+    Parts(Vec<ContentPart<'a>>),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart<'a> {
+    Text { text: &'a str },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Serialize)]
+struct ImageUrl {
+    /// The picture inlined as a data URL
+    url: String,
+}
+
+impl<'a> From<&'a ChatMessage> for RequestMessage<'a> {
+    fn from(message: &'a ChatMessage) -> Self {
+        let content = match &message.image {
+            None => MessageContent::Text(&message.content),
+            Some(image) => MessageContent::Parts(vec![
+                ContentPart::Text {
+                    text: &message.content,
+                },
+                ContentPart::ImageUrl {
+                    image_url: ImageUrl {
+                        url: image.data_url(),
+                    },
+                },
+            ]),
+        };
+        Self {
+            role: message.role,
+            content,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    format_type: String,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamOptions {
+    /// Adds a final event carrying the token usage
+    include_usage: bool,
+}
+
+/// Response structure from the DeepSeek API
+#[derive(Debug, Deserialize)]
+struct DeepSeekResponse {
+    #[allow(dead_code)]
+    id: String,
+    choices: Vec<Choice>,
+    #[allow(dead_code)]
+    created: u64,
+    #[allow(dead_code)]
+    model: String,
+    #[allow(dead_code)]
+    object: String,
+    usage: Usage,
+}
+
+#[derive(Debug, Deserialize)]
+struct Choice {
+    #[allow(dead_code)]
+    finish_reason: String,
+    #[allow(dead_code)]
+    index: u32,
+    message: ChatMessage,
+}
+
+/// One event of a streamed reply
+#[derive(Debug, Deserialize)]
+struct DeepSeekChunk {
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkChoice {
+    delta: ChunkDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkDelta {
+    content: Option<String>,
+}
+
+/// Provider for DeepSeek and other OpenAI compatible chat completion APIs
+pub struct DeepSeekProvider {
+    /// API endpoint for the DeepSeek service
+    api_endpoint: String,
+    /// API token for authentication
+    api_token: String,
+    /// Model to use for generating responses
+    model_name: String,
+    /// Timeout and streaming
+    options: RequestOptions,
+}
+
+impl DeepSeekProvider {
+    pub fn new(api_token: &str, model_name: &str) -> Self {
+        Self {
+            api_endpoint: DEFAULT_ENDPOINT.to_string(),
+            api_token: api_token.to_string(),
+            model_name: model_name.to_string(),
+            options: RequestOptions::default(),
+        }
+    }
+
+    /// Set the request timeout and whether replies are streamed
+    pub fn with_options(mut self, options: RequestOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Use another OpenAI compatible endpoint
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.api_endpoint = endpoint.to_string();
+        self
+    }
+}
+
+impl LlmProvider for DeepSeekProvider {
+    fn name(&self) -> &'static str {
+        "DeepSeek"
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    fn complete(
+        &self,
+        messages: &[ChatMessage],
+        params: &GenerationParams,
+        ctx: &RequestContext,
+    ) -> Result<Completion, LlmError> {
+        // Prepare request payload
+        let request = DeepSeekRequest {
+            messages: messages.iter().map(RequestMessage::from).collect(),
+            model: &self.model_name,
+            frequency_penalty: 0.0,
+            max_tokens: params.max_tokens,
+            presence_penalty: 0.0,
+            response_format: ResponseFormat {
+                format_type: if params.json_output {
+                    "json_object".to_string()
+                } else {
+                    "text".to_string()
+                },
+            },
+            stop: None,
+            stream: self.options.stream,
+            stream_options: self.options.stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+            temperature: params.temperature,
+            top_p: params.top_p,
+            tools: None,
+            tool_choice: "none".to_string(),
+            logprobs: false,
+            top_logprobs: None,
+        };
+
+        let json_payload = serde_json::to_string(&request)
+            .map_err(|e| LlmError::Fatal(anyhow::anyhow!("Failed to serialize request: {}", e)))?;
+
+        let authorization = format!("Bearer {}", self.api_token);
+        let headers = [("Authorization", authorization.as_str())];
+
+        if self.options.stream {
+            return post_sse(
+                &self.api_endpoint,
+                &headers,
+                &json_payload,
+                &self.options,
+                ctx,
+                parse_stream_event,
+            );
+        }
+
+        let response_str = post_json(
+            &self.api_endpoint,
+            &headers,
+            &json_payload,
+            &self.options,
+            ctx,
+        )?;
+
+        let api_response: DeepSeekResponse = parse_json_response(&response_str)?;
+
+        // Extract the assistant's response
+        match api_response.choices.into_iter().next() {
+            Some(choice) => Ok(Completion {
+                content: choice.message.content,
+                usage: Some(api_response.usage),
+            }),
+            None => {
+                let error_msg = "No response choices returned from API".to_string();
+                log::warn!("{}", error_msg);
+                Ok(Completion {
+                    content: error_msg,
+                    usage: Some(api_response.usage),
+                })
+            }
+        }
+    }
+}
+
+/// Decode one server-sent event, the stream ends with a literal "[DONE]"
+fn parse_stream_event(data: &str) -> Result<StreamDelta, LlmError> {
+    if data == "[DONE]" {
+        return Ok(StreamDelta {
+            done: true,
+            ..Default::default()
+        });
+    }
+
+    let chunk: DeepSeekChunk = parse_json_response(data)?;
+    Ok(StreamDelta {
+        text: chunk
+            .choices
+            .into_iter()
+            .filter_map(|choice| choice.delta.content)
+            .collect(),
+        usage: chunk.usage,
+        done: false,
+    })
+}
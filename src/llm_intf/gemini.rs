@@ -0,0 +1,350 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    parse_json_response, post_json, post_sse, ChatMessage, ChatRole, Completion, GenerationParams,
+    LlmError, LlmProvider, RequestContext, RequestOptions, StreamDelta, Usage,
+};
+
+pub const API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+pub const DEFAULT_MODEL: &str = "gemini-2.0-flash";
+/// Threshold applied to every harm category unless configured otherwise
+pub const DEFAULT_SAFETY_THRESHOLD: &str = "BLOCK_MEDIUM_AND_ABOVE";
+
+const HARM_CATEGORIES: [&str; 4] = [
+    "HARM_CATEGORY_HARASSMENT",
+    "HARM_CATEGORY_HATE_SPEECH",
+    "HARM_CATEGORY_SEXUALLY_EXPLICIT",
+    "HARM_CATEGORY_DANGEROUS_CONTENT",
+];
+
+/// Spoken instead of an error when Gemini refuses to answer
+const BLOCKED_REPLY: &str = "这个问题我不方便回答，我们聊点别的吧。";
+
+/// Request structure for the Gemini generateContent API
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<Content<'a>>,
+    contents: Vec<Content<'a>>,
+    generation_config: GenerationConfig,
+    safety_settings: Vec<SafetySetting<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Content<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'a str>,
+    parts: Vec<Part<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Part<'a> {
+    Text {
+        text: &'a str,
+    },
+    #[serde(rename_all = "camelCase")]
+    InlineData {
+        inline_data: Blob<'a>,
+    },
+}
+
+/// Picture sent inline with the request
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Blob<'a> {
+    mime_type: &'static str,
+    /// Base64 encoded bytes
+    data: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerationConfig {
+    max_output_tokens: u32,
+    temperature: f32,
+    top_p: f32,
+    /// "application/json" makes the model return a JSON object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct SafetySetting<'a> {
+    category: &'a str,
+    threshold: &'a str,
+}
+
+/// Response structure from the Gemini generateContent API
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiResponse {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    prompt_feedback: Option<PromptFeedback>,
+    usage_metadata: Option<UsageMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    content: Option<CandidateContent>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CandidateContent {
+    #[serde(default)]
+    parts: Vec<CandidatePart>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CandidatePart {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    block_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u32,
+    #[serde(default)]
+    candidates_token_count: u32,
+    #[serde(default)]
+    total_token_count: u32,
+}
+
+/// Provider for Google's Gemini models
+pub struct GeminiProvider {
+    api_base: String,
+    api_key: String,
+    model_name: String,
+    safety_threshold: String,
+    options: RequestOptions,
+}
+
+impl GeminiProvider {
+    pub fn new(api_key: &str, model_name: &str) -> Self {
+        Self {
+            api_base: API_BASE.to_string(),
+            api_key: api_key.to_string(),
+            model_name: model_name.to_string(),
+            safety_threshold: DEFAULT_SAFETY_THRESHOLD.to_string(),
+            options: RequestOptions::default(),
+        }
+    }
+
+    /// Set the request timeout and whether replies are streamed
+    pub fn with_options(mut self, options: RequestOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Use another API base URL, the model and method are appended to it
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.api_base = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Threshold such as "BLOCK_ONLY_HIGH" applied to all harm categories
+    pub fn with_safety_threshold(mut self, threshold: &str) -> Self {
+        self.safety_threshold = threshold.to_string();
+        self
+    }
+
+    /// Gemini calls the assistant role "model"
+    fn gemini_role(role: ChatRole) -> &'static str {
+        match role {
+            ChatRole::Assistant => "model",
+            ChatRole::User | ChatRole::System => "user",
+        }
+    }
+
+    /// Parts of a message, the picture goes before the text about it
+    fn parts(message: &ChatMessage) -> Vec<Part<'_>> {
+        let image = message.image.as_ref().map(|image| Part::InlineData {
+            inline_data: Blob {
+                mime_type: image.media_type,
+                data: &image.data,
+            },
+        });
+        image
+            .into_iter()
+            .chain(std::iter::once(Part::Text {
+                text: &message.content,
+            }))
+            .collect()
+    }
+}
+
+impl LlmProvider for GeminiProvider {
+    fn name(&self) -> &'static str {
+        "Gemini"
+    }
+
+    fn model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    fn complete(
+        &self,
+        messages: &[ChatMessage],
+        params: &GenerationParams,
+        ctx: &RequestContext,
+    ) -> Result<Completion, LlmError> {
+        let system_parts: Vec<Part> = messages
+            .iter()
+            .filter(|msg| msg.role == ChatRole::System)
+            .map(|msg| Part::Text { text: &msg.content })
+            .collect();
+
+        let request = GeminiRequest {
+            system_instruction: if system_parts.is_empty() {
+                None
+            } else {
+                Some(Content {
+                    role: None,
+                    parts: system_parts,
+                })
+            },
+            contents: messages
+                .iter()
+                .filter(|msg| msg.role != ChatRole::System)
+                .map(|msg| Content {
+                    role: Some(Self::gemini_role(msg.role)),
+                    parts: Self::parts(msg),
+                })
+                .collect(),
+            generation_config: GenerationConfig {
+                max_output_tokens: params.max_tokens,
+                temperature: params.temperature,
+                top_p: params.top_p,
+                response_mime_type: params.json_output.then_some("application/json"),
+            },
+            safety_settings: HARM_CATEGORIES
+                .iter()
+                .map(|&category| SafetySetting {
+                    category,
+                    threshold: &self.safety_threshold,
+                })
+                .collect(),
+        };
+
+        let json_payload = serde_json::to_string(&request)
+            .map_err(|e| LlmError::Fatal(anyhow::anyhow!("Failed to serialize request: {}", e)))?;
+
+        let headers = [("x-goog-api-key", self.api_key.as_str())];
+
+        if self.options.stream {
+            let url = format!(
+                "{}/{}:streamGenerateContent?alt=sse",
+                self.api_base, self.model_name
+            );
+
+            // Every event is a partial GenerateContentResponse
+            let mut blocked = false;
+            let completion = post_sse(&url, &headers, &json_payload, &self.options, ctx, |data| {
+                let chunk = decode_response(parse_json_response(data)?);
+                blocked |= chunk.blocked;
+                Ok(StreamDelta {
+                    text: chunk.text,
+                    usage: chunk.usage,
+                    done: chunk.finished,
+                })
+            })?;
+
+            return Ok(if blocked {
+                Completion {
+                    content: BLOCKED_REPLY.to_string(),
+                    usage: completion.usage,
+                }
+            } else {
+                completion
+            });
+        }
+
+        let url = format!("{}/{}:generateContent", self.api_base, self.model_name);
+        let response_str = post_json(&url, &headers, &json_payload, &self.options, ctx)?;
+
+        let api_response: GeminiResponse = parse_json_response(&response_str)?;
+        let has_candidates = !api_response.candidates.is_empty();
+
+        let reply = decode_response(api_response);
+        let content = if reply.blocked {
+            BLOCKED_REPLY.to_string()
+        } else if has_candidates {
+            reply.text
+        } else {
+            return Err(LlmError::Fatal(anyhow::anyhow!(
+                "No candidates returned from API"
+            )));
+        };
+
+        Ok(Completion {
+            content,
+            usage: reply.usage,
+        })
+    }
+}
+
+/// Text, usage and safety verdict of a response or of one streamed chunk
+struct DecodedResponse {
+    text: String,
+    usage: Option<Usage>,
+    blocked: bool,
+    finished: bool,
+}
+
+fn decode_response(response: GeminiResponse) -> DecodedResponse {
+    let usage = response.usage_metadata.map(|usage| Usage {
+        prompt_tokens: usage.prompt_token_count,
+        completion_tokens: usage.candidates_token_count,
+        total_tokens: usage.total_token_count,
+    });
+
+    // A blocked prompt comes back without candidates
+    if let Some(reason) = response
+        .prompt_feedback
+        .and_then(|feedback| feedback.block_reason)
+    {
+        log::warn!("Gemini blocked the prompt: {}", reason);
+        return DecodedResponse {
+            text: String::new(),
+            usage,
+            blocked: true,
+            finished: true,
+        };
+    }
+
+    let Some(candidate) = response.candidates.into_iter().next() else {
+        return DecodedResponse {
+            text: String::new(),
+            usage,
+            blocked: false,
+            finished: false,
+        };
+    };
+
+    let blocked = candidate.finish_reason.as_deref() == Some("SAFETY");
+    if blocked {
+        log::warn!("Gemini stopped the reply for safety reasons");
+    }
+
+    DecodedResponse {
+        text: candidate
+            .content
+            .map(|content| content.parts.into_iter().map(|part| part.text).collect())
+            .unwrap_or_default(),
+        usage,
+        blocked,
+        finished: candidate.finish_reason.is_some(),
+    }
+}
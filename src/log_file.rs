@@ -0,0 +1,348 @@
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::sys;
+use log::{Level, Log, Metadata, Record};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::config::LogConfig;
+use crate::crash_report;
+use crate::remote_log;
+use crate::sd_card;
+
+const LOG_DIR: &str = "/vfat/logs";
+/// Lines logged before the card is mounted are kept in RAM up to this size
+const MAX_EARLY_BYTES: usize = 16 * 1024;
+/// Lines are collected before writing, warnings and errors are written right away
+const WRITE_BUFFER_BYTES: usize = 4096;
+/// How long to wait before opening the file again after a write failed
+const REOPEN_INTERVAL_MS: i64 = 10_000;
+
+static ESP_LOGGER: EspLogger = EspLogger::new();
+static LOGGER: FileLogger = FileLogger;
+static SINK: Mutex<Sink> = Mutex::new(Sink::Buffering(Vec::new()));
+
+thread_local! {
+    /// Set while a line is written, so logging done by the file code itself stays on the console
+    static IN_LOG: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Install the logger, before anything else logs
+///
+/// Everything goes to the serial console as before, and is mirrored to the SD card once
+/// `start_file_log` is called and to the network once `remote_log::start` is. A panic hook
+/// marks the log before the device restarts.
+pub fn init_logging() {
+    if log::set_logger(&LOGGER).is_ok() {
+        ESP_LOGGER.initialize();
+    }
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // The panicking thread may hold the lock, then only the console gets the message
+        let _ = IN_LOG.try_with(|in_log| {
+            if in_log.replace(true) {
+                return;
+            }
+            if let Ok(mut sink) = SINK.try_lock() {
+                let line = format!("[{:>10}] PANIC {}\n", uptime_ms(), info);
+                sink.write(line.as_bytes(), true);
+            }
+            in_log.set(false);
+        });
+        default_hook(info);
+    }));
+}
+
+/// Open /vfat/logs/boot-N.log for this boot and write out the lines logged so far
+pub fn start_file_log(config: &LogConfig) {
+    if !config.enabled {
+        with_sink(|sink| *sink = Sink::Disabled);
+        return;
+    }
+
+    if let Err(e) = std::fs::create_dir_all(LOG_DIR) {
+        with_sink(|sink| *sink = Sink::Disabled);
+        log::warn!("Failed to create {}: {}", LOG_DIR, e);
+        return;
+    }
+
+    let previous_boot = newest_boot_number();
+    let boot = previous_boot.map_or(1, |n| n + 1);
+    remove_old_logs(boot, config.keep_boots.max(1));
+
+    let crash = crash_report::reset_reason();
+    if let (Some(reason), Some(previous)) = (crash, previous_boot) {
+        // Mark the log that ended abruptly, the panic message may already be in it
+        append_line(
+            &log_path(previous),
+            &format!("=== device reset: {} ===\n", reason),
+        );
+    }
+
+    let path = log_path(boot);
+    let mut file = LogFile {
+        path: path.clone(),
+        writer: None,
+        written: 0,
+        max_bytes: config.max_file_kb * 1024,
+        retry_at_ms: 0,
+    };
+    let header = match crash {
+        Some(reason) => format!("=== boot {}, previous boot crashed: {} ===\n", boot, reason),
+        None => format!("=== boot {} ===\n", boot),
+    };
+    file.write(header.as_bytes(), false);
+
+    with_sink(|sink| {
+        if let Sink::Buffering(early) = std::mem::replace(sink, Sink::Disabled) {
+            file.write(&early, true);
+        }
+        *sink = Sink::Writing(file);
+    });
+
+    log::info!("Logging to {}", path);
+}
+
+/// Write out buffered lines and close the file, used before the card is unmounted
+pub fn close() {
+    with_sink(|sink| {
+        if let Sink::Writing(file) = sink {
+            file.write(b"=== clean shutdown ===\n", true);
+        }
+        *sink = Sink::Disabled;
+    });
+}
+
+struct FileLogger;
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        ESP_LOGGER.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        ESP_LOGGER.log(record);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format!(
+            "[{:>10}] {:<5} {}: {}\n",
+            uptime_ms(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        with_sink(|sink| sink.write(line.as_bytes(), record.level() <= Level::Warn));
+        remote_log::forward(record.level(), &line);
+    }
+
+    fn flush(&self) {
+        with_sink(|sink| {
+            if let Sink::Writing(file) = sink {
+                file.flush();
+            }
+        });
+    }
+}
+
+/// Where log lines go at the moment
+enum Sink {
+    /// The card isn't mounted yet
+    Buffering(Vec<u8>),
+    Writing(LogFile),
+    /// Logging to the card is turned off or the file was closed
+    Disabled,
+}
+
+impl Sink {
+    fn write(&mut self, line: &[u8], flush: bool) {
+        match self {
+            Sink::Buffering(early) => {
+                if early.len() + line.len() <= MAX_EARLY_BYTES {
+                    early.extend_from_slice(line);
+                }
+            }
+            Sink::Writing(file) => file.write(line, flush),
+            Sink::Disabled => {}
+        }
+    }
+}
+
+/// The log of this boot, rotated to boot-N.1.log when it grows too large
+struct LogFile {
+    path: String,
+    /// None until opened and after a failed write
+    writer: Option<BufWriter<File>>,
+    written: u64,
+    max_bytes: u64,
+    retry_at_ms: i64,
+}
+
+impl LogFile {
+    fn write(&mut self, line: &[u8], flush: bool) {
+        if self.written + line.len() as u64 > self.max_bytes {
+            self.rotate();
+        }
+        if self.writer.is_none() && !self.open() {
+            return;
+        }
+
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        let result =
+            writer
+                .write_all(line)
+                .and_then(|_| if flush { writer.flush() } else { Ok(()) });
+
+        match result {
+            Ok(_) => self.written += line.len() as u64,
+            Err(e) => self.failed("write log file", &e),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(e) = writer.flush() {
+                self.failed("flush log file", &e);
+            }
+        }
+    }
+
+    fn open(&mut self) -> bool {
+        if uptime_ms() < self.retry_at_ms || !sd_card::is_available() {
+            return false;
+        }
+
+        let opened = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path);
+        match opened {
+            Ok(file) => {
+                self.written = file.metadata().map(|m| m.len()).unwrap_or(0);
+                self.writer = Some(BufWriter::with_capacity(WRITE_BUFFER_BYTES, file));
+                true
+            }
+            Err(e) => {
+                self.failed("open log file", &e);
+                false
+            }
+        }
+    }
+
+    /// Keep the previous part as boot-N.1.log, so a boot never takes more than twice the limit
+    fn rotate(&mut self) {
+        self.flush();
+        self.writer = None;
+
+        let rotated = self.path.replace(".log", ".1.log");
+        // FAT can't rename over an existing file
+        let _ = std::fs::remove_file(&rotated);
+        if let Err(e) = std::fs::rename(&self.path, &rotated) {
+            // Start over in the same file rather than letting it grow without bound
+            let _ = std::fs::remove_file(&self.path);
+            log::warn!("Failed to rotate {}: {}", self.path, e);
+        }
+        self.written = 0;
+    }
+
+    fn failed(&mut self, context: &str, error: &std::io::Error) {
+        self.writer = None;
+        self.retry_at_ms = uptime_ms() + REOPEN_INTERVAL_MS;
+        sd_card::report_io_error(context, error);
+    }
+}
+
+/// Run `f` on the sink, unless this thread is already writing a line
+///
+/// Whatever the file code logs, e.g. a failed write, then only reaches the console
+/// instead of deadlocking on the sink.
+fn with_sink<R>(f: impl FnOnce(&mut Sink) -> R) -> Option<R> {
+    IN_LOG.with(|in_log| {
+        if in_log.replace(true) {
+            return None;
+        }
+
+        // A panic while writing leaves the sink usable, at worst with a partial line
+        let mut sink: MutexGuard<'_, Sink> = SINK.lock().unwrap_or_else(|e| e.into_inner());
+        let result = f(&mut sink);
+        drop(sink);
+
+        in_log.set(false);
+        Some(result)
+    })
+}
+
+fn uptime_ms() -> i64 {
+    (unsafe { sys::esp_timer_get_time() }) / 1000
+}
+
+fn log_path(boot: u32) -> String {
+    format!("{}/boot-{}.log", LOG_DIR, boot)
+}
+
+/// Boot number of boot-N.log and its rotated part boot-N.1.log
+fn boot_number(file_name: &str) -> Option<u32> {
+    let rest = file_name.strip_prefix("boot-")?;
+    let number = rest
+        .strip_suffix(".1.log")
+        .or_else(|| rest.strip_suffix(".log"))?;
+    number.parse().ok()
+}
+
+fn boot_numbers() -> Vec<(u32, String)> {
+    let Ok(entries) = std::fs::read_dir(LOG_DIR) else {
+        return Vec::new();
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            boot_number(&name).map(|boot| (boot, name))
+        })
+        .collect()
+}
+
+fn newest_boot_number() -> Option<u32> {
+    boot_numbers().into_iter().map(|(boot, _)| boot).max()
+}
+
+/// Delete the logs of all but the last `keep` boots, counting the one starting now
+fn remove_old_logs(boot: u32, keep: u32) {
+    for (number, name) in boot_numbers() {
+        if number + keep <= boot {
+            let path = format!("{}/{}", LOG_DIR, name);
+            if let Err(e) = std::fs::remove_file(&path) {
+                log::warn!("Failed to remove old log {}: {}", path, e);
+            }
+        }
+    }
+}
+
+fn append_line(path: &str, line: &str) {
+    let written = std::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        log::warn!("Failed to mark {}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_number() {
+        assert_eq!(boot_number("boot-7.log"), Some(7));
+        assert_eq!(boot_number("boot-12.1.log"), Some(12));
+        assert_eq!(boot_number("boot-.log"), None);
+        assert_eq!(boot_number("audio1.wav"), None);
+    }
+}
@@ -1,34 +1,104 @@
 use anyhow;
 use esp_idf_svc::hal::{
+    gpio::AnyIOPin,
     peripherals::Peripherals,
 };
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sntp::EspSntp;
 use esp_idf_svc::sys;
 use std::time::Instant;
 
+mod alarms;
+mod answer_cache;
+mod audio_codec;
 mod audio_device;
 mod audio_processing;
+mod buttons;
+mod camera;
+mod cloud_tts;
+mod config;
+mod connectivity;
+mod console;
+mod content_filter;
+mod crash_report;
+mod dashboard;
+mod diagnostics;
+mod display;
+mod earcon;
+mod encoder;
+mod factory_reset;
+mod facts;
 mod http_client;
+mod intent;
+mod knowledge;
+mod known_networks;
+mod language;
+mod led;
 mod llm_intf;
+mod log_file;
+mod mdns;
+mod memory;
+mod metrics;
+mod mqtt;
+mod music;
+mod notes;
+mod offline_commands;
+mod ota;
+mod playback;
+mod power;
+mod provisioning;
+mod radio;
+mod recordings;
+mod remote_log;
+mod satellite;
 mod sd_card;
+mod self_test;
+mod session;
+mod settings;
+mod smart_home;
+mod speakers;
 mod speech_recognition;
+mod stacks;
+mod story;
+mod stt;
+mod supervisor;
+mod transcript_log;
 mod transcription;
+mod translation;
 mod tts;
+mod upload_queue;
+mod usage;
+mod voice_commands;
+mod watchdog;
 mod wifi;
 
 use audio_device::{configure_max98357_pins, init_i2s_tx};
 use audio_processing::{create_feed_task, create_fetch_task};
+use config::{ConfigStore, PowerSaveMode};
+use known_networks::KnownNetworks;
+use settings::Settings;
 use speech_recognition::init_speech_recognition;
-use transcription::start_transcription_worker;
-use wifi::initialize_wifi;
+use stt::start_streaming_transcriber;
+use transcription::{start_transcription_worker, TranscriptionMessage};
+use wifi::{initialize_wifi, start_supervisor};
 
-fn main() -> anyhow::Result<()> {
+fn main() {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     sys::link_patches();
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Bind the log crate to the ESP Logging facilities, mirrored to the SD card once it is mounted
+    log_file::init_logging();
 
+    // Returning from main would leave a device that neither listens nor says why
+    if let Err(e) = run() {
+        self_test::boot_failed(&e);
+    }
+}
+
+/// Bring up every part of the device, only returns when one it can't do without failed
+fn run() -> anyhow::Result<()> {
     log::info!("Starting AI Chatbox application");
 
     // Create a performance timer to measure initialization time
@@ -43,49 +113,149 @@ fn main() -> anyhow::Result<()> {
         }
     };
 
-    // Connect to Wi-Fi and store the wifi object to maintain ownership throughout the program's lifetime
-    let _wifi = match initialize_wifi(peripherals.modem) {
-        Ok(wifi) => {
-            log::info!("WiFi connected successfully");
-            wifi
+    // The default NVS partition can only be taken once, share clones of it with every user
+    let nvs_partition = EspDefaultNvsPartition::take()?;
+    // A factory reset asked for on the last boot is done before anything reads the settings
+    let factory_reset = factory_reset::reset_nvs_if_requested(&nvs_partition);
+
+    // Keep the message of a panic for the next boot, the log file may not get it
+    crash_report::install(nvs_partition.clone());
+
+    // Mount SD card with proper error handling
+    let mut sd = sd_card::SdCard::new("/vfat");
+    match sd.mount_spi() {
+        // Remounts the card when it glitches, instead of failing every file operation until reboot
+        Ok(()) => sd_card::start_recovery(sd)?,
+        Err(e) => {
+            // Boards without an SD slot keep the configuration in internal flash and record into RAM
+            log::error!("Failed to mount SD card, using internal flash instead: {}", e);
+            if let Err(e) = sd_card::mount_internal_flash("/vfat") {
+                log::error!("Failed to mount internal flash: {}", e);
+                return Err(anyhow::anyhow!("No storage available: {}", e));
+            }
         }
+    }
+
+    if factory_reset {
+        factory_reset::delete_user_data();
+    }
+
+    // Models and voice data of an update are written before anything maps their partitions
+    ota::install_staged_assets();
+
+    // The configuration lives on the SD card, so it can only be loaded after mounting
+    let mut config_store = ConfigStore::new(nvs_partition.clone());
+    let settings = Settings::new(nvs_partition.clone())?;
+
+    // WiFi, pins and the fetch task are set up once, so these settings only apply at boot
+    let boot_config = config_store.load();
+    let streaming_config = boot_config.stt.streaming.clone();
+    let pins = boot_config.pins.clone();
+    log_file::start_file_log(&boot_config.log);
+    let crash = crash_report::check_previous_boot();
+    if let Err(e) = watchdog::init(&boot_config.watchdog) {
+        log::warn!("Failed to set up the task watchdog: {}", e);
+    }
+    // Restarts the pipeline tasks when they fail, and the device when they keep failing or hang
+    if let Err(e) = supervisor::start(&boot_config.supervisor) {
+        log::warn!("Failed to start the task supervisor: {}", e);
+    }
+    if let Err(e) = memory::start(&boot_config.memory) {
+        log::warn!("Failed to start the memory sampler: {}", e);
+    }
+    if let Err(e) = metrics::start(&boot_config.metrics) {
+        log::warn!("Failed to start the metrics summary: {}", e);
+    }
+
+    // The LED and the display show what's going on from the start, including while WiFi connects
+    if let Err(e) = led::start(&boot_config.led, peripherals.rmt.channel0) {
+        log::warn!("Failed to start the status LED: {}", e);
+    }
+    if let Err(e) = display::start(&boot_config.display, peripherals.i2c0, peripherals.spi3) {
+        log::warn!("Failed to start the display: {}", e);
+    }
+    if let Err(e) = camera::start(
+        &boot_config.camera,
+        peripherals.i2c1,
+        peripherals.ledc.timer0,
+        peripherals.ledc.channel0,
+    ) {
+        log::warn!("Failed to start the camera: {}", e);
+    }
+
+    // Connect to Wi-Fi, the supervisor takes ownership of the wifi object once the worker runs
+    let sys_loop = EspSystemEventLoop::take()?;
+    let wifi = match initialize_wifi(
+        peripherals.modem,
+        sys_loop.clone(),
+        nvs_partition.clone(),
+        &boot_config.wifi,
+        &boot_config.network,
+    ) {
+        Ok(wifi) => Some(wifi),
         Err(e) => {
-            log::error!("Failed to connect to WiFi: {}", e);
-            return Err(e);
+            // Keep going, the offline voice commands still work without network
+            log::error!("Failed to start WiFi, staying offline: {}", e);
+            None
         }
     };
+    // Bluetooth is only used for provisioning, which is over for this boot
+    provisioning::release_bluetooth();
+
+    // Headless devices are debugged through a log collector on the network
+    let network_started = wifi.is_some();
+    if network_started {
+        if let Err(e) = remote_log::start(&boot_config.log.remote, &boot_config.network.hostname) {
+            log::warn!("Failed to start remote logging: {}", e);
+        }
+    }
+
+    // Let companion apps find the device without knowing its address
+    if network_started {
+        if let Err(e) = mdns::start(&boot_config.network.hostname, &boot_config.capabilities()) {
+            log::warn!("Failed to start mDNS: {}", e);
+        }
+    }
+
+    // Alarms and the transcripts go by the wall clock, which is kept in sync from here on; the
+    // supervisor restarts it on every reconnect, a failed poll is only retried after an hour
+    let _sntp = if network_started {
+        match EspSntp::new_default() {
+            Ok(sntp) => Some(sntp),
+            Err(e) => {
+                log::warn!("Failed to start SNTP: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
 
     // Configure MAX98357 control pins first
-    let sd_pin_driver = configure_max98357_pins(peripherals.pins.gpio5)?;
+    let sd_pin_driver = configure_max98357_pins(gpio(pins.speaker_enable))?;
 
     // Initialize I2S TX driver for audio output
     let i2s_tx_driver = init_i2s_tx(
         peripherals.i2s1,
-        peripherals.pins.gpio2,
-        peripherals.pins.gpio3,
-        peripherals.pins.gpio1,
+        gpio(pins.speaker_bclk),
+        gpio(pins.speaker_dout),
+        gpio(pins.speaker_ws),
     )?;
 
     log::info!("I2S TX channel configured for audio output");
 
     // Test the LLM helper
-    /*match test_llm_helper() {
+    /*match test_llm_helper(boot_config.llm.primary.api_key.as_deref().unwrap_or(config::BUILT_IN_API_KEY)) {
         Ok(_) => log::info!("LLM test completed successfully"),
         Err(e) => log::error!("LLM test failed: {}", e),
     }*/
 
-    // Mount SD card with proper error handling
-    let mut sd = sd_card::SdCard::new("/vfat");
-    if let Err(e) = sd.mount_spi() {
-        log::error!("Failed to mount SD card: {}", e);
-        return Err(anyhow::anyhow!("Failed to mount SD card: {}", e));
-    }
-
     // Initialize speech recognition system
-    let (afe_handle, afe_data, multinet, model_data) = init_speech_recognition()?;
+    let (afe_handle, afe_data, multinet, model_data) =
+        init_speech_recognition(&boot_config.speech_models, &boot_config.smart_home)?;
 
     // Start the transcription worker thread
-    let (transcription_tx, transcription_response_rx) = match start_transcription_worker(i2s_tx_driver, sd_pin_driver) {
+    let (transcription_tx, transcription_event_rx) = match start_transcription_worker(i2s_tx_driver, sd_pin_driver, config_store, settings) {
         Ok((tx, rx)) => (tx, rx),
         Err(e) => {
             log::error!("Failed to start transcription worker: {}", e);
@@ -97,13 +267,157 @@ fn main() -> anyhow::Result<()> {
     };
     log::info!("Transcription worker started successfully");
 
+    // Pending timers and alarms are loaded from NVS and ring through the worker
+    if let Err(e) = alarms::start(
+        &boot_config.alarms,
+        nvs_partition.clone(),
+        transcription_tx.clone(),
+    ) {
+        log::warn!("Failed to start the alarms: {}", e);
+    }
+
+    // Say so after a crash, the details are in the log and the status
+    if let Some(crash) = crash {
+        let _ = transcription_tx.send(TranscriptionMessage::Speak {
+            text: crash.announcement(),
+        });
+    }
+
+    // As a Home Assistant satellite the utterances go to its voice pipeline instead
+    let audio_stream_tx = if network_started && boot_config.satellite.enabled {
+        match satellite::start(
+            &boot_config.satellite,
+            &boot_config.network.hostname,
+            transcription_tx.clone(),
+        ) {
+            Ok(tx) => {
+                if let Err(e) = mdns::advertise_wyoming(boot_config.satellite.port) {
+                    log::warn!("Failed to advertise the Wyoming satellite: {}", e);
+                }
+                Some(tx)
+            }
+            Err(e) => {
+                log::warn!("Failed to start the Wyoming satellite: {}", e);
+                None
+            }
+        }
+    } else {
+        match streaming_config {
+            Some(streaming_config) => Some(start_streaming_transcriber(
+                streaming_config,
+                transcription_tx.clone(),
+            )?),
+            None => None,
+        }
+    };
+
+    // Keep room on the card for new recordings, recordings stay in RAM without a card
+    if !sd_card::is_internal_flash() {
+        let storage_tx = transcription_tx.clone();
+        sd_card::start_space_monitor("/vfat", boot_config.storage, move |free_bytes| {
+            let _ = storage_tx.send(TranscriptionMessage::StorageLow { free_bytes });
+        })?;
+    }
+
+    // Reconnect when WiFi drops, and pause the network services meanwhile
+    if let Some(wifi) = wifi {
+        let network_tx = transcription_tx.clone();
+        start_supervisor(
+            wifi,
+            sys_loop,
+            boot_config.wifi.clone(),
+            boot_config.network.clone(),
+            nvs_partition.clone(),
+            move |connected| {
+                if connected && !unsafe { sys::esp_sntp_restart() } {
+                    log::warn!("Failed to restart SNTP");
+                }
+                let _ = network_tx.send(TranscriptionMessage::NetworkChanged { connected });
+            },
+        )?;
+    }
+
+    // Accept runtime adjustments from the serial console
+    console::start_console(
+        transcription_tx.clone(),
+        KnownNetworks::new(nvs_partition.clone()),
+        Settings::new(nvs_partition.clone())?,
+    )?;
+
+    // Buttons work without network, like the offline voice commands
+    if let Err(e) = buttons::start(
+        &boot_config.buttons,
+        &boot_config.encoder,
+        transcription_tx.clone(),
+    ) {
+        log::warn!("Failed to start the buttons: {}", e);
+    }
+    if let Err(e) = encoder::start(&boot_config.encoder, peripherals.pcnt0, transcription_tx.clone()) {
+        log::warn!("Failed to start the rotary encoder: {}", e);
+    }
+
+    // Status page and control API for browsers and companion apps on the local network
+    let _dashboard = if network_started && boot_config.web.enabled {
+        match dashboard::start(&boot_config.web, transcription_tx.clone()) {
+            Ok(server) => {
+                if let Err(e) = mdns::advertise_service(boot_config.web.port) {
+                    log::warn!("Failed to advertise the web dashboard: {}", e);
+                }
+                Some(server)
+            }
+            Err(e) => {
+                log::warn!("Failed to start the web dashboard: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Telemetry and remote control for home automation, if a broker is configured
+    if network_started {
+        if let Err(e) = mqtt::start(
+            &boot_config.mqtt,
+            &boot_config.network.hostname,
+            transcription_tx.clone(),
+        ) {
+            log::warn!("Failed to start MQTT: {}", e);
+        }
+    }
+
+    // Look for new firmware in the background, if an update server is configured
+    if let Err(e) = ota::start_scheduled_checks(
+        boot_config.ota.clone(),
+        boot_config.speech_models.clone(),
+        transcription_tx.clone(),
+    ) {
+        log::warn!("Failed to start firmware update checks: {}", e);
+    }
+
+    // Idling lets the modem sleep as much as it can, the microphone is read for that from the start
+    let wifi_power_save = network_started.then_some(boot_config.wifi.power_save);
+    if let Err(e) = power::start(&boot_config.power, wifi_power_save) {
+        log::warn!("Failed to start idle power management: {}", e);
+    }
+    let feed_power_save = if boot_config.power.idle_minutes > 0 {
+        PowerSaveMode::Max
+    } else {
+        boot_config.wifi.power_save
+    };
+
+    // Says what is broken once everything runs, the microphone check waits for the feed task
+    if let Err(e) = self_test::start(&boot_config, transcription_tx.clone()) {
+        log::warn!("Failed to start the self-test: {}", e);
+    }
+
     // Create the feed task
     let _feed_task = create_feed_task(
         afe_handle,
         afe_data,
         peripherals.i2s0,
-        peripherals.pins.gpio42,
-        peripherals.pins.gpio41,
+        gpio(pins.mic_clk),
+        gpio(pins.mic_data),
+        feed_power_save,
     )?;
 
     // Create the fetch task
@@ -113,9 +427,13 @@ fn main() -> anyhow::Result<()> {
         multinet,
         model_data,
         transcription_tx,
-        transcription_response_rx,
+        transcription_event_rx,
+        audio_stream_tx,
     )?;
 
+    // Everything came up, so an update that got this far is kept
+    ota::mark_running_valid();
+
     // Log initialization time
     log::info!(
         "AI Chatbox initialization completed in {} ms",
@@ -129,3 +447,9 @@ fn main() -> anyhow::Result<()> {
         std::thread::sleep(std::time::Duration::from_secs(1));
     }
 }
+
+/// Pin taken by its number from the configuration
+fn gpio(pin: u8) -> AnyIOPin {
+    // Checked by `AppConfig::validate`, and the numbered pins of `Peripherals` are never used
+    unsafe { AnyIOPin::new(pin as i32) }
+}
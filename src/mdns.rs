@@ -0,0 +1,70 @@
+use esp_idf_svc::mdns::EspMdns;
+use std::sync::Mutex;
+
+/// Service companion apps browse for
+const SERVICE_TYPE: &str = "_ai-chatbox";
+const SERVICE_PROTO: &str = "_tcp";
+/// Service Home Assistant discovers Wyoming satellites by
+const WYOMING_SERVICE_TYPE: &str = "_wyoming";
+
+/// Kept alive for the lifetime of the program, dropping it stops the responder
+static MDNS: Mutex<Option<Responder>> = Mutex::new(None);
+
+struct Responder {
+    mdns: EspMdns,
+    /// TXT record values of the advertised services
+    txt: Vec<(String, String)>,
+}
+
+/// Answer queries for `<hostname>.local` on the local network
+///
+/// `capabilities` end up in the TXT record of the services advertised later, so apps can
+/// tell what this device supports before connecting.
+pub fn start(hostname: &str, capabilities: &[&str]) -> anyhow::Result<()> {
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name("AI Chatbox")?;
+
+    let txt = vec![
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("capabilities".to_string(), capabilities.join(",")),
+    ];
+    *MDNS.lock().unwrap() = Some(Responder { mdns, txt });
+
+    log::info!("Reachable as {}.local", hostname);
+    Ok(())
+}
+
+/// Advertise a control server listening on `port` as `_ai-chatbox._tcp`
+pub fn advertise_service(port: u16) -> anyhow::Result<()> {
+    add_service(SERVICE_TYPE, port)
+}
+
+/// Advertise the Wyoming satellite listening on `port` as `_wyoming._tcp`
+pub fn advertise_wyoming(port: u16) -> anyhow::Result<()> {
+    add_service(WYOMING_SERVICE_TYPE, port)
+}
+
+fn add_service(service_type: &str, port: u16) -> anyhow::Result<()> {
+    let mut responder = MDNS.lock().unwrap();
+    let Some(responder) = responder.as_mut() else {
+        return Err(anyhow::anyhow!("mDNS responder is not running"));
+    };
+
+    let txt: Vec<(&str, &str)> = responder
+        .txt
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    responder
+        .mdns
+        .add_service(None, service_type, SERVICE_PROTO, port, &txt)?;
+
+    log::info!(
+        "Advertising {}.{} on port {}",
+        service_type,
+        SERVICE_PROTO,
+        port
+    );
+    Ok(())
+}
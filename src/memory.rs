@@ -0,0 +1,158 @@
+use esp_idf_svc::sys;
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::MemoryConfig;
+use crate::dashboard::{self, LiveEvent};
+use crate::metrics::{self, LogMetricsSink, MemoryMetrics, MetricsSink};
+use crate::mqtt::MqttMetricsSink;
+use crate::stacks;
+
+/// Samples averaged for the alarm, so a single allocation peak doesn't set it off
+const ALARM_WINDOW: usize = 5;
+/// Free memory has to climb this far above the floor before the alarm can go off again
+const ALARM_HYSTERESIS: u32 = 8 * 1024;
+
+/// Watches free internal RAM averaged over the last samples
+struct LowMemoryAlarm {
+    floor: u32,
+    recent: VecDeque<u32>,
+    raised: bool,
+}
+
+impl LowMemoryAlarm {
+    fn new(floor: u32) -> Self {
+        Self {
+            floor,
+            recent: VecDeque::with_capacity(ALARM_WINDOW),
+            raised: false,
+        }
+    }
+
+    /// Add a sample, returns the average when it just fell below the floor
+    fn update(&mut self, free_internal: u32) -> Option<u32> {
+        if self.recent.len() == ALARM_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(free_internal);
+        let average = (self.recent.iter().map(|&free| free as u64).sum::<u64>()
+            / self.recent.len() as u64) as u32;
+
+        if self.raised {
+            self.raised = average < self.floor + ALARM_HYSTERESIS;
+            None
+        } else if average < self.floor {
+            self.raised = true;
+            Some(average)
+        } else {
+            None
+        }
+    }
+}
+
+/// Sample free memory every `config.interval_secs`, does nothing when that is 0
+///
+/// Each sample is logged with the change since the last one and published as metrics. A live
+/// event warns when free internal RAM trends below `config.floor_kb`. The stack use of the
+/// registered threads is published along, and logged whenever one of them reaches a new peak.
+pub fn start(config: &MemoryConfig) -> anyhow::Result<()> {
+    if config.interval_secs == 0 {
+        return Ok(());
+    }
+
+    let interval = Duration::from_secs(config.interval_secs as u64);
+    let floor = config.floor_kb * 1024;
+    thread::Builder::new()
+        .name("memory".to_string())
+        .stack_size(3 * 1024)
+        .spawn(move || monitor_loop(interval, floor))?;
+
+    Ok(())
+}
+
+fn monitor_loop(interval: Duration, floor: u32) {
+    let mut alarm = LowMemoryAlarm::new(floor);
+    let mut last = sample();
+    let mut last_stacks = Vec::new();
+
+    loop {
+        thread::sleep(interval);
+
+        let metrics = sample();
+        log::info!(
+            "Memory: {} KB free ({:+} KB), internal {} KB ({:+} KB), largest block {} KB, PSRAM {} of {} KB free ({:+} KB), lowest {} KB",
+            metrics.free_heap / 1024,
+            delta_kb(metrics.free_heap, last.free_heap),
+            metrics.free_internal / 1024,
+            delta_kb(metrics.free_internal, last.free_internal),
+            metrics.largest_free_block / 1024,
+            metrics.psram_free / 1024,
+            metrics.psram_total / 1024,
+            delta_kb(metrics.psram_free, last.psram_free),
+            metrics.min_free_heap / 1024
+        );
+        MqttMetricsSink.record_memory(&metrics);
+        metrics::set_gauge("free_internal_bytes", metrics.free_internal as i64);
+        metrics::set_gauge("psram_free_bytes", metrics.psram_free as i64);
+
+        let stacks = stacks::stack_metrics();
+        MqttMetricsSink.record_stacks(&stacks);
+        if stacks != last_stacks {
+            LogMetricsSink.record_stacks(&stacks);
+            last_stacks = stacks;
+        }
+
+        if let Some(average) = alarm.update(metrics.free_internal) {
+            log::warn!(
+                "Free internal RAM is down to {} KB on average, below the floor of {} KB",
+                average / 1024,
+                floor / 1024
+            );
+            dashboard::publish(LiveEvent::LowMemory {
+                free_internal: average,
+                floor,
+            });
+        }
+        last = metrics;
+    }
+}
+
+fn sample() -> MemoryMetrics {
+    unsafe {
+        MemoryMetrics {
+            free_heap: sys::esp_get_free_heap_size(),
+            min_free_heap: sys::esp_get_minimum_free_heap_size(),
+            free_internal: sys::heap_caps_get_free_size(sys::MALLOC_CAP_INTERNAL) as u32,
+            largest_free_block: sys::heap_caps_get_largest_free_block(sys::MALLOC_CAP_INTERNAL)
+                as u32,
+            psram_total: sys::heap_caps_get_total_size(sys::MALLOC_CAP_SPIRAM) as u32,
+            psram_free: sys::heap_caps_get_free_size(sys::MALLOC_CAP_SPIRAM) as u32,
+        }
+    }
+}
+
+fn delta_kb(now: u32, before: u32) -> i64 {
+    (now as i64 - before as i64) / 1024
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_low_memory_alarm() {
+        let mut alarm = LowMemoryAlarm::new(50 * 1024);
+        assert_eq!(alarm.update(60 * 1024), None);
+        // One dip doesn't pull the average below the floor
+        assert_eq!(alarm.update(40 * 1024), None);
+        assert!(alarm.update(40 * 1024).is_some());
+        // Raised once until the average recovers past the hysteresis
+        assert_eq!(alarm.update(40 * 1024), None);
+        for _ in 0..ALARM_WINDOW {
+            alarm.update(70 * 1024);
+        }
+        assert!(!alarm.raised);
+        assert_eq!(delta_kb(10 * 1024, 14 * 1024), -4);
+    }
+}
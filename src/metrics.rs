@@ -0,0 +1,327 @@
+use serde::Serialize;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::MetricsConfig;
+
+mod registry;
+
+pub use registry::{
+    increment, record_time, set_gauge, snapshot, start_turn, turn_answered, MetricsSnapshot,
+};
+
+/// Timing and outcome of one request to a remote service
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RequestMetrics {
+    /// Service the request went to, e.g. "DeepSeek"
+    pub service: String,
+    pub model: String,
+    /// Attempt number within the retry loop, starting at 1
+    pub attempt: u32,
+    /// Host name lookup
+    pub dns_ms: Option<u64>,
+    /// TCP connect and TLS handshake, esp_http_client doesn't report them separately
+    pub connect_ms: Option<u64>,
+    /// From the request being sent until the response headers arrived
+    pub ttfb_ms: Option<u64>,
+    /// From the start of the request until the first text of a streamed reply
+    pub first_token_ms: Option<u64>,
+    pub total_ms: u64,
+    pub status: Option<u16>,
+    pub success: bool,
+}
+
+/// Capacity and health of the storage the device records to
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StorageMetrics {
+    /// The internal flash is used because there is no SD card
+    pub internal_flash: bool,
+    /// False while the card is being remounted
+    pub available: bool,
+    pub total_bytes: Option<u64>,
+    pub free_bytes: Option<u64>,
+    /// Failed file operations since boot
+    pub io_errors: u32,
+    /// Times the card was mounted again after errors since boot
+    pub remounts: u32,
+    /// Result of a short write benchmark, when one was run
+    pub write_kb_per_sec: Option<u32>,
+}
+
+impl StorageMetrics {
+    /// Answer to "存储还剩多少"
+    pub fn spoken_summary(&self) -> String {
+        let name = if self.internal_flash { "内部存储" } else { "存储卡" };
+        if !self.available {
+            return format!("{}暂时无法使用，正在重新挂载", name);
+        }
+
+        let mut summary = match (self.total_bytes, self.free_bytes) {
+            (Some(total), Some(free)) => format!(
+                "{}一共{}，还剩{}。",
+                name,
+                spoken_size(total),
+                spoken_size(free)
+            ),
+            _ => format!("{}的容量读取失败。", name),
+        };
+        if let Some(speed) = self.write_kb_per_sec {
+            summary.push_str(&format!("写入速度每秒{}千字节。", speed));
+        }
+        if self.io_errors > 0 {
+            summary.push_str(&format!("开机以来出现过{}次读写错误。", self.io_errors));
+        }
+        summary
+    }
+}
+
+/// WiFi link quality and how the last LLM request went
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NetworkMetrics {
+    pub connected: bool,
+    pub ssid: Option<String>,
+    /// Signal strength of the last sample in dBm
+    pub rssi: Option<i8>,
+    /// Smoothed over the recent samples, single readings jump by several dB
+    pub average_rssi: Option<i8>,
+    pub channel: Option<u8>,
+    /// Times the connection dropped since boot
+    pub disconnects: u32,
+    /// Duration of the last successful LLM request
+    pub last_llm_ms: Option<u64>,
+}
+
+impl NetworkMetrics {
+    /// Answer to "网络怎么样"
+    pub fn spoken_summary(&self) -> String {
+        let mut summary = match (&self.ssid, self.average_rssi.or(self.rssi)) {
+            (Some(ssid), Some(rssi)) if self.connected => format!(
+                "已连接无线网络{}，信号{}，强度负{}。",
+                ssid,
+                signal_quality(rssi),
+                rssi.unsigned_abs()
+            ),
+            _ if self.connected => "已连接无线网络。".to_string(),
+            _ => "无线网络没有连接。".to_string(),
+        };
+        if self.disconnects > 0 {
+            summary.push_str(&format!("开机以来断开过{}次。", self.disconnects));
+        }
+        if let Some(ms) = self.last_llm_ms {
+            summary.push_str(&format!(
+                "上一次大模型回答用了{}点{}秒。",
+                ms / 1000,
+                ms % 1000 / 100
+            ));
+        }
+        summary
+    }
+}
+
+/// Free memory in bytes, internal RAM runs out long before the PSRAM does
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryMetrics {
+    /// Internal RAM and PSRAM together
+    pub free_heap: u32,
+    /// Lowest `free_heap` since boot
+    pub min_free_heap: u32,
+    pub free_internal: u32,
+    /// Largest internal allocation that can still succeed, small with a fragmented heap
+    pub largest_free_block: u32,
+    pub psram_total: u32,
+    pub psram_free: u32,
+}
+
+/// Stack use of one thread, in bytes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StackMetrics {
+    pub name: &'static str,
+    pub size: u32,
+    /// Least free stack since the thread started, FreeRTOS's high-water mark
+    pub min_free: u32,
+}
+
+/// Rough rating of a signal strength, -67 dBm is the usual minimum for a reliable link
+pub fn signal_quality(rssi: i8) -> &'static str {
+    match rssi {
+        -55.. => "很好",
+        -67..=-56 => "良好",
+        -75..=-68 => "一般",
+        _ => "很差",
+    }
+}
+
+/// Sizes as the on-device voice reads them, whole megabytes or gigabytes with one decimal
+fn spoken_size(bytes: u64) -> String {
+    const MB: u64 = 1024 * 1024;
+    const GB: u64 = 1024 * MB;
+
+    if bytes >= GB {
+        let tenths = bytes * 10 / GB;
+        format!("{}点{}吉字节", tenths / 10, tenths % 10)
+    } else {
+        format!("{}兆字节", bytes / MB)
+    }
+}
+
+/// Receives metrics of every request, e.g. to log them or report them elsewhere
+pub trait MetricsSink: Send + Sync {
+    fn record_request(&self, metrics: &RequestMetrics);
+
+    /// Storage health, reported when it was queried
+    fn record_storage(&self, _metrics: &StorageMetrics) {}
+
+    /// WiFi link quality, reported when it was queried
+    fn record_network(&self, _metrics: &NetworkMetrics) {}
+
+    /// Free memory, reported by the periodic sampler
+    fn record_memory(&self, _metrics: &MemoryMetrics) {}
+
+    /// Stack use of the long-running threads, reported by the periodic sampler or on request
+    fn record_stacks(&self, _metrics: &[StackMetrics]) {}
+
+    /// Counters, gauges and timers of all modules, reported periodically or on request
+    fn record_snapshot(&self, _snapshot: &MetricsSnapshot) {}
+}
+
+/// Log a summary of the counters, gauges and timers every `config.log_interval_mins`
+pub fn start(config: &MetricsConfig) -> anyhow::Result<()> {
+    if config.log_interval_mins == 0 {
+        return Ok(());
+    }
+
+    let interval = Duration::from_secs(config.log_interval_mins as u64 * 60);
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .stack_size(3 * 1024)
+        .spawn(move || loop {
+            thread::sleep(interval);
+            LogMetricsSink.record_snapshot(&snapshot());
+        })?;
+
+    Ok(())
+}
+
+/// Sink writing one log line per request
+pub struct LogMetricsSink;
+
+impl MetricsSink for LogMetricsSink {
+    fn record_request(&self, m: &RequestMetrics) {
+        log::info!(
+            "{} request #{} ({}): status {}, dns {} ms, connect+tls {} ms, ttfb {} ms, first token {} ms, total {} ms{}",
+            m.service,
+            m.attempt,
+            m.model,
+            m.status.map_or("-".to_string(), |s| s.to_string()),
+            format_ms(m.dns_ms),
+            format_ms(m.connect_ms),
+            format_ms(m.ttfb_ms),
+            format_ms(m.first_token_ms),
+            m.total_ms,
+            if m.success { "" } else { ", failed" }
+        );
+    }
+
+    fn record_storage(&self, m: &StorageMetrics) {
+        log::info!(
+            "Storage ({}): {}, {} of {} KB free, {} I/O errors, {} remounts, write speed {} KB/s",
+            if m.internal_flash { "internal flash" } else { "SD card" },
+            if m.available { "available" } else { "unavailable" },
+            format_kb(m.free_bytes),
+            format_kb(m.total_bytes),
+            m.io_errors,
+            m.remounts,
+            m.write_kb_per_sec.map_or("-".to_string(), |kbps| kbps.to_string())
+        );
+    }
+
+    fn record_network(&self, m: &NetworkMetrics) {
+        log::info!(
+            "Network: {}, ssid {}, rssi {} dBm (average {}), channel {}, {} disconnects, last LLM request {} ms",
+            if m.connected { "connected" } else { "disconnected" },
+            m.ssid.as_deref().unwrap_or("-"),
+            m.rssi.map_or("-".to_string(), |rssi| rssi.to_string()),
+            m.average_rssi.map_or("-".to_string(), |rssi| rssi.to_string()),
+            m.channel.map_or("-".to_string(), |channel| channel.to_string()),
+            m.disconnects,
+            format_ms(m.last_llm_ms)
+        );
+    }
+
+    fn record_stacks(&self, metrics: &[StackMetrics]) {
+        let stacks: Vec<String> = metrics
+            .iter()
+            .map(|m| format!("{} {} of {}", m.name, m.size.saturating_sub(m.min_free), m.size))
+            .collect();
+        log::info!("Peak stack use in bytes: {}", stacks.join(", "));
+    }
+
+    fn record_snapshot(&self, snapshot: &MetricsSnapshot) {
+        let mut parts: Vec<String> = Vec::new();
+        parts.extend(snapshot.counters.iter().map(|(name, value)| format!("{} {}", name, value)));
+        parts.extend(snapshot.gauges.iter().map(|(name, value)| format!("{} {}", name, value)));
+        parts.extend(snapshot.timers.iter().map(|(name, timer)| {
+            format!(
+                "{} {}x avg {} ms (min {}, max {}, last {})",
+                name,
+                timer.count,
+                timer.average_ms(),
+                timer.min_ms,
+                timer.max_ms,
+                timer.last_ms
+            )
+        }));
+        if parts.is_empty() {
+            log::info!("Metrics: nothing recorded yet");
+        } else {
+            log::info!("Metrics: {}", parts.join(", "));
+        }
+    }
+}
+
+fn format_kb(bytes: Option<u64>) -> String {
+    bytes.map_or("-".to_string(), |b| (b / 1024).to_string())
+}
+
+fn format_ms(value: Option<u64>) -> String {
+    value.map_or("-".to_string(), |ms| ms.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_summary() {
+        let metrics = NetworkMetrics {
+            connected: true,
+            ssid: Some("home".to_string()),
+            rssi: Some(-80),
+            average_rssi: Some(-62),
+            disconnects: 1,
+            last_llm_ms: Some(1850),
+            ..Default::default()
+        };
+        assert_eq!(
+            metrics.spoken_summary(),
+            "已连接无线网络home，信号良好，强度负62。开机以来断开过1次。上一次大模型回答用了1点8秒。"
+        );
+        assert_eq!(signal_quality(-55), "很好");
+        assert_eq!(signal_quality(-90), "很差");
+    }
+
+    #[test]
+    fn test_storage_summary() {
+        let metrics = StorageMetrics {
+            available: true,
+            total_bytes: Some(15_931_539_456),
+            free_bytes: Some(300 * 1024 * 1024),
+            io_errors: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            metrics.spoken_summary(),
+            "存储卡一共14点8吉字节，还剩300兆字节。开机以来出现过2次读写错误。"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Prefix of the names on the /metrics endpoint
+const PROMETHEUS_PREFIX: &str = "ai_chatbox";
+
+static REGISTRY: Mutex<MetricsSnapshot> = Mutex::new(MetricsSnapshot::new());
+/// End of the utterance being answered, the start of the turn's latency
+static TURN_STARTED: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Durations recorded under one name since boot, in milliseconds
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TimerStats {
+    pub count: u64,
+    pub total_ms: u64,
+    pub min_ms: u64,
+    pub max_ms: u64,
+    pub last_ms: u64,
+}
+
+impl TimerStats {
+    pub fn average_ms(&self) -> u64 {
+        self.total_ms.checked_div(self.count).unwrap_or(0)
+    }
+
+    fn add(&mut self, ms: u64) {
+        self.min_ms = if self.count == 0 {
+            ms
+        } else {
+            self.min_ms.min(ms)
+        };
+        self.max_ms = self.max_ms.max(ms);
+        self.last_ms = ms;
+        self.total_ms += ms;
+        self.count += 1;
+    }
+}
+
+/// Counters, gauges and timers of all modules, as exported
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    /// Events since boot, e.g. wake words heard or failed requests
+    pub counters: BTreeMap<&'static str, u64>,
+    /// Last value of a quantity that goes up and down, e.g. the WiFi signal
+    pub gauges: BTreeMap<&'static str, i64>,
+    pub timers: BTreeMap<&'static str, TimerStats>,
+}
+
+impl MetricsSnapshot {
+    const fn new() -> Self {
+        Self {
+            counters: BTreeMap::new(),
+            gauges: BTreeMap::new(),
+            timers: BTreeMap::new(),
+        }
+    }
+
+    /// Prometheus text format, timers become summaries without quantiles
+    pub fn prometheus_text(&self) -> String {
+        let mut text = String::new();
+        for (name, value) in &self.counters {
+            let name = format!("{}_{}_total", PROMETHEUS_PREFIX, name);
+            let _ = writeln!(text, "# TYPE {} counter\n{} {}", name, name, value);
+        }
+        for (name, value) in &self.gauges {
+            let name = format!("{}_{}", PROMETHEUS_PREFIX, name);
+            let _ = writeln!(text, "# TYPE {} gauge\n{} {}", name, name, value);
+        }
+        for (name, timer) in &self.timers {
+            let name = format!("{}_{}_milliseconds", PROMETHEUS_PREFIX, name);
+            let _ = writeln!(
+                text,
+                "# TYPE {} summary\n{}_sum {}\n{}_count {}",
+                name, name, timer.total_ms, name, timer.count
+            );
+        }
+        text
+    }
+}
+
+/// Count an event
+pub fn increment(name: &'static str) {
+    *REGISTRY.lock().unwrap().counters.entry(name).or_default() += 1;
+}
+
+/// Set the current value of a quantity
+pub fn set_gauge(name: &'static str, value: i64) {
+    REGISTRY.lock().unwrap().gauges.insert(name, value);
+}
+
+/// Record how long something took
+pub fn record_time(name: &'static str, duration: Duration) {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .timers
+        .entry(name)
+        .or_default()
+        .add(duration.as_millis() as u64);
+}
+
+/// The user stopped speaking, the turn's latency counts from here
+pub fn start_turn() {
+    *TURN_STARTED.lock().unwrap() = Some(Instant::now());
+}
+
+/// The reply starts playing, records the turn's latency as "turn"
+pub fn turn_answered() {
+    if let Some(started) = TURN_STARTED.lock().unwrap().take() {
+        record_time("turn", started.elapsed());
+    }
+}
+
+/// Everything recorded since boot
+pub fn snapshot() -> MetricsSnapshot {
+    REGISTRY.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_text() {
+        let mut snapshot = MetricsSnapshot::default();
+        snapshot.counters.insert("wake_words", 3);
+        snapshot.gauges.insert("wifi_rssi", -61);
+        let mut stt = TimerStats::default();
+        stt.add(800);
+        stt.add(1200);
+        assert_eq!(
+            (stt.min_ms, stt.max_ms, stt.average_ms()),
+            (800, 1200, 1000)
+        );
+        snapshot.timers.insert("stt", stt);
+
+        assert_eq!(
+            snapshot.prometheus_text(),
+            "# TYPE ai_chatbox_wake_words_total counter\n\
+             ai_chatbox_wake_words_total 3\n\
+             # TYPE ai_chatbox_wifi_rssi gauge\n\
+             ai_chatbox_wifi_rssi -61\n\
+             # TYPE ai_chatbox_stt_milliseconds summary\n\
+             ai_chatbox_stt_milliseconds_sum 2000\n\
+             ai_chatbox_stt_milliseconds_count 2\n"
+        );
+    }
+}
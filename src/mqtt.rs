@@ -0,0 +1,342 @@
+use esp_idf_svc::mqtt::client::{
+    Details, EspMqttClient, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::MqttConfig;
+use crate::dashboard::{self, LiveEvent};
+use crate::earcon::Earcon;
+use crate::metrics::{
+    self, LogMetricsSink, MemoryMetrics, MetricsSink, MetricsSnapshot, NetworkMetrics,
+    RequestMetrics, StackMetrics, StorageMetrics,
+};
+use crate::playback;
+use crate::sd_card;
+use crate::settings;
+use crate::transcription::TranscriptionMessage;
+use crate::wifi;
+
+/// Longest text the say command takes, in characters
+const MAX_SAY_CHARS: usize = 500;
+
+/// The client and the prefix of its topics, None while MQTT is off
+static CLIENT: Mutex<Option<Publisher>> = Mutex::new(None);
+/// Set by the client's event callback, nothing is published while the broker is away
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+struct Publisher {
+    client: EspMqttClient<'static>,
+    prefix: String,
+}
+
+/// What the client's event callback hands to the MQTT thread
+///
+/// The callback runs on the client's task and must not use the client, so everything that
+/// needs it happens on the thread.
+enum Incoming {
+    Connected,
+    Message { topic: String, payload: Vec<u8> },
+}
+
+/// Connect to the broker in `config`, does nothing when no URL is configured
+///
+/// Publishes under `<topic_prefix>/`:
+/// - `availability`: "online", or "offline" as the last will, retained
+/// - `status`: the dashboard's status JSON every `status_interval_secs`, retained
+/// - `metrics/request`, `metrics/storage`, `metrics/network`: JSON
+/// - `metrics/memory`, `metrics/stacks`, `metrics/summary`: JSON, retained
+/// - `transcript`, `reply`: plain text
+/// - `event`: the dashboard's live events as JSON, without the chatty progress ones
+/// - `settings/<key>`: a setting changed at runtime, e.g. `settings/volume`, retained and
+///   cleared when the setting is removed
+///
+/// Commands are taken from `<topic_prefix>/cmd/say` (text to speak), `cmd/announce` (text to
+/// speak after a chime, ahead of the conversation), `cmd/volume` (0 to 100) and
+/// `cmd/restart_session`.
+pub fn start(
+    config: &MqttConfig,
+    hostname: &str,
+    transcription_tx: Sender<TranscriptionMessage>,
+) -> anyhow::Result<()> {
+    if config.url.is_empty() {
+        return Ok(());
+    }
+
+    let prefix = config.topic_prefix.clone();
+    let availability = format!("{}/availability", prefix);
+    let client_id = config.client_id.as_deref().unwrap_or(hostname);
+    let client_config = MqttClientConfiguration {
+        client_id: Some(client_id),
+        username: config.username.as_deref(),
+        password: config.password.as_deref(),
+        lwt: Some(LwtConfiguration {
+            topic: &availability,
+            payload: b"offline",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let client = EspMqttClient::new_cb(&config.url, &client_config, move |event| {
+        match event.payload() {
+            EventPayload::Connected(_) => {
+                CONNECTED.store(true, Ordering::Relaxed);
+                let _ = tx.send(Incoming::Connected);
+            }
+            EventPayload::Disconnected => {
+                CONNECTED.store(false, Ordering::Relaxed);
+                log::warn!("Disconnected from the MQTT broker, reconnecting");
+            }
+            // Commands are short, a fragmented message is nothing we'd understand
+            EventPayload::Received {
+                topic: Some(topic),
+                data,
+                details: Details::Complete,
+                ..
+            } => {
+                let _ = tx.send(Incoming::Message {
+                    topic: topic.to_string(),
+                    payload: data.to_vec(),
+                });
+            }
+            EventPayload::Error(e) => log::warn!("MQTT error: {:?}", e),
+            _ => {}
+        }
+    })?;
+    *CLIENT.lock().unwrap() = Some(Publisher {
+        client,
+        prefix: prefix.clone(),
+    });
+
+    settings::on_change(|change| {
+        let value = change.value.as_deref().unwrap_or_default();
+        publish(&format!("settings/{}", change.key), value.as_bytes(), true);
+    });
+
+    let interval = Duration::from_secs(config.status_interval_secs);
+    thread::Builder::new()
+        .name("mqtt".to_string())
+        .stack_size(8 * 1024)
+        .spawn(move || mqtt_loop(rx, &prefix, interval, transcription_tx))?;
+
+    log::info!("Connecting to the MQTT broker at {}", config.url);
+    Ok(())
+}
+
+/// Forward a conversation event, called for every event the dashboard publishes
+pub fn publish_event(event: &LiveEvent) {
+    if !CONNECTED.load(Ordering::Relaxed) {
+        return;
+    }
+    match event {
+        LiveEvent::PartialTranscript { .. } | LiveEvent::SpeechProgress { .. } => return,
+        LiveEvent::Transcript { text } => publish("transcript", text.as_bytes(), false),
+        LiveEvent::Reply { text } => publish("reply", text.as_bytes(), false),
+        _ => {}
+    }
+    publish_json("event", event, false);
+}
+
+/// Publishes metrics to `<topic_prefix>/metrics/...`, request metrics are logged as well
+pub struct MqttMetricsSink;
+
+impl MetricsSink for MqttMetricsSink {
+    fn record_request(&self, metrics: &RequestMetrics) {
+        LogMetricsSink.record_request(metrics);
+        publish_json("metrics/request", metrics, false);
+    }
+
+    fn record_storage(&self, metrics: &StorageMetrics) {
+        publish_json("metrics/storage", metrics, true);
+    }
+
+    fn record_network(&self, metrics: &NetworkMetrics) {
+        publish_json("metrics/network", metrics, true);
+    }
+
+    fn record_memory(&self, metrics: &MemoryMetrics) {
+        publish_json("metrics/memory", metrics, true);
+    }
+
+    fn record_stacks(&self, metrics: &[StackMetrics]) {
+        publish_json("metrics/stacks", &metrics, true);
+    }
+
+    fn record_snapshot(&self, snapshot: &MetricsSnapshot) {
+        publish_json("metrics/summary", snapshot, true);
+    }
+}
+
+fn mqtt_loop(
+    rx: Receiver<Incoming>,
+    prefix: &str,
+    interval: Duration,
+    transcription_tx: Sender<TranscriptionMessage>,
+) {
+    let command_prefix = format!("{}/cmd/", prefix);
+    let mut next_status = Instant::now() + interval;
+
+    loop {
+        match rx.recv_timeout(next_status.saturating_duration_since(Instant::now())) {
+            Ok(Incoming::Connected) => {
+                log::info!("Connected to the MQTT broker");
+                // Subscriptions don't survive a reconnect without a persistent session
+                let commands = format!("{}+", command_prefix);
+                if let Some(publisher) = CLIENT.lock().unwrap().as_mut() {
+                    if let Err(e) = publisher.client.subscribe(&commands, QoS::AtLeastOnce) {
+                        log::warn!("Failed to subscribe to {}: {}", commands, e);
+                    }
+                }
+                publish("availability", b"online", true);
+                next_status = Instant::now();
+            }
+            Ok(Incoming::Message { topic, payload }) => {
+                let Some(command) = topic.strip_prefix(&command_prefix) else {
+                    continue;
+                };
+                // Played ahead of whatever the worker is doing, like an alarm
+                if command == "announce" {
+                    match announcement(&payload) {
+                        Ok(text) if playback::announce(Earcon::Announcement, &text) => {
+                            log::info!("MQTT announcement: {}", text);
+                        }
+                        Ok(_) => log::warn!("Ignoring MQTT announcement, playback is not running"),
+                        Err(e) => log::warn!("Ignoring MQTT command announce: {}", e),
+                    }
+                    continue;
+                }
+                match parse_command(command, &payload) {
+                    Ok(message) => {
+                        log::info!("MQTT command: {}", command);
+                        let _ = transcription_tx.send(message);
+                    }
+                    Err(e) => log::warn!("Ignoring MQTT command {}: {}", command, e),
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if CONNECTED.load(Ordering::Relaxed) {
+                    publish_json("status", &dashboard::status(), true);
+                    MqttMetricsSink.record_storage(&sd_card::storage_metrics("/vfat", false));
+                    MqttMetricsSink.record_network(&wifi::network_metrics());
+                    MqttMetricsSink.record_snapshot(&metrics::snapshot());
+                }
+                next_status = Instant::now() + interval;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// Text of an announcement, the same limits as the say command
+fn announcement(payload: &[u8]) -> anyhow::Result<String> {
+    let text = std::str::from_utf8(payload)?.trim();
+    if text.is_empty() || text.chars().count() > MAX_SAY_CHARS {
+        return Err(anyhow::anyhow!(
+            "text must be 1 to {} characters",
+            MAX_SAY_CHARS
+        ));
+    }
+    Ok(text.to_string())
+}
+
+/// Message for the worker from a command topic, e.g. "volume" with "60"
+fn parse_command(command: &str, payload: &[u8]) -> anyhow::Result<TranscriptionMessage> {
+    let payload = std::str::from_utf8(payload)?.trim();
+    match command {
+        "say" => {
+            if payload.is_empty() || payload.chars().count() > MAX_SAY_CHARS {
+                return Err(anyhow::anyhow!(
+                    "text must be 1 to {} characters",
+                    MAX_SAY_CHARS
+                ));
+            }
+            Ok(TranscriptionMessage::Speak {
+                text: payload.to_string(),
+            })
+        }
+        "volume" => {
+            let volume: u8 = payload.parse()?;
+            if volume > 100 {
+                return Err(anyhow::anyhow!("volume must be between 0 and 100"));
+            }
+            Ok(TranscriptionMessage::SetVolume(volume))
+        }
+        "restart_session" => Ok(TranscriptionMessage::RestartSession),
+        _ => Err(anyhow::anyhow!("unknown command")),
+    }
+}
+
+/// Publish to a topic outside the prefix, e.g. to switch a light, fails while the broker is away
+pub fn publish_to(topic: &str, payload: &[u8]) -> anyhow::Result<()> {
+    if !CONNECTED.load(Ordering::Relaxed) {
+        return Err(anyhow::anyhow!("Not connected to the MQTT broker"));
+    }
+    let mut client = CLIENT.lock().unwrap();
+    let publisher = client
+        .as_mut()
+        .ok_or_else(|| anyhow::anyhow!("MQTT is off"))?;
+    // A command must arrive, unlike the status updates
+    publisher
+        .client
+        .enqueue(topic, QoS::AtLeastOnce, false, payload)?;
+    Ok(())
+}
+
+fn publish(subtopic: &str, payload: &[u8], retain: bool) {
+    if !CONNECTED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut client = CLIENT.lock().unwrap();
+    let Some(publisher) = client.as_mut() else {
+        return;
+    };
+    let topic = format!("{}/{}", publisher.prefix, subtopic);
+    // Queued for the client's task, so a slow broker doesn't hold up the caller
+    if let Err(e) = publisher
+        .client
+        .enqueue(&topic, QoS::AtMostOnce, retain, payload)
+    {
+        log::debug!("Failed to publish to {}: {}", topic, e);
+    }
+}
+
+fn publish_json(subtopic: &str, value: &impl Serialize, retain: bool) {
+    match serde_json::to_vec(value) {
+        Ok(json) => publish(subtopic, &json, retain),
+        Err(e) => log::warn!("Failed to serialize {}: {}", subtopic, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert!(matches!(
+            parse_command("say", "  你好 ".as_bytes()),
+            Ok(TranscriptionMessage::Speak { text }) if text == "你好"
+        ));
+        assert!(matches!(
+            parse_command("volume", b"60"),
+            Ok(TranscriptionMessage::SetVolume(60))
+        ));
+        assert!(parse_command("volume", b"120").is_err());
+        assert!(parse_command("say", b"").is_err());
+        assert!(matches!(
+            parse_command("restart_session", b""),
+            Ok(TranscriptionMessage::RestartSession)
+        ));
+        assert!(parse_command("reboot", b"").is_err());
+        assert_eq!(announcement(" 开饭了 ".as_bytes()).unwrap(), "开饭了");
+        assert!(announcement(b"").is_err());
+    }
+}
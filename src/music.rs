@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use symphonia::core::probe::Hint;
+
+use crate::radio;
+use crate::stacks;
+
+/// Where the music library lives on the card, subdirectories included
+const MUSIC_DIR: &str = "/vfat/music";
+/// File types the decoders understand
+const EXTENSIONS: [&str; 2] = ["mp3", "aac"];
+/// Decoded chunks waiting to be played, reading from the card needs far less than the radio
+const BUFFERED_CHUNKS: usize = 16;
+/// The MP3 and AAC decoders need the room
+const MUSIC_STACK_SIZE: usize = 24 * 1024;
+
+/// What is playing, for skipping to the next track
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+struct Session {
+    tracks: Arc<Vec<PathBuf>>,
+    /// Index of the track being decoded, advanced by the music thread
+    current: Arc<AtomicUsize>,
+}
+
+/// A track that started playing
+pub struct Playing {
+    /// File name without the extension, as spoken
+    pub title: String,
+    /// Decoded audio of this track and the ones after it
+    pub chunks: Receiver<Vec<i16>>,
+}
+
+/// Every track in the library, in file name order
+pub fn scan() -> Vec<PathBuf> {
+    let mut tracks = Vec::new();
+    scan_dir(Path::new(MUSIC_DIR), &mut tracks);
+    tracks.sort();
+    tracks
+}
+
+/// Play the library from the first track whose name contains `query`, from the start without
+/// one
+///
+/// None when the library is empty or nothing matches.
+pub fn play(query: &str) -> anyhow::Result<Option<Playing>> {
+    let tracks = scan();
+    log::info!("Found {} tracks in {}", tracks.len(), MUSIC_DIR);
+    let Some(index) = find_track(&tracks, query) else {
+        return Ok(None);
+    };
+    start(Arc::new(tracks), index).map(Some)
+}
+
+/// Skip to the track after the one playing, wrapping around at the end of the library
+///
+/// None when nothing from the library was played yet.
+pub fn next() -> anyhow::Result<Option<Playing>> {
+    let next = SESSION.lock().unwrap().as_ref().map(|session| {
+        let index = (session.current.load(Ordering::Relaxed) + 1) % session.tracks.len();
+        (session.tracks.clone(), index)
+    });
+    match next {
+        Some((tracks, index)) => start(tracks, index).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn start(tracks: Arc<Vec<PathBuf>>, index: usize) -> anyhow::Result<Playing> {
+    let (tx, rx) = mpsc::sync_channel(BUFFERED_CHUNKS);
+    let current = Arc::new(AtomicUsize::new(index));
+    let title = track_title(&tracks[index]);
+
+    // The thread of the last session stops once its receiver is dropped
+    *SESSION.lock().unwrap() = Some(Session {
+        tracks: tracks.clone(),
+        current: current.clone(),
+    });
+    thread::Builder::new()
+        .name("music".to_string())
+        .stack_size(MUSIC_STACK_SIZE)
+        .spawn(move || {
+            let _stack = stacks::register("music", MUSIC_STACK_SIZE);
+            play_from(&tracks, index, &current, &tx);
+        })?;
+    Ok(Playing { title, chunks: rx })
+}
+
+/// Decode the tracks from `index` to the end of the library
+fn play_from(tracks: &[PathBuf], index: usize, current: &AtomicUsize, tx: &SyncSender<Vec<i16>>) {
+    for (index, path) in tracks.iter().enumerate().skip(index) {
+        current.store(index, Ordering::Relaxed);
+        log::info!("Playing {}", path.display());
+        match play_track(path, tx) {
+            Ok(true) => (),
+            // Stopped or skipped
+            Ok(false) => return,
+            // A broken file shouldn't end the whole library
+            Err(e) => log::warn!("Failed to play {}: {:#}", path.display(), e),
+        }
+    }
+    log::info!("Reached the end of the music library");
+}
+
+fn play_track(path: &Path, tx: &SyncSender<Vec<i16>>) -> anyhow::Result<bool> {
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    radio::decode(Box::new(File::open(path)?), &hint, tx)
+}
+
+fn scan_dir(dir: &Path, tracks: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Failed to read {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            scan_dir(&path, tracks);
+        } else if is_track(&path) {
+            tracks.push(path);
+        }
+    }
+}
+
+fn is_track(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()))
+}
+
+/// Index of the first track whose title contains `query`, 0 for an empty query
+fn find_track(tracks: &[PathBuf], query: &str) -> Option<usize> {
+    if tracks.is_empty() {
+        return None;
+    }
+    if query.is_empty() {
+        return Some(0);
+    }
+    let query = query.to_lowercase();
+    // Artists are often only in the directory name, "周杰伦/晴天.mp3"
+    tracks.iter().position(|path| {
+        let name = path.strip_prefix(MUSIC_DIR).unwrap_or(path);
+        name.to_string_lossy().to_lowercase().contains(&query)
+    })
+}
+
+fn track_title(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_track() {
+        let tracks: Vec<PathBuf> = ["/vfat/music/周杰伦/晴天.mp3", "/vfat/music/Yesterday.MP3"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(find_track(&tracks, ""), Some(0));
+        assert_eq!(find_track(&tracks, "yesterday"), Some(1));
+        assert_eq!(find_track(&tracks, "周杰伦"), Some(0));
+        assert_eq!(find_track(&tracks, "稻香"), None);
+        assert_eq!(find_track(&[], ""), None);
+
+        assert!(is_track(&tracks[1]));
+        assert!(!is_track(Path::new("/vfat/music/cover.jpg")));
+        assert_eq!(track_title(&tracks[0]), "晴天");
+    }
+}
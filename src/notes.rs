@@ -0,0 +1,100 @@
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::transcript_log::{format_utc, wall_clock_secs};
+
+pub const NOTES_DIR: &str = "/vfat/notes";
+/// Notes taken before SNTP set the clock
+const UNSYNCED_STEM: &str = "unsynced";
+
+/// Set while utterances are written to the notes instead of being answered
+static DICTATING: AtomicBool = AtomicBool::new(false);
+
+/// A day's note file, as listed by the web API
+#[derive(Debug, Serialize)]
+pub struct NoteFile {
+    /// "20241016", or "unsynced"
+    pub date: String,
+    pub bytes: u64,
+}
+
+pub fn set_dictating(dictating: bool) {
+    DICTATING.store(dictating, Ordering::Relaxed);
+    log::info!("Dictation {}", if dictating { "started" } else { "ended" });
+}
+
+/// Whether utterances go to the notes, skipping the LLM and the speech
+pub fn is_dictating() -> bool {
+    DICTATING.load(Ordering::Relaxed)
+}
+
+/// Append an utterance to the note file of the local day, stamped with the local time
+pub fn append(text: &str, utc_offset_mins: i32) -> anyhow::Result<()> {
+    let (stem, time) = match wall_clock_secs() {
+        Some(secs) => {
+            let local = (secs as i64 + utc_offset_mins as i64 * 60) as u64;
+            let (date, time) = format_utc(local);
+            (date.replace('-', ""), time[..5].to_string())
+        }
+        None => (UNSYNCED_STEM.to_string(), "--:--".to_string()),
+    };
+
+    std::fs::create_dir_all(NOTES_DIR)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(note_path(&stem))?;
+    writeln!(file, "{} {}", time, text.replace('\n', " "))?;
+    Ok(())
+}
+
+/// The note files, newest day first
+pub fn list() -> Vec<NoteFile> {
+    let Ok(entries) = std::fs::read_dir(NOTES_DIR) else {
+        return Vec::new();
+    };
+    let mut notes: Vec<NoteFile> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let date = name.strip_suffix(".txt")?;
+            is_note_name(date).then(|| NoteFile {
+                date: date.to_string(),
+                bytes: entry.metadata().map(|m| m.len()).unwrap_or(0),
+            })
+        })
+        .collect();
+    notes.sort_by(|a, b| b.date.cmp(&a.date));
+    notes
+}
+
+/// Text of the notes of `date` ("20241016"), None for a day without notes or a malformed date
+pub fn read(date: &str) -> Option<String> {
+    if !is_note_name(date) {
+        return None;
+    }
+    std::fs::read_to_string(note_path(date)).ok()
+}
+
+fn note_path(stem: &str) -> String {
+    format!("{}/{}.txt", NOTES_DIR, stem)
+}
+
+/// Only names `append` writes, so a request can't reach outside the notes
+fn is_note_name(name: &str) -> bool {
+    name == UNSYNCED_STEM || (name.len() == 8 && name.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_note_name() {
+        assert!(is_note_name("20241016"));
+        assert!(is_note_name("unsynced"));
+        assert!(!is_note_name("../config"));
+        assert!(!is_note_name("2024101"));
+    }
+}
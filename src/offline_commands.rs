@@ -0,0 +1,120 @@
+use crate::smart_home::FIRST_OFFLINE_ID;
+
+/// Commands MultiNet recognizes on the device, so a few things keep working while the network is down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OfflineCommand {
+    /// "运行了多久", the wall clock isn't set without network so report the uptime
+    Uptime,
+    /// "大声一点"
+    VolumeUp,
+    /// "小声一点"
+    VolumeDown,
+    /// "再说一遍", repeat the last answer
+    ReplayLastAnswer,
+    /// "自我检测"
+    SelfTest,
+    /// "定时五分钟"
+    TimerFiveMinutes,
+    /// "定时十分钟"
+    TimerTenMinutes,
+    /// "取消闹钟", cancels the timers as well
+    CancelAlarms,
+    /// A pinyin phrase of `[[smart_home.devices]]`, by its command id
+    SmartHome(i32),
+}
+
+/// Spoken once when the device goes offline
+pub const OFFLINE_ANNOUNCEMENT: &str =
+    "当前离线。离线时可以问我运行了多久，让我大声一点、小声一点、再说一遍、自我检测，或者定时五分钟";
+
+impl OfflineCommand {
+    pub const ALL: [OfflineCommand; 8] = [
+        OfflineCommand::Uptime,
+        OfflineCommand::VolumeUp,
+        OfflineCommand::VolumeDown,
+        OfflineCommand::ReplayLastAnswer,
+        OfflineCommand::SelfTest,
+        OfflineCommand::TimerFiveMinutes,
+        OfflineCommand::TimerTenMinutes,
+        OfflineCommand::CancelAlarms,
+    ];
+
+    /// Command id registered with MultiNet, id 1 is taken by "我有个问题"
+    pub fn command_id(&self) -> i32 {
+        match self {
+            OfflineCommand::Uptime => 2,
+            OfflineCommand::VolumeUp => 3,
+            OfflineCommand::VolumeDown => 4,
+            OfflineCommand::ReplayLastAnswer => 5,
+            OfflineCommand::SelfTest => 6,
+            OfflineCommand::TimerFiveMinutes => 7,
+            OfflineCommand::TimerTenMinutes => 8,
+            OfflineCommand::CancelAlarms => 9,
+            OfflineCommand::SmartHome(id) => *id,
+        }
+    }
+
+    /// Phrase in the pinyin notation MultiNet's Chinese model expects
+    pub fn phrase(&self) -> &'static str {
+        match self {
+            OfflineCommand::Uptime => "yun xing le duo jiu",
+            OfflineCommand::VolumeUp => "da sheng yi dian",
+            OfflineCommand::VolumeDown => "xiao sheng yi dian",
+            OfflineCommand::ReplayLastAnswer => "zai shuo yi bian",
+            OfflineCommand::SelfTest => "zi wo jian ce",
+            OfflineCommand::TimerFiveMinutes => "ding shi wu fen zhong",
+            OfflineCommand::TimerTenMinutes => "ding shi shi fen zhong",
+            OfflineCommand::CancelAlarms => "qu xiao nao zhong",
+            // Registered from the configuration, see smart_home::offline_phrases
+            OfflineCommand::SmartHome(_) => "",
+        }
+    }
+
+    pub fn from_command_id(id: i32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|command| command.command_id() == id)
+            .or_else(|| (id >= FIRST_OFFLINE_ID).then_some(OfflineCommand::SmartHome(id)))
+    }
+}
+
+/// Spoken uptime, e.g. "已经运行了2小时5分钟"
+pub fn format_uptime(secs: u64) -> String {
+    let hours = secs / 3600;
+    let minutes = secs % 3600 / 60;
+
+    match (hours, minutes) {
+        (0, 0) => "刚刚启动，运行了不到一分钟".to_string(),
+        (0, m) => format!("已经运行了{}分钟", m),
+        (h, 0) => format!("已经运行了{}小时", h),
+        (h, m) => format!("已经运行了{}小时{}分钟", h, m),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_ids_round_trip() {
+        for command in OfflineCommand::ALL {
+            assert_eq!(
+                OfflineCommand::from_command_id(command.command_id()),
+                Some(command)
+            );
+        }
+        assert_eq!(OfflineCommand::from_command_id(1), None);
+        assert_eq!(
+            OfflineCommand::from_command_id(101),
+            Some(OfflineCommand::SmartHome(101))
+        );
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(30), "刚刚启动，运行了不到一分钟");
+        assert_eq!(format_uptime(125), "已经运行了2分钟");
+        assert_eq!(format_uptime(7200), "已经运行了2小时");
+        assert_eq!(format_uptime(7500), "已经运行了2小时5分钟");
+    }
+}
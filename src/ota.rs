@@ -0,0 +1,389 @@
+use esp_idf_svc::http::client::Configuration as HttpConfiguration;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate};
+use esp_idf_svc::sys::{self, esp};
+use serde::Deserialize;
+use std::io::Read;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use crate::config::{OtaConfig, SpeechModelConfig};
+use crate::connectivity;
+use crate::console;
+use crate::http_client::{download_to_file, read_response_body, HttpError, PooledConnection};
+use crate::sd_card;
+use crate::transcription::TranscriptionMessage;
+
+mod assets;
+
+pub use assets::{Asset, AssetManifest};
+
+/// Downloaded images wait here until they are written to flash
+const FIRMWARE_DIR: &str = "/vfat/ota";
+const MANIFEST_TIMEOUT: Duration = Duration::from_secs(15);
+const WRITE_CHUNK_SIZE: usize = 4096;
+
+/// Latest firmware as described by the manifest, e.g.
+/// `{"version": "0.2.0", "url": "https://example.com/ai-chatbox.bin", "sha256": "..."}`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    /// Where the application image (not the merged flash image) can be downloaded
+    pub url: String,
+    /// Hex digest of the image
+    pub sha256: String,
+    /// Speech models and voice data, installed whenever they differ from the ones on the device
+    #[serde(flatten)]
+    pub assets: AssetManifest,
+}
+
+/// What the manifest offers that the device doesn't have yet
+#[derive(Debug)]
+pub struct Update {
+    pub manifest: Manifest,
+    /// The firmware is newer than the running one
+    pub firmware: bool,
+    pub assets: Vec<Asset>,
+}
+
+impl Update {
+    /// What is about to be installed, e.g. "新版本0.2.0和语音合成数据"
+    pub fn spoken_summary(&self) -> String {
+        let mut parts = Vec::new();
+        if self.firmware {
+            parts.push(format!("新版本{}", self.manifest.version));
+        }
+        for asset in &self.assets {
+            let name = asset.spoken_name().to_string();
+            if !parts.contains(&name) {
+                parts.push(name);
+            }
+        }
+        parts.join("和")
+    }
+}
+
+/// Version of the running firmware
+pub fn current_version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Fetch the manifest, None when it offers nothing the device doesn't already have
+pub fn check(config: &OtaConfig, models: &SpeechModelConfig) -> anyhow::Result<Option<Update>> {
+    if config.manifest_url.is_empty() {
+        return Err(anyhow::anyhow!("No ota.manifest_url configured"));
+    }
+
+    let body = fetch_manifest(&config.manifest_url)?;
+    let manifest: Manifest = serde_json::from_str(&body)
+        .map_err(|e| anyhow::anyhow!("Invalid firmware manifest: {}", e))?;
+
+    let firmware = is_newer(&manifest.version, current_version());
+    let assets = assets::pending(&manifest.assets, models);
+    if !firmware && assets.is_empty() {
+        log::info!(
+            "Firmware {} and its assets are up to date",
+            current_version()
+        );
+        return Ok(None);
+    }
+
+    let update = Update {
+        manifest,
+        firmware,
+        assets,
+    };
+    log::info!(
+        "Update available: {}, running firmware {}",
+        update.spoken_summary(),
+        current_version()
+    );
+    Ok(Some(update))
+}
+
+/// Install the assets, then the firmware, each verified against the manifest's hash
+///
+/// The running firmware stays the boot partition until the new image was verified, so a
+/// failed download or a hash mismatch changes nothing. An interrupted download is resumed by
+/// the next attempt. Reboot afterwards to use what was installed, model and voice partitions
+/// are written during that boot by `install_staged_assets`.
+pub fn install(update: &Update) -> anyhow::Result<()> {
+    if !sd_card::is_available() || sd_card::is_internal_flash() {
+        return Err(anyhow::anyhow!("Updates need the SD card"));
+    }
+    std::fs::create_dir_all(FIRMWARE_DIR)?;
+
+    let firmware = if update.firmware {
+        update.manifest.version.as_str()
+    } else {
+        current_version()
+    };
+    for asset in &update.assets {
+        asset.install(firmware)?;
+    }
+
+    if update.firmware {
+        let manifest = &update.manifest;
+        let path = format!("{}/{}.bin", FIRMWARE_DIR, file_stem(&manifest.version));
+        download_verified(&manifest.url, &path, &manifest.sha256)?;
+
+        let result = flash(&path);
+        if let Err(e) = std::fs::remove_file(&path) {
+            log::warn!("Failed to remove {}: {}", path, e);
+        }
+        result?;
+        log::info!(
+            "Firmware {} installed, it starts after a reboot",
+            manifest.version
+        );
+    }
+    Ok(())
+}
+
+/// Write the model and voice partition images staged by `install`, call it before the speech
+/// models and the TTS voice are loaded
+///
+/// A new firmware has to confirm itself first, `mark_running_valid` then reboots to get here
+/// again.
+pub fn install_staged_assets() {
+    assets::install_staged(current_version(), !is_pending_verify());
+}
+
+/// Confirm the running firmware works
+///
+/// With rollback enabled in the bootloader, an update that never gets this far is replaced
+/// by the previous firmware on the next reset.
+pub fn mark_running_valid() {
+    let confirming = is_pending_verify();
+    if let Err(e) = EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        log::warn!("Failed to mark the running firmware as valid: {}", e);
+        return;
+    }
+    if confirming && assets::has_staged() {
+        log::info!("Firmware confirmed, rebooting to install the new models and voice data");
+        console::reboot();
+    }
+}
+
+/// Whether the running firmware was just updated and hasn't confirmed itself yet
+fn is_pending_verify() -> bool {
+    let mut state = 0;
+    let running = unsafe { sys::esp_ota_get_running_partition() };
+    esp!(unsafe { sys::esp_ota_get_state_partition(running, &mut state) }).is_ok()
+        && state == sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
+
+/// Check for updates every `check_interval_hours` in the background
+///
+/// Updates found are only logged unless `auto_install` is set, then the conversation worker
+/// installs them between utterances.
+pub fn start_scheduled_checks(
+    config: OtaConfig,
+    models: SpeechModelConfig,
+    transcription_tx: Sender<TranscriptionMessage>,
+) -> anyhow::Result<()> {
+    if config.manifest_url.is_empty() || config.check_interval_hours == 0 {
+        return Ok(());
+    }
+
+    let interval = Duration::from_secs(config.check_interval_hours as u64 * 3600);
+    std::thread::Builder::new()
+        .name("ota_check".to_string())
+        .stack_size(8 * 1024)
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            if !connectivity::has_wifi() {
+                continue;
+            }
+
+            match check(&config, &models) {
+                Ok(Some(_)) if config.auto_install => {
+                    let _ = transcription_tx.send(TranscriptionMessage::UpdateFirmware);
+                }
+                Ok(Some(update)) => log::info!(
+                    "Say \"更新固件\" or type 'ota' on the console to install {}",
+                    update.spoken_summary()
+                ),
+                Ok(None) => {}
+                Err(e) => log::warn!("Update check failed: {}", e),
+            }
+        })?;
+
+    log::info!(
+        "Checking for updates every {} hours",
+        config.check_interval_hours
+    );
+    Ok(())
+}
+
+fn fetch_manifest(url: &str) -> anyhow::Result<String> {
+    let http_config = HttpConfiguration {
+        timeout: Some(MANIFEST_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+    let mut client = PooledConnection::open(url, &http_config)?;
+    client.send(Method::Get, |client| {
+        client
+            .initiate_request(Method::Get, url, &[])
+            .map_err(|e| anyhow::anyhow!("Failed to request the manifest: {}", e))?;
+        client
+            .initiate_response()
+            .map_err(|e| anyhow::anyhow!("Failed to get the manifest: {}", e))
+    })?;
+
+    if client.status() != 200 {
+        return Err(HttpError::from_response(&mut client).into());
+    }
+    let body = read_response_body(&mut client)?;
+    client.release();
+    Ok(body)
+}
+
+/// Download a file to the SD card and check it against the manifest's hash, returns its size
+fn download_verified(url: &str, path: &str, expected_sha256: &str) -> anyhow::Result<u64> {
+    let mut logged_percent = 0;
+    let size = download_to_file(url, path, |received, total| {
+        let Some(total) = total.filter(|&total| total > 0) else {
+            return;
+        };
+        let percent = received * 100 / total;
+        if percent >= logged_percent + 10 {
+            logged_percent = percent;
+            log::info!("Downloaded {}% of {}", percent, url);
+        }
+    })?;
+
+    let digest = file_sha256(path)?;
+    if !digest.eq_ignore_ascii_case(expected_sha256.trim()) {
+        // Would otherwise be taken for a complete download by the next attempt
+        let _ = std::fs::remove_file(path);
+        return Err(anyhow::anyhow!(
+            "{} has SHA-256 {}, the manifest expects {}",
+            url,
+            digest,
+            expected_sha256
+        ));
+    }
+    Ok(size)
+}
+
+fn file_sha256(path: &str) -> anyhow::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; WRITE_CHUNK_SIZE];
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(to_hex(&hasher.finish()))
+}
+
+/// Write a verified image to the next OTA slot and make it the boot partition
+fn flash(path: &str) -> anyhow::Result<()> {
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+
+    if let Err(e) = write_image(&mut update, path) {
+        let _ = update.abort();
+        return Err(e);
+    }
+    // Checks the image header and switches the boot partition
+    update.complete()?;
+    Ok(())
+}
+
+fn write_image(update: &mut EspOtaUpdate<'_>, path: &str) -> anyhow::Result<()> {
+    let mut file = std::fs::File::open(path)?;
+    let mut buffer = vec![0u8; WRITE_CHUNK_SIZE];
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        update.write(&buffer[..bytes_read])?;
+    }
+    Ok(())
+}
+
+/// SHA-256 through mbedTLS, which uses the hardware accelerator
+struct Sha256(sys::mbedtls_sha256_context);
+
+impl Sha256 {
+    fn new() -> Self {
+        let mut context = sys::mbedtls_sha256_context::default();
+        unsafe {
+            sys::mbedtls_sha256_init(&mut context);
+            sys::mbedtls_sha256_starts(&mut context, 0);
+        }
+        Self(context)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        unsafe { sys::mbedtls_sha256_update(&mut self.0, data.as_ptr(), data.len()) };
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let mut digest = [0u8; 32];
+        unsafe { sys::mbedtls_sha256_finish(&mut self.0, digest.as_mut_ptr()) };
+        digest
+    }
+}
+
+impl Drop for Sha256 {
+    fn drop(&mut self) {
+        unsafe { sys::mbedtls_sha256_free(&mut self.0) };
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Whether `candidate` is a higher dotted version than `current`, pre-release suffixes are ignored
+fn is_newer(candidate: &str, current: &str) -> bool {
+    let parse = |version: &str| -> Vec<u64> {
+        version
+            .trim()
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or("")
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    parse(candidate) > parse(current)
+}
+
+/// Part of the version that is safe in a file name
+fn file_stem(version: &str) -> String {
+    version
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '-')
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("0.2.0", "0.1.0"));
+        assert!(is_newer("v0.10.0", "0.9.3"));
+        assert!(is_newer("1.0", "0.9.9"));
+        assert!(!is_newer("0.1.0", "0.1.0"));
+        assert!(!is_newer("0.1.0-beta", "0.1.0"));
+        assert!(!is_newer("0.0.9", "0.1.0"));
+    }
+
+    #[test]
+    fn test_file_stem() {
+        assert_eq!(file_stem("0.2.0-rc1"), "0.2.0-rc1");
+        assert_eq!(file_stem("../../boot"), "....boot");
+    }
+}
@@ -0,0 +1,345 @@
+use esp_idf_svc::sys::{self, esp};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io::Read;
+
+use super::{download_verified, to_hex, Sha256, FIRMWARE_DIR, WRITE_CHUNK_SIZE};
+use crate::config::{ModelLocation, SpeechModelConfig};
+use crate::sd_card::is_safe_relative_path;
+
+/// Fingerprints of the assets installed so far, so each is only written once
+const INSTALLED_FILE: &str = "/vfat/ota/installed.json";
+/// Partition images downloaded and verified, waiting to be written at the next boot
+const STAGED_FILE: &str = "/vfat/ota/staged.json";
+/// Partition the TTS engine maps its voice from
+const VOICE_DATA_PARTITION: &str = "voice_data";
+/// Flash is erased in sectors of this size
+const SECTOR_SIZE: usize = 4096;
+
+/// Speech models and TTS voice data offered by the manifest, all optional
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AssetManifest {
+    /// srmodels.bin for a flash model partition
+    pub models: Option<Image>,
+    /// Model files for the SD card, relative to `speech_models.path`
+    pub model_files: Vec<ModelFile>,
+    /// esp-tts voice data for the voice_data partition
+    pub voice_data: Option<Image>,
+}
+
+/// A partition image to download
+#[derive(Debug, Clone, Deserialize)]
+pub struct Image {
+    pub url: String,
+    pub sha256: String,
+}
+
+/// One file of a model directory, e.g. "wn9_hilexin/_MODEL_INFO_"
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelFile {
+    pub path: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+/// A verified partition image on the SD card
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StagedImage {
+    key: String,
+    partition: String,
+    path: String,
+    sha256: String,
+    /// Firmware the image goes with, it is dropped when another one boots, e.g. after a rollback
+    firmware: String,
+}
+
+/// An asset that differs from the installed one
+#[derive(Debug, Clone)]
+pub enum Asset {
+    ModelPartition { partition: String, image: Image },
+    ModelFiles { dir: String, files: Vec<ModelFile> },
+    VoiceData(Image),
+}
+
+impl Asset {
+    /// Name in the list of installed assets
+    fn key(&self) -> &'static str {
+        match self {
+            Asset::ModelPartition { .. } => "models",
+            Asset::ModelFiles { .. } => "model_files",
+            Asset::VoiceData(_) => "voice_data",
+        }
+    }
+
+    fn fingerprint(&self) -> String {
+        match self {
+            Asset::ModelPartition { image, .. } | Asset::VoiceData(image) => image.sha256.clone(),
+            Asset::ModelFiles { files, .. } => files_fingerprint(files),
+        }
+    }
+
+    pub fn spoken_name(&self) -> &'static str {
+        match self {
+            Asset::ModelPartition { .. } | Asset::ModelFiles { .. } => "语音识别模型",
+            Asset::VoiceData(_) => "语音合成数据",
+        }
+    }
+
+    /// Download and verify the asset for `firmware`
+    ///
+    /// Model files are installed right away. Partition images are only staged, the models and
+    /// the TTS voice map their partitions while running, see `install_staged`.
+    pub fn install(&self, firmware: &str) -> anyhow::Result<()> {
+        let (partition, image) = match self {
+            Asset::ModelPartition { partition, image } => (partition.as_str(), image),
+            Asset::VoiceData(image) => (VOICE_DATA_PARTITION, image),
+            Asset::ModelFiles { dir, files } => {
+                install_files(dir, files)?;
+                return record_installed(self.key(), &self.fingerprint());
+            }
+        };
+
+        let path = format!("{}/{}.bin", FIRMWARE_DIR, partition);
+        download_verified(&image.url, &path, &image.sha256)?;
+        let mut staged = load_staged();
+        staged.retain(|staged| staged.key != self.key());
+        staged.push(StagedImage {
+            key: self.key().to_string(),
+            partition: partition.to_string(),
+            path,
+            sha256: image.sha256.clone(),
+            firmware: firmware.to_string(),
+        });
+        std::fs::write(STAGED_FILE, serde_json::to_string_pretty(&staged)?)?;
+        log::info!("Staged new {} for the next boot", self.key());
+        Ok(())
+    }
+}
+
+/// Write the partition images staged for `running_firmware`, before anything maps them
+///
+/// Only done once the firmware confirmed itself. Images staged for another firmware are
+/// thrown away, their fingerprint was never recorded so the next check offers them again.
+pub fn install_staged(running_firmware: &str, firmware_confirmed: bool) {
+    let staged = load_staged();
+    if staged.is_empty() {
+        return;
+    }
+    let for_running = staged
+        .iter()
+        .all(|image| image.firmware == running_firmware);
+    if for_running && !firmware_confirmed {
+        log::info!("New models and voice data wait for the firmware to confirm itself");
+        return;
+    }
+
+    for image in &staged {
+        if !for_running {
+            log::warn!(
+                "Dropping the new {}, it was meant for firmware {}",
+                image.key,
+                image.firmware
+            );
+        } else if let Err(e) = write_partition(&image.partition, &image.path, &image.sha256)
+            .and_then(|()| record_installed(&image.key, &image.sha256))
+        {
+            log::error!("Failed to install the new {}: {}", image.key, e);
+        }
+        if let Err(e) = std::fs::remove_file(&image.path) {
+            log::warn!("Failed to remove {}: {}", image.path, e);
+        }
+    }
+    if let Err(e) = std::fs::remove_file(STAGED_FILE) {
+        log::warn!("Failed to remove {}: {}", STAGED_FILE, e);
+    }
+}
+
+/// Whether partition images wait for the next boot
+pub fn has_staged() -> bool {
+    !load_staged().is_empty()
+}
+
+/// Assets of the manifest that aren't installed yet, for where this device keeps its models
+pub fn pending(manifest: &AssetManifest, models: &SpeechModelConfig) -> Vec<Asset> {
+    let mut assets = Vec::new();
+
+    // Like speech_recognition::load_models
+    let model_partition = models.location != ModelLocation::SdCard
+        && cfg!(esp_idf_model_in_flash)
+        && find_partition(&models.partition).is_some();
+    match (&manifest.models, model_partition) {
+        (Some(image), true) => assets.push(Asset::ModelPartition {
+            partition: models.partition.clone(),
+            image: image.clone(),
+        }),
+        (_, false) if !manifest.model_files.is_empty() => assets.push(Asset::ModelFiles {
+            dir: models.path.clone(),
+            files: manifest.model_files.clone(),
+        }),
+        _ => {}
+    }
+    if let Some(image) = &manifest.voice_data {
+        assets.push(Asset::VoiceData(image.clone()));
+    }
+
+    let installed = load_installed();
+    let staged = load_staged();
+    assets.retain(|asset| {
+        let fingerprint = asset.fingerprint();
+        installed.get(asset.key()) != Some(&fingerprint)
+            && !staged
+                .iter()
+                .any(|image| image.key == asset.key() && image.sha256 == fingerprint)
+    });
+    assets
+}
+
+fn load_installed() -> BTreeMap<String, String> {
+    std::fs::read_to_string(INSTALLED_FILE)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn record_installed(key: &str, fingerprint: &str) -> anyhow::Result<()> {
+    let mut installed = load_installed();
+    installed.insert(key.to_string(), fingerprint.to_string());
+    std::fs::write(INSTALLED_FILE, serde_json::to_string_pretty(&installed)?)?;
+    log::info!("Installed new {}", key);
+    Ok(())
+}
+
+fn load_staged() -> Vec<StagedImage> {
+    std::fs::read_to_string(STAGED_FILE)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+/// Changes whenever any file is added, removed or changed
+fn files_fingerprint(files: &[ModelFile]) -> String {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(file.path.as_bytes());
+        hasher.update(file.sha256.as_bytes());
+    }
+    to_hex(&hasher.finish())
+}
+
+fn find_partition(label: &str) -> Option<&'static sys::esp_partition_t> {
+    let label = CString::new(label).ok()?;
+    let partition = unsafe {
+        sys::esp_partition_find_first(
+            sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            sys::esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+            label.as_ptr(),
+        )
+    };
+    unsafe { partition.as_ref() }
+}
+
+/// Replace the contents of a data partition with the verified image at `path`
+///
+/// Nothing may map the partition meanwhile. It is read back afterwards, only a matching
+/// digest counts as installed.
+fn write_partition(label: &str, path: &str, sha256: &str) -> anyhow::Result<()> {
+    let partition =
+        find_partition(label).ok_or_else(|| anyhow::anyhow!("No '{}' partition", label))?;
+    let size = std::fs::metadata(path)?.len() as usize;
+    if size > partition.size as usize {
+        return Err(anyhow::anyhow!(
+            "Image of {} bytes doesn't fit the '{}' partition of {} bytes",
+            size,
+            label,
+            partition.size
+        ));
+    }
+
+    log::info!("Writing {} bytes to the '{}' partition", size, label);
+    let erase_size = size.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+    esp!(unsafe { sys::esp_partition_erase_range(partition, 0, erase_size) })?;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut buffer = vec![0u8; WRITE_CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        esp!(unsafe {
+            sys::esp_partition_write(partition, offset, buffer.as_ptr() as *const _, bytes_read)
+        })?;
+        offset += bytes_read;
+    }
+
+    // Read back, a flash write can fail silently on a worn sector
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    while offset < size {
+        let len = WRITE_CHUNK_SIZE.min(size - offset);
+        esp!(unsafe {
+            sys::esp_partition_read(partition, offset, buffer.as_mut_ptr() as *mut _, len)
+        })?;
+        hasher.update(&buffer[..len]);
+        offset += len;
+    }
+    let digest = to_hex(&hasher.finish());
+    if !digest.eq_ignore_ascii_case(sha256.trim()) {
+        return Err(anyhow::anyhow!(
+            "The '{}' partition reads back as {}, expected {}",
+            label,
+            digest,
+            sha256
+        ));
+    }
+    Ok(())
+}
+
+/// Download every model file next to the models, then move them all into place
+fn install_files(dir: &str, files: &[ModelFile]) -> anyhow::Result<()> {
+    let mut downloaded = Vec::new();
+    for file in files {
+        if !is_safe_relative_path(&file.path) {
+            return Err(anyhow::anyhow!(
+                "Model file path '{}' is not allowed",
+                file.path
+            ));
+        }
+        let target = format!("{}/{}", dir, file.path);
+        let staging = format!("{}.new", target);
+        if let Some(parent) = std::path::Path::new(&target).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        download_verified(&file.url, &staging, &file.sha256)?;
+        downloaded.push((staging, target));
+    }
+
+    // Only replaced once all of them arrived, a half updated model wouldn't load
+    for (staging, target) in downloaded {
+        if std::path::Path::new(&target).exists() {
+            std::fs::remove_file(&target)?;
+        }
+        std::fs::rename(&staging, &target)?;
+    }
+    log::info!("Updated {} model files in {}", files.len(), dir);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asset_manifest() {
+        let manifest: AssetManifest = serde_json::from_str(
+            r#"{"voice_data": {"url": "https://example.com/voice.dat", "sha256": "ab"}}"#,
+        )
+        .unwrap();
+        assert!(manifest.models.is_none());
+        assert!(manifest.model_files.is_empty());
+        assert_eq!(manifest.voice_data.unwrap().sha256, "ab");
+    }
+}
@@ -0,0 +1,608 @@
+use esp_idf_svc::hal::{
+    gpio::{Output, OutputPin, PinDriver},
+    i2s::{I2sDriver, I2sTx},
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio_processing::{self, State};
+use crate::cloud_tts::CloudTts;
+use crate::config::{ThinkingConfig, ThinkingSound};
+use crate::dashboard::{self, LiveEvent};
+use crate::earcon::Earcon;
+use crate::metrics;
+use crate::stacks;
+use crate::transcription::TranscriptionEvent;
+use crate::tts::{play_samples, TtsEngine};
+use crate::usage;
+use crate::watchdog;
+
+/// Requests to the playback thread, played in the order they were sent
+enum PlaybackCommand {
+    Speak {
+        text: String,
+        /// Use the cloud voice if one is configured
+        cloud_voice: bool,
+        /// Report PlaybackFinished to the fetch task once played
+        is_reply: bool,
+        /// Cut the speech off at this time, wherever it got to
+        stop_at: Option<Instant>,
+    },
+    /// Play a reply synthesized elsewhere, signalling `played` once it's done
+    PlayAudio {
+        samples: Vec<i16>,
+        played: Sender<()>,
+    },
+    /// Play audio that isn't a reply, e.g. an announcement recorded on a phone, as its 16 kHz
+    /// mono chunks arrive until the sender is dropped
+    PlayClip(Receiver<Vec<i16>>),
+    Earcon(Earcon),
+    SetSpeed(u32),
+    SetVolume(u8),
+    SetCloudTts(Option<CloudTts>),
+    /// Speak the last reply again
+    ReplayLast,
+    /// Play the thinking feedback while the speaker is idle, until StopThinking
+    StartThinking(ThinkingConfig),
+    StopThinking,
+    /// Play 16 kHz mono chunks of e.g. a radio station whenever nothing else plays, until
+    /// StopStream or the sender is dropped
+    PlayStream(Receiver<Vec<i16>>),
+    StopStream,
+    /// Hold the stream where it is until ResumeStream
+    PauseStream,
+    ResumeStream,
+}
+
+/// Set from the web dashboard, speech is still synthesized and timed but the amplifier stays off
+static MUTED: AtomicBool = AtomicBool::new(false);
+/// Set by the stop button, whatever is playing ends at the next chunk of audio
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Deadline of the speech playing right now, if it has one
+static STOP_AT: Mutex<Option<Instant>> = Mutex::new(None);
+/// Set while speech or a reply is playing, not for earcons
+static PLAYING: AtomicBool = AtomicBool::new(false);
+/// Set while a thinking guard is alive, i.e. while the LLM is working on a reply
+static THINKING: AtomicBool = AtomicBool::new(false);
+/// Set while a stream is playing or waiting for speech to finish
+static STREAMING: AtomicBool = AtomicBool::new(false);
+/// For announcements that can't wait for the transcription worker, set once the thread started
+static ANNOUNCE_TX: Mutex<Option<Sender<PlaybackCommand>>> = Mutex::new(None);
+
+/// Spoken when asked to repeat before anything was answered
+const NOTHING_TO_REPLAY: &str = "还没有可以重复的回答";
+/// Its peak use is reported by the stacks module
+const PLAYBACK_STACK_SIZE: usize = 16 * 1024;
+/// How long to wait for the next chunk of a stream before checking for commands again
+const STREAM_CHUNK_TIMEOUT: Duration = Duration::from_millis(100);
+/// A clip whose next chunk takes longer than this to arrive is cut off, its sender stalled
+const CLIP_CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reply kept for the "再说一遍" commands
+struct LastReply {
+    text: String,
+    /// Audio of a reply spoken in the cloud voice, replaying it needs no network
+    samples: Option<Vec<i16>>,
+}
+
+/// Thinking feedback in progress on the playback thread
+struct Thinking {
+    config: ThinkingConfig,
+    next_at: Instant,
+    /// The filler phrase is only said the first time
+    said_phrase: bool,
+}
+
+/// Last stage of the pipeline: synthesizes and plays speech on its own thread,
+/// so the next utterance can be transcribed and answered meanwhile
+pub struct Playback {
+    tx: Sender<PlaybackCommand>,
+}
+
+impl Playback {
+    /// Start the playback thread, it owns the speaker from now on
+    pub fn start(
+        tts_engine: TtsEngine,
+        cloud_tts: Option<CloudTts>,
+        i2s_driver: I2sDriver<'static, I2sTx>,
+        sd_pin_driver: PinDriver<'static, impl OutputPin, Output>,
+        event_tx: Sender<TranscriptionEvent>,
+        duck_percent: u8,
+    ) -> anyhow::Result<Self> {
+        let (tx, rx) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("playback".to_string())
+            .stack_size(PLAYBACK_STACK_SIZE) // Increase stack size for TTS operations
+            .spawn(move || {
+                let _stack = stacks::register("playback", PLAYBACK_STACK_SIZE);
+                playback_loop(
+                    rx,
+                    tts_engine,
+                    cloud_tts,
+                    i2s_driver,
+                    sd_pin_driver,
+                    event_tx,
+                    duck_percent,
+                )
+            })?;
+
+        *ANNOUNCE_TX.lock().unwrap() = Some(tx.clone());
+        Ok(Self { tx })
+    }
+
+    /// Speak a short message in the on-device voice
+    pub fn speak(&self, text: &str) {
+        self.send(PlaybackCommand::Speak {
+            text: text.to_string(),
+            cloud_voice: false,
+            is_reply: false,
+            stop_at: None,
+        });
+    }
+
+    /// Speak the reply to an utterance, in the cloud voice when asked for
+    pub fn speak_reply(&self, text: &str, cloud_voice: bool) {
+        dashboard::publish(LiveEvent::Reply {
+            text: text.to_string(),
+        });
+        self.send(PlaybackCommand::Speak {
+            text: text.to_string(),
+            cloud_voice,
+            is_reply: true,
+            stop_at: None,
+        });
+    }
+
+    /// Speak a long reply like `speak_reply`, stopping at `stop_at` if it's still playing then
+    pub fn speak_reply_until(&self, text: &str, cloud_voice: bool, stop_at: Instant) {
+        dashboard::publish(LiveEvent::Reply {
+            text: text.to_string(),
+        });
+        self.send(PlaybackCommand::Speak {
+            text: text.to_string(),
+            cloud_voice,
+            is_reply: true,
+            stop_at: Some(stop_at),
+        });
+    }
+
+    /// Play the audio of a reply at the output sample rate, reports PlaybackFinished like a reply
+    pub fn play_reply_audio(&self, samples: Vec<i16>, played: Sender<()>) {
+        self.send(PlaybackCommand::PlayAudio { samples, played });
+    }
+
+    /// Speak the last reply again without asking the LLM, reports PlaybackFinished like a reply
+    pub fn replay_last(&self) {
+        self.send(PlaybackCommand::ReplayLast);
+    }
+
+    pub fn earcon(&self, earcon: Earcon) {
+        self.send(PlaybackCommand::Earcon(earcon));
+    }
+
+    pub fn set_speed(&self, speed: u32) {
+        self.send(PlaybackCommand::SetSpeed(speed));
+    }
+
+    /// Volume in percent of full scale, applies to both voices and earcons
+    pub fn set_volume(&self, volume: u8) {
+        self.send(PlaybackCommand::SetVolume(volume));
+    }
+
+    /// Play the chunks of a stream whenever nothing else plays, replacing the last stream
+    pub fn play_stream(&self, chunks: Receiver<Vec<i16>>) {
+        self.send(PlaybackCommand::PlayStream(chunks));
+    }
+
+    pub fn stop_stream(&self) {
+        self.send(PlaybackCommand::StopStream);
+    }
+
+    pub fn pause_stream(&self) {
+        self.send(PlaybackCommand::PauseStream);
+    }
+
+    pub fn resume_stream(&self) {
+        self.send(PlaybackCommand::ResumeStream);
+    }
+
+    pub fn set_cloud_tts(&self, cloud_tts: Option<CloudTts>) {
+        self.send(PlaybackCommand::SetCloudTts(cloud_tts));
+    }
+
+    /// Play the thinking feedback until the returned guard is dropped, e.g. when the reply arrived
+    pub fn thinking(&self, config: &ThinkingConfig) -> ThinkingGuard<'_> {
+        THINKING.store(true, Ordering::Relaxed);
+        if config.sound != ThinkingSound::Off {
+            self.send(PlaybackCommand::StartThinking(config.clone()));
+        }
+        ThinkingGuard { playback: self }
+    }
+
+    fn send(&self, command: PlaybackCommand) {
+        if self.tx.send(command).is_err() {
+            log::error!("Playback thread has exited");
+        }
+    }
+}
+
+/// Stops the thinking feedback when dropped
+pub struct ThinkingGuard<'a> {
+    playback: &'a Playback,
+}
+
+impl Drop for ThinkingGuard<'_> {
+    fn drop(&mut self) {
+        THINKING.store(false, Ordering::Relaxed);
+        self.playback.send(PlaybackCommand::StopThinking);
+    }
+}
+
+fn playback_loop(
+    rx: Receiver<PlaybackCommand>,
+    mut tts_engine: TtsEngine,
+    mut cloud_tts: Option<CloudTts>,
+    mut i2s_driver: I2sDriver<'static, I2sTx>,
+    mut sd_pin_driver: PinDriver<'static, impl OutputPin, Output>,
+    event_tx: Sender<TranscriptionEvent>,
+    duck_percent: u8,
+) {
+    log::info!("Playback thread started");
+
+    let mut thinking: Option<Thinking> = None;
+    let mut last_reply: Option<LastReply> = None;
+    let mut stream: Option<Receiver<Vec<i16>>> = None;
+    let mut stream_paused = false;
+
+    loop {
+        let command = match (&mut thinking, &stream) {
+            // Streams play a chunk at a time, so commands don't wait for them
+            (None, Some(chunks)) if !stream_paused => match rx.try_recv() {
+                Ok(command) => command,
+                Err(TryRecvError::Empty) => {
+                    let volume = tts_engine.get_config().volume;
+                    let playing = play_stream_chunk(
+                        chunks,
+                        volume,
+                        duck_percent,
+                        &mut i2s_driver,
+                        &mut sd_pin_driver,
+                    );
+                    if !playing {
+                        stream = None;
+                        STREAMING.store(false, Ordering::Relaxed);
+                        sd_pin_driver.set_low().unwrap();
+                    }
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => break,
+            },
+            (Some(state), _) => {
+                match rx.recv_timeout(state.next_at.saturating_duration_since(Instant::now())) {
+                    Ok(command) => command,
+                    Err(RecvTimeoutError::Timeout) => {
+                        enable_amplifier(&mut sd_pin_driver);
+                        let volume = tts_engine.get_config().volume;
+                        let result = if state.config.sound == ThinkingSound::Phrase
+                            && !state.said_phrase
+                        {
+                            state.said_phrase = true;
+                            tts_engine.synthesize_and_play(&state.config.phrase, &mut i2s_driver)
+                        } else {
+                            Earcon::Thinking.play(volume, &mut i2s_driver)
+                        };
+                        sd_pin_driver.set_low().unwrap();
+
+                        if let Err(e) = result {
+                            log::warn!("Failed to play thinking feedback: {}", e);
+                        }
+                        state.next_at =
+                            Instant::now() + Duration::from_millis(state.config.interval_ms);
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            (None, _) => match rx.recv() {
+                Ok(command) => command,
+                Err(_) => break,
+            },
+        };
+        // A stop only applies to what was playing when it was asked for
+        STOP_REQUESTED.store(false, Ordering::Relaxed);
+        let _watch = watchdog::watch();
+        let plays_speech = matches!(
+            command,
+            PlaybackCommand::Speak { .. }
+                | PlaybackCommand::PlayAudio { .. }
+                | PlaybackCommand::PlayClip(_)
+                | PlaybackCommand::ReplayLast
+        );
+        PLAYING.store(plays_speech, Ordering::Relaxed);
+        let started = Instant::now();
+
+        match command {
+            PlaybackCommand::Speak {
+                text,
+                cloud_voice,
+                is_reply,
+                stop_at,
+            } => {
+                if is_reply {
+                    metrics::turn_answered();
+                }
+                *STOP_AT.lock().unwrap() = stop_at;
+                let volume = tts_engine.get_config().volume;
+                let mut cloud_samples = None;
+                dashboard::publish(LiveEvent::SpeechStarted { text: text.clone() });
+
+                enable_amplifier(&mut sd_pin_driver); // Enable the amplifier only while playing
+                let result = match &cloud_tts {
+                    Some(cloud) if cloud_voice => match cloud.synthesize(&text) {
+                        Ok(samples) => {
+                            metrics::record_time("tts", started.elapsed());
+                            let result = play_samples(&samples, volume, &mut i2s_driver);
+                            cloud_samples = Some(samples);
+                            result
+                        }
+                        Err(e) => {
+                            // The on-device voice mangles other languages, but silence would be worse
+                            log::warn!("Cloud TTS failed, using the on-device voice: {}", e);
+                            tts_engine.synthesize_and_play(&text, &mut i2s_driver)
+                        }
+                    },
+                    _ => tts_engine.synthesize_and_play(&text, &mut i2s_driver),
+                };
+                sd_pin_driver.set_low().unwrap();
+                *STOP_AT.lock().unwrap() = None;
+
+                if let Err(e) = result {
+                    log::error!("Failed to speak '{}': {}", text, e);
+                }
+                dashboard::publish(LiveEvent::SpeechFinished);
+
+                if is_reply {
+                    last_reply = Some(LastReply {
+                        text,
+                        samples: cloud_samples,
+                    });
+                    report_finished(&event_tx);
+                }
+            }
+            PlaybackCommand::PlayAudio { samples, played } => {
+                metrics::turn_answered();
+                enable_amplifier(&mut sd_pin_driver);
+                let result = play_samples(&samples, tts_engine.get_config().volume, &mut i2s_driver);
+                sd_pin_driver.set_low().unwrap();
+
+                if let Err(e) = result {
+                    log::error!("Failed to play the reply audio: {}", e);
+                }
+
+                // Replaying needs the audio, there's no text to synthesize it again from
+                last_reply = Some(LastReply {
+                    text: String::new(),
+                    samples: Some(samples),
+                });
+                report_finished(&event_tx);
+                let _ = played.send(());
+            }
+            PlaybackCommand::PlayClip(chunks) => {
+                let volume = tts_engine.get_config().volume;
+                enable_amplifier(&mut sd_pin_driver);
+                loop {
+                    let samples = match chunks.recv_timeout(CLIP_CHUNK_TIMEOUT) {
+                        Ok(samples) => samples,
+                        Err(RecvTimeoutError::Timeout) => {
+                            log::warn!("The audio clip stalled, cutting it off");
+                            break;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    if let Err(e) = play_samples(&samples, volume, &mut i2s_driver) {
+                        log::error!("Failed to play the audio clip: {}", e);
+                        break;
+                    }
+                    if stop_requested() {
+                        break;
+                    }
+                }
+                sd_pin_driver.set_low().unwrap();
+            }
+            PlaybackCommand::ReplayLast => {
+                enable_amplifier(&mut sd_pin_driver);
+                let result = match &last_reply {
+                    Some(LastReply {
+                        samples: Some(samples),
+                        ..
+                    }) => play_samples(samples, tts_engine.get_config().volume, &mut i2s_driver),
+                    Some(reply) => tts_engine.synthesize_and_play(&reply.text, &mut i2s_driver),
+                    None => tts_engine.synthesize_and_play(NOTHING_TO_REPLAY, &mut i2s_driver),
+                };
+                sd_pin_driver.set_low().unwrap();
+
+                if let Err(e) = result {
+                    log::error!("Failed to replay the last reply: {}", e);
+                }
+                report_finished(&event_tx);
+            }
+            PlaybackCommand::Earcon(earcon) => {
+                enable_amplifier(&mut sd_pin_driver);
+                if let Err(e) = earcon.play(tts_engine.get_config().volume, &mut i2s_driver) {
+                    log::warn!("Failed to play earcon: {}", e);
+                }
+                sd_pin_driver.set_low().unwrap();
+            }
+            PlaybackCommand::SetSpeed(speed) => {
+                let mut tts_config = tts_engine.get_config().clone();
+                tts_config.speed = speed;
+                tts_engine.set_config(tts_config);
+            }
+            PlaybackCommand::SetVolume(volume) => {
+                let mut tts_config = tts_engine.get_config().clone();
+                tts_config.volume = volume;
+                tts_engine.set_config(tts_config);
+            }
+            PlaybackCommand::SetCloudTts(cloud) => cloud_tts = cloud,
+            PlaybackCommand::StartThinking(config) => {
+                // Counted from when the speaker went idle, not from when the question was sent
+                thinking = Some(Thinking {
+                    next_at: Instant::now() + Duration::from_millis(config.delay_ms),
+                    config,
+                    said_phrase: false,
+                });
+            }
+            PlaybackCommand::StopThinking => thinking = None,
+            PlaybackCommand::PlayStream(chunks) => {
+                stream = Some(chunks);
+                stream_paused = false;
+                STREAMING.store(true, Ordering::Relaxed);
+            }
+            PlaybackCommand::StopStream => {
+                // The stream's thread notices the receiver is gone and stops fetching
+                stream = None;
+                STREAMING.store(false, Ordering::Relaxed);
+                sd_pin_driver.set_low().unwrap();
+            }
+            // The stream's thread blocks once its buffer is full, so nothing is skipped
+            PlaybackCommand::PauseStream => {
+                stream_paused = true;
+                STREAMING.store(false, Ordering::Relaxed);
+                sd_pin_driver.set_low().unwrap();
+            }
+            PlaybackCommand::ResumeStream => {
+                stream_paused = false;
+                STREAMING.store(stream.is_some(), Ordering::Relaxed);
+            }
+        }
+        PLAYING.store(false, Ordering::Relaxed);
+        if plays_speech {
+            usage::record_speaking(started.elapsed());
+        }
+    }
+
+    log::info!("Playback thread terminated");
+}
+
+/// Silence the speaker from the next sound on, without touching the volume
+pub fn set_muted(muted: bool) {
+    MUTED.store(muted, Ordering::Relaxed);
+    log::info!("Speaker {}", if muted { "muted" } else { "unmuted" });
+}
+
+pub fn is_muted() -> bool {
+    MUTED.load(Ordering::Relaxed)
+}
+
+/// Speech or a reply is playing right now
+pub fn is_playing() -> bool {
+    PLAYING.load(Ordering::Relaxed)
+}
+
+/// The LLM is working on a reply
+pub fn is_thinking() -> bool {
+    THINKING.load(Ordering::Relaxed)
+}
+
+/// A stream such as a radio station or music is playing, or waiting for speech to finish
+pub fn is_streaming() -> bool {
+    STREAMING.load(Ordering::Relaxed)
+}
+
+/// Play `earcon` and speak `text` without queueing behind the transcription worker, which may
+/// be waiting for the LLM, e.g. for a reminder in the middle of a conversation
+///
+/// False before the playback thread started.
+pub fn announce(earcon: Earcon, text: &str) -> bool {
+    let tx = ANNOUNCE_TX.lock().unwrap();
+    let Some(tx) = tx.as_ref() else {
+        return false;
+    };
+    let speak = PlaybackCommand::Speak {
+        text: text.to_string(),
+        cloud_voice: false,
+        is_reply: false,
+        stop_at: None,
+    };
+    tx.send(PlaybackCommand::Earcon(earcon)).is_ok() && tx.send(speak).is_ok()
+}
+
+/// Play `earcon` and the 16 kHz mono chunks sent to `chunks` like `announce`
+pub fn announce_audio(earcon: Earcon, chunks: Receiver<Vec<i16>>) -> bool {
+    let tx = ANNOUNCE_TX.lock().unwrap();
+    let Some(tx) = tx.as_ref() else {
+        return false;
+    };
+    tx.send(PlaybackCommand::Earcon(earcon)).is_ok()
+        && tx.send(PlaybackCommand::PlayClip(chunks)).is_ok()
+}
+
+/// Cut the speech or reply playing right now short
+pub fn stop_speaking() {
+    STOP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Checked by the synthesis and playback loops between chunks of audio
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::Relaxed)
+        || STOP_AT
+            .lock()
+            .unwrap()
+            .is_some_and(|stop_at| Instant::now() >= stop_at)
+}
+
+fn enable_amplifier(sd_pin_driver: &mut PinDriver<'static, impl OutputPin, Output>) {
+    if !is_muted() {
+        sd_pin_driver.set_high().unwrap();
+    }
+}
+
+/// Play the next chunk of a stream, false once it ended or the stop button was pressed
+fn play_stream_chunk(
+    chunks: &Receiver<Vec<i16>>,
+    volume: u8,
+    duck_percent: u8,
+    i2s_driver: &mut I2sDriver<'static, I2sTx>,
+    sd_pin_driver: &mut PinDriver<'static, impl OutputPin, Output>,
+) -> bool {
+    if stop_requested() {
+        log::info!("Stream stopped");
+        return false;
+    }
+    // Turned down after the wake word so the microphone can make out the user over it
+    let recording = audio_processing::current_state() == State::Recording;
+    let volume = if recording {
+        (volume as u32 * duck_percent as u32 / 100) as u8
+    } else {
+        volume
+    };
+    if recording && volume == 0 {
+        sd_pin_driver.set_low().unwrap();
+        thread::sleep(STREAM_CHUNK_TIMEOUT);
+        return true;
+    }
+
+    match chunks.recv_timeout(STREAM_CHUNK_TIMEOUT) {
+        Ok(samples) => {
+            enable_amplifier(sd_pin_driver);
+            if let Err(e) = play_samples(&samples, volume, i2s_driver) {
+                log::warn!("Failed to play the stream: {}", e);
+            }
+            true
+        }
+        // Still buffering
+        Err(RecvTimeoutError::Timeout) => true,
+        Err(RecvTimeoutError::Disconnected) => false,
+    }
+}
+
+/// Tell the fetch task the reply to the last utterance has been played
+fn report_finished(event_tx: &Sender<TranscriptionEvent>) {
+    if event_tx.send(TranscriptionEvent::PlaybackFinished).is_err() {
+        log::warn!("Fetch task is no longer listening for events");
+    }
+}
@@ -0,0 +1,137 @@
+use esp_idf_svc::sys::{self, esp};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio_processing::{self, State};
+use crate::config::{PowerConfig, PowerSaveMode};
+use crate::playback;
+use crate::wifi;
+
+/// How often idle time is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// None until `start`. Idle is entered and left with the lock held, so an activity can't
+/// slip in between the check and the switch and leave the device idle while in use.
+static ACTIVITY: Mutex<Option<Activity>> = Mutex::new(None);
+/// What `leave_idle` goes back to, set by `start`
+static ACTIVE: Mutex<Option<ActiveMode>> = Mutex::new(None);
+
+struct Activity {
+    /// Last time somebody used the device
+    last: Instant,
+    /// Running at the idle clock with the modem asleep
+    idle: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ActiveMode {
+    cpu_mhz: u32,
+    /// None without WiFi
+    wifi_power_save: Option<PowerSaveMode>,
+    idle_cpu_mhz: u32,
+}
+
+/// Idle after `config.idle_minutes` without activity, does nothing when that is 0
+///
+/// Idling lowers the CPU clock and lets WiFi sleep through beacons. The microphone keeps
+/// feeding the AFE, so the wake word is still heard and brings everything back at once.
+/// `wifi_power_save` is the configured mode, None when WiFi didn't start.
+pub fn start(config: &PowerConfig, wifi_power_save: Option<PowerSaveMode>) -> anyhow::Result<()> {
+    if config.idle_minutes == 0 {
+        return Ok(());
+    }
+
+    *ACTIVE.lock().unwrap() = Some(ActiveMode {
+        cpu_mhz: sys::CONFIG_ESP_DEFAULT_CPU_FREQ_MHZ,
+        wifi_power_save,
+        idle_cpu_mhz: config.idle_cpu_mhz,
+    });
+    *ACTIVITY.lock().unwrap() = Some(Activity {
+        last: Instant::now(),
+        idle: false,
+    });
+
+    let idle_after = Duration::from_secs(config.idle_minutes as u64 * 60);
+    thread::Builder::new()
+        .name("power".to_string())
+        .stack_size(3 * 1024)
+        .spawn(move || power_loop(idle_after))?;
+
+    log::info!(
+        "Idling after {} minutes without activity",
+        config.idle_minutes
+    );
+    Ok(())
+}
+
+/// Somebody is using the device, leave idle right away and start counting again
+pub fn activity() {
+    let mut activity = ACTIVITY.lock().unwrap();
+    let Some(activity) = activity.as_mut() else {
+        return;
+    };
+    activity.last = Instant::now();
+    if std::mem::take(&mut activity.idle) {
+        leave_idle();
+    }
+}
+
+fn power_loop(idle_after: Duration) {
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+
+        // A long recording or reply is activity even without new events
+        if audio_processing::current_state() == State::Recording
+            || playback::is_playing()
+            || playback::is_streaming()
+            || playback::is_thinking()
+        {
+            activity();
+            continue;
+        }
+
+        let mut activity = ACTIVITY.lock().unwrap();
+        if let Some(activity) = activity.as_mut() {
+            if !activity.idle && activity.last.elapsed() >= idle_after {
+                activity.idle = true;
+                enter_idle();
+            }
+        }
+    }
+}
+
+fn enter_idle() {
+    let Some(active) = *ACTIVE.lock().unwrap() else {
+        return;
+    };
+    log::info!("Idle, CPU at {} MHz", active.idle_cpu_mhz);
+    set_cpu_mhz(active.idle_cpu_mhz);
+    if active.wifi_power_save.is_some() {
+        wifi::apply_power_save(PowerSaveMode::Max);
+    }
+}
+
+fn leave_idle() {
+    let Some(active) = *ACTIVE.lock().unwrap() else {
+        return;
+    };
+    log::info!("Active, CPU at {} MHz", active.cpu_mhz);
+    set_cpu_mhz(active.cpu_mhz);
+    if let Some(mode) = active.wifi_power_save {
+        wifi::apply_power_save(mode);
+    }
+}
+
+/// Run the CPU at a fixed clock, light sleep stays off since the microphone never stops
+fn set_cpu_mhz(mhz: u32) {
+    let config = sys::esp_pm_config_t {
+        max_freq_mhz: mhz as i32,
+        min_freq_mhz: mhz as i32,
+        light_sleep_enable: false,
+    };
+    let result = esp!(unsafe { sys::esp_pm_configure(&config as *const _ as *const _) });
+    if let Err(e) = result {
+        log::warn!("Failed to set the CPU clock to {} MHz: {}", mhz, e);
+    }
+}
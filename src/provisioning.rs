@@ -0,0 +1,329 @@
+use esp_idf_svc::http::server::{Configuration as HttpServerConfig, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, EspWifi,
+};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::known_networks::Credentials;
+
+mod ble;
+
+pub use ble::{release_bluetooth, run_ble_provisioning};
+
+/// Largest form the portal accepts
+const MAX_FORM_BYTES: usize = 512;
+
+/// Open a WiFi access point with a setup page until the user picks a network
+///
+/// The access point is named "AI-Chatbox-XXXX" after the end of the MAC address and has no
+/// password. Every DNS query is answered with the device's address, so phones show the page
+/// on their own. Returns None when nobody submitted the form within `timeout`.
+pub fn run_portal(
+    wifi: &mut EspWifi<'static>,
+    timeout: Duration,
+    last_error: Option<&str>,
+) -> anyhow::Result<Option<Credentials>> {
+    let ap_ssid = access_point_name(wifi);
+    let ap_config = AccessPointConfiguration {
+        ssid: ap_ssid
+            .as_str()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Access point name too long"))?,
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    };
+
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    // Keep the station interface so nearby networks can be scanned
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        ap_config,
+    ))?;
+    wifi.start()?;
+
+    let networks = scan_networks(wifi);
+    let ip = wifi.ap_netif().get_ip_info()?.ip;
+    log::warn!(
+        "No WiFi connection, join the access point '{}' and open http://{}/ to set one up",
+        ap_ssid,
+        ip
+    );
+
+    let page = portal_page(&networks, last_error);
+    let (tx, rx) = mpsc::channel();
+    let server = start_server(page, ip, tx)?;
+
+    let stop_dns = Arc::new(AtomicBool::new(false));
+    let dns = {
+        let stop_dns = stop_dns.clone();
+        std::thread::Builder::new()
+            .name("portal_dns".to_string())
+            .stack_size(4096)
+            .spawn(move || run_dns(ip, &stop_dns))?
+    };
+
+    let credentials = rx.recv_timeout(timeout).ok();
+
+    // Give the browser a moment to receive the response before the access point goes away
+    std::thread::sleep(Duration::from_secs(1));
+    drop(server);
+    stop_dns.store(true, Ordering::Relaxed);
+    let _ = dns.join();
+
+    match &credentials {
+        Some(credentials) => log::info!("Network '{}' chosen in the portal", credentials.ssid),
+        None => log::warn!("Nobody set up WiFi within {} seconds", timeout.as_secs()),
+    }
+    Ok(credentials)
+}
+
+fn access_point_name(wifi: &EspWifi<'static>) -> String {
+    match wifi.ap_netif().get_mac() {
+        Ok(mac) => format!("AI-Chatbox-{:02X}{:02X}", mac[4], mac[5]),
+        Err(_) => "AI-Chatbox".to_string(),
+    }
+}
+
+/// SSIDs of the networks in range, strongest first and without duplicates
+fn scan_networks(wifi: &mut EspWifi<'static>) -> Vec<String> {
+    let mut access_points = crate::wifi::scan(wifi);
+    access_points.sort_by(|a, b| b.1.cmp(&a.1));
+
+    let mut networks: Vec<String> = Vec::new();
+    for (ssid, _) in access_points {
+        if !networks.contains(&ssid) {
+            networks.push(ssid);
+        }
+    }
+    networks
+}
+
+fn start_server(
+    page: String,
+    ip: Ipv4Addr,
+    tx: mpsc::Sender<Credentials>,
+) -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&HttpServerConfig {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Get, move |req| -> anyhow::Result<()> {
+        req.into_response(200, None, &[("Content-Type", "text/html; charset=utf-8")])?
+            .write_all(page.as_bytes())?;
+        Ok(())
+    })?;
+
+    server.fn_handler(
+        "/save",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let mut body = Vec::new();
+            let mut buffer = [0u8; 128];
+            while body.len() < MAX_FORM_BYTES {
+                let n = req.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                body.extend_from_slice(&buffer[..n]);
+            }
+
+            let form = parse_form(&String::from_utf8_lossy(&body));
+            let field = |name: &str| {
+                form.iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or_default()
+            };
+            // A network typed in by hand wins over the list, hidden networks aren't in it
+            let mut ssid = field("manual_ssid");
+            if ssid.is_empty() {
+                ssid = field("ssid");
+            }
+            let password = field("password");
+
+            let message = if ssid.is_empty() {
+                "请选择或输入WiFi名称"
+            } else {
+                let _ = tx.send(Credentials { ssid, password });
+                "正在连接，现在可以关闭此页面。连接失败时配网热点会重新出现。"
+            };
+            req.into_response(200, None, &[("Content-Type", "text/html; charset=utf-8")])?
+                .write_all(message_page(message).as_bytes())?;
+            Ok(())
+        },
+    )?;
+
+    // Phones probe fixed URLs to detect captive portals, send all of them to the setup page
+    let location = format!("http://{}/", ip);
+    server.fn_handler("/*", Method::Get, move |req| -> anyhow::Result<()> {
+        req.into_response(302, Some("Found"), &[("Location", location.as_str())])?;
+        Ok(())
+    })?;
+
+    Ok(server)
+}
+
+/// Answer every DNS query with the portal's address until `stop` is set
+fn run_dns(ip: Ipv4Addr, stop: &AtomicBool) {
+    let socket = match UdpSocket::bind("0.0.0.0:53") {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("Failed to start the portal DNS server: {}", e);
+            return;
+        }
+    };
+    let _ = socket.set_read_timeout(Some(Duration::from_millis(500)));
+
+    let mut buffer = [0u8; 512];
+    while !stop.load(Ordering::Relaxed) {
+        let Ok((len, peer)) = socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        if let Some(reply) = dns_reply(&buffer[..len], ip) {
+            let _ = socket.send_to(&reply, peer);
+        }
+    }
+}
+
+/// Response to a standard query with one A record pointing at `ip`
+fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    const HEADER_LEN: usize = 12;
+    if query.len() < HEADER_LEN || query[2] & 0x80 != 0 {
+        return None;
+    }
+    let question_count = u16::from_be_bytes([query[4], query[5]]);
+    if question_count != 1 {
+        return None;
+    }
+
+    // Name labels end with a zero byte, followed by type and class
+    let mut end = HEADER_LEN;
+    while *query.get(end)? != 0 {
+        end += query[end] as usize + 1;
+    }
+    let question_end = end + 5;
+    if question_end > query.len() {
+        return None;
+    }
+
+    let mut reply = Vec::with_capacity(question_end + 16);
+    reply.extend_from_slice(&query[..2]);
+    // Response, recursion desired and available, no error
+    reply.extend_from_slice(&[0x81, 0x80]);
+    reply.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 0]);
+    reply.extend_from_slice(&query[HEADER_LEN..question_end]);
+    // Pointer to the name in the question, type A, class IN, TTL 60 s
+    reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+    reply.extend_from_slice(&ip.octets());
+    Some(reply)
+}
+
+/// Fields of an application/x-www-form-urlencoded body
+fn parse_form(body: &str) -> Vec<(String, String)> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (url_decode(key), url_decode(value))
+        })
+        .collect()
+}
+
+fn url_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const PAGE_HEAD: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+<title>AI Chatbox WiFi</title><style>body{font-family:sans-serif;margin:2em;max-width:30em}\
+select,input{width:100%;padding:.5em;margin:.3em 0 1em;box-sizing:border-box}\
+.error{color:#c00}</style></head><body>";
+
+fn portal_page(networks: &[String], last_error: Option<&str>) -> String {
+    let mut page = String::from(PAGE_HEAD);
+    page.push_str("<h2>连接WiFi</h2>");
+    if let Some(error) = last_error {
+        page.push_str(&format!("<p class=\"error\">{}</p>", escape_html(error)));
+    }
+
+    page.push_str(
+        "<form method=\"post\" action=\"/save\"><label>附近的网络</label><select name=\"ssid\">",
+    );
+    for ssid in networks {
+        let ssid = escape_html(ssid);
+        page.push_str(&format!("<option value=\"{}\">{}</option>", ssid, ssid));
+    }
+    page.push_str(
+        "</select><label>或输入网络名称（隐藏网络）</label><input name=\"manual_ssid\">\
+<label>密码（开放网络留空）</label><input name=\"password\" type=\"password\">\
+<input type=\"submit\" value=\"连接\"></form></body></html>",
+    );
+    page
+}
+
+fn message_page(message: &str) -> String {
+    format!("{}<p>{}</p></body></html>", PAGE_HEAD, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_form() {
+        let form = parse_form("ssid=My+Home%E7%BD%91&manual_ssid=&password=a%26b%3Dc");
+        assert_eq!(form[0], ("ssid".to_string(), "My Home网".to_string()));
+        assert_eq!(form[1], ("manual_ssid".to_string(), String::new()));
+        assert_eq!(form[2], ("password".to_string(), "a&b=c".to_string()));
+        assert_eq!(url_decode("100%"), "100%");
+    }
+
+    #[test]
+    fn test_dns_reply() {
+        // Query for "a.cn", type A, class IN
+        let query = [
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 1, b'a', 2, b'c', b'n', 0, 0, 1, 0, 1,
+        ];
+        let reply = dns_reply(&query, Ipv4Addr::new(192, 168, 71, 1)).unwrap();
+        assert_eq!(&reply[..2], &[0x12, 0x34]);
+        assert_eq!(&reply[6..8], &[0, 1]);
+        assert_eq!(&reply[reply.len() - 4..], &[192, 168, 71, 1]);
+
+        assert!(dns_reply(&query[..15], Ipv4Addr::LOCALHOST).is_none());
+    }
+}
@@ -0,0 +1,129 @@
+use esp_idf_svc::sys::{self, esp};
+use esp_idf_svc::wifi::{Configuration, EspWifi};
+use std::ffi::CString;
+use std::time::{Duration, Instant};
+
+use crate::config::BUILT_IN_BLE_POP_SECRET;
+use crate::known_networks::Credentials;
+
+/// Advertise the ESP provisioning service over BLE until a phone set up a working network
+///
+/// Works with Espressif's "ESP BLE Prov" apps: the device shows up as "PROV_XXXX" and asks
+/// for `pop` as the proof of possession, or for the one derived from the MAC when `pop` is
+/// empty. The manager joins the network itself and reports success or failure to the app, so
+/// a wrong password can be corrected right there. Returns None when no network was set up
+/// within `timeout`.
+pub fn run_ble_provisioning(
+    wifi: &mut EspWifi<'static>,
+    timeout: Duration,
+    pop: &str,
+) -> anyhow::Result<Option<Credentials>> {
+    let service_name = CString::new(service_name(wifi))?;
+    let pop = CString::new(proof_of_possession(wifi, pop)?)?;
+
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+
+    // Without a scheme event handler the Bluetooth memory stays reserved, so provisioning
+    // can run again when the chosen network doesn't work after all
+    let config = sys::wifi_prov_mgr_config_t {
+        scheme: unsafe { sys::wifi_prov_scheme_ble },
+        ..Default::default()
+    };
+    esp!(unsafe { sys::wifi_prov_mgr_init(config) })?;
+
+    let started = esp!(unsafe {
+        sys::wifi_prov_mgr_start_provisioning(
+            sys::wifi_prov_security_WIFI_PROV_SECURITY_1,
+            pop.as_ptr() as *const _,
+            service_name.as_ptr(),
+            std::ptr::null(),
+        )
+    });
+    if let Err(e) = started {
+        unsafe { sys::wifi_prov_mgr_deinit() };
+        return Err(e.into());
+    }
+    log::warn!(
+        "No WiFi connection, set one up with the ESP BLE Prov app on device {:?}",
+        service_name
+    );
+
+    let deadline = Instant::now() + timeout;
+    let mut credentials = None;
+    while Instant::now() < deadline {
+        std::thread::sleep(Duration::from_secs(1));
+        if !crate::wifi::has_ip(wifi) {
+            continue;
+        }
+
+        if let Ok(Configuration::Client(client)) = wifi.get_configuration() {
+            credentials = Some(Credentials {
+                ssid: client.ssid.to_string(),
+                password: client.password.to_string(),
+            });
+        }
+        // Leave the app time to read the connection status before BLE goes away
+        std::thread::sleep(Duration::from_secs(2));
+        break;
+    }
+
+    // Stops advertising if the manager hasn't already
+    unsafe { sys::wifi_prov_mgr_deinit() };
+
+    match &credentials {
+        Some(credentials) => log::info!("Network '{}' set up over BLE", credentials.ssid),
+        None => log::warn!(
+            "Nobody set up WiFi over BLE within {} seconds",
+            timeout.as_secs()
+        ),
+    }
+    Ok(credentials)
+}
+
+/// Hand the memory reserved for Bluetooth to the heap once WiFi is set up
+///
+/// The controller can't be started again afterwards, provisioning runs at boot only.
+pub fn release_bluetooth() {
+    let status = unsafe { sys::esp_bt_controller_get_status() };
+    if status != sys::esp_bt_controller_status_t_ESP_BT_CONTROLLER_STATUS_IDLE {
+        log::warn!("Bluetooth is still running, keeping its memory");
+        return;
+    }
+    match esp!(unsafe { sys::esp_bt_mem_release(sys::esp_bt_mode_t_ESP_BT_MODE_BTDM) }) {
+        Ok(()) => log::info!("Released the memory reserved for Bluetooth"),
+        Err(e) => log::warn!("Failed to release the memory of Bluetooth: {}", e),
+    }
+}
+
+/// `configured`, or the first 8 hex digits of SHA-256 over BLE_POP_SECRET and the MAC in
+/// uppercase hex, which ble_pop.sh prints for the device's label
+fn proof_of_possession(wifi: &EspWifi<'static>, configured: &str) -> anyhow::Result<String> {
+    if !configured.is_empty() {
+        return Ok(configured.to_string());
+    }
+    let secret = BUILT_IN_BLE_POP_SECRET
+        .ok_or_else(|| anyhow::anyhow!("No ble_pop configured and no BLE_POP_SECRET built in"))?;
+    let mac = wifi.sta_netif().get_mac()?;
+    let mac: String = mac.iter().map(|byte| format!("{:02X}", byte)).collect();
+
+    let input = format!("{}{}", secret, mac);
+    let mut digest = [0u8; 32];
+    if unsafe { sys::mbedtls_sha256(input.as_ptr(), input.len(), digest.as_mut_ptr(), 0) } != 0 {
+        return Err(anyhow::anyhow!("Failed to derive the proof of possession"));
+    }
+    log::info!("BLE proof of possession derived from MAC {}", mac);
+    Ok(digest[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// The apps only list devices whose name starts with "PROV_"
+fn service_name(wifi: &EspWifi<'static>) -> String {
+    match wifi.sta_netif().get_mac() {
+        Ok(mac) => format!("PROV_{:02X}{:02X}", mac[4], mac[5]),
+        Err(_) => "PROV_CHATBOX".to_string(),
+    }
+}
@@ -0,0 +1,139 @@
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use std::io::{ErrorKind, Read};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream, ReadOnlySource};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::cloud_tts::to_output_format;
+use crate::http_client::HttpError;
+use crate::stacks;
+
+/// Decoded chunks waiting to be played, a few seconds of audio to ride out network hiccups
+const BUFFERED_CHUNKS: usize = 64;
+/// A station that sends nothing for this long is given up on
+const READ_TIMEOUT: Duration = Duration::from_secs(15);
+/// The MP3 and AAC decoders need the room
+const RADIO_STACK_SIZE: usize = 24 * 1024;
+
+/// Fetch and decode the stream at `url` on its own thread
+///
+/// Chunks of 16 kHz mono audio come out of the receiver until the stream ends or fails, dropping
+/// the receiver stops the stream.
+pub fn open(url: &str) -> anyhow::Result<Receiver<Vec<i16>>> {
+    let (tx, rx) = mpsc::sync_channel(BUFFERED_CHUNKS);
+    let url = url.to_string();
+    thread::Builder::new()
+        .name("radio".to_string())
+        .stack_size(RADIO_STACK_SIZE)
+        .spawn(move || {
+            let _stack = stacks::register("radio", RADIO_STACK_SIZE);
+            match stream(&url, &tx) {
+                Ok(()) => log::info!("Stream {} ended", url),
+                Err(e) => log::warn!("Stream {} failed: {:#}", url, e),
+            }
+        })?;
+    Ok(rx)
+}
+
+fn stream(url: &str, tx: &SyncSender<Vec<i16>>) -> anyhow::Result<()> {
+    let http_config = HttpConfiguration {
+        timeout: Some(READ_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+    let mut connection = EspHttpConnection::new(&http_config)?;
+    // Without in-band titles, the decoder would take them for audio
+    connection.initiate_request(Method::Get, url, &[("Icy-MetaData", "0")])?;
+    connection.initiate_response()?;
+    if connection.status() != 200 {
+        return Err(HttpError::from_response(&mut connection).into());
+    }
+
+    let mut hint = Hint::new();
+    match connection.header("Content-Type") {
+        Some(content_type) if content_type.contains("aac") => hint.with_extension("aac"),
+        _ => hint.with_extension("mp3"),
+    };
+    log::info!("Playing stream {}", url);
+
+    decode(
+        Box::new(ReadOnlySource::new(HttpBody(connection))),
+        &hint,
+        tx,
+    )?;
+    Ok(())
+}
+
+/// Decode MP3 or AAC audio from `source` into chunks for `tx`
+///
+/// Returns false when the receiver was dropped before the audio ended.
+pub fn decode(
+    source: Box<dyn MediaSource>,
+    hint: &Hint,
+    tx: &SyncSender<Vec<i16>>,
+) -> anyhow::Result<bool> {
+    let source = MediaSourceStream::new(source, Default::default());
+    let probed = symphonia::default::get_probe().format(
+        hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| anyhow::anyhow!("Stream has no audio track"))?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Ok(true)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt frame only costs a few milliseconds of audio
+            Err(DecodeError::DecodeError(e)) => {
+                log::debug!("Skipping a corrupt frame: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = *decoded.spec();
+        let mut samples = SampleBuffer::<i16>::new(decoded.capacity() as u64, spec);
+        samples.copy_interleaved_ref(decoded);
+        let chunk = to_output_format(samples.samples(), spec.channels.count() as u16, spec.rate);
+        // The receiver is gone once the stream was stopped
+        if tx.send(chunk).is_err() {
+            return Ok(false);
+        }
+    }
+}
+
+/// Response body as a reader for the decoder
+struct HttpBody(EspHttpConnection);
+
+impl Read for HttpBody {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0
+            .read(buf)
+            .map_err(|e| std::io::Error::other(e.to_string()))
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+use crate::config::{RecordingConfig, RetentionPolicy};
+
+/// Transcribed recordings are moved here by the archive policy
+pub const ARCHIVE_DIR: &str = "/vfat/archive";
+
+/// Applies the retention policy to recordings once they were transcribed
+pub struct RecordingRetention {
+    config: RecordingConfig,
+    /// Recordings kept by the keep_last policy, oldest first
+    kept: VecDeque<String>,
+}
+
+impl RecordingRetention {
+    pub fn new(config: &RecordingConfig) -> Self {
+        Self {
+            config: config.clone(),
+            kept: VecDeque::new(),
+        }
+    }
+
+    /// Apply a reloaded configuration, recordings kept so far stay tracked
+    pub fn set_config(&mut self, config: &RecordingConfig) {
+        self.config = config.clone();
+    }
+
+    /// A recording was transcribed and is no longer needed by the device
+    pub fn transcribed(&mut self, path: &str) {
+        match self.config.retention {
+            RetentionPolicy::Keep => {}
+            RetentionPolicy::Delete => remove_recording(path),
+            RetentionPolicy::KeepLast => {
+                self.kept.retain(|kept| kept != path);
+                self.kept.push_back(path.to_string());
+                while self.kept.len() > self.config.keep_last {
+                    if let Some(oldest) = self.kept.pop_front() {
+                        remove_recording(&oldest);
+                    }
+                }
+            }
+            RetentionPolicy::Archive => {
+                if let Err(e) = archive_recording(path) {
+                    log::warn!("Failed to archive {}: {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+/// Move a recording into the archive under a name that doesn't clash with earlier boots
+fn archive_recording(path: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(ARCHIVE_DIR)?;
+
+    let stem = std::path::Path::new(path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("utterance");
+
+    // Every session numbers its recordings from 0
+    let archived_path = (0..)
+        .map(|n| format!("{}/{}_{}.wav", ARCHIVE_DIR, stem, n))
+        .find(|candidate| !std::path::Path::new(candidate).exists())
+        .unwrap();
+
+    std::fs::rename(path, &archived_path)?;
+    log::info!("Archived {} as {}", path, archived_path);
+    Ok(())
+}
+
+fn remove_recording(path: &str) {
+    if let Err(e) = std::fs::remove_file(path) {
+        log::warn!("Failed to remove {}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rec-{}-{}.wav", name, std::process::id()));
+        std::fs::write(&path, b"RIFF").unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn exists(path: &str) -> bool {
+        std::path::Path::new(path).exists()
+    }
+
+    fn retention(retention: RetentionPolicy, keep_last: usize) -> RecordingRetention {
+        RecordingRetention::new(&RecordingConfig {
+            retention,
+            keep_last,
+        })
+    }
+
+    #[test]
+    fn test_keep_and_delete() {
+        let kept = recording("keep");
+        retention(RetentionPolicy::Keep, 0).transcribed(&kept);
+        assert!(exists(&kept));
+
+        let deleted = recording("delete");
+        retention(RetentionPolicy::Delete, 0).transcribed(&deleted);
+        assert!(!exists(&deleted));
+        let _ = std::fs::remove_file(&kept);
+    }
+
+    #[test]
+    fn test_keep_last() {
+        let mut retention = retention(RetentionPolicy::KeepLast, 2);
+        let paths: Vec<String> = (0..3).map(|i| recording(&format!("last{}", i))).collect();
+
+        retention.transcribed(&paths[0]);
+        retention.transcribed(&paths[1]);
+        // Transcribed again, e.g. after a failed upload, it counts as the newest
+        retention.transcribed(&paths[0]);
+        retention.transcribed(&paths[2]);
+        assert!(exists(&paths[0]));
+        assert!(!exists(&paths[1]));
+        assert!(exists(&paths[2]));
+
+        for path in &paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use log::Level;
+use std::cell::Cell;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{RemoteLogConfig, RemoteLogProtocol};
+
+/// Lines waiting to be sent, further lines are dropped while the collector can't keep up
+const QUEUE_LEN: usize = 64;
+/// How long to wait before connecting or resolving the collector again after a failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Name the device's messages carry in syslog
+const APP_NAME: &str = "ai-chatbox";
+/// Syslog facility "user-level messages"
+const FACILITY_USER: u8 = 1;
+
+/// Set by `start`, nothing is forwarded before
+static QUEUE: OnceLock<SyncSender<Entry>> = OnceLock::new();
+
+thread_local! {
+    /// Set on the sender thread, whose own failures would otherwise be queued for sending
+    static IS_SENDER: Cell<bool> = const { Cell::new(false) };
+}
+
+struct Entry {
+    level: Level,
+    /// Formatted like in the log file, without the line break
+    line: String,
+}
+
+/// Send the log to `config.host`, does nothing when that is empty
+///
+/// Lines logged while WiFi is down or the collector is unreachable are dropped, the log file
+/// on the card still has them.
+pub fn start(config: &RemoteLogConfig, hostname: &str) -> anyhow::Result<()> {
+    if config.host.is_empty() {
+        return Ok(());
+    }
+
+    let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+    let destination = Destination {
+        host: config.host.clone(),
+        port: config.port,
+        hostname: hostname.to_string(),
+        protocol: config.protocol,
+        address: None,
+        udp: None,
+        tcp: None,
+        retry_at: Instant::now(),
+    };
+    thread::Builder::new()
+        .name("remote_log".to_string())
+        .stack_size(4 * 1024)
+        .spawn(move || send_loop(rx, destination))?;
+    let _ = QUEUE.set(tx);
+
+    log::info!(
+        "Sending the log to {}:{} ({:?})",
+        config.host,
+        config.port,
+        config.protocol
+    );
+    Ok(())
+}
+
+/// Queue a log line for sending, called by the logger for every line it writes
+pub fn forward(level: Level, line: &str) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    if IS_SENDER.with(Cell::get) {
+        return;
+    }
+
+    let entry = Entry {
+        level,
+        line: line.trim_end().to_string(),
+    };
+    // Dropped while the queue is full, logging must never wait for the network
+    let _ = queue.try_send(entry);
+}
+
+fn send_loop(rx: Receiver<Entry>, mut destination: Destination) {
+    IS_SENDER.with(|is_sender| is_sender.set(true));
+    for entry in rx {
+        destination.send(&entry);
+    }
+}
+
+/// The collector and the socket to it
+struct Destination {
+    host: String,
+    port: u16,
+    /// This device's name in syslog messages
+    hostname: String,
+    protocol: RemoteLogProtocol,
+    address: Option<SocketAddr>,
+    udp: Option<UdpSocket>,
+    /// Connected viewer, None until connected and after a failed write
+    tcp: Option<TcpStream>,
+    retry_at: Instant,
+}
+
+impl Destination {
+    fn send(&mut self, entry: &Entry) {
+        let result = match self.protocol {
+            RemoteLogProtocol::Syslog => self.send_syslog(entry),
+            RemoteLogProtocol::Tcp => self.send_tcp(entry),
+        };
+        if let Err(e) = result {
+            self.failed(e);
+        }
+    }
+
+    fn send_syslog(&mut self, entry: &Entry) -> std::io::Result<()> {
+        let Some(address) = self.address()? else {
+            return Ok(());
+        };
+        if self.udp.is_none() {
+            self.udp = Some(UdpSocket::bind("0.0.0.0:0")?);
+        }
+        let Some(udp) = self.udp.as_ref() else {
+            return Ok(());
+        };
+
+        let message = syslog_message(entry.level, &self.hostname, &entry.line);
+        udp.send_to(message.as_bytes(), address)?;
+        Ok(())
+    }
+
+    fn send_tcp(&mut self, entry: &Entry) -> std::io::Result<()> {
+        if self.tcp.is_none() {
+            let Some(address) = self.address()? else {
+                return Ok(());
+            };
+            let stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+            stream.set_nodelay(true)?;
+            self.tcp = Some(stream);
+        }
+        let Some(stream) = self.tcp.as_mut() else {
+            return Ok(());
+        };
+
+        stream.write_all(entry.line.as_bytes())?;
+        stream.write_all(b"\n")
+    }
+
+    /// Collector's address, None while waiting to retry after a failure
+    fn address(&mut self) -> std::io::Result<Option<SocketAddr>> {
+        if self.address.is_none() {
+            if Instant::now() < self.retry_at {
+                return Ok(None);
+            }
+            self.address = (self.host.as_str(), self.port).to_socket_addrs()?.next();
+        }
+        Ok(self.address)
+    }
+
+    /// Start over with resolving and connecting, after a pause
+    fn failed(&mut self, error: std::io::Error) {
+        // Not sent anywhere but the console and the card, see IS_SENDER
+        log::warn!("Failed to send the log to {}: {}", self.host, error);
+        self.address = None;
+        self.udp = None;
+        self.tcp = None;
+        self.retry_at = Instant::now() + RETRY_INTERVAL;
+    }
+}
+
+/// RFC 5424 message, without a timestamp since the clock may not be set
+fn syslog_message(level: Level, hostname: &str, line: &str) -> String {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    format!(
+        "<{}>1 - {} {} - - - {}",
+        FACILITY_USER * 8 + severity,
+        hostname,
+        APP_NAME,
+        line
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syslog_message() {
+        assert_eq!(
+            syslog_message(Level::Warn, "kitchen", "[      1200] WARN  wifi: lost"),
+            "<12>1 - kitchen ai-chatbox - - - [      1200] WARN  wifi: lost"
+        );
+        assert!(syslog_message(Level::Info, "kitchen", "").starts_with("<14>1 "));
+    }
+}
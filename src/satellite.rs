@@ -0,0 +1,498 @@
+use serde_json::{json, Map, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::cloud_tts::{is_playable_format, to_output_format, OUTPUT_SAMPLE_RATE};
+use crate::config::SatelliteConfig;
+use crate::ota;
+use crate::stt::AudioStreamMessage;
+use crate::transcription::TranscriptionMessage;
+
+/// Format of the utterance audio fetched from the AFE
+const SAMPLE_RATE: u32 = 16000;
+const SAMPLE_WIDTH: u32 = 2;
+const CHANNELS: u32 = 1;
+/// Version of the Wyoming protocol the events follow
+const WYOMING_VERSION: &str = "1.5.3";
+/// Limits on what the server sends, anything larger is treated as a broken connection
+const MAX_HEADER_BYTES: u64 = 4 * 1024;
+const MAX_DATA_BYTES: u64 = 16 * 1024;
+const MAX_PAYLOAD_BYTES: u64 = 64 * 1024;
+/// Longest answer kept for playing, in samples at the output rate
+const MAX_REPLY_SAMPLES: usize = 60 * OUTPUT_SAMPLE_RATE as usize;
+/// How much longer than the answer itself playing it may take, e.g. behind an earcon
+const PLAYBACK_GRACE: Duration = Duration::from_secs(5);
+
+/// Writing half of the connection to Home Assistant, None while it isn't connected
+static SERVER: Mutex<Option<TcpStream>> = Mutex::new(None);
+/// Set by run-satellite and cleared by pause-satellite, utterances are answered locally meanwhile
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// A Wyoming event: a JSON header line, followed by the data and payload it announces
+#[derive(Debug, PartialEq)]
+struct Event {
+    kind: String,
+    data: Value,
+    payload: Vec<u8>,
+}
+
+/// Audio of the pipeline's answer as it arrives, converted to the output format chunk by chunk
+struct IncomingReply {
+    rate: u32,
+    channels: u16,
+    samples: Vec<i16>,
+}
+
+impl IncomingReply {
+    /// Follow the format announced by audio-start, None for formats the speaker can't play
+    fn start(data: &Value) -> Option<Self> {
+        let width = data["width"].as_u64().unwrap_or(SAMPLE_WIDTH as u64);
+        if width != SAMPLE_WIDTH as u64 {
+            log::warn!("Ignoring {} byte samples from Home Assistant", width);
+            return None;
+        }
+
+        let rate = data["rate"].as_u64().unwrap_or(SAMPLE_RATE as u64);
+        let channels = data["channels"].as_u64().unwrap_or(CHANNELS as u64);
+        let format = u16::try_from(channels)
+            .ok()
+            .zip(u32::try_from(rate).ok())
+            .filter(|&(channels, rate)| is_playable_format(channels, rate));
+        let Some((channels, rate)) = format else {
+            log::warn!(
+                "Ignoring audio with {} channels at {} Hz from Home Assistant",
+                channels,
+                rate
+            );
+            return None;
+        };
+
+        Some(Self {
+            rate,
+            channels,
+            samples: Vec::new(),
+        })
+    }
+
+    fn push(&mut self, payload: &[u8]) {
+        let chunk: Vec<i16> = payload
+            .chunks_exact(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        let converted = to_output_format(&chunk, self.channels, self.rate);
+
+        let room = MAX_REPLY_SAMPLES.saturating_sub(self.samples.len());
+        if converted.len() > room {
+            log::warn!("Answer from Home Assistant is too long, cutting it short");
+        }
+        self.samples
+            .extend_from_slice(&converted[..converted.len().min(room)]);
+    }
+}
+
+/// Where the audio of the current utterance goes, decided at its first frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Route {
+    Undecided,
+    HomeAssistant,
+    /// Home Assistant isn't there, the recording is transcribed and answered on the device
+    Local,
+}
+
+/// Serve Home Assistant's Wyoming integration as a voice satellite on `config.port`
+///
+/// Utterances recorded after the wake word are streamed to Home Assistant's voice pipeline,
+/// which transcribes and answers them and sends back the audio of the answer. While Home
+/// Assistant isn't connected the returned sender hands each recording to the local pipeline.
+pub fn start(
+    config: &SatelliteConfig,
+    hostname: &str,
+    transcription_tx: Sender<TranscriptionMessage>,
+) -> anyhow::Result<Sender<AudioStreamMessage>> {
+    let listener = TcpListener::bind(("0.0.0.0", config.port))?;
+    let name = config.name.clone().unwrap_or_else(|| hostname.to_string());
+    let (tx, rx) = mpsc::channel();
+
+    let server_tx = transcription_tx.clone();
+    thread::Builder::new()
+        .name("wyoming".to_string())
+        .stack_size(8 * 1024)
+        .spawn(move || accept_loop(listener, &name, &server_tx))?;
+    thread::Builder::new()
+        .name("wyoming_audio".to_string())
+        .stack_size(6 * 1024)
+        .spawn(move || audio_loop(rx, transcription_tx))?;
+
+    log::info!("Waiting for Home Assistant on port {}", config.port);
+    Ok(tx)
+}
+
+/// Serve one Home Assistant connection at a time
+fn accept_loop(listener: TcpListener, name: &str, transcription_tx: &Sender<TranscriptionMessage>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Failed to accept a Wyoming connection: {}", e);
+                continue;
+            }
+        };
+
+        match stream.peer_addr() {
+            Ok(peer) => log::info!("Home Assistant connected from {}", peer),
+            Err(_) => log::info!("Home Assistant connected"),
+        }
+        if let Err(e) = serve(stream, name, transcription_tx) {
+            log::warn!("Wyoming connection failed: {}", e);
+        }
+
+        *SERVER.lock().unwrap() = None;
+        RUNNING.store(false, Ordering::Relaxed);
+        log::info!("Home Assistant disconnected, answering on the device");
+    }
+}
+
+/// Answer the events Home Assistant sends until it disconnects
+fn serve(
+    stream: TcpStream,
+    name: &str,
+    transcription_tx: &Sender<TranscriptionMessage>,
+) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    *SERVER.lock().unwrap() = Some(stream);
+
+    // The text of the answer comes before its audio
+    let mut reply_text: Option<String> = None;
+    let mut reply: Option<IncomingReply> = None;
+
+    while let Some(event) = read_event(&mut reader)? {
+        match event.kind.as_str() {
+            "describe" => send_event("info", &info(name), &[])?,
+            "ping" => send_event("pong", &json!({ "text": event.data["text"] }), &[])?,
+            "run-satellite" => {
+                log::info!("Home Assistant is ready for voice commands");
+                RUNNING.store(true, Ordering::Relaxed);
+            }
+            "pause-satellite" => {
+                log::info!("Home Assistant paused the satellite");
+                RUNNING.store(false, Ordering::Relaxed);
+            }
+            "transcript" => {
+                let text = event.data["text"].as_str().unwrap_or_default().to_string();
+                transcription_tx
+                    .send(TranscriptionMessage::SatelliteTranscript { text })
+                    .map_err(|e| anyhow::anyhow!("{}", e))?;
+            }
+            "synthesize" => reply_text = event.data["text"].as_str().map(str::to_string),
+            "audio-start" => reply = IncomingReply::start(&event.data),
+            "audio-chunk" => {
+                if let Some(reply) = reply.as_mut() {
+                    reply.push(&event.payload);
+                }
+            }
+            "audio-stop" => {
+                if let Some(reply) = reply.take() {
+                    play_reply(reply_text.take(), reply.samples, transcription_tx)?;
+                    send_event("played", &json!({}), &[])?;
+                }
+            }
+            "error" => {
+                log::warn!(
+                    "Home Assistant's voice pipeline failed: {}",
+                    event.data["text"]
+                );
+                reply_text = None;
+                reply = None;
+            }
+            other => log::debug!("Ignoring Wyoming event {}", other),
+        }
+    }
+
+    Ok(())
+}
+
+/// Have the worker play the answer and wait until it has been played
+fn play_reply(
+    text: Option<String>,
+    samples: Vec<i16>,
+    transcription_tx: &Sender<TranscriptionMessage>,
+) -> anyhow::Result<()> {
+    let duration = Duration::from_millis(samples.len() as u64 * 1000 / OUTPUT_SAMPLE_RATE as u64);
+    let (played_tx, played_rx) = mpsc::channel();
+    transcription_tx
+        .send(TranscriptionMessage::SatelliteReply {
+            text,
+            samples,
+            played: played_tx,
+        })
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    if played_rx.recv_timeout(duration + PLAYBACK_GRACE).is_err() {
+        log::warn!("Answer from Home Assistant wasn't played in time");
+    }
+    Ok(())
+}
+
+/// Hand the audio of each utterance to Home Assistant, or to the local pipeline without it
+fn audio_loop(rx: Receiver<AudioStreamMessage>, transcription_tx: Sender<TranscriptionMessage>) {
+    let mut route = Route::Undecided;
+
+    for message in rx {
+        match message {
+            AudioStreamMessage::Audio(samples) => {
+                if route == Route::Undecided {
+                    route = match start_pipeline() {
+                        Ok(()) => Route::HomeAssistant,
+                        Err(e) => {
+                            log::info!("Answering on the device: {}", e);
+                            Route::Local
+                        }
+                    };
+                }
+
+                if route == Route::HomeAssistant {
+                    if let Err(e) = send_event("audio-chunk", &audio_format(), &pcm_bytes(&samples))
+                    {
+                        log::warn!(
+                            "Lost Home Assistant mid-utterance, answering on the device: {}",
+                            e
+                        );
+                        route = Route::Local;
+                    }
+                }
+            }
+            AudioStreamMessage::End { fallback } => {
+                let streamed = route == Route::HomeAssistant
+                    && match send_event("audio-stop", &json!({}), &[]) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            log::warn!(
+                                "Failed to end the utterance, answering on the device: {}",
+                                e
+                            );
+                            false
+                        }
+                    };
+                route = Route::Undecided;
+
+                if !streamed && transcription_tx.send(fallback).is_err() {
+                    log::warn!("Transcription worker has exited, stop the satellite");
+                    break;
+                }
+            }
+            AudioStreamMessage::Discard => route = Route::Undecided,
+        }
+    }
+}
+
+/// Ask Home Assistant to run its pipeline on the utterance that follows
+fn start_pipeline() -> anyhow::Result<()> {
+    if !RUNNING.load(Ordering::Relaxed) {
+        return Err(anyhow::anyhow!(
+            "Home Assistant isn't running the satellite"
+        ));
+    }
+
+    // The wake word was already detected here, so the pipeline starts at speech to text
+    let format = audio_format();
+    send_event(
+        "run-pipeline",
+        &json!({
+            "start_stage": "asr",
+            "end_stage": "tts",
+            "restart_on_end": false,
+            "snd_format": format,
+        }),
+        &[],
+    )?;
+    send_event("audio-start", &format, &[])
+}
+
+/// Write an event to Home Assistant, dropping the connection if that fails
+fn send_event(kind: &str, data: &Value, payload: &[u8]) -> anyhow::Result<()> {
+    let mut server = SERVER.lock().unwrap();
+    let Some(stream) = server.as_mut() else {
+        return Err(anyhow::anyhow!("Home Assistant isn't connected"));
+    };
+
+    if let Err(e) = write_event(stream, kind, data, payload) {
+        // Wakes the reading thread up, which then cleans up after the connection
+        let _ = stream.shutdown(Shutdown::Both);
+        *server = None;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn write_event(
+    writer: &mut impl Write,
+    kind: &str,
+    data: &Value,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    let data = match data.as_object() {
+        Some(fields) if !fields.is_empty() => data.to_string(),
+        _ => String::new(),
+    };
+
+    let mut header = json!({ "type": kind, "version": WYOMING_VERSION });
+    if !data.is_empty() {
+        header["data_length"] = data.len().into();
+    }
+    if !payload.is_empty() {
+        header["payload_length"] = payload.len().into();
+    }
+
+    // One write, so the frames of an event can't be split by a failure halfway
+    let mut event = header.to_string().into_bytes();
+    event.push(b'\n');
+    event.extend_from_slice(data.as_bytes());
+    event.extend_from_slice(payload);
+    writer.write_all(&event)
+}
+
+/// Read the next event, None once the server closed the connection
+fn read_event(reader: &mut impl BufRead) -> anyhow::Result<Option<Event>> {
+    let mut line = String::new();
+    let read = reader
+        .by_ref()
+        .take(MAX_HEADER_BYTES)
+        .read_line(&mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(anyhow::anyhow!("Event header is too long"));
+    }
+
+    let header: Value = serde_json::from_str(&line)?;
+    let kind = header["type"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Event without a type: {}", line.trim_end()))?
+        .to_string();
+
+    // Older servers put the data in the header itself
+    let mut data = match &header["data"] {
+        Value::Object(fields) => fields.clone(),
+        _ => Map::new(),
+    };
+    if let Some(length) = header["data_length"].as_u64() {
+        let bytes = read_block(reader, length, MAX_DATA_BYTES)?;
+        if let Value::Object(fields) = serde_json::from_slice(&bytes)? {
+            data.extend(fields);
+        }
+    }
+
+    let payload = match header["payload_length"].as_u64() {
+        Some(length) => read_block(reader, length, MAX_PAYLOAD_BYTES)?,
+        None => Vec::new(),
+    };
+
+    Ok(Some(Event {
+        kind,
+        data: Value::Object(data),
+        payload,
+    }))
+}
+
+fn read_block(reader: &mut impl Read, length: u64, max_length: u64) -> anyhow::Result<Vec<u8>> {
+    if length > max_length {
+        return Err(anyhow::anyhow!(
+            "{} bytes is more than the {} accepted",
+            length,
+            max_length
+        ));
+    }
+
+    let mut block = vec![0; length as usize];
+    reader.read_exact(&mut block)?;
+    Ok(block)
+}
+
+/// Describes the device to Home Assistant in answer to describe
+fn info(name: &str) -> Value {
+    json!({
+        "satellite": {
+            "name": name,
+            "description": "AI Chatbox",
+            "attribution": {
+                "name": "paul356",
+                "url": "https://github.com/paul356/ai-chatbox",
+            },
+            "installed": true,
+            "version": ota::current_version(),
+            "area": null,
+            // The AFE notices when the user stopped talking, so the pipeline needn't
+            "has_vad": true,
+            "active_wake_words": [],
+            "max_active_wake_words": 0,
+            "supports_trigger": false,
+        }
+    })
+}
+
+fn audio_format() -> Value {
+    json!({ "rate": SAMPLE_RATE, "width": SAMPLE_WIDTH, "channels": CHANNELS })
+}
+
+fn pcm_bytes(samples: &[i16]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_event_round_trip() {
+        let mut buffer = Vec::new();
+        write_event(&mut buffer, "audio-chunk", &audio_format(), &[1, 2, 3, 4]).unwrap();
+        write_event(&mut buffer, "audio-stop", &json!({}), &[]).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        let chunk = read_event(&mut reader).unwrap().unwrap();
+        assert_eq!(chunk.kind, "audio-chunk");
+        assert_eq!(chunk.data["rate"], 16000);
+        assert_eq!(chunk.payload, [1, 2, 3, 4]);
+
+        let stop = read_event(&mut reader).unwrap().unwrap();
+        assert_eq!(stop.kind, "audio-stop");
+        assert!(stop.payload.is_empty());
+        assert!(read_event(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_event_limits() {
+        let header = format!(
+            "{{\"type\":\"audio-chunk\",\"payload_length\":{}}}\n",
+            MAX_PAYLOAD_BYTES + 1
+        );
+        assert!(read_event(&mut Cursor::new(header)).is_err());
+        assert!(read_event(&mut Cursor::new("{\"data\":{}}\n")).is_err());
+    }
+
+    #[test]
+    fn test_reply_format() {
+        let reply = IncomingReply::start(&json!({})).unwrap();
+        assert_eq!((reply.rate, reply.channels), (SAMPLE_RATE, CHANNELS as u16));
+        let reply = IncomingReply::start(&json!({"rate": 22050, "channels": 2})).unwrap();
+        assert_eq!((reply.rate, reply.channels), (22050, 2));
+
+        assert!(IncomingReply::start(&json!({"rate": 0})).is_none());
+        assert!(IncomingReply::start(&json!({"rate": 7})).is_none());
+        assert!(IncomingReply::start(&json!({"rate": 4_294_983_296u64})).is_none());
+        assert!(IncomingReply::start(&json!({"channels": 0})).is_none());
+        assert!(IncomingReply::start(&json!({"channels": 3})).is_none());
+        assert!(IncomingReply::start(&json!({"channels": 65_537})).is_none());
+        assert!(IncomingReply::start(&json!({"width": 4})).is_none());
+    }
+}
@@ -15,6 +15,15 @@ use esp_idf_svc::sys::{
     sdspi_host_init, sdspi_host_set_card_clk, sdspi_host_do_transaction, sdspi_host_remove_device, sdspi_host_io_int_enable, sdspi_host_io_int_wait, sdspi_host_get_real_freq, sdspi_host_get_dma_info, spi_bus_initialize, esp_vfs_fat_sdspi_mount, sdspi_device_config_t, spi_bus_config_t,
 };
 use esp_idf_svc::sys;
+use esp_idf_svc::sys::esp_littlefs;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::StorageConfig;
+use crate::metrics::StorageMetrics;
+use crate::session::SESSIONS_DIR;
 
 #[allow(dead_code)]
 const SDMMC_SLOT_FLAG_INTERNAL_PULLUP: c_uint = 1 << 0;
@@ -35,11 +44,47 @@ const SDMMC_DELAY_PHASE_0: u32 = 0;
 const SDSPI_DEFAULT_HOST: i32 = 2;
 const SDSPI_DEFAULT_DMA: u32 = 3;
 
+/// How often the recovery thread looks for reported I/O errors
+const RECOVERY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const INITIAL_REMOUNT_DELAY: Duration = Duration::from_secs(1);
+const MAX_REMOUNT_DELAY: Duration = Duration::from_secs(30);
+
+/// Cleared while the card is being remounted, file operations on it would fail meanwhile
+static CARD_AVAILABLE: AtomicBool = AtomicBool::new(true);
+/// Set by whoever hits an I/O error, the recovery thread then remounts the card
+static REMOUNT_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// The mounted card, shared by the recovery thread and whoever needs the card released
+static CARD: Mutex<Option<SdCard>> = Mutex::new(None);
+/// Set while a `ReleasedCard` guard exists, the card must not be remounted behind its back
+static RELEASED: AtomicBool = AtomicBool::new(false);
+/// Set when no card could be mounted and the internal flash partition is used instead
+static INTERNAL_FLASH: AtomicBool = AtomicBool::new(false);
+/// Failed file operations reported since boot
+static IO_ERRORS: AtomicU32 = AtomicU32::new(0);
+/// Successful remounts after errors since boot
+static REMOUNTS: AtomicU32 = AtomicU32::new(0);
+
+/// LittleFS partition mounted in place of a missing card, see partitions.csv
+const FLASH_PARTITION_LABEL: &str = "storage";
+
+/// Written and deleted again to measure the write speed
+const BENCHMARK_FILE: &str = ".speedtest.tmp";
+const BENCHMARK_BYTES: usize = 64 * 1024;
+const BENCHMARK_CHUNK_BYTES: usize = 4096;
+
+/// Directories whose recordings may be deleted to make room, the pending queue is never touched
+const RECORDING_DIRS: [&str; 2] = ["/vfat/archive", "/vfat"];
+/// Recordings this recent may still be on their way to the speech to text service
+const MIN_RECORDING_AGE: Duration = Duration::from_secs(600);
+
 pub struct SdCard {
     mount_point: CString,
     card_handle: *mut sdmmc_card_t,
 }
 
+// The handle is only used by the thread owning the card, e.g. the recovery thread
+unsafe impl Send for SdCard {}
+
 impl SdCard {
     pub fn new(mpoint: &str) -> Self {
         let mount_point = CString::new(mpoint).unwrap();
@@ -187,7 +232,8 @@ impl SdCard {
         };
 
         let ret = unsafe { spi_bus_initialize(sd_host.slot as u32, &bus_cfg, SDSPI_DEFAULT_DMA) };
-        if ret != ESP_OK {
+        // The bus stays initialized when the card is remounted
+        if ret != ESP_OK && ret != sys::ESP_ERR_INVALID_STATE as i32 {
             log::error!("Failed to initialize SPI bus");
             esp! { ret }?;
         }
@@ -209,12 +255,436 @@ impl SdCard {
 
         Ok(())
     }
+
+    /// Unmount the filesystem, files still open on it can't be used afterwards
+    pub fn unmount(&mut self) -> anyhow::Result<()> {
+        if self.card_handle.is_null() {
+            return Ok(());
+        }
+
+        let ret = unsafe { esp_vfs_fat_sdcard_unmount(self.mount_point.as_ptr(), self.card_handle) };
+        // The handle is freed even when unmounting failed, don't use it again
+        self.card_handle = std::ptr::null_mut();
+        esp!(ret)?;
+
+        log::info!("SD card unmounted");
+        Ok(())
+    }
+
+    /// Unmount the card and mount it again, e.g. after it was reseated
+    pub fn remount_spi(&mut self) -> anyhow::Result<()> {
+        // The card may already be gone, so a failure here doesn't stop the mount below
+        if let Err(e) = self.unmount() {
+            log::warn!("Failed to unmount SD card: {}", e);
+        }
+
+        self.mount_spi()
+    }
 }
 
 impl Drop for SdCard {
     fn drop(&mut self) {
-        if self.card_handle != std::ptr::null_mut() {
-            unsafe { esp_vfs_fat_sdcard_unmount(self.mount_point.as_ptr(), self.card_handle); }
+        if let Err(e) = self.unmount() {
+            log::warn!("Failed to unmount SD card: {}", e);
+        }
+    }
+}
+
+/// The card unmounted for a reboot or firmware update, mounted again when dropped
+pub struct ReleasedCard {
+    _private: (),
+}
+
+impl Drop for ReleasedCard {
+    fn drop(&mut self) {
+        let remounted = match CARD.lock().unwrap().as_mut() {
+            Some(card) => card.mount_spi(),
+            None => Ok(()),
+        };
+        RELEASED.store(false, Ordering::Relaxed);
+
+        match remounted {
+            Ok(()) => CARD_AVAILABLE.store(true, Ordering::Relaxed),
+            // The recovery thread keeps trying
+            Err(e) => report_io_error("mounting the released card", &e),
         }
     }
 }
+
+/// Flush and unmount the card so nothing is lost when the device restarts
+///
+/// Recording pauses until the returned guard is dropped, which mounts the card again.
+pub fn release() -> anyhow::Result<ReleasedCard> {
+    let mut card = CARD.lock().unwrap();
+    RELEASED.store(true, Ordering::Relaxed);
+    CARD_AVAILABLE.store(false, Ordering::Relaxed);
+
+    if let Some(card) = card.as_mut() {
+        if let Err(e) = card.unmount() {
+            RELEASED.store(false, Ordering::Relaxed);
+            REMOUNT_REQUESTED.store(true, Ordering::Relaxed);
+            return Err(e);
+        }
+    }
+
+    Ok(ReleasedCard { _private: () })
+}
+
+/// Whether files on the card can be used, false while it is being remounted
+pub fn is_available() -> bool {
+    CARD_AVAILABLE.load(Ordering::Relaxed)
+}
+
+/// Whether the internal flash is mounted because there is no card
+///
+/// It holds only a few dozen KB, so recordings are kept in RAM and no session directories are
+/// made. The configuration, settings and notes fit, log files should be turned off.
+pub fn is_internal_flash() -> bool {
+    INTERNAL_FLASH.load(Ordering::Relaxed)
+}
+
+/// Mount the internal flash partition where the card would be, for boards without an SD slot
+pub fn mount_internal_flash(mount_point: &str) -> anyhow::Result<()> {
+    let base_path = CString::new(mount_point)?;
+    let partition_label = CString::new(FLASH_PARTITION_LABEL)?;
+    let mut conf: esp_littlefs::esp_vfs_littlefs_conf_t = unsafe { std::mem::zeroed() };
+    conf.base_path = base_path.as_ptr();
+    conf.partition_label = partition_label.as_ptr();
+    // A new board has an empty partition
+    conf.set_format_if_mount_failed(1);
+
+    esp!(unsafe { esp_littlefs::esp_vfs_littlefs_register(&conf) })?;
+    INTERNAL_FLASH.store(true, Ordering::Relaxed);
+    log::info!("Mounted internal flash partition '{}' at {}", FLASH_PARTITION_LABEL, mount_point);
+    Ok(())
+}
+
+/// Report a failed file operation on the card, which is then remounted in the background
+pub fn report_io_error(context: &str, error: &dyn std::fmt::Display) {
+    IO_ERRORS.fetch_add(1, Ordering::Relaxed);
+    if is_internal_flash() {
+        // There is no card to remount, the next operation may well succeed
+        log::error!("Internal flash error while {}: {}", context, error);
+        return;
+    }
+
+    log::error!("SD card error while {}: {}", context, error);
+    CARD_AVAILABLE.store(false, Ordering::Relaxed);
+    REMOUNT_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Start the thread that remounts the card after I/O errors were reported, so a glitch
+/// doesn't leave every file operation failing until the next reboot
+pub fn start_recovery(card: SdCard) -> anyhow::Result<()> {
+    *CARD.lock().unwrap() = Some(card);
+
+    std::thread::Builder::new()
+        .name("sd_recovery".to_string())
+        .stack_size(4 * 1024)
+        .spawn(|| loop {
+            std::thread::sleep(RECOVERY_POLL_INTERVAL);
+            // A released card stays unmounted, whatever fails meanwhile
+            if RELEASED.load(Ordering::Relaxed) || !REMOUNT_REQUESTED.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+
+            let mut delay = INITIAL_REMOUNT_DELAY;
+            loop {
+                let remounted = {
+                    let mut card = CARD.lock().unwrap();
+                    if RELEASED.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    card.as_mut().map_or(Ok(()), SdCard::remount_spi)
+                };
+
+                match remounted {
+                    Ok(()) => {
+                        // Errors reported while remounting came from the old mount
+                        REMOUNT_REQUESTED.store(false, Ordering::Relaxed);
+                        CARD_AVAILABLE.store(true, Ordering::Relaxed);
+                        REMOUNTS.fetch_add(1, Ordering::Relaxed);
+                        log::info!("SD card remounted");
+                        break;
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to remount SD card: {}, retrying in {:?}", e, delay);
+                        std::thread::sleep(delay);
+                        delay = (delay * 2).min(MAX_REMOUNT_DELAY);
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Size and free space of the mounted filesystem
+#[derive(Debug, Clone, Copy)]
+pub struct DiskUsage {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+}
+
+impl DiskUsage {
+    pub fn used_percent(&self) -> u8 {
+        if self.total_bytes == 0 {
+            return 0;
+        }
+        ((self.total_bytes - self.free_bytes) * 100 / self.total_bytes) as u8
+    }
+}
+
+pub fn disk_usage(mount_point: &str) -> anyhow::Result<DiskUsage> {
+    if is_internal_flash() {
+        let partition_label = CString::new(FLASH_PARTITION_LABEL)?;
+        let mut total_bytes = 0usize;
+        let mut used_bytes = 0usize;
+        esp!(unsafe { esp_littlefs::esp_littlefs_info(partition_label.as_ptr(), &mut total_bytes, &mut used_bytes) })?;
+        return Ok(DiskUsage {
+            total_bytes: total_bytes as u64,
+            free_bytes: total_bytes.saturating_sub(used_bytes) as u64,
+        });
+    }
+
+    let mount_point = CString::new(mount_point)?;
+    let mut total_bytes = 0u64;
+    let mut free_bytes = 0u64;
+
+    esp!(unsafe { sys::esp_vfs_fat_info(mount_point.as_ptr(), &mut total_bytes, &mut free_bytes) })?;
+
+    Ok(DiskUsage {
+        total_bytes,
+        free_bytes,
+    })
+}
+
+/// Capacity and error counters of the storage, with `benchmark` also its write speed
+///
+/// The benchmark writes 64 KB and takes a fraction of a second on a healthy card.
+pub fn storage_metrics(mount_point: &str, benchmark: bool) -> StorageMetrics {
+    let available = is_available();
+    let usage = if available {
+        disk_usage(mount_point)
+            .map_err(|e| log::warn!("Failed to read the size of {}: {}", mount_point, e))
+            .ok()
+    } else {
+        None
+    };
+    let write_kb_per_sec = if available && benchmark {
+        match measure_write_speed(mount_point) {
+            Ok(speed) => Some(speed),
+            Err(e) => {
+                report_io_error("measuring the write speed", &e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    StorageMetrics {
+        internal_flash: is_internal_flash(),
+        available,
+        total_bytes: usage.map(|u| u.total_bytes),
+        free_bytes: usage.map(|u| u.free_bytes),
+        io_errors: IO_ERRORS.load(Ordering::Relaxed),
+        remounts: REMOUNTS.load(Ordering::Relaxed),
+        write_kb_per_sec,
+    }
+}
+
+/// Write a file through to the card and return the speed in KB/s
+fn measure_write_speed(mount_point: &str) -> std::io::Result<u32> {
+    let path = format!("{}/{}", mount_point, BENCHMARK_FILE);
+    let chunk = [0x5au8; BENCHMARK_CHUNK_BYTES];
+
+    let started = Instant::now();
+    let written = std::fs::File::create(&path).and_then(|mut file| {
+        for _ in 0..BENCHMARK_BYTES / BENCHMARK_CHUNK_BYTES {
+            file.write_all(&chunk)?;
+        }
+        file.sync_all()
+    });
+    let elapsed = started.elapsed();
+    let _ = std::fs::remove_file(&path);
+    written?;
+
+    let kb_per_sec = (BENCHMARK_BYTES as u128 * 1000) / 1024 / elapsed.as_millis().max(1);
+    Ok(kb_per_sec as u32)
+}
+
+/// A recording that can be deleted to free space
+struct Candidate {
+    path: String,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Start a thread that deletes the oldest recordings when the card fills up, and calls
+/// `on_low_space` with the free bytes when even that doesn't leave enough room
+pub fn start_space_monitor(
+    mount_point: &str,
+    config: StorageConfig,
+    on_low_space: impl Fn(u64) + Send + 'static,
+) -> anyhow::Result<()> {
+    let mount_point = mount_point.to_string();
+
+    std::thread::Builder::new()
+        .name("sd_space".to_string())
+        .stack_size(6 * 1024)
+        .spawn(move || {
+            // Only warn again once the card had room in between
+            let mut warned = false;
+            loop {
+                std::thread::sleep(Duration::from_secs(config.check_interval_secs.max(1)));
+                if !is_available() {
+                    continue;
+                }
+
+                match free_space(&mount_point, &config) {
+                    Ok(free_bytes) => {
+                        let low = free_bytes < config.low_space_warning_mb * 1024 * 1024;
+                        if low && !warned {
+                            log::warn!("SD card is nearly full, {} KB left", free_bytes / 1024);
+                            on_low_space(free_bytes);
+                        }
+                        warned = low;
+                    }
+                    Err(e) => log::warn!("Failed to check free space on the SD card: {}", e),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Delete recordings while the card is fuller than allowed, returns the free space left
+fn free_space(mount_point: &str, config: &StorageConfig) -> anyhow::Result<u64> {
+    let usage = disk_usage(mount_point)?;
+    let allowed_used = usage.total_bytes / 100 * config.max_usage_percent.min(100) as u64;
+    let used = usage.total_bytes - usage.free_bytes;
+    if used <= allowed_used {
+        return Ok(usage.free_bytes);
+    }
+
+    log::info!("SD card is {}% full, deleting old recordings", usage.used_percent());
+    let mut freed = 0;
+    for path in oldest_first(recording_candidates(), used - allowed_used) {
+        let removed = std::fs::metadata(&path).and_then(|meta| {
+            std::fs::remove_file(&path)?;
+            Ok(meta.len())
+        });
+        match removed {
+            Ok(size) => {
+                log::info!("Deleted {} to free space", path);
+                freed += size;
+            }
+            Err(e) => log::warn!("Failed to delete {}: {}", path, e),
+        }
+    }
+
+    Ok(usage.free_bytes + freed)
+}
+
+/// WAV recordings old enough to be done with, from the sessions, the archive and the root of the card
+fn recording_candidates() -> Vec<Candidate> {
+    let now = SystemTime::now();
+
+    let session_dirs = std::fs::read_dir(SESSIONS_DIR)
+        .into_iter()
+        .flat_map(|entries| entries.flatten())
+        .map(|entry| entry.path().to_string_lossy().into_owned());
+    let dirs: Vec<String> = RECORDING_DIRS
+        .iter()
+        .map(|dir| dir.to_string())
+        .chain(session_dirs)
+        .collect();
+
+    dirs.iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter_map(|entry| {
+            let path = entry.path();
+            let name = path.file_name()?.to_str()?;
+            let in_root = path.parent() == Some(std::path::Path::new("/vfat"));
+            if !name.ends_with(".wav") || (in_root && !name.starts_with("audio")) {
+                return None;
+            }
+
+            let meta = entry.metadata().ok()?;
+            let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            // Timestamps from a boot with a synced clock lie in the future, those are old too
+            let recent = now
+                .duration_since(modified)
+                .map_or(false, |age| age < MIN_RECORDING_AGE);
+            if recent || !meta.is_file() {
+                return None;
+            }
+
+            Some(Candidate {
+                path: path.to_string_lossy().into_owned(),
+                size: meta.len(),
+                modified,
+            })
+        })
+        .collect()
+}
+
+/// Paths of the oldest candidates that together free at least `bytes`
+fn oldest_first(mut candidates: Vec<Candidate>, bytes: u64) -> Vec<String> {
+    candidates.sort_by_key(|candidate| candidate.modified);
+
+    let mut freed = 0;
+    candidates
+        .into_iter()
+        .take_while(|candidate| {
+            let needed = freed < bytes;
+            freed += candidate.size;
+            needed
+        })
+        .map(|candidate| candidate.path)
+        .collect()
+}
+
+/// A path below some directory, without ways out of it
+pub fn is_safe_relative_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.starts_with('/')
+        && path
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_safe_relative_path() {
+        assert!(is_safe_relative_path("wn9_hilexin/_MODEL_INFO_"));
+        assert!(!is_safe_relative_path("../config.toml"));
+        assert!(!is_safe_relative_path("/vfat/config.toml"));
+        assert!(!is_safe_relative_path("mn7_cn//wn9_data"));
+        assert!(!is_safe_relative_path(""));
+    }
+
+    #[test]
+    fn test_oldest_first() {
+        let candidate = |path: &str, size, secs| Candidate {
+            path: path.to_string(),
+            size,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        };
+        let candidates = vec![
+            candidate("/vfat/audio3.wav", 100, 30),
+            candidate("/vfat/archive/audio0_0.wav", 100, 10),
+            candidate("/vfat/audio1.wav", 100, 20),
+        ];
+
+        assert_eq!(
+            oldest_first(candidates, 150),
+            vec!["/vfat/archive/audio0_0.wav", "/vfat/audio1.wav"]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::AppConfig;
+use crate::connectivity;
+use crate::console;
+use crate::diagnostics::{self, Target};
+use crate::earcon::Earcon;
+use crate::sd_card;
+use crate::transcription::TranscriptionMessage;
+
+/// Written and deleted again to check that the storage takes writes
+const PROBE_PATH: &str = "/vfat/selftest.tmp";
+/// Chunks the microphone gets to show a signal, a few seconds of audio
+const MIC_PROBE_CHUNKS: u32 = 100;
+/// Lowest difference between the smallest and largest sample that counts as a signal,
+/// a disconnected microphone reads all zeros or a single value
+const MIN_MIC_SPREAD: i32 = 16;
+/// How long to wait for the feed task to judge the microphone
+const MIC_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long WiFi gets to connect before it counts as broken
+const WIFI_TIMEOUT: Duration = Duration::from_secs(20);
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// HTTPS requests to the services need the room
+const SELF_TEST_STACK_SIZE: usize = 16 * 1024;
+/// Time to read the error on the console before the device restarts
+const BOOT_FAILURE_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+/// What the feed task found out about the microphone, see `MicProbe`
+static MIC: Mutex<MicCheck> = Mutex::new(MicCheck::Pending);
+/// Set when starting up failed, the LED shows an error until the restart
+static BOOT_FAILED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MicCheck {
+    /// No chunk read yet, or too few to tell
+    Pending,
+    Signal,
+    /// Every chunk read the same value
+    Flat,
+}
+
+/// Looks at the first chunks read from the microphone, for the self-test
+pub struct MicProbe {
+    chunks: u32,
+    min: i16,
+    max: i16,
+    done: bool,
+}
+
+impl Default for MicProbe {
+    fn default() -> Self {
+        Self {
+            chunks: 0,
+            min: i16::MAX,
+            max: i16::MIN,
+            done: false,
+        }
+    }
+}
+
+impl MicProbe {
+    /// Check a chunk of 16 bit samples, does nothing once the microphone was judged
+    pub fn check(&mut self, chunk: &[u8]) {
+        if self.done {
+            return;
+        }
+        if let Some(result) = self.update(chunk) {
+            self.done = true;
+            *MIC.lock().unwrap() = result;
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) -> Option<MicCheck> {
+        for sample in chunk.chunks_exact(2) {
+            let sample = i16::from_le_bytes([sample[0], sample[1]]);
+            self.min = self.min.min(sample);
+            self.max = self.max.max(sample);
+        }
+        self.chunks += 1;
+
+        if self.max as i32 - self.min as i32 >= MIN_MIC_SPREAD {
+            Some(MicCheck::Signal)
+        } else if self.chunks >= MIC_PROBE_CHUNKS {
+            Some(MicCheck::Flat)
+        } else {
+            None
+        }
+    }
+}
+
+/// Check the microphone, speaker, storage, WiFi and the services in the background
+///
+/// Only what is broken is said, a working device just beeps once.
+pub fn start(
+    config: &AppConfig,
+    transcription_tx: Sender<TranscriptionMessage>,
+) -> anyhow::Result<()> {
+    if !config.self_test.enabled {
+        return Ok(());
+    }
+
+    let targets = diagnostics::targets(config);
+    thread::Builder::new()
+        .name("self_test".to_string())
+        .stack_size(SELF_TEST_STACK_SIZE)
+        .spawn(move || {
+            let problems = run(&targets, &transcription_tx);
+            if let Some(text) = spoken_summary(&problems) {
+                let _ = transcription_tx.send(TranscriptionMessage::Speak { text });
+            }
+        })?;
+    Ok(())
+}
+
+/// Whether a file can be written to the card, or to internal flash without one
+pub fn storage_writable() -> bool {
+    std::fs::write(PROBE_PATH, b"ok").is_ok() && std::fs::remove_file(PROBE_PATH).is_ok()
+}
+
+/// Log why starting up failed and restart, instead of leaving the device dead and silent
+pub fn boot_failed(error: &anyhow::Error) -> ! {
+    BOOT_FAILED.store(true, Ordering::Relaxed);
+    log::error!(
+        "Starting up failed, restarting in {} s: {:#}",
+        BOOT_FAILURE_RESTART_DELAY.as_secs(),
+        error
+    );
+    thread::sleep(BOOT_FAILURE_RESTART_DELAY);
+    console::reboot()
+}
+
+/// Whether starting up failed, the device restarts shortly
+pub fn has_boot_failed() -> bool {
+    BOOT_FAILED.load(Ordering::Relaxed)
+}
+
+/// What is broken, as spoken
+fn run(targets: &[Target], transcription_tx: &Sender<TranscriptionMessage>) -> Vec<String> {
+    log::info!("Running the boot self-test");
+    // Nothing can tell whether it was heard, a missing beep is for the user to notice
+    let _ = transcription_tx.send(TranscriptionMessage::Earcon(Earcon::SelfTest));
+
+    let mut problems = Vec::new();
+    wait_for(MIC_TIMEOUT, || *MIC.lock().unwrap() != MicCheck::Pending);
+    let mic = *MIC.lock().unwrap();
+    match mic {
+        MicCheck::Signal => log::info!("Self-test: microphone OK"),
+        MicCheck::Flat => {
+            log::error!("Self-test: the microphone reads nothing but silence");
+            problems.push("麦克风没有声音".to_string());
+        }
+        MicCheck::Pending => {
+            log::error!("Self-test: no audio from the microphone");
+            problems.push("麦克风没有数据".to_string());
+        }
+    }
+
+    if storage_writable() {
+        log::info!("Self-test: storage OK");
+    } else {
+        log::error!("Self-test: writing {} failed", PROBE_PATH);
+        problems.push(if sd_card::is_internal_flash() {
+            "内部存储无法写入".to_string()
+        } else {
+            "存储卡无法写入".to_string()
+        });
+    }
+
+    if wait_for(WIFI_TIMEOUT, connectivity::has_wifi) {
+        log::info!("Self-test: WiFi OK");
+        let reports = diagnostics::run(targets);
+        problems.extend(reports.iter().filter_map(diagnostics::spoken_failure));
+    } else {
+        log::error!("Self-test: WiFi is not connected");
+        problems.push("无线网络没有连上".to_string());
+    }
+
+    if problems.is_empty() {
+        log::info!("Self-test passed");
+    }
+    problems
+}
+
+/// Poll `done` until it returns true, false when `timeout` passed first
+fn wait_for(timeout: Duration, done: impl Fn() -> bool) -> bool {
+    let started = Instant::now();
+    while !done() {
+        if started.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    true
+}
+
+/// One sentence listing what is broken, None when nothing is
+fn spoken_summary(problems: &[String]) -> Option<String> {
+    if problems.is_empty() {
+        return None;
+    }
+    Some(format!("开机自检发现问题：{}。", problems.join("；")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(samples: &[i16]) -> Vec<u8> {
+        samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_mic_probe() {
+        let mut probe = MicProbe::default();
+        assert_eq!(probe.update(&chunk(&[-3, 0, 4])), None);
+        assert_eq!(
+            probe.update(&chunk(&[120, -80, 40])),
+            Some(MicCheck::Signal)
+        );
+
+        let mut probe = MicProbe::default();
+        let silence = chunk(&[0; 64]);
+        for _ in 1..MIC_PROBE_CHUNKS {
+            assert_eq!(probe.update(&silence), None);
+        }
+        assert_eq!(probe.update(&silence), Some(MicCheck::Flat));
+    }
+
+    #[test]
+    fn test_spoken_summary() {
+        assert_eq!(spoken_summary(&[]), None);
+        let problems = vec!["麦克风没有声音".to_string(), "存储卡无法写入".to_string()];
+        assert_eq!(
+            spoken_summary(&problems).as_deref(),
+            Some("开机自检发现问题：麦克风没有声音；存储卡无法写入。")
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use crate::llm_intf::ChatMessage;
+use crate::sd_card;
+use crate::transcript_log::{format_utc, wall_clock_secs};
+
+/// Every conversation gets a directory here with its recordings, transcript and LLM history
+pub const SESSIONS_DIR: &str = "/vfat/sessions";
+const TRANSCRIPT_FILE: &str = "transcript.txt";
+const HISTORY_FILE: &str = "history.json";
+
+/// Number of the next session, 0 until the sessions directory was scanned
+static NEXT_NUMBER: AtomicU32 = AtomicU32::new(0);
+/// Directory of the conversation in progress
+static CURRENT_DIR: Mutex<Option<String>> = Mutex::new(None);
+
+/// One conversation, from the wake word until the user says goodbye
+pub struct Session {
+    dir: String,
+    next_recording: u32,
+}
+
+impl Session {
+    /// Create the directory of a new conversation and make it the current one
+    ///
+    /// Directories are numbered on from the highest one on the card, so names never collide
+    /// across reboots, and carry the start time once the clock is set.
+    pub fn start() -> Option<Self> {
+        if !sd_card::is_available() {
+            return None;
+        }
+
+        let number = next_number();
+        let id = match wall_clock_secs() {
+            Some(secs) => {
+                let (date, time) = format_utc(secs);
+                format!(
+                    "{:06}-{}-{}",
+                    number,
+                    date.replace('-', ""),
+                    time.replace(':', "")
+                )
+            }
+            None => format!("{:06}", number),
+        };
+
+        let dir = format!("{}/{}", SESSIONS_DIR, id);
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            sd_card::report_io_error(&format!("creating {}", dir), &e);
+            return None;
+        }
+
+        log::info!("Started session {}", id);
+        *CURRENT_DIR.lock().unwrap() = Some(dir.clone());
+        Some(Self {
+            dir,
+            next_recording: 0,
+        })
+    }
+
+    /// Path for the next utterance of this conversation
+    pub fn next_recording_path(&mut self) -> String {
+        let path = format!("{}/utterance-{}.wav", self.dir, self.next_recording);
+        self.next_recording += 1;
+        path
+    }
+}
+
+/// Transcript of the conversation in progress, None before the first session started
+pub fn transcript_path() -> Option<String> {
+    current_file(TRANSCRIPT_FILE)
+}
+
+/// Keep the LLM history of the conversation in progress next to its recordings
+pub fn save_history(messages: &[ChatMessage]) {
+    let Some(path) = current_file(HISTORY_FILE) else {
+        return;
+    };
+
+    match serde_json::to_string_pretty(messages) {
+        Ok(text) => {
+            if let Err(e) = std::fs::write(&path, text) {
+                log::warn!("Failed to write {}: {}", path, e);
+            }
+        }
+        Err(e) => log::warn!("Failed to serialize the LLM history: {}", e),
+    }
+}
+
+fn current_file(name: &str) -> Option<String> {
+    let current_dir = CURRENT_DIR.lock().unwrap();
+    current_dir.as_ref().map(|dir| format!("{}/{}", dir, name))
+}
+
+fn next_number() -> u32 {
+    if NEXT_NUMBER.load(Ordering::Relaxed) == 0 {
+        let highest = std::fs::read_dir(SESSIONS_DIR)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|entry| session_number(&entry.file_name().to_string_lossy()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0);
+        // Only the fetch task starts sessions, so there is no race between load and store
+        NEXT_NUMBER.store(highest + 1, Ordering::Relaxed);
+    }
+
+    NEXT_NUMBER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Number at the start of a session directory's name, e.g. 12 for "000012-20250301-081500"
+fn session_number(name: &str) -> Option<u32> {
+    name.split('-').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_number() {
+        assert_eq!(session_number("000012-20250301-081500"), Some(12));
+        assert_eq!(session_number("000007"), Some(7));
+        assert_eq!(session_number("notes"), None);
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{AppConfig, RetentionPolicy};
+use crate::voice_commands::ReplyLength;
+
+/// NVS namespace holding user settings changed at runtime
+const SETTINGS_NVS_NAMESPACE: &str = "settings";
+/// Longest string value we expect to keep in the settings namespace
+const MAX_STR_LEN: usize = 256;
+
+/// Name of the persona selected by voice
+pub const KEY_ACTIVE_PERSONA: Key<String> = Key::new("persona");
+/// Generation parameter overrides set from the console or by voice
+pub const KEY_MAX_TOKENS: Key<u32> = Key::new("max_tokens");
+pub const KEY_TEMPERATURE: Key<f32> = Key::new("temperature");
+pub const KEY_TOP_P: Key<f32> = Key::new("top_p");
+/// Reply length chosen by voice
+pub const KEY_REPLY_LENGTH: Key<ReplyLength> = Key::new("reply_len");
+/// Speaker volume in percent, changed by voice or remote control
+pub const KEY_VOLUME: Key<u8> = Key::new("volume");
+/// Replacements for values of config.toml set from the console, see `apply_overrides`
+pub const KEY_LLM_ENDPOINT: Key<String> = Key::new("llm_endpoint");
+pub const KEY_STT_URL: Key<String> = Key::new("stt_url");
+pub const KEY_EXIT_PHRASES: Key<Vec<String>> = Key::new("exit_phrases");
+pub const KEY_RETENTION: Key<RetentionPolicy> = Key::new("retention");
+pub const KEY_KEEP_RECORDINGS: Key<u32> = Key::new("keep_last");
+
+/// Called with every change, from the thread that made it
+type Listener = Arc<dyn Fn(&SettingChange) + Send + Sync>;
+
+static LISTENERS: Mutex<Vec<Listener>> = Mutex::new(Vec::new());
+
+/// Name of a setting along with the type of its value
+pub struct Key<T> {
+    name: &'static str,
+    value_type: PhantomData<fn() -> T>,
+}
+
+impl<T> Key<T> {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value_type: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for Key<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Key<T> {}
+
+/// A setting was stored or removed
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingChange {
+    pub key: &'static str,
+    /// New value as text, None once removed so the default applies again
+    pub value: Option<String>,
+}
+
+/// Types a setting can hold, each stored as a string or an integer since NVS has nothing else
+pub trait SettingValue: Sized {
+    fn read(settings: &Settings, name: &str) -> Option<Self>;
+    fn write(&self, settings: &mut Settings, name: &str) -> anyhow::Result<()>;
+    /// How the value is reported to change listeners
+    fn to_text(&self) -> String;
+}
+
+impl SettingValue for String {
+    fn read(settings: &Settings, name: &str) -> Option<Self> {
+        settings.get_str(name)
+    }
+
+    fn write(&self, settings: &mut Settings, name: &str) -> anyhow::Result<()> {
+        settings.set_str(name, self)
+    }
+
+    fn to_text(&self) -> String {
+        self.clone()
+    }
+}
+
+impl SettingValue for u32 {
+    fn read(settings: &Settings, name: &str) -> Option<Self> {
+        settings.get_u32(name)
+    }
+
+    fn write(&self, settings: &mut Settings, name: &str) -> anyhow::Result<()> {
+        settings.set_u32(name, *self)
+    }
+
+    fn to_text(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl SettingValue for u8 {
+    fn read(settings: &Settings, name: &str) -> Option<Self> {
+        settings
+            .get_u32(name)
+            .and_then(|value| u8::try_from(value).ok())
+    }
+
+    fn write(&self, settings: &mut Settings, name: &str) -> anyhow::Result<()> {
+        settings.set_u32(name, *self as u32)
+    }
+
+    fn to_text(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// Stored as its bit pattern
+impl SettingValue for f32 {
+    fn read(settings: &Settings, name: &str) -> Option<Self> {
+        settings.get_u32(name).map(f32::from_bits)
+    }
+
+    fn write(&self, settings: &mut Settings, name: &str) -> anyhow::Result<()> {
+        settings.set_u32(name, self.to_bits())
+    }
+
+    fn to_text(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// Stored as a JSON list
+impl SettingValue for Vec<String> {
+    fn read(settings: &Settings, name: &str) -> Option<Self> {
+        settings
+            .get_str(name)
+            .and_then(|value| serde_json::from_str(&value).ok())
+    }
+
+    fn write(&self, settings: &mut Settings, name: &str) -> anyhow::Result<()> {
+        settings.set_str(name, &self.to_text())
+    }
+
+    fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Stored by its name in config.toml, e.g. "keep_last"
+impl SettingValue for RetentionPolicy {
+    fn read(settings: &Settings, name: &str) -> Option<Self> {
+        settings
+            .get_str(name)
+            .and_then(|value| serde_json::from_value(serde_json::Value::String(value)).ok())
+    }
+
+    fn write(&self, settings: &mut Settings, name: &str) -> anyhow::Result<()> {
+        settings.set_str(name, &self.to_text())
+    }
+
+    fn to_text(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => name,
+            _ => String::new(),
+        }
+    }
+}
+
+impl SettingValue for ReplyLength {
+    fn read(settings: &Settings, name: &str) -> Option<Self> {
+        settings
+            .get_str(name)
+            .and_then(|value| ReplyLength::from_name(&value))
+    }
+
+    fn write(&self, settings: &mut Settings, name: &str) -> anyhow::Result<()> {
+        settings.set_str(name, self.as_str())
+    }
+
+    fn to_text(&self) -> String {
+        self.as_str().to_string()
+    }
+}
+
+/// Small wrapper around an NVS namespace for settings that survive reboots
+pub struct Settings {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Settings {
+    pub fn new(nvs_partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        let nvs = EspNvs::new(nvs_partition, SETTINGS_NVS_NAMESPACE, true)?;
+        Ok(Self { nvs })
+    }
+
+    /// Read a setting, returning None when it is unset or unreadable
+    pub fn get<T: SettingValue>(&self, key: Key<T>) -> Option<T> {
+        T::read(self, key.name)
+    }
+
+    /// Store a setting and tell the listeners
+    pub fn set<T: SettingValue>(&mut self, key: Key<T>, value: T) -> anyhow::Result<()> {
+        value.write(self, key.name)?;
+        notify(SettingChange {
+            key: key.name,
+            value: Some(value.to_text()),
+        });
+        Ok(())
+    }
+
+    /// Replace the values of `config` that were changed from the console
+    pub fn apply_overrides(&self, config: &mut AppConfig) {
+        if let Some(endpoint) = self.get(KEY_LLM_ENDPOINT) {
+            config.llm.primary.endpoint = Some(endpoint);
+        }
+        if let Some(url) = self.get(KEY_STT_URL) {
+            config.stt.url = Some(url);
+        }
+        if let Some(phrases) = self.get(KEY_EXIT_PHRASES) {
+            config.assistant.exit_phrases = phrases;
+        }
+        if let Some(retention) = self.get(KEY_RETENTION) {
+            config.recordings.retention = retention;
+        }
+        if let Some(keep_last) = self.get(KEY_KEEP_RECORDINGS) {
+            config.recordings.keep_last = keep_last as usize;
+        }
+    }
+
+    /// Remove a setting so its default applies again
+    pub fn remove<T>(&mut self, key: Key<T>) -> anyhow::Result<()> {
+        self.nvs.remove(key.name)?;
+        notify(SettingChange {
+            key: key.name,
+            value: None,
+        });
+        Ok(())
+    }
+
+    fn get_str(&self, key: &str) -> Option<String> {
+        let mut buffer = [0u8; MAX_STR_LEN];
+
+        match self.nvs.get_str(key, &mut buffer) {
+            Ok(value) => value.map(|s| s.to_string()),
+            Err(e) => {
+                log::warn!("Failed to read setting '{}': {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        if value.len() >= MAX_STR_LEN {
+            return Err(anyhow::anyhow!("Value for setting '{}' is too long", key));
+        }
+
+        self.nvs.set_str(key, value)?;
+        Ok(())
+    }
+
+    fn get_u32(&self, key: &str) -> Option<u32> {
+        match self.nvs.get_u32(key) {
+            Ok(value) => value,
+            Err(e) => {
+                log::warn!("Failed to read setting '{}': {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn set_u32(&mut self, key: &str, value: u32) -> anyhow::Result<()> {
+        self.nvs.set_u32(key, value)?;
+        Ok(())
+    }
+}
+
+/// Call `listener` with every setting changed from now on, e.g. to mirror them elsewhere
+pub fn on_change(listener: impl Fn(&SettingChange) + Send + Sync + 'static) {
+    LISTENERS.lock().unwrap().push(Arc::new(listener));
+}
+
+fn notify(change: SettingChange) {
+    log::info!("Setting '{}' changed to {:?}", change.key, change.value);
+    // Called without the lock, a listener may register another one or change a setting itself
+    let listeners = LISTENERS.lock().unwrap().clone();
+    for listener in listeners {
+        listener(&change);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let listener_seen = seen.clone();
+        on_change(move |change| {
+            if change.key == "test_notify" {
+                listener_seen.lock().unwrap().push(change.value.clone());
+                // Registering from inside a listener must not deadlock
+                on_change(|_| {});
+            }
+        });
+
+        notify(SettingChange {
+            key: "test_notify",
+            value: Some("1".to_string()),
+        });
+        notify(SettingChange {
+            key: "test_notify",
+            value: None,
+        });
+        assert_eq!(*seen.lock().unwrap(), [Some("1".to_string()), None]);
+    }
+
+    #[test]
+    fn test_to_text() {
+        let phrases = vec!["再见".to_string(), "stop".to_string()];
+        assert_eq!(phrases.to_text(), r#"["再见","stop"]"#);
+        assert_eq!(RetentionPolicy::KeepLast.to_text(), "keep_last");
+        assert_eq!(ReplyLength::Brief.to_text(), ReplyLength::Brief.as_str());
+        assert_eq!(0.5f32.to_text(), "0.5");
+    }
+}
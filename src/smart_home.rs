@@ -0,0 +1,239 @@
+use serde_json::{Map, Value};
+
+use crate::config::{SmartHomeConfig, SmartHomeDeviceConfig};
+use crate::mqtt;
+use crate::voice_commands::normalize_transcript;
+
+/// MultiNet command ids of the devices start here, two per device for on and off
+pub const FIRST_OFFLINE_ID: i32 = 100;
+
+const ON_VERBS: [&str; 3] = ["打开", "开启", "开"];
+const OFF_VERBS: [&str; 4] = ["关闭", "关掉", "关上", "关"];
+const SET_VERBS: [&str; 3] = ["调到", "调成", "设为"];
+/// Politeness the user may put in front, "帮我打开客厅灯"
+const FILLERS: [&str; 3] = ["请", "帮我", "把"];
+
+/// What to do with a device
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    On,
+    Off,
+    /// Set a level, e.g. brightness or temperature
+    Set(String),
+}
+
+/// Device and action in "打开客厅灯", "把客厅灯关掉" or "把客厅灯调到50"
+///
+/// Only the exact device names count, anything else is a question for the LLM.
+pub fn parse<'a>(
+    config: &'a SmartHomeConfig,
+    text: &str,
+) -> Option<(&'a SmartHomeDeviceConfig, Action)> {
+    let mut text = normalize_transcript(text);
+    while let Some(rest) = FILLERS.iter().find_map(|f| text.strip_prefix(f)) {
+        text = rest.to_string();
+    }
+
+    config.devices.iter().find_map(|device| {
+        let name = device.name.as_str();
+        if name.is_empty() {
+            return None;
+        }
+        if let Some(rest) = text.strip_suffix(name) {
+            return verb_action(rest).map(|action| (device, action));
+        }
+        let rest = text.strip_prefix(name)?;
+        if let Some(value) = SET_VERBS.iter().find_map(|v| rest.strip_prefix(v)) {
+            let value = value.trim_end_matches('度');
+            return value
+                .parse::<f64>()
+                .is_ok()
+                .then(|| (device, Action::Set(value.to_string())));
+        }
+        verb_action(rest).map(|action| (device, action))
+    })
+}
+
+/// Carry out a smart_home intent, None for any other intent
+///
+/// The slots are `device`, `action` ("on", "off" or "set") and `value` for "set". Returns what
+/// to say about it, the error is said instead of the LLM's reply.
+pub fn handle_intent(
+    config: &SmartHomeConfig,
+    intent: &str,
+    slots: &Map<String, Value>,
+) -> Option<Result<String, String>> {
+    if intent != "smart_home" {
+        return None;
+    }
+    let slot = |name: &str| match slots.get(name) {
+        Some(Value::String(value)) => value.trim().to_string(),
+        Some(Value::Number(value)) => value.to_string(),
+        _ => String::new(),
+    };
+
+    let name = slot("device");
+    let device = match config.find_device(&name) {
+        Ok(device) => device,
+        Err(names) if names.is_empty() => return Some(Err(format!("没有找到叫{}的设备", name))),
+        Err(names) => return Some(Err(format!("是指{}中的哪一个？", names.join("、")))),
+    };
+    // Goes into the payload template as it is, only a number can't break out of it
+    let value = slot("value");
+    let action = match slot("action").as_str() {
+        "on" => Action::On,
+        "off" => Action::Off,
+        "set" if value.parse::<f64>().is_ok() => Action::Set(value),
+        "set" => return Some(Err(format!("没听清要把{}调到多少", device.name))),
+        _ => return Some(Err(format!("没听清要把{}怎么样", device.name))),
+    };
+    Some(execute(device, &action))
+}
+
+/// Publish the payload for `action`, returns what to say about it
+pub fn execute(device: &SmartHomeDeviceConfig, action: &Action) -> Result<String, String> {
+    let (template, done) = match action {
+        Action::On => (&device.on_payload, format!("{}已打开", device.name)),
+        Action::Off => (&device.off_payload, format!("{}已关闭", device.name)),
+        Action::Set(value) => (
+            &device.set_payload,
+            format!("{}已调到{}", device.name, value),
+        ),
+    };
+    let Some(template) = template else {
+        return Err(format!("{}不支持这个操作", device.name));
+    };
+    let payload = match action {
+        Action::Set(value) => template.replace("{value}", value),
+        _ => template.clone(),
+    };
+
+    log::info!("Publishing '{}' to {}", payload, device.topic);
+    match mqtt::publish_to(&device.topic, payload.as_bytes()) {
+        Ok(()) => Ok(done),
+        Err(e) => {
+            log::warn!("Failed to switch {}: {}", device.name, e);
+            Err(format!("没有连上MQTT服务器，无法控制{}", device.name))
+        }
+    }
+}
+
+/// Pinyin phrases of the devices for MultiNet, by command id
+pub fn offline_phrases(config: &SmartHomeConfig) -> Vec<(i32, &str)> {
+    config
+        .devices
+        .iter()
+        .enumerate()
+        .flat_map(|(index, device)| {
+            let id = FIRST_OFFLINE_ID + 2 * index as i32;
+            [(id, &device.on_pinyin), (id + 1, &device.off_pinyin)]
+        })
+        .filter_map(|(id, phrase)| phrase.as_deref().map(|phrase| (id, phrase)))
+        .collect()
+}
+
+/// Device and action of a MultiNet command id from `offline_phrases`
+pub fn offline_action(
+    config: &SmartHomeConfig,
+    command_id: i32,
+) -> Option<(&SmartHomeDeviceConfig, Action)> {
+    let offset = usize::try_from(command_id - FIRST_OFFLINE_ID).ok()?;
+    let device = config.devices.get(offset / 2)?;
+    let action = if offset % 2 == 0 {
+        Action::On
+    } else {
+        Action::Off
+    };
+    Some((device, action))
+}
+
+/// Names of the devices for the system prompt, so the LLM uses them in smart_home intents
+pub fn prompt(config: &SmartHomeConfig) -> Option<String> {
+    if config.devices.is_empty() {
+        return None;
+    }
+    let names: Vec<&str> = config.devices.iter().map(|d| d.name.as_str()).collect();
+    Some(format!("可以控制的设备有：{}。", names.join("、")))
+}
+
+fn verb_action(verb: &str) -> Option<Action> {
+    if ON_VERBS.contains(&verb) {
+        Some(Action::On)
+    } else if OFF_VERBS.contains(&verb) {
+        Some(Action::Off)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SmartHomeConfig {
+        SmartHomeConfig {
+            devices: vec![SmartHomeDeviceConfig {
+                name: "客厅灯".to_string(),
+                topic: "home/light/set".to_string(),
+                off_pinyin: Some("guan bi ke ting deng".to_string()),
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn test_handle_intent() {
+        let mut config = config();
+        let slots = |json: &str| serde_json::from_str::<Map<String, Value>>(json).unwrap();
+        let handle = |config: &SmartHomeConfig, json| {
+            handle_intent(config, "smart_home", &slots(json)).unwrap()
+        };
+        assert!(handle_intent(&config, "timer", &slots("{}")).is_none());
+        assert_eq!(
+            handle(&config, r#"{"device": "卧室灯", "action": "on"}"#),
+            Err("没有找到叫卧室灯的设备".to_string())
+        );
+        let injection = r#"{"device": "客厅灯", "action": "set", "value": "1, \"on\": true"}"#;
+        assert_eq!(
+            handle(&config, injection),
+            Err("没听清要把客厅灯调到多少".to_string())
+        );
+
+        config.devices.push(SmartHomeDeviceConfig {
+            name: "卧室灯".to_string(),
+            ..config.devices[0].clone()
+        });
+        assert_eq!(
+            handle(&config, r#"{"device": "灯", "action": "on"}"#),
+            Err("是指客厅灯、卧室灯中的哪一个？".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = config();
+        let action = |text| parse(&config, text).map(|(_, action)| action);
+        assert_eq!(action("打开客厅灯。"), Some(Action::On));
+        assert_eq!(action("帮我把客厅灯关掉"), Some(Action::Off));
+        assert_eq!(
+            action("把客厅灯调到50"),
+            Some(Action::Set("50".to_string()))
+        );
+        assert_eq!(action("把客厅灯调到最亮"), None);
+        assert_eq!(action("打开卧室灯"), None);
+        assert_eq!(action("客厅灯为什么打不开"), None);
+    }
+
+    #[test]
+    fn test_offline_commands() {
+        let config = config();
+        assert_eq!(
+            offline_phrases(&config),
+            vec![(101, "guan bi ke ting deng")]
+        );
+        let (device, action) = offline_action(&config, 101).unwrap();
+        assert_eq!((device.name.as_str(), action), ("客厅灯", Action::Off));
+        assert!(offline_action(&config, 102).is_none());
+        assert!(offline_action(&config, 9).is_none());
+    }
+}
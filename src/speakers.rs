@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::SpeakerConfig;
+use crate::sd_card::is_safe_relative_path;
+
+/// One TOML file per person, so profiles can be added and edited on a PC
+pub const PROFILES_DIR: &str = "/vfat/speakers";
+/// Voice prints kept per person, each enrollment adds one recorded under other conditions
+const MAX_EMBEDDINGS: usize = 5;
+/// Characters FAT doesn't allow in file names, besides the path separator
+const RESERVED_CHARS: [char; 8] = ['\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Who a person is and how they like to be answered, e.g. /vfat/speakers/小明.toml
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeakerProfile {
+    pub name: String,
+    /// Free form text added to the system prompt, e.g. "八岁，喜欢恐龙"
+    pub preferences: String,
+    /// Voice prints from the speech to text service, filled in by enrolling
+    pub embeddings: Vec<Vec<f32>>,
+}
+
+impl SpeakerProfile {
+    /// Sentence appended to the system prompt while this person is talking
+    pub fn prompt(&self) -> String {
+        if self.preferences.is_empty() {
+            format!("现在和你说话的是{}。", self.name)
+        } else {
+            format!("现在和你说话的是{}。{}", self.name, self.preferences)
+        }
+    }
+}
+
+/// Recognizes known people by comparing voice prints with their profiles
+pub struct SpeakerRegistry {
+    enabled: bool,
+    min_similarity: f32,
+    profiles: Vec<SpeakerProfile>,
+}
+
+impl SpeakerRegistry {
+    /// Read every profile on the SD card when speaker identification is enabled
+    pub fn load(config: &SpeakerConfig) -> Self {
+        let profiles = if config.enabled {
+            load_profiles()
+        } else {
+            Vec::new()
+        };
+
+        if config.enabled {
+            log::info!("Loaded {} speaker profiles", profiles.len());
+        }
+
+        Self {
+            enabled: config.enabled,
+            min_similarity: config.min_similarity,
+            profiles,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Profile of the person whose voice print is closest, if it is close enough
+    pub fn identify(&self, embedding: &[f32]) -> Option<&SpeakerProfile> {
+        if !self.enabled {
+            return None;
+        }
+
+        self.profiles
+            .iter()
+            .filter_map(|profile| {
+                profile
+                    .embeddings
+                    .iter()
+                    .map(|known| cosine_similarity(known, embedding))
+                    .reduce(f32::max)
+                    .map(|similarity| (profile, similarity))
+            })
+            .filter(|(_, similarity)| *similarity >= self.min_similarity)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(profile, similarity)| {
+                log::info!("Speaker is {} (similarity {:.2})", profile.name, similarity);
+                profile
+            })
+    }
+
+    /// Remember a voice print for a person, creating their profile if needed
+    pub fn enroll(&mut self, name: &str, embedding: Vec<f32>) -> anyhow::Result<&SpeakerProfile> {
+        if !is_valid_name(name) {
+            return Err(anyhow::anyhow!("'{}' can't name a profile file", name));
+        }
+        let index = match self.profiles.iter().position(|p| p.name == name) {
+            Some(index) => index,
+            None => {
+                self.profiles.push(SpeakerProfile {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                self.profiles.len() - 1
+            }
+        };
+
+        let profile = &mut self.profiles[index];
+        profile.embeddings.push(embedding);
+        if profile.embeddings.len() > MAX_EMBEDDINGS {
+            profile.embeddings.remove(0);
+        }
+
+        std::fs::create_dir_all(PROFILES_DIR)?;
+        let text = toml::to_string(profile)?;
+        std::fs::write(format!("{}/{}.toml", PROFILES_DIR, name), text)?;
+
+        log::info!("Enrolled the voice of {}", name);
+        Ok(profile)
+    }
+}
+
+/// Whether `name` can be the stem of a file right in PROFILES_DIR
+fn is_valid_name(name: &str) -> bool {
+    is_safe_relative_path(name) && !name.contains('/') && !name.contains(RESERVED_CHARS)
+}
+
+fn load_profiles() -> Vec<SpeakerProfile> {
+    let entries = match std::fs::read_dir(PROFILES_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "toml"))
+        .filter_map(|path| {
+            let text = std::fs::read_to_string(&path).ok()?;
+            match toml::from_str::<SpeakerProfile>(&text) {
+                Ok(mut profile) => {
+                    // The file name is the name unless the profile says otherwise
+                    if profile.name.is_empty() {
+                        profile.name = path.file_stem()?.to_string_lossy().into_owned();
+                    }
+                    Some(profile)
+                }
+                Err(e) => {
+                    log::warn!("Failed to parse {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Cosine of the angle between two voice prints or embeddings, 0 when their lengths differ
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identify_closest_profile() {
+        let registry = SpeakerRegistry {
+            enabled: true,
+            min_similarity: 0.8,
+            profiles: vec![
+                SpeakerProfile {
+                    name: "小明".to_string(),
+                    embeddings: vec![vec![1.0, 0.0, 0.0]],
+                    ..Default::default()
+                },
+                SpeakerProfile {
+                    name: "妈妈".to_string(),
+                    embeddings: vec![vec![0.0, 1.0, 0.0]],
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let speaker = registry.identify(&[0.9, 0.1, 0.0]).map(|p| p.name.as_str());
+        assert_eq!(speaker, Some("小明"));
+        // Nobody is close enough
+        assert!(registry.identify(&[0.5, 0.5, 0.7]).is_none());
+        // Embeddings from another model can't be compared
+        assert!(registry.identify(&[1.0, 0.0]).is_none());
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("小明"));
+        assert!(is_valid_name("Tom Lee"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name("../config"));
+        assert!(!is_valid_name("家人/妈妈"));
+        assert!(!is_valid_name("妈妈?"));
+    }
+}
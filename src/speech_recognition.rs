@@ -2,7 +2,10 @@ use anyhow;
 use esp_idf_svc::sys::esp_sr;
 use std::ffi::CString;
 
+use crate::config::{ModelLocation, SmartHomeConfig, SpeechModelConfig};
 use crate::llm_intf::{ChatRole, LlmHelper};
+use crate::offline_commands::OfflineCommand;
+use crate::smart_home;
 
 /// Add this function to print all fields of afe_config
 pub fn print_afe_config(afe_config: *const esp_sr::afe_config_t) {
@@ -83,10 +86,7 @@ pub fn print_afe_config(afe_config: *const esp_sr::afe_config_t) {
 }
 
 /// Test function for LLM functionality with improved error handling
-pub fn test_llm_helper() -> anyhow::Result<()> {
-    // Get token from environment variable at compile time
-    let token = env!("LLM_AUTH_TOKEN");
-
+pub fn test_llm_helper(token: &str) -> anyhow::Result<()> {
     log::info!("Creating LlmHelper instance to test DeepSeek API integration");
 
     // Create LLM helper with error handling
@@ -109,12 +109,7 @@ pub fn test_llm_helper() -> anyhow::Result<()> {
     let response = llm.send_message(
         "Hello! I'm testing the ESP32-S3 integration with DeepSeek AI. Can you confirm this is working?".to_string(),
         ChatRole::User
-    );
-
-    if response.starts_with("Error:") {
-        log::error!("LLM API error: {}", response);
-        return Err(anyhow::anyhow!("LLM API request failed: {}", response));
-    }
+    ).map_err(|e| anyhow::anyhow!("LLM API request failed: {}", e))?;
 
     log::info!("Received response from DeepSeek API: {}", response);
 
@@ -128,8 +123,70 @@ pub fn test_llm_helper() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Load the WakeNet, MultiNet and AFE models from a flash partition or the SD card
+fn load_models(config: &SpeechModelConfig) -> anyhow::Result<*mut esp_sr::srmodel_list_t> {
+    use esp_idf_svc::sys;
+
+    let label = CString::new(config.partition.as_str())?;
+    let partition = unsafe {
+        sys::esp_partition_find_first(
+            sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            sys::esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+            label.as_ptr(),
+        )
+    };
+
+    // esp-sr only builds srmodels.bin for the partition with CONFIG_MODEL_IN_FLASH
+    let built_for_flash = cfg!(esp_idf_model_in_flash);
+    let use_flash = match config.location {
+        ModelLocation::Flash if !built_for_flash => {
+            return Err(anyhow::anyhow!(
+                "The firmware was built with CONFIG_MODEL_IN_SDCARD, set CONFIG_MODEL_IN_FLASH to \
+                 load the speech recognition models from flash"
+            ));
+        }
+        ModelLocation::Flash if partition.is_null() => {
+            return Err(anyhow::anyhow!(
+                "No '{}' partition for the speech recognition models",
+                config.partition
+            ));
+        }
+        ModelLocation::Flash => true,
+        ModelLocation::SdCard => false,
+        ModelLocation::Auto => built_for_flash && !partition.is_null(),
+    };
+
+    let models = if use_flash {
+        log::info!(
+            "Loading speech recognition models from the '{}' partition",
+            config.partition
+        );
+        // The models are memory mapped and stay in flash
+        unsafe { esp_sr::srmodel_mmap_init(partition as *const _) }
+    } else {
+        log::info!("Loading speech recognition models from {}", config.path);
+        let base_path = CString::new(config.path.as_str())?;
+        unsafe { esp_sr::srmodel_sdcard_init(base_path.as_ptr()) }
+    };
+
+    if models.is_null() {
+        let source = if use_flash {
+            &config.partition
+        } else {
+            &config.path
+        };
+        return Err(anyhow::anyhow!(
+            "No speech recognition models found in {}",
+            source
+        ));
+    }
+    Ok(models)
+}
+
 /// Initialize speech recognition system and return handles
 pub fn init_speech_recognition(
+    model_config: &SpeechModelConfig,
+    smart_home: &SmartHomeConfig,
 ) -> anyhow::Result<(
     *mut esp_sr::esp_afe_sr_iface_t,
     *mut esp_sr::esp_afe_sr_data_t,
@@ -139,18 +196,16 @@ pub fn init_speech_recognition(
     use esp_idf_svc::sys::esp_sr::{
         afe_config_free, afe_config_init, esp_afe_handle_from_config, esp_mn_commands_add,
         esp_mn_commands_clear, esp_mn_commands_update, esp_mn_handle_from_name, esp_srmodel_filter,
-        esp_srmodel_init,
     };
 
     // Initialize speech recognition models
-    let part_name = CString::new("/vfat").unwrap();
-    let models = unsafe { esp_srmodel_init(part_name.as_ptr()) };
-    if models.is_null() {
-        log::error!("Failed to initialize speech recognition models");
-        return Err(anyhow::anyhow!(
-            "Failed to initialize speech recognition models"
-        ));
-    }
+    let models = match load_models(model_config) {
+        Ok(models) => models,
+        Err(e) => {
+            log::error!("Failed to initialize speech recognition models: {}", e);
+            return Err(e);
+        }
+    };
 
     let input_format = CString::new("M").unwrap();
     let afe_config = unsafe {
@@ -239,6 +294,14 @@ pub fn init_speech_recognition(
     unsafe {
         esp_mn_commands_clear();
         esp_mn_commands_add(1, Vec::from(b"wo you ge wen ti\0").as_ptr() as *const i8);
+        for command in OfflineCommand::ALL {
+            let phrase = CString::new(command.phrase()).unwrap();
+            esp_mn_commands_add(command.command_id(), phrase.as_ptr());
+        }
+        for (id, phrase) in smart_home::offline_phrases(smart_home) {
+            let phrase = CString::new(phrase).unwrap();
+            esp_mn_commands_add(id, phrase.as_ptr());
+        }
         esp_mn_commands_update();
     }
 
@@ -0,0 +1,60 @@
+use esp_idf_svc::sys;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+use crate::metrics::StackMetrics;
+
+/// Threads whose stack use is reported, each registers itself
+static TASKS: Mutex<Vec<Task>> = Mutex::new(Vec::new());
+
+struct Task {
+    name: &'static str,
+    /// FreeRTOS handle as an address, valid for as long as the entry exists
+    handle: usize,
+    size: u32,
+}
+
+/// The calling thread's stack use is reported until this is dropped
+pub struct Registration {
+    handle: usize,
+    /// Has to be dropped by the thread it registered, before that thread ends
+    thread_bound: PhantomData<*const ()>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        TASKS
+            .lock()
+            .unwrap()
+            .retain(|task| task.handle != self.handle);
+    }
+}
+
+/// Report the stack use of the calling thread as `name`, which was given `size` bytes of stack
+pub fn register(name: &'static str, size: usize) -> Registration {
+    let handle = unsafe { sys::xTaskGetCurrentTaskHandle() } as usize;
+    TASKS.lock().unwrap().push(Task {
+        name,
+        handle,
+        size: size as u32,
+    });
+    Registration {
+        handle,
+        thread_bound: PhantomData,
+    }
+}
+
+/// How close each registered thread came to the end of its stack so far
+pub fn stack_metrics() -> Vec<StackMetrics> {
+    // Held while reading, so no thread can unregister and end meanwhile
+    let tasks = TASKS.lock().unwrap();
+    tasks
+        .iter()
+        .map(|task| StackMetrics {
+            name: task.name,
+            size: task.size,
+            // Counted in bytes on ESP-IDF, not in words
+            min_free: unsafe { sys::uxTaskGetStackHighWaterMark(task.handle as sys::TaskHandle_t) },
+        })
+        .collect()
+}
@@ -0,0 +1,57 @@
+use crate::config::StoryConfig;
+use crate::llm_intf::{ChatRequestBuilder, Completion, LlmError, LlmHelper};
+
+const STORY_INSTRUCTION: &str = "你是一位给孩子讲睡前故事的讲述者。根据用户的要求讲一个完整的故事，\
+语气温柔平缓，情节安宁，不要有吓人的内容，结尾让人放松、想睡觉。只输出故事本身，不要标题、列表或表情符号，\
+因为它会被朗读出来。";
+/// Where a story cut short may end
+const SENTENCE_ENDS: [char; 4] = ['。', '！', '？', '…'];
+
+/// Ask the LLM for a bedtime story for `request`, outside the conversation history
+pub fn tell(llm: &LlmHelper, config: &StoryConfig, request: &str) -> Result<Completion, LlmError> {
+    let instruction = format!(
+        "{}讲完以后单独说“{}”。",
+        STORY_INSTRUCTION, config.end_marker
+    );
+    let request = ChatRequestBuilder::new(llm.generation_params())
+        .system(instruction)
+        .user(request)
+        .max_tokens(config.max_tokens)
+        .temperature(1.0)
+        .json_output(false)
+        .build();
+    llm.complete_request(&request)
+}
+
+/// The part of the story to speak, up to the end marker
+///
+/// Ends at the last full sentence, for a story that hit the token limit before the marker and
+/// for "故事讲完了" leaving "故事" behind.
+pub fn finish<'a>(story: &'a str, end_marker: &str) -> &'a str {
+    let story = story.find(end_marker).map_or(story, |end| &story[..end]);
+    let story = story
+        .char_indices()
+        .rev()
+        .find(|(_, c)| SENTENCE_ENDS.contains(c))
+        .map_or(story, |(end, c)| &story[..end + c.len_utf8()]);
+    story.trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finish() {
+        assert_eq!(
+            finish("小兔子睡着了。\n讲完了\n晚安", "讲完了"),
+            "小兔子睡着了。"
+        );
+        assert_eq!(
+            finish("小兔子睡着了。故事讲完了。", "讲完了"),
+            "小兔子睡着了。"
+        );
+        assert_eq!(finish("小兔子睡着了！月亮慢慢", "讲完了"), "小兔子睡着了！");
+        assert_eq!(finish("从前有一只小兔子", "讲完了"), "从前有一只小兔子");
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow;
+use esp_idf_svc::http::client::Configuration as HttpConfiguration;
+use esp_idf_svc::http::Method;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::audio_codec::{AudioUpload, UploadFormat};
+use crate::http_client::{
+    read_response_body, send_multipart_request, AttemptError, HttpError, MultipartData,
+    MultipartFile, PooledConnection, RetryPolicy,
+};
+use crate::language::Language;
+
+mod generic;
+mod streaming;
+mod vosk;
+mod whisper;
+
+pub use generic::GenericSttProvider;
+pub use streaming::{start_streaming_transcriber, AudioStreamMessage, StreamingConfig};
+pub use vosk::VoskProvider;
+pub use whisper::WhisperProvider;
+
+/// URL of the VOSK server the firmware was built with, used unless the configuration provides one
+const DEFAULT_VOSK_URL: &str = match option_env!("VOS_URL") {
+    Some(url) => url,
+    None => "",
+};
+const DEFAULT_STT_TIMEOUT_SECS: u64 = 30;
+/// Failed recordings are queued for a later retry, so only retry right away once
+const DEFAULT_STT_ATTEMPTS: u32 = 2;
+/// Results less certain than this are treated as not understood
+const DEFAULT_MIN_CONFIDENCE: f32 = 0.3;
+
+/// Which transcription service recordings are sent to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SttProviderKind {
+    /// The bundled vosk_server.py, returns the transcript as plain text
+    #[default]
+    Vosk,
+    /// OpenAI's /v1/audio/transcriptions or a compatible server
+    Whisper,
+    /// Any multipart endpoint, described by the fields below
+    Generic,
+}
+
+/// Settings of the speech to text service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SttConfig {
+    pub provider: SttProviderKind,
+    /// Endpoint URL, defaults to the VOS_URL the firmware was built with or OpenAI's API for whisper
+    pub url: Option<String>,
+    /// Sent as a bearer token when set
+    pub api_key: Option<String>,
+    /// Whisper model name, defaults to "whisper-1"
+    pub model: Option<String>,
+    /// Language of the recordings for whisper, defaults to "zh"; "auto" lets whisper detect it
+    pub language: Option<String>,
+    /// Form field carrying the audio file
+    pub file_field: String,
+    /// Extra text fields added to the form, e.g. { language = "zh" }
+    pub fields: BTreeMap<String, String>,
+    /// Dotted path of the transcript in a JSON response, e.g. "result.text";
+    /// unset means the whole body is the transcript
+    pub text_field: Option<String>,
+    /// Dotted path of a 0..1 confidence score in a JSON response, if the service reports one
+    pub confidence_field: Option<String>,
+    /// Dotted path of a speaker embedding (array of numbers) in a JSON response, e.g. "spk"
+    /// of vosk-server with a speaker model, used to recognize who is talking
+    pub speaker_field: Option<String>,
+    /// Ask the user to repeat when the service is less confident than this
+    pub min_confidence: f32,
+    pub timeout_secs: u64,
+    /// Retries of a failed upload before the recording is queued for later
+    pub retry: RetryPolicy,
+    /// Compress recordings before uploading them, the service has to accept the format
+    pub upload_format: UploadFormat,
+    /// Stream audio to a websocket recognizer while the user talks, the service
+    /// above is only used when streaming fails. Read at boot.
+    pub streaming: Option<StreamingConfig>,
+}
+
+impl Default for SttConfig {
+    fn default() -> Self {
+        Self {
+            provider: SttProviderKind::Vosk,
+            url: None,
+            api_key: None,
+            model: None,
+            language: None,
+            file_field: "file".to_string(),
+            fields: BTreeMap::new(),
+            text_field: None,
+            confidence_field: None,
+            speaker_field: None,
+            min_confidence: DEFAULT_MIN_CONFIDENCE,
+            timeout_secs: DEFAULT_STT_TIMEOUT_SECS,
+            retry: RetryPolicy {
+                max_attempts: DEFAULT_STT_ATTEMPTS,
+                ..Default::default()
+            },
+            upload_format: UploadFormat::Wav,
+            streaming: None,
+        }
+    }
+}
+
+/// Text recognized from one utterance
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Transcription {
+    pub text: String,
+    /// Between 0 and 1, only set when the service reports one
+    pub confidence: Option<f32>,
+    /// Spoken language, only set when the service detects it
+    pub language: Option<Language>,
+    /// Voice print of the speaker, only set when the service computes one
+    pub speaker_embedding: Option<Vec<f32>>,
+}
+
+impl Transcription {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            confidence: None,
+            language: None,
+            speaker_embedding: None,
+        }
+    }
+
+    /// Whether the text is worth answering, as opposed to silence, noise or a guess
+    pub fn is_usable(&self, min_confidence: f32) -> bool {
+        // Only punctuation and whitespace is what recognizers typically return for noise
+        let has_words = self.text.chars().any(char::is_alphanumeric);
+        let confident = self.confidence.map_or(true, |c| c >= min_confidence);
+        has_words && confident
+    }
+}
+
+/// A speech to text backend turning a recording into text
+pub trait SttProvider: Send {
+    /// Name of the service used in logs
+    fn name(&self) -> &'static str;
+
+    /// Transcribe a recording already read into memory
+    fn transcribe(&self, audio: &AudioUpload) -> anyhow::Result<Transcription>;
+}
+
+/// URL recordings are sent to, the provider's default unless the configuration sets one
+pub fn stt_url(config: &SttConfig) -> &str {
+    let default_url = match config.provider {
+        SttProviderKind::Whisper => whisper::DEFAULT_URL,
+        _ => DEFAULT_VOSK_URL,
+    };
+    config.url.as_deref().unwrap_or(default_url)
+}
+
+/// Create the provider selected in the configuration
+pub fn create_stt_provider(config: &SttConfig) -> Box<dyn SttProvider> {
+    let url = stt_url(config);
+    if url.is_empty() {
+        log::warn!("No speech to text URL configured, set [stt] url in config.toml");
+    }
+    let timeout = std::time::Duration::from_secs(config.timeout_secs);
+
+    let provider: Box<dyn SttProvider> = match config.provider {
+        SttProviderKind::Vosk => {
+            Box::new(VoskProvider::new(url, timeout).with_retry(config.retry.clone()))
+        }
+        SttProviderKind::Whisper => {
+            let api_key = config.api_key.as_deref().unwrap_or_else(|| {
+                log::warn!("No api_key configured for the Whisper speech to text service");
+                ""
+            });
+            let mut provider =
+                WhisperProvider::new(url, timeout, api_key).with_retry(config.retry.clone());
+            if let Some(model) = &config.model {
+                provider = provider.with_model(model);
+            }
+            if let Some(language) = &config.language {
+                provider = provider.with_language(language);
+            }
+            Box::new(provider)
+        }
+        SttProviderKind::Generic => Box::new(GenericSttProvider::new(url, timeout, config)),
+    };
+
+    log::info!("Using {} speech to text at {}", provider.name(), url);
+    provider
+}
+
+/// POST a recording as a multipart form and return the response body
+fn post_audio(
+    url: &str,
+    timeout: std::time::Duration,
+    retry: &RetryPolicy,
+    headers: &[(&str, &str)],
+    fields: &[(&str, &str)],
+    file: &MultipartFile,
+) -> anyhow::Result<String> {
+    let http_config = HttpConfiguration {
+        timeout: Some(timeout),
+        // Only used for https endpoints
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+    // Audio streamed while it is recorded can't be sent a second time
+    let no_retries = RetryPolicy::no_retries();
+    let retry = match file.data {
+        MultipartData::Stream(_) => &no_retries,
+        _ => retry,
+    };
+
+    let upload = |_attempt: u32| -> Result<String, AttemptError> {
+        let mut client =
+            PooledConnection::open(url, &http_config).map_err(AttemptError::Transient)?;
+
+        // Send the multipart request and get response
+        client
+            .send(Method::Post, |client| send_multipart_request(client, url, headers, fields, file))
+            .map_err(AttemptError::Transient)?;
+
+        // Process the response
+        let status = client.status();
+        log::info!("Response status: {}", status);
+        if status != 200 {
+            return Err(retry.status_error(HttpError::from_response(&mut client)));
+        }
+
+        let body = read_response_body(&mut client).map_err(AttemptError::Transient)?;
+        client.release();
+        Ok(body)
+    };
+
+    retry
+        .run("Speech to text upload", std::thread::sleep, upload)
+        .map_err(AttemptError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcription_usable() {
+        assert!(Transcription::new("今天天气怎么样").is_usable(0.3));
+        assert!(!Transcription::new("").is_usable(0.3));
+        assert!(!Transcription::new(" 。，").is_usable(0.3));
+
+        let unsure = Transcription {
+            text: "天气".to_string(),
+            confidence: Some(0.2),
+            language: None,
+            speaker_embedding: None,
+        };
+        assert!(!unsure.is_usable(0.3));
+        assert!(unsure.is_usable(0.0));
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow;
+
+use super::{post_audio, SttConfig, SttProvider, Transcription};
+use crate::audio_codec::AudioUpload;
+use crate::http_client::{MultipartFile, RetryPolicy};
+
+/// Provider for any multipart transcription endpoint described in the configuration
+pub struct GenericSttProvider {
+    url: String,
+    timeout: std::time::Duration,
+    retry: RetryPolicy,
+    authorization: Option<String>,
+    file_field: String,
+    fields: Vec<(String, String)>,
+    text_field: Option<String>,
+    confidence_field: Option<String>,
+    speaker_field: Option<String>,
+}
+
+impl GenericSttProvider {
+    pub fn new(url: &str, timeout: std::time::Duration, config: &SttConfig) -> Self {
+        Self {
+            url: url.to_string(),
+            timeout,
+            retry: config.retry.clone(),
+            authorization: config.api_key.as_ref().map(|key| format!("Bearer {}", key)),
+            file_field: config.file_field.clone(),
+            fields: config.fields.clone().into_iter().collect(),
+            text_field: config.text_field.clone(),
+            confidence_field: config.confidence_field.clone(),
+            speaker_field: config.speaker_field.clone(),
+        }
+    }
+}
+
+impl SttProvider for GenericSttProvider {
+    fn name(&self) -> &'static str {
+        "generic"
+    }
+
+    fn transcribe(&self, audio: &AudioUpload) -> anyhow::Result<Transcription> {
+        let headers: Vec<(&str, &str)> = self
+            .authorization
+            .as_deref()
+            .map(|auth| ("Authorization", auth))
+            .into_iter()
+            .collect();
+        let fields: Vec<(&str, &str)> = self
+            .fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let file = MultipartFile {
+            field: &self.file_field,
+            path: &audio.file_name,
+            content_type: audio.content_type,
+            data: audio.multipart_data(),
+        };
+
+        let response_text = post_audio(&self.url, self.timeout, &self.retry, &headers, &fields, &file)?;
+
+        let Some(text_field) = &self.text_field else {
+            return Ok(Transcription::new(response_text.trim().trim_matches('"')));
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&response_text)
+            .map_err(|e| anyhow::anyhow!("Response is not JSON ({}): {}", e, response_text))?;
+
+        let text = lookup(&json, text_field)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Field '{}' missing in response: {}",
+                    text_field,
+                    response_text
+                )
+            })?
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Field '{}' is not a string", text_field))?;
+
+        // A missing score only means the service didn't report one this time
+        let confidence = self
+            .confidence_field
+            .as_deref()
+            .and_then(|path| lookup(&json, path))
+            .and_then(|value| value.as_f64())
+            .map(|value| value as f32);
+
+        let speaker_embedding = self
+            .speaker_field
+            .as_deref()
+            .and_then(|path| lookup(&json, path))
+            .and_then(|value| value.as_array())
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|value| value.as_f64())
+                    .map(|value| value as f32)
+                    .collect()
+            });
+
+        Ok(Transcription {
+            text: text.trim().to_string(),
+            confidence,
+            language: None,
+            speaker_embedding,
+        })
+    }
+}
+
+/// Follow a dotted path such as "result.text" into a JSON response
+fn lookup<'a>(json: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.').try_fold(json, |value, key| value.get(key))
+}
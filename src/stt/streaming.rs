@@ -0,0 +1,421 @@
+use anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::Transcription;
+use crate::dashboard::{self, LiveEvent};
+use crate::http_client::{WebSocket, WebSocketMessage};
+use crate::transcription::TranscriptionMessage;
+
+/// Sample rate of the PCM frames fetched from the AFE
+const SAMPLE_RATE: u32 = 16000;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for the final hypothesis once the user stopped talking
+const FINAL_RESULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wire protocol spoken by the streaming server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamingProtocol {
+    /// alphacep/vosk-server's websocket server
+    #[default]
+    Vosk,
+    /// FunASR's websocket runtime, used in 2pass mode
+    Funasr,
+}
+
+/// Settings of the `[stt.streaming]` table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamingConfig {
+    /// WebSocket endpoint, e.g. "ws://192.168.1.10:2700"
+    pub url: String,
+    #[serde(default)]
+    pub protocol: StreamingProtocol,
+}
+
+/// Messages from the fetch task to the streaming thread
+#[derive(Debug)]
+pub enum AudioStreamMessage {
+    /// PCM samples of the utterance being recorded
+    Audio(Vec<i16>),
+    /// The utterance ended; `fallback` is handed to the worker instead if streaming failed,
+    /// its recording transcribed the usual way
+    End { fallback: TranscriptionMessage },
+    /// The utterance is abandoned, e.g. after the exit command
+    Discard,
+}
+
+#[derive(Debug, Deserialize)]
+struct VoskResult {
+    partial: Option<String>,
+    text: Option<String>,
+    /// Per word details of a final result, requested with "words" in the config message
+    #[serde(default)]
+    result: Vec<VoskWord>,
+    /// Speaker embedding, sent when the server has a speaker model loaded
+    spk: Option<Vec<f32>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VoskWord {
+    conf: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct FunasrResult {
+    #[serde(default)]
+    mode: String,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    is_final: bool,
+}
+
+/// Hypothesis of the current utterance
+#[derive(Debug, Default)]
+struct Transcript {
+    /// Segments the server won't revise any more
+    committed: String,
+    /// Latest hypothesis for the segment still being spoken
+    partial: String,
+    /// Word confidences of the committed segments, when the server reports them
+    confidences: Vec<f32>,
+    /// Speaker embedding of the latest committed segment
+    speaker_embedding: Option<Vec<f32>>,
+}
+
+impl Transcript {
+    /// Apply one server message, returns true when it finalized a segment
+    fn update(&mut self, protocol: StreamingProtocol, message: &str) -> anyhow::Result<bool> {
+        match protocol {
+            StreamingProtocol::Vosk => {
+                let result: VoskResult = serde_json::from_str(message)?;
+                if let Some(text) = result.text {
+                    self.committed.push_str(&join_cjk_words(&text));
+                    self.partial.clear();
+                    self.confidences
+                        .extend(result.result.iter().map(|word| word.conf));
+                    if result.spk.is_some() {
+                        self.speaker_embedding = result.spk;
+                    }
+                    Ok(true)
+                } else {
+                    if let Some(partial) = result.partial {
+                        self.partial = join_cjk_words(&partial);
+                    }
+                    Ok(false)
+                }
+            }
+            StreamingProtocol::Funasr => {
+                let result: FunasrResult = serde_json::from_str(message)?;
+                match result.mode.as_str() {
+                    // The second pass re-recognizes the whole segment
+                    "2pass-offline" | "offline" => {
+                        self.committed.push_str(&result.text);
+                        self.partial.clear();
+                        Ok(true)
+                    }
+                    // Online results only carry the text of the latest chunk
+                    _ => {
+                        self.partial.push_str(&result.text);
+                        Ok(result.is_final)
+                    }
+                }
+            }
+        }
+    }
+
+    fn text(&self) -> String {
+        format!("{}{}", self.committed, self.partial)
+            .trim()
+            .to_string()
+    }
+
+    fn transcription(&self) -> Transcription {
+        let confidence = if self.confidences.is_empty() {
+            None
+        } else {
+            Some(self.confidences.iter().sum::<f32>() / self.confidences.len() as f32)
+        };
+
+        Transcription {
+            text: self.text(),
+            confidence,
+            language: None,
+            speaker_embedding: self.speaker_embedding.clone(),
+        }
+    }
+}
+
+/// One utterance streamed over a websocket connection
+struct StreamSession {
+    socket: WebSocket,
+    protocol: StreamingProtocol,
+    transcript: Transcript,
+}
+
+impl StreamSession {
+    fn connect(config: &StreamingConfig) -> anyhow::Result<Self> {
+        let mut session = Self {
+            socket: WebSocket::connect(&config.url, CONNECT_TIMEOUT)?,
+            protocol: config.protocol,
+            transcript: Transcript::default(),
+        };
+
+        let start_message = match config.protocol {
+            StreamingProtocol::Vosk => {
+                json!({ "config": { "sample_rate": SAMPLE_RATE, "words": 1 } })
+            }
+            StreamingProtocol::Funasr => json!({
+                "mode": "2pass",
+                "chunk_size": [5, 10, 5],
+                "chunk_interval": 10,
+                "wav_name": "esp32",
+                "wav_format": "pcm",
+                "audio_fs": SAMPLE_RATE,
+                "is_speaking": true,
+            }),
+        };
+        session.socket.send_text(&start_message.to_string())?;
+
+        log::info!("Streaming transcription connected to {}", config.url);
+        Ok(session)
+    }
+
+    /// Send a frame of 16 bit little endian PCM and pick up partial hypotheses
+    fn send_audio(&mut self, samples: &[i16]) -> anyhow::Result<()> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.socket.send_binary(&bytes)?;
+
+        while let Some(message) = self.socket.try_recv() {
+            match message {
+                WebSocketMessage::Text(message) => {
+                    let before = self.transcript.text();
+                    self.transcript.update(self.protocol, &message)?;
+                    let text = self.transcript.text();
+                    log::debug!("Partial transcription: {}", text);
+                    if text != before {
+                        dashboard::publish(LiveEvent::PartialTranscript { text });
+                    }
+                }
+                WebSocketMessage::Closed => {
+                    return Err(anyhow::anyhow!(
+                        "Server closed the connection mid-utterance"
+                    ))
+                }
+                WebSocketMessage::Binary(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Signal the end of speech and wait for the final hypothesis
+    fn finish(mut self) -> anyhow::Result<Transcription> {
+        let end_message = match self.protocol {
+            StreamingProtocol::Vosk => json!({ "eof": 1 }),
+            StreamingProtocol::Funasr => json!({ "is_speaking": false }),
+        };
+        self.socket.send_text(&end_message.to_string())?;
+
+        let deadline = Instant::now() + FINAL_RESULT_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.socket.recv_timeout(remaining) {
+                Some(WebSocketMessage::Text(message)) => {
+                    if self.transcript.update(self.protocol, &message)? {
+                        break;
+                    }
+                }
+                Some(WebSocketMessage::Binary(_)) => {}
+                Some(WebSocketMessage::Closed) => break,
+                None => {
+                    // Speaking the last partial beats uploading the whole recording again
+                    if self.transcript.text().is_empty() {
+                        return Err(anyhow::anyhow!("Timed out waiting for the final result"));
+                    }
+                    log::warn!("No final result from the streaming server, using the partial one");
+                    break;
+                }
+            }
+        }
+
+        Ok(self.transcript.transcription())
+    }
+}
+
+/// Chinese models separate words by spaces, which the rest of the pipeline doesn't expect
+fn join_cjk_words(text: &str) -> String {
+    let mut joined = String::with_capacity(text.len());
+    let mut words = text.split_whitespace().peekable();
+
+    while let Some(word) = words.next() {
+        joined.push_str(word);
+        if let Some(next) = words.peek() {
+            let ascii_boundary = word.ends_with(|c: char| c.is_ascii_alphanumeric())
+                && next.starts_with(|c: char| c.is_ascii_alphanumeric());
+            if ascii_boundary {
+                joined.push(' ');
+            }
+        }
+    }
+
+    joined
+}
+
+/// Stream the audio of each utterance to the server and hand the transcript to the worker
+fn stream_worker(
+    config: StreamingConfig,
+    rx: Receiver<AudioStreamMessage>,
+    transcription_tx: Sender<TranscriptionMessage>,
+) {
+    let mut session: Option<StreamSession> = None;
+    // Set once streaming failed for the current utterance, its recording is transcribed instead
+    let mut failed = false;
+
+    for message in rx {
+        match message {
+            AudioStreamMessage::Audio(samples) => {
+                if failed {
+                    continue;
+                }
+
+                if session.is_none() {
+                    match StreamSession::connect(&config) {
+                        Ok(new_session) => session = Some(new_session),
+                        Err(e) => {
+                            log::warn!("Failed to start streaming transcription: {}", e);
+                            failed = true;
+                            continue;
+                        }
+                    }
+                }
+
+                if let Some(active) = session.as_mut() {
+                    if let Err(e) = active.send_audio(&samples) {
+                        log::warn!("Streaming transcription failed: {}", e);
+                        session = None;
+                        failed = true;
+                    }
+                }
+            }
+            AudioStreamMessage::End { fallback } => {
+                let result = match session.take() {
+                    Some(active) => active.finish(),
+                    None => Err(anyhow::anyhow!("no streaming session")),
+                };
+                failed = false;
+
+                let message = match result {
+                    Ok(transcription) => {
+                        log::info!("Streaming transcription completed: {}", transcription.text);
+                        let path = match fallback {
+                            TranscriptionMessage::TranscribeFile { path } => Some(path),
+                            _ => None,
+                        };
+                        TranscriptionMessage::Transcript { transcription, path }
+                    }
+                    Err(e) => {
+                        log::warn!("Transcribing the recording instead of streaming ({})", e);
+                        fallback
+                    }
+                };
+
+                if transcription_tx.send(message).is_err() {
+                    log::warn!("Transcription worker has exited, stop streaming");
+                    break;
+                }
+            }
+            AudioStreamMessage::Discard => {
+                // Dropping the session closes the connection
+                session = None;
+                failed = false;
+            }
+        }
+    }
+}
+
+/// Start the thread streaming utterances to a websocket speech recognizer
+pub fn start_streaming_transcriber(
+    config: StreamingConfig,
+    transcription_tx: Sender<TranscriptionMessage>,
+) -> anyhow::Result<Sender<AudioStreamMessage>> {
+    let (tx, rx) = mpsc::channel();
+
+    log::info!(
+        "Streaming utterances to {} ({:?} protocol)",
+        config.url,
+        config.protocol
+    );
+
+    thread::Builder::new()
+        .name("stt_stream".to_string())
+        .stack_size(8 * 1024)
+        .spawn(move || stream_worker(config, rx, transcription_tx))?;
+
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_cjk_words() {
+        assert_eq!(join_cjk_words("今天 天气 怎么样"), "今天天气怎么样");
+        assert_eq!(join_cjk_words("打开 wifi 设置"), "打开wifi设置");
+        assert_eq!(join_cjk_words("play hello world"), "play hello world");
+        assert_eq!(join_cjk_words("  "), "");
+    }
+
+    #[test]
+    fn test_vosk_transcript() {
+        let mut transcript = Transcript::default();
+        assert!(!transcript
+            .update(StreamingProtocol::Vosk, r#"{"partial": "今天 天气"}"#)
+            .unwrap());
+        assert_eq!(transcript.text(), "今天天气");
+
+        let final_result = r#"{"text": "今天 天气 好", "result": [
+            {"conf": 1.0, "word": "今天"}, {"conf": 0.5, "word": "天气"}, {"conf": 0.6, "word": "好"}
+        ], "spk": [0.5, -0.5]}"#;
+        assert!(transcript
+            .update(StreamingProtocol::Vosk, final_result)
+            .unwrap());
+        assert!(!transcript
+            .update(StreamingProtocol::Vosk, r#"{"partial": "吗"}"#)
+            .unwrap());
+
+        let transcription = transcript.transcription();
+        assert_eq!(transcription.text, "今天天气好吗");
+        assert!((transcription.confidence.unwrap() - 0.7).abs() < 1e-6);
+        assert_eq!(transcription.speaker_embedding, Some(vec![0.5, -0.5]));
+        assert!(transcript
+            .update(StreamingProtocol::Vosk, "not json")
+            .is_err());
+    }
+
+    #[test]
+    fn test_funasr_transcript() {
+        let mut transcript = Transcript::default();
+        let online = |text: &str| format!(r#"{{"mode": "2pass-online", "text": "{}"}}"#, text);
+        assert!(!transcript
+            .update(StreamingProtocol::Funasr, &online("今天"))
+            .unwrap());
+        assert!(!transcript
+            .update(StreamingProtocol::Funasr, &online("天汽"))
+            .unwrap());
+        assert_eq!(transcript.text(), "今天天汽");
+
+        // The second pass replaces what the online passes guessed
+        let offline = r#"{"mode": "2pass-offline", "text": "今天天气", "is_final": true}"#;
+        assert!(transcript
+            .update(StreamingProtocol::Funasr, offline)
+            .unwrap());
+        assert_eq!(transcript.text(), "今天天气");
+        assert_eq!(transcript.transcription().confidence, None);
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow;
+
+use super::{post_audio, SttProvider, Transcription};
+use crate::audio_codec::AudioUpload;
+use crate::http_client::{MultipartFile, RetryPolicy};
+
+/// Provider for the bundled vosk_server.py, which answers with the bare transcript
+pub struct VoskProvider {
+    url: String,
+    timeout: std::time::Duration,
+    retry: RetryPolicy,
+}
+
+impl VoskProvider {
+    pub fn new(url: &str, timeout: std::time::Duration) -> Self {
+        Self {
+            url: url.to_string(),
+            timeout,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+impl SttProvider for VoskProvider {
+    fn name(&self) -> &'static str {
+        "VOSK"
+    }
+
+    fn transcribe(&self, audio: &AudioUpload) -> anyhow::Result<Transcription> {
+        let file = MultipartFile {
+            field: "file",
+            path: &audio.file_name,
+            content_type: audio.content_type,
+            data: audio.multipart_data(),
+        };
+        let response_text = post_audio(&self.url, self.timeout, &self.retry, &[], &[], &file)?;
+
+        Ok(Transcription::new(
+            response_text.trim_end_matches('"').trim_start_matches('"'),
+        ))
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow;
+use serde::Deserialize;
+
+use super::{post_audio, SttProvider, Transcription};
+use crate::audio_codec::AudioUpload;
+use crate::http_client::{MultipartFile, RetryPolicy};
+use crate::language::Language;
+
+pub const DEFAULT_URL: &str = "https://api.openai.com/v1/audio/transcriptions";
+pub const DEFAULT_MODEL: &str = "whisper-1";
+pub const DEFAULT_LANGUAGE: &str = "zh";
+/// Configured instead of a language code to let Whisper detect the language
+const AUTO_LANGUAGE: &str = "auto";
+
+/// Response of the transcriptions endpoint in the verbose_json format
+#[derive(Debug, Deserialize)]
+struct WhisperResponse {
+    text: String,
+    /// Detected language as an English name, e.g. "chinese"
+    #[serde(default)]
+    language: Option<String>,
+    #[serde(default)]
+    segments: Vec<WhisperSegment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WhisperSegment {
+    avg_logprob: f32,
+    no_speech_prob: f32,
+}
+
+impl WhisperResponse {
+    /// Average probability of the decoded tokens, discounted where the segment is likely silence
+    fn confidence(&self) -> Option<f32> {
+        if self.segments.is_empty() {
+            return None;
+        }
+
+        let total: f32 = self
+            .segments
+            .iter()
+            .map(|segment| segment.avg_logprob.exp() * (1.0 - segment.no_speech_prob))
+            .sum();
+        Some(total / self.segments.len() as f32)
+    }
+}
+
+/// Provider for OpenAI's Whisper API and compatible servers such as faster-whisper-server
+pub struct WhisperProvider {
+    url: String,
+    timeout: std::time::Duration,
+    authorization: String,
+    model: String,
+    language: String,
+    retry: RetryPolicy,
+}
+
+impl WhisperProvider {
+    pub fn new(url: &str, timeout: std::time::Duration, api_key: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            timeout,
+            authorization: format!("Bearer {}", api_key),
+            model: DEFAULT_MODEL.to_string(),
+            language: DEFAULT_LANGUAGE.to_string(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    /// ISO-639-1 code of the spoken language, improves accuracy and latency
+    pub fn with_language(mut self, language: &str) -> Self {
+        self.language = language.to_string();
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+impl SttProvider for WhisperProvider {
+    fn name(&self) -> &'static str {
+        "Whisper"
+    }
+
+    fn transcribe(&self, audio: &AudioUpload) -> anyhow::Result<Transcription> {
+        let headers = [("Authorization", self.authorization.as_str())];
+        let mut fields = vec![
+            ("model", self.model.as_str()),
+            // Segment statistics and the language are only included in the verbose format
+            ("response_format", "verbose_json"),
+        ];
+        if self.language != AUTO_LANGUAGE {
+            fields.push(("language", self.language.as_str()));
+        }
+        let file = MultipartFile {
+            field: "file",
+            path: &audio.file_name,
+            content_type: audio.content_type,
+            data: audio.multipart_data(),
+        };
+
+        let response_text = post_audio(&self.url, self.timeout, &self.retry, &headers, &fields, &file)?;
+        let response: WhisperResponse = serde_json::from_str(&response_text).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to parse Whisper response ({}): {}",
+                e,
+                response_text
+            )
+        })?;
+
+        Ok(Transcription {
+            text: response.text.trim().to_string(),
+            confidence: response.confidence(),
+            language: response.language.as_deref().and_then(Language::from_name),
+            speaker_embedding: None,
+        })
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::SupervisorConfig;
+use crate::console;
+use crate::metrics;
+
+/// How often heartbeats and ended threads are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Pause before a failed task starts over, so a persistent failure doesn't spin
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+/// Failures older than this don't count towards restarting the device
+const RESTART_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Components the supervisor looks after, each registers itself
+static COMPONENTS: Mutex<Vec<Component>> = Mutex::new(Vec::new());
+/// Set by `start`, which runs before any component is started
+static STALL_SECS: AtomicU32 = AtomicU32::new(0);
+static MAX_RESTARTS: AtomicU32 = AtomicU32::new(0);
+
+struct Component {
+    name: &'static str,
+    /// None until the first heartbeat and while restarting, stalls aren't checked then
+    last_beat: Option<Instant>,
+    /// The thread ended and can't be started again, only restarting the device helps
+    ended: bool,
+    /// When the component failed within the restart window
+    failures: VecDeque<Instant>,
+}
+
+/// The calling thread counts as ended once this is dropped, for threads that can't be restarted
+pub struct Alive {
+    name: &'static str,
+    /// Dropped when the thread ends, also by a panic unwinding it
+    thread_bound: PhantomData<*const ()>,
+}
+
+impl Drop for Alive {
+    fn drop(&mut self) {
+        with_component(self.name, |component| component.ended = true);
+    }
+}
+
+/// Watch the registered components, restarting the device when one hangs or ended
+pub fn start(config: &SupervisorConfig) -> anyhow::Result<()> {
+    STALL_SECS.store(config.stall_secs, Ordering::Relaxed);
+    MAX_RESTARTS.store(config.max_restarts, Ordering::Relaxed);
+
+    thread::Builder::new()
+        .name("supervisor".to_string())
+        .stack_size(3 * 1024)
+        .spawn(check_loop)?;
+    Ok(())
+}
+
+/// Run `body` as `name` for good, starting it over when it fails
+///
+/// A task failing more often than `max_restarts` within ten minutes restarts the device, as
+/// does a task that stops calling `heartbeat`.
+pub fn run(name: &'static str, mut body: impl FnMut() -> anyhow::Result<()>) -> ! {
+    loop {
+        let error = match body() {
+            Ok(()) => anyhow::anyhow!("returned"),
+            Err(e) => e,
+        };
+        log::error!("{} failed: {:#}", name, error);
+        metrics::increment("task_restarts");
+
+        let max_restarts = MAX_RESTARTS.load(Ordering::Relaxed);
+        let restart = with_component(name, |component| {
+            component.last_beat = None;
+            record_failure(&mut component.failures, Instant::now(), max_restarts)
+        });
+        if !restart {
+            restart_device(&format!("{} keeps failing", name));
+        }
+        log::warn!("Restarting {} in {} s", name, RESTART_DELAY.as_secs());
+        thread::sleep(RESTART_DELAY);
+    }
+}
+
+/// Watch the calling thread as `name`, the device restarts once the thread ends
+pub fn alive(name: &'static str) -> Alive {
+    with_component(name, |component| component.ended = false);
+    Alive {
+        name,
+        thread_bound: PhantomData,
+    }
+}
+
+/// Tell the supervisor `name` is making progress
+pub fn heartbeat(name: &'static str) {
+    with_component(name, |component| component.last_beat = Some(Instant::now()));
+}
+
+fn check_loop() {
+    loop {
+        thread::sleep(CHECK_INTERVAL);
+
+        let stall_secs = STALL_SECS.load(Ordering::Relaxed);
+        let problem = COMPONENTS.lock().unwrap().iter().find_map(|component| {
+            if component.ended {
+                return Some(format!("{} ended", component.name));
+            }
+            let silent = component.last_beat?.elapsed();
+            (stall_secs > 0 && silent >= Duration::from_secs(stall_secs as u64)).then(|| {
+                format!(
+                    "{} hangs, no heartbeat for {} s",
+                    component.name,
+                    silent.as_secs()
+                )
+            })
+        });
+        if let Some(problem) = problem {
+            restart_device(&problem);
+        }
+    }
+}
+
+fn restart_device(reason: &str) -> ! {
+    log::error!("Restarting the device: {}", reason);
+    console::reboot()
+}
+
+/// Look up `name`, registering it on first use
+fn with_component<T>(name: &'static str, f: impl FnOnce(&mut Component) -> T) -> T {
+    let mut components = COMPONENTS.lock().unwrap();
+    let index = match components
+        .iter()
+        .position(|component| component.name == name)
+    {
+        Some(index) => index,
+        None => {
+            components.push(Component {
+                name,
+                last_beat: None,
+                ended: false,
+                failures: VecDeque::new(),
+            });
+            components.len() - 1
+        }
+    };
+    f(&mut components[index])
+}
+
+/// Add a failure at `now`, false when that makes more than `max_restarts` in the window
+fn record_failure(failures: &mut VecDeque<Instant>, now: Instant, max_restarts: u32) -> bool {
+    while failures
+        .front()
+        .is_some_and(|failed| now.duration_since(*failed) >= RESTART_WINDOW)
+    {
+        failures.pop_front();
+    }
+    failures.push_back(now);
+    failures.len() <= max_restarts as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_failure() {
+        let start = Instant::now();
+        let mut failures = VecDeque::new();
+        assert!(record_failure(&mut failures, start, 2));
+        assert!(record_failure(
+            &mut failures,
+            start + Duration::from_secs(60),
+            2
+        ));
+        assert!(!record_failure(
+            &mut failures,
+            start + Duration::from_secs(120),
+            2
+        ));
+
+        // The first two fell out of the window
+        let later = start + RESTART_WINDOW + Duration::from_secs(90);
+        assert!(record_failure(&mut failures, later, 2));
+        assert_eq!(failures.len(), 2);
+    }
+}
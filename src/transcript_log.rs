@@ -0,0 +1,166 @@
+use std::io::Write;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::TranscriptConfig;
+use crate::session;
+
+pub const TRANSCRIPT_DIR: &str = "/vfat/transcripts";
+/// Older parts of a day's transcript kept after rotation, as YYYYMMDD.1.txt and so on
+const MAX_ROTATED_FILES: u32 = 3;
+/// Anything earlier means SNTP hasn't set the clock yet (2024-01-01 UTC)
+const MIN_VALID_UNIX_SECS: u64 = 1_704_067_200;
+
+/// Who said a line of the transcript
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speaker {
+    User,
+    Assistant,
+}
+
+impl Speaker {
+    fn label(&self) -> &'static str {
+        match self {
+            Speaker::User => "用户",
+            Speaker::Assistant => "助手",
+        }
+    }
+}
+
+/// Daily text files on the SD card with everything the device heard and answered,
+/// plus a copy in the directory of each conversation
+pub struct TranscriptLog {
+    enabled: bool,
+    max_file_bytes: u64,
+    boot: Instant,
+}
+
+impl TranscriptLog {
+    pub fn new(config: &TranscriptConfig) -> Self {
+        if config.enabled {
+            if let Err(e) = std::fs::create_dir_all(TRANSCRIPT_DIR) {
+                log::warn!("Failed to create {}: {}", TRANSCRIPT_DIR, e);
+            }
+        }
+
+        Self {
+            enabled: config.enabled,
+            max_file_bytes: config.max_file_kb * 1024,
+            boot: Instant::now(),
+        }
+    }
+
+    /// Append one line, failures are only logged since the conversation goes on regardless
+    pub fn record(&self, speaker: Speaker, text: &str) {
+        if !self.enabled {
+            return;
+        }
+
+        let (file_stem, timestamp) = match wall_clock_secs() {
+            Some(secs) => {
+                let (date, time) = format_utc(secs);
+                (date.replace('-', ""), format!("{} {}", date, time))
+            }
+            // Without a clock, lines are stamped with the uptime and collected in one file
+            None => (
+                "unsynced".to_string(),
+                format!("+{}s", self.boot.elapsed().as_secs()),
+            ),
+        };
+
+        let path = format!("{}/{}.txt", TRANSCRIPT_DIR, file_stem);
+        self.rotate_if_full(&path, &file_stem);
+
+        let line = format!(
+            "[{}] {}: {}\n",
+            timestamp,
+            speaker.label(),
+            text.replace('\n', " ")
+        );
+        append_line(&path, &line);
+        if let Some(session_path) = session::transcript_path() {
+            append_line(&session_path, &line);
+        }
+    }
+
+    fn rotate_if_full(&self, path: &str, file_stem: &str) {
+        let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if size < self.max_file_bytes {
+            return;
+        }
+
+        let rotated = |index: u32| format!("{}/{}.{}.txt", TRANSCRIPT_DIR, file_stem, index);
+
+        // Shift YYYYMMDD.1.txt to .2 and so on, the oldest part falls off the end
+        let _ = std::fs::remove_file(rotated(MAX_ROTATED_FILES));
+        for index in (1..MAX_ROTATED_FILES).rev() {
+            let _ = std::fs::rename(rotated(index), rotated(index + 1));
+        }
+
+        match std::fs::rename(path, rotated(1)) {
+            Ok(_) => log::info!("Rotated transcript {} after {} bytes", path, size),
+            Err(e) => log::warn!("Failed to rotate transcript {}: {}", path, e),
+        }
+    }
+}
+
+fn append_line(path: &str, line: &str) {
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+
+    if let Err(e) = written {
+        log::warn!("Failed to write transcript {}: {}", path, e);
+    }
+}
+
+/// Seconds since the Unix epoch, None until the clock has been set
+pub fn wall_clock_secs() -> Option<u64> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    (secs >= MIN_VALID_UNIX_SECS).then_some(secs)
+}
+
+/// Format Unix seconds as ("YYYY-MM-DD", "HH:MM:SS") in UTC
+pub fn format_utc(secs: u64) -> (String, String) {
+    let days = (secs / 86_400) as i64;
+    let remainder = secs % 86_400;
+
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!(
+            "{:02}:{:02}:{:02}",
+            remainder / 3600,
+            remainder % 3600 / 60,
+            remainder % 60
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(
+            format_utc(0),
+            ("1970-01-01".to_string(), "00:00:00".to_string())
+        );
+        assert_eq!(
+            format_utc(1_709_210_096),
+            ("2024-02-29".to_string(), "12:34:56".to_string())
+        );
+    }
+}
@@ -4,63 +4,255 @@ use esp_idf_svc::hal::{
     gpio::PinDriver,
     i2s::{I2sDriver, I2sTx},
 };
-use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::collections::VecDeque;
+use std::sync::mpsc::{
+    self, Receiver, RecvError, RecvTimeoutError, Sender, SyncSender, TryRecvError, TrySendError,
+};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::http_client::{read_response, send_multipart_request};
-use crate::llm_intf::{ChatRole, LlmHelper};
+use crate::alarms;
+use crate::answer_cache::AnswerCache;
+use crate::camera;
+use crate::cloud_tts::CloudTts;
+use crate::config::{AppConfig, ConfigStore, BUILT_IN_API_KEY};
+use crate::connectivity::{self, Connectivity};
+use crate::console;
+use crate::content_filter::{ContentFilter, KIDS_MODE_PROMPT};
+use crate::dashboard::{self, LiveEvent};
+use crate::diagnostics;
+use crate::earcon::Earcon;
+use crate::factory_reset;
+use crate::facts;
+use crate::http_client::enter_turn;
+use crate::intent::{IntentReply, CHAT_INTENT, INTENT_INSTRUCTION};
+use crate::knowledge::KnowledgeBase;
+use crate::language::Language;
+use crate::llm_intf::{create_provider, create_providers, CancellationToken, ChatRole, GenerationParams, LlmError, LlmHelper};
+use crate::metrics::{self, LogMetricsSink, MetricsSink};
+use crate::mqtt::MqttMetricsSink;
+use crate::music;
+use crate::notes;
+use crate::offline_commands::{format_uptime, OfflineCommand, OFFLINE_ANNOUNCEMENT};
+use crate::ota;
+use crate::radio;
+use crate::playback::Playback;
+use crate::speakers::{SpeakerProfile, SpeakerRegistry};
+use crate::settings::{
+    Settings, KEY_ACTIVE_PERSONA, KEY_MAX_TOKENS, KEY_REPLY_LENGTH, KEY_TEMPERATURE, KEY_TOP_P,
+    KEY_VOLUME,
+};
+use crate::sd_card;
+use crate::self_test;
+use crate::session;
+use crate::smart_home;
+use crate::stacks;
+use crate::story;
+use crate::stt::Transcription;
+use crate::supervisor;
+use crate::transcript_log::{Speaker, TranscriptLog};
+use crate::translation;
 use crate::tts::{TtsConfig, TtsEngine};
+use crate::upload_queue::QueueOutcome;
+use crate::usage::{self, UsageTracker};
+use crate::voice_commands::{is_confirmation, is_exit_phrase, parse_voice_command, ReplyLength, VoiceCommand};
+use crate::watchdog;
+use crate::wifi;
+
+mod stt_stage;
+
+/// Generation parameters suitable for an embedded device, used unless overridden at runtime
+const DEFAULT_MAX_TOKENS: u32 = 512;
+/// Sampling temperature used when neither the persona nor a runtime override sets it
+const DEFAULT_TEMPERATURE: f32 = 0.7;
+const DEFAULT_TOP_P: f32 = 0.9;
+/// Step applied by the "更有创意"/"更严谨" voice commands
+const TEMPERATURE_STEP: f32 = 0.2;
+/// TTS speed used when the active persona doesn't override it
+const DEFAULT_TTS_SPEED: u32 = 3;
+/// Utterances waiting for the busy worker, beyond this the oldest is stale and dropped
+const MAX_PENDING_UTTERANCES: usize = 2;
+/// How often the dispatcher retries handing queued messages to a busy worker
+const DISPATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Step and floor of the "大声一点"/"小声一点" offline commands, in percent
+const VOLUME_STEP: u8 = 20;
+const MIN_VOLUME: u8 = 20;
+/// Stack sizes of the threads, their peak use is reported by the stacks module
+const STT_STAGE_STACK_SIZE: usize = 16 * 1024;
+const WORKER_STACK_SIZE: usize = 16 * 1024;
+const DISPATCH_STACK_SIZE: usize = 4 * 1024;
+
+/// What the user said last, shown on the web dashboard
+static LAST_TRANSCRIPT: Mutex<Option<String>> = Mutex::new(None);
 
 /// Define message types for the transcription thread
 #[derive(Debug)]
 pub enum TranscriptionMessage {
     TranscribeFile { path: String },
+    /// Utterance recorded in RAM, 16 kHz mono PCM
+    TranscribeSamples { samples: Vec<i16> },
+    /// Text of an utterance already transcribed by the streaming recognizer, along with its recording
+    Transcript {
+        transcription: Transcription,
+        path: Option<String>,
+    },
     RestartSession,
+    /// Change generation parameters at runtime; unset fields keep their value
+    SetGenerationParams {
+        max_tokens: Option<u32>,
+        temperature: Option<f32>,
+        top_p: Option<f32>,
+    },
+    /// Abort the LLM request in flight, e.g. when the user barges in
+    CancelPending,
+    /// Command MultiNet recognized on the device while offline
+    OfflineCommand(OfflineCommand),
+    /// The SD card is nearly full even after deleting old recordings
+    StorageLow { free_bytes: u64 },
+    /// WiFi was lost or came back, from the WiFi supervisor
+    NetworkChanged { connected: bool },
+    /// Check the route to each configured service and log where it breaks
+    RunDiagnostics,
+    /// Install new firmware if the manifest offers any, then reboot
+    UpdateFirmware,
+    /// Wipe settings and conversations and reboot into WiFi provisioning, already confirmed
+    FactoryReset,
+    /// Say something that isn't a reply, e.g. typed into the web dashboard
+    Speak { text: String },
+    /// Play a short sound, e.g. the boot self-test's beep
+    Earcon(Earcon),
+    /// Volume in percent, from a remote control
+    SetVolume(u8),
+    /// Turn the volume up or down by `delta` percent without saying so, e.g. from a volume knob
+    ChangeVolume { delta: i32 },
+    /// Typed question for the conversation's LLM session, the reply text or the error is
+    /// handed to the dashboard as the outcome of request `id`; spoken too when `speak` is set
+    Chat {
+        id: u32,
+        text: String,
+        speak: bool,
+    },
+    /// What the Home Assistant voice pipeline heard, it answers on its own
+    SatelliteTranscript { text: String },
+    /// Audio of the Home Assistant pipeline's answer at the output sample rate, `played` is
+    /// signalled once it has been played
+    SatelliteReply {
+        text: Option<String>,
+        samples: Vec<i16>,
+        played: Sender<()>,
+    },
     Shutdown,
 }
 
-/// Worker function for the transcription thread
+impl TranscriptionMessage {
+    /// Messages carrying something the user said, the only ones dropped when they pile up
+    fn is_utterance(&self) -> bool {
+        matches!(
+            self,
+            TranscriptionMessage::TranscribeFile { .. }
+                | TranscriptionMessage::TranscribeSamples { .. }
+                | TranscriptionMessage::Transcript { .. }
+        )
+    }
+}
+
+/// What the worker reports back to the fetch task, so its state changes don't depend on matching strings
+#[derive(Debug)]
+pub enum TranscriptionEvent {
+    /// What the user said
+    Transcript(String),
+    /// The user ended the conversation, the fetch task goes back to waiting for the wake word
+    ExitCommand,
+    /// Nothing usable was recognized and the user was asked to repeat
+    NotUnderstood,
+    /// The speech to text service failed
+    Error(String),
+    /// The network services became unreachable, on-device commands take over until the next transcript
+    Offline,
+    /// WiFi is back, utterances go to the speech to text service again
+    Online,
+    /// The LLM answered and its reply is about to be spoken
+    LlmReplyStarted,
+    /// The reply to the last utterance has been played
+    PlaybackFinished,
+}
+
+/// What the speech to text stage hands on to the conversation worker
+enum StageMessage {
+    /// Text of an utterance, with the ID its requests are traced under
+    Utterance {
+        transcription: Transcription,
+        turn_id: u32,
+    },
+    /// The utterance couldn't be transcribed and was queued for a retry, or given up on
+    TranscriptionFailed {
+        error: String,
+        outcome: QueueOutcome,
+    },
+    /// The recordings queued while offline have all been transcribed
+    QueueDrained,
+    /// Start a new session with the configuration reloaded from the SD card
+    RestartSession(Box<AppConfig>),
+    /// Messages not about speech, passed through unchanged
+    Control(TranscriptionMessage),
+}
+
+/// Worker function for the conversation stage, asking the LLM and handing replies to playback
 fn transcription_worker(
-    rx: Receiver<TranscriptionMessage>,
-    response_tx: Sender<String>,
-    mut i2s_driver: I2sDriver<'static, I2sTx>,
-    mut sd_pin_driver: PinDriver<'static, impl OutputPin, esp_idf_svc::hal::gpio::Output>,
+    rx: Receiver<StageMessage>,
+    event_tx: Sender<TranscriptionEvent>,
+    i2s_driver: I2sDriver<'static, I2sTx>,
+    sd_pin_driver: PinDriver<'static, impl OutputPin, esp_idf_svc::hal::gpio::Output>,
+    mut config: AppConfig,
+    mut settings: Settings,
+    cancel_token: CancellationToken,
 ) -> anyhow::Result<()> {
     log::info!("Transcription worker thread started");
 
-    // Get token from environment variable at compile time
-    let token = env!("LLM_AUTH_TOKEN");
+    let mut content_filter = ContentFilter::load(&config.filter);
+    let mut answer_cache = AnswerCache::load(&config.cache);
+    let mut transcript_log = TranscriptLog::new(&config.transcripts);
+    let mut knowledge = KnowledgeBase::new(&config.knowledge);
+    // Language of the current conversation, follows the user when they switch
+    let mut session_language = Language::default();
+    let mut active_persona = settings.get(KEY_ACTIVE_PERSONA);
+    let mut reply_length = settings.get(KEY_REPLY_LENGTH).unwrap_or_default();
+    let mut usage_tracker = UsageTracker::load(config.alarms.utc_offset_mins);
+    let mut speaker_registry = SpeakerRegistry::load(&config.speakers);
+    // Person recognized by their voice, the replies are personalized for them
+    let mut current_speaker: Option<SpeakerProfile> = None;
+    let mut volume = settings.get(KEY_VOLUME).map_or(100, |volume| volume.min(100));
+    // A factory reset was asked for by voice, the next utterance has to confirm it
+    let mut awaiting_reset_confirmation = false;
+    // Set by "翻译模式", until "退出翻译" or the next session
+    let mut translating = false;
+
+    // Runtime overrides persisted in NVS take the place of the built-in defaults
+    let mut base_params = GenerationParams {
+        max_tokens: settings.get(KEY_MAX_TOKENS).unwrap_or(DEFAULT_MAX_TOKENS),
+        temperature: settings.get(KEY_TEMPERATURE).unwrap_or(DEFAULT_TEMPERATURE),
+        top_p: settings.get(KEY_TOP_P).unwrap_or(DEFAULT_TOP_P),
+        json_output: false,
+    };
 
     // Create and configure the LLM helper
-    let mut llm = match LlmHelper::new(token, "deepseek-chat") {
+    let mut llm = match LlmHelper::with_provider(create_provider(&config.llm.primary, BUILT_IN_API_KEY)) {
         helper => {
             log::info!("LLM helper created successfully");
             helper
         }
     };
-
-    // Configure with parameters suitable for embedded device
-    llm.configure(
-        Some(512), // Max tokens to generate in response
-        Some(0.7), // Temperature - balanced between deterministic and creative
-        Some(0.9), // Top-p - slightly more focused sampling
-    );
-
-    // Send initial system message to set context
-    llm.send_message(
-        "接下来的请求来自一个语音转文字服务，请小心中间可能有一些字词被识别成同音的字词。请不要使用列表，回答保持一个段落。"
-            .to_string(),
-        ChatRole::System,
-    );
-
-    log::info!("LLM helper initialized with system prompt");
+    llm.set_cancellation_token(cancel_token.clone());
+    // Only publishes while a broker is connected, logs the requests either way
+    llm.set_metrics_sink(Arc::new(MqttMetricsSink));
 
     // Initialize TTS engine
-    let mut tts_engine = match TtsEngine::new_with_config(TtsConfig {
+    let tts_engine = match TtsEngine::new_with_config(TtsConfig {
         max_chunk_chars: 30, // Smaller chunks for embedded device
         chunk_delay_ms: 100, // Longer delay to allow watchdog reset
-        speed: 3,
+        speed: DEFAULT_TTS_SPEED,
+        volume,
     }) {
         Ok(engine) => {
             log::info!("TTS engine initialized successfully with chunking configuration");
@@ -72,83 +264,748 @@ fn transcription_worker(
         }
     };
 
-    sd_pin_driver.set_high().unwrap();
-    let _ = tts_engine.synthesize_and_play("你好，乐鑫", &mut i2s_driver);
-    sd_pin_driver.set_low().unwrap();
+    // Replies are played on their own thread while the next utterance is handled
+    let playback = Playback::start(
+        tts_engine,
+        CloudTts::from_config(&config.cloud_tts),
+        i2s_driver,
+        sd_pin_driver,
+        event_tx.clone(),
+        config.music.duck_percent,
+    )?;
+
+    // Send initial system message to set context
+    start_llm_session(&mut llm, &playback, &config, active_persona.as_deref(), current_speaker.as_ref(), &base_params, reply_length, session_language);
+
+    log::info!("LLM helper initialized with system prompt");
+
+    playback.speak(&config.assistant.greeting);
+
+    // Set while an utterance is handled, an utterance already waiting afterwards arrived meanwhile
+    let mut handled_utterance = false;
 
     loop {
-        match rx.recv() {
-            Ok(TranscriptionMessage::TranscribeFile { path }) => {
-                log::info!("Received request to transcribe file: {}", path);
-
-                match transcribe_audio(&path) {
-                    Ok(transcription) => {
-                        log::info!("Transcription completed: {}", transcription);
-
-                        if transcription != "" {
-                            // Send the transcription back even if LLM fails
-                            if let Err(e) = response_tx.send(transcription.clone()) {
-                                log::error!("Failed to send transcription response: {}", e);
-                            }
+        let message = match rx.try_recv() {
+            Ok(message) => {
+                // A cancelled answer is never coming, so there's nothing to promise
+                if handled_utterance
+                    && !cancel_token.is_cancelled()
+                    && matches!(message, StageMessage::Utterance { .. })
+                {
+                    // The user spoke while the last answer was on its way, let them know it's being worked on
+                    log::info!("Another utterance is waiting, playing the still thinking earcon");
+                    playback.earcon(Earcon::StillThinking);
+                }
+                Ok(message)
+            }
+            Err(TryRecvError::Empty) => rx.recv(),
+            Err(TryRecvError::Disconnected) => Err(RecvError),
+        };
+        handled_utterance = false;
+        // Watched while handling the message, waiting for the next one can take forever
+        let _watch = watchdog::watch();
 
-                            if transcription == "再见" {
-                                sd_pin_driver.set_high().unwrap();
-                                let _ =
-                                    tts_engine.synthesize_and_play("再见", &mut i2s_driver);
-                                sd_pin_driver.set_low().unwrap();
-                                continue;
-                            }
+        match message {
+            Ok(StageMessage::Utterance {
+                transcription,
+                turn_id,
+            }) => {
+                handled_utterance = true;
+                let _turn = enter_turn(turn_id);
+                // A cancel sent before this utterance was meant for an earlier one
+                cancel_token.reset();
 
-                            // Send the transcription to the LLM
-                            log::info!("Sending transcription to LLM...");
+                log::info!(
+                    "Transcription completed: {} (confidence {:?})",
+                    transcription.text,
+                    transcription.confidence
+                );
+
+                if connectivity::go_online() {
+                    log::info!("Speech to text service is reachable again");
+                }
+
+                let usable = transcription.is_usable(config.stt.min_confidence);
+                let language = transcription
+                    .language
+                    .unwrap_or_else(|| Language::detect(&transcription.text));
+                let speaker_embedding = transcription.speaker_embedding;
+                let transcription = transcription.text;
+
+                let mut prompt_changed = false;
+                if usable && language != session_language {
+                    log::info!("User switched from {:?} to {:?}", session_language, language);
+                    session_language = language;
+                    prompt_changed = true;
+                }
+
+                // Keep the last recognized person when this utterance's voice print is inconclusive
+                if let Some(speaker) = speaker_embedding
+                    .as_deref()
+                    .and_then(|embedding| speaker_registry.identify(embedding))
+                {
+                    if current_speaker.as_ref().map(|s| &s.name) != Some(&speaker.name) {
+                        current_speaker = Some(speaker.clone());
+                        prompt_changed = true;
+                    }
+                }
+
+                if prompt_changed {
+                    llm.replace_system_prompt(session_system_prompt(
+                        &config,
+                        active_persona.as_deref(),
+                        current_speaker.as_ref(),
+                        session_language,
+                    ));
+                }
+
+                if !usable {
+                    // Answering a misheard question is worse than asking again
+                    log::warn!("Transcription is empty or unreliable, asking to repeat");
+                    send_event(&event_tx, TranscriptionEvent::NotUnderstood);
+                    playback.speak("我没听清，请再说一遍");
+                    continue;
+                }
+
+                transcript_log.record(Speaker::User, &transcription);
+                usage::record_question();
+
+                // The utterance after asking for a factory reset confirms or cancels it
+                if std::mem::take(&mut awaiting_reset_confirmation) {
+                    let reply = if is_confirmation(&transcription) {
+                        factory_reset(&playback)
+                    } else {
+                        "好的，不恢复出厂设置了".to_string()
+                    };
+                    transcript_log.record(Speaker::Assistant, &reply);
+                    playback.speak(&reply);
+                    continue;
+                }
+
+                if is_exit_phrase(&transcription, &config.assistant.exit_phrases) {
+                    send_event(&event_tx, TranscriptionEvent::ExitCommand);
+                    transcript_log.record(Speaker::Assistant, "再见");
+                    playback.speak("再见");
+                    continue;
+                }
 
-                            let response = llm.send_message(transcription, ChatRole::User);
+                remember_transcript(&transcription);
 
-                            if response.starts_with("Error:") {
-                                log::error!("LLM API error: {}", response);
+                // Send the transcription back even if LLM fails
+                send_event(&event_tx, TranscriptionEvent::Transcript(transcription.clone()));
+
+                // While dictating, utterances are only written down until "结束听写"
+                if notes::is_dictating() {
+                    if parse_voice_command(&transcription) == Some(VoiceCommand::StopDictation) {
+                        notes::set_dictating(false);
+                        playback.speak("听写结束，笔记已保存");
+                        continue;
+                    }
+                    match notes::append(&transcription, config.alarms.utc_offset_mins) {
+                        Ok(()) => playback.earcon(Earcon::NoteSaved),
+                        Err(e) => {
+                            log::warn!("Failed to save the note: {}", e);
+                            playback.speak("笔记保存失败");
+                        }
+                    }
+                    continue;
+                }
+
+                // In translation mode every utterance is translated instead of answered
+                if translating {
+                    if parse_voice_command(&transcription) == Some(VoiceCommand::StopTranslation) {
+                        translating = false;
+                        playback.speak("已退出翻译模式");
+                        continue;
+                    }
+                    let translated = {
+                        let _thinking = playback.thinking(&config.thinking);
+                        translation::translate(&llm, &transcription)
+                    };
+                    match translated {
+                        Ok((completion, language)) => {
+                            if let Some(usage) = &completion.usage {
+                                usage_tracker.record(usage);
+                            }
+                            let text = content_filter.apply(completion.content.trim());
+                            log::info!("Translated into {}: {}", language.code(), text);
+                            transcript_log.record(Speaker::Assistant, &text);
+                            send_event(&event_tx, TranscriptionEvent::LlmReplyStarted);
+                            // The on-device voice only speaks Chinese
+                            playback.speak_reply(&text, true);
+                        }
+                        Err(e) => {
+                            log::error!("Translation failed: {}", e);
+                            playback.speak("翻译失败，请再说一遍");
+                        }
+                    }
+                    continue;
+                }
+
+                // Switching a light shouldn't wait for the LLM
+                if let Some((device, action)) = smart_home::parse(&config.smart_home, &transcription) {
+                    let reply = smart_home::execute(device, &action).unwrap_or_else(|error| error);
+                    transcript_log.record(Speaker::Assistant, &reply);
+                    playback.speak(&reply);
+                    continue;
+                }
+
+                // Device commands are handled locally instead of asking the LLM; without a camera
+                // "看看这是什么" is a question for it as well
+                let command = parse_voice_command(&transcription).filter(|command| {
+                    *command != VoiceCommand::DescribeImage || config.camera.enabled
+                });
+                if let Some(command) = command {
+                    let reply = match command {
+                        VoiceCommand::ReplayLastAnswer => {
+                            // Played from what the speaker still has, no LLM round trip
+                            playback.replay_last();
+                            continue;
+                        }
+                        VoiceCommand::SwitchPersona(name) => {
+                            if config.find_persona(&name).is_some() {
+                                if let Err(e) = settings.set(KEY_ACTIVE_PERSONA, name.clone()) {
+                                    log::warn!("Failed to persist active persona: {}", e);
+                                }
+                                active_persona = Some(name.clone());
+                                llm.clear_history();
+                                start_llm_session(&mut llm, &playback, &config, active_persona.as_deref(), current_speaker.as_ref(), &base_params, reply_length, session_language);
+                                format!("已切换到{}模式", name)
                             } else {
-                                log::info!("LLM response: {}", response);
-
-                                // Convert LLM response to audio using TTS
-                                log::info!("Converting LLM response to audio...");
-
-                                sd_pin_driver.set_high().unwrap(); // Ensure SD pin is enabled
-                                if let Err(e) =
-                                    tts_engine.synthesize_and_play(&response, &mut i2s_driver)
-                                {
-                                    log::error!("Failed to synthesize and play audio: {}", e);
-                                } else {
-                                    log::info!(
-                                        "Audio synthesis and playback completed successfully"
-                                    );
+                                log::warn!("Unknown persona requested: {}", name);
+                                format!("没有找到{}模式", name)
+                            }
+                        }
+                        VoiceCommand::ResetPersona => {
+                            if let Err(e) = settings.remove(KEY_ACTIVE_PERSONA) {
+                                log::warn!("Failed to clear active persona: {}", e);
+                            }
+                            active_persona = None;
+                            llm.clear_history();
+                            start_llm_session(&mut llm, &playback, &config, None, current_speaker.as_ref(), &base_params, reply_length, session_language);
+                            "已切换到默认模式".to_string()
+                        }
+                        VoiceCommand::QueryTokenUsage => usage_tracker.spoken_summary(),
+                        VoiceCommand::QueryDailyUsage => usage::daily_summary(),
+                        VoiceCommand::QueryStorage => {
+                            let metrics = sd_card::storage_metrics("/vfat", true);
+                            LogMetricsSink.record_storage(&metrics);
+                            metrics.spoken_summary()
+                        }
+                        VoiceCommand::QueryNetwork => {
+                            let metrics = wifi::network_metrics();
+                            LogMetricsSink.record_network(&metrics);
+                            metrics.spoken_summary()
+                        }
+                        VoiceCommand::DiagnoseNetwork => {
+                            playback.speak("正在检查网络，请稍等");
+                            diagnostics::spoken_summary(&diagnostics::run(&diagnostics::targets(&config)))
+                        }
+                        VoiceCommand::UpdateFirmware => update_firmware(&config, &playback),
+                        VoiceCommand::PlayRadio(name) => match config.radio.find_station(&name) {
+                            None if config.radio.stations.is_empty() => "还没有配置电台".to_string(),
+                            None => format!("没有找到{}电台", name),
+                            // The announcement plays first, the stream takes over once it's done
+                            Some(station) => match radio::open(&station.url) {
+                                Ok(chunks) => {
+                                    playback.play_stream(chunks);
+                                    format!("正在播放{}", station.name)
+                                }
+                                Err(e) => {
+                                    log::error!("Failed to open station {}: {}", station.name, e);
+                                    "电台打开失败".to_string()
+                                }
+                            },
+                        },
+                        VoiceCommand::PlayMusic(query) => match music::play(&query) {
+                            Ok(Some(playing)) => {
+                                playback.play_stream(playing.chunks);
+                                format!("正在播放{}", playing.title)
+                            }
+                            Ok(None) if query.is_empty() => "存储卡的music文件夹里没有音乐".to_string(),
+                            Ok(None) => format!("没有找到{}", query),
+                            Err(e) => {
+                                log::error!("Failed to play music: {}", e);
+                                "音乐播放失败".to_string()
+                            }
+                        },
+                        VoiceCommand::NextTrack => match music::next() {
+                            Ok(Some(playing)) => {
+                                playback.play_stream(playing.chunks);
+                                format!("下一首，{}", playing.title)
+                            }
+                            Ok(None) => "现在没有在播放音乐".to_string(),
+                            Err(e) => {
+                                log::error!("Failed to play the next track: {}", e);
+                                "音乐播放失败".to_string()
+                            }
+                        },
+                        VoiceCommand::StopPlaying => {
+                            playback.stop_stream();
+                            "已停止播放".to_string()
+                        }
+                        VoiceCommand::PausePlaying => {
+                            playback.pause_stream();
+                            "已暂停".to_string()
+                        }
+                        VoiceCommand::ResumePlaying => {
+                            playback.resume_stream();
+                            "继续播放".to_string()
+                        }
+                        VoiceCommand::StartDictation => {
+                            notes::set_dictating(true);
+                            "开始听写，说“结束听写”退出".to_string()
+                        }
+                        VoiceCommand::StopDictation => "现在没有在听写".to_string(),
+                        VoiceCommand::StartTranslation if config.cloud_tts.api_key.is_none() => {
+                            "翻译模式要用云端语音朗读英文，请先配置 cloud_tts".to_string()
+                        }
+                        VoiceCommand::StartTranslation => {
+                            translating = true;
+                            "已进入翻译模式，说中文翻译成英文，说英文翻译成中文，说“退出翻译”结束".to_string()
+                        }
+                        VoiceCommand::StopTranslation => "现在不在翻译模式".to_string(),
+                        VoiceCommand::BedtimeStory => {
+                            let told = {
+                                let _thinking = playback.thinking(&config.thinking);
+                                story::tell(&llm, &config.story, &transcription)
+                            };
+                            match told {
+                                Ok(completion) => {
+                                    if let Some(usage) = &completion.usage {
+                                        usage_tracker.record(usage);
+                                    }
+                                    let story = story::finish(&completion.content, &config.story.end_marker);
+                                    let text = content_filter.apply(story);
+                                    log::info!("Telling a story of {} characters", text.chars().count());
+                                    transcript_log.record(Speaker::Assistant, &text);
+                                    let use_cloud_voice = config
+                                        .language(session_language)
+                                        .map_or(false, |overrides| overrides.cloud_tts);
+                                    let max_duration = Duration::from_secs(config.story.max_minutes as u64 * 60);
+                                    let story_volume = volume as u32 * config.story.volume_percent as u32 / 100;
+
+                                    // Played in order, the usual voice is back once the story ended
+                                    send_event(&event_tx, TranscriptionEvent::LlmReplyStarted);
+                                    playback.set_speed(config.story.tts_speed);
+                                    playback.set_volume(story_volume as u8);
+                                    playback.speak_reply_until(&text, use_cloud_voice, Instant::now() + max_duration);
+                                    playback.set_speed(persona_tts_speed(&config, active_persona.as_deref()));
+                                    playback.set_volume(volume);
+                                    continue;
+                                }
+                                Err(e) => {
+                                    log::error!("Failed to get a story: {}", e);
+                                    "故事没想出来，请再说一遍".to_string()
                                 }
-                                sd_pin_driver.set_low().unwrap(); // Ensure SD pin is disabled
                             }
                         }
+                        VoiceCommand::DescribeImage if !camera::is_ready() => {
+                            "摄像头没有启动，请检查连接后重启".to_string()
+                        }
+                        VoiceCommand::DescribeImage => match camera::capture() {
+                            Err(e) => {
+                                log::error!("Failed to take a picture: {}", e);
+                                "拍照失败，请再试一次".to_string()
+                            }
+                            Ok(jpeg) => {
+                                let described = {
+                                    let _thinking = playback.thinking(&config.thinking);
+                                    camera::describe(&llm, config.camera.vision.as_ref(), &transcription, &jpeg)
+                                };
+                                match described {
+                                    Ok(completion) => {
+                                        if let Some(usage) = &completion.usage {
+                                            usage_tracker.record(usage);
+                                        }
+                                        let text = content_filter.apply(completion.content.trim());
+                                        // Follow-ups like "它能吃吗" need to know what was seen
+                                        llm.record_exchange(&transcription, &text);
+                                        text
+                                    }
+                                    Err(e) => {
+                                        log::error!("Failed to describe the picture: {}", e);
+                                        "没看清楚，请再试一次".to_string()
+                                    }
+                                }
+                            }
+                        },
+                        VoiceCommand::FactoryReset => {
+                            awaiting_reset_confirmation = true;
+                            "确定要恢复出厂设置吗？所有设置和对话记录都会被清除，确定的话请说“确定”".to_string()
+                        }
+                        VoiceCommand::AdjustTemperature { increase } => {
+                            let current = llm.generation_params().temperature;
+                            let temperature = if increase {
+                                (current + TEMPERATURE_STEP).min(2.0)
+                            } else {
+                                (current - TEMPERATURE_STEP).max(0.0)
+                            };
+                            update_generation_params(
+                                &mut llm,
+                                &mut settings,
+                                &mut base_params,
+                                None,
+                                Some(temperature),
+                                None,
+                            );
+                            if increase {
+                                "好的，回答会更有创意一些".to_string()
+                            } else {
+                                "好的，回答会更严谨一些".to_string()
+                            }
+                        }
+                        VoiceCommand::SetReplyLength(length) => {
+                            reply_length = length;
+                            if let Err(e) = settings.set(KEY_REPLY_LENGTH, length) {
+                                log::warn!("Failed to persist reply length: {}", e);
+                            }
+                            apply_reply_length(&mut llm, &base_params, length);
+                            match length {
+                                ReplyLength::Brief => "好的，之后我会简短回答".to_string(),
+                                ReplyLength::Normal => "好的，回答恢复正常长度".to_string(),
+                                ReplyLength::Detailed => "好的，之后我会回答得详细一些".to_string(),
+                            }
+                        }
+                        VoiceCommand::EnrollSpeaker(name) => match speaker_embedding {
+                            _ if !speaker_registry.is_enabled() => "还没有开启声音识别".to_string(),
+                            None => "语音识别服务没有提供声纹，无法记住你的声音".to_string(),
+                            Some(embedding) => match speaker_registry.enroll(&name, embedding) {
+                                Ok(profile) => {
+                                    current_speaker = Some(profile.clone());
+                                    llm.replace_system_prompt(session_system_prompt(
+                                        &config,
+                                        active_persona.as_deref(),
+                                        current_speaker.as_ref(),
+                                        session_language,
+                                    ));
+                                    format!("好的{}，我记住你的声音了", name)
+                                }
+                                Err(e) => {
+                                    log::warn!("Failed to save the profile of {}: {}", name, e);
+                                    "没能保存你的声音".to_string()
+                                }
+                            },
+                        },
+                    };
+
+                    transcript_log.record(Speaker::Assistant, &reply);
+                    playback.speak_reply(&reply, false);
+                    continue;
+                }
+
+                let cache_key = answer_cache.key(active_persona.as_deref(), &transcription);
+                let cached_answer = cache_key.as_deref().and_then(|key| answer_cache.get(key));
+
+                let reply = if let Some(answer) = cached_answer {
+                    log::info!("Answering from cache: {}", answer);
+                    llm.record_exchange(&transcription, &answer);
+                    Some(answer)
+                } else {
+                    // Send the transcription to the LLM
+                    log::info!("Sending transcription to LLM...");
+
+                    let started = Instant::now();
+                    let (response, from_notes) = {
+                        let _thinking = playback.thinking(&config.thinking);
+                        let context = knowledge.context(&transcription);
+                        let from_notes = context.is_some();
+                        llm.set_context(context);
+                        (llm.send_message(transcription, ChatRole::User), from_notes)
+                    };
+
+                    if let Some(usage) = llm.take_last_usage() {
+                        usage_tracker.record(&usage);
                     }
-                    Err(e) => {
-                        log::error!("Failed to transcribe audio: {}", e);
-                        // Send error message back
-                        if let Err(e) = response_tx.send(format!("Error: {}", e)) {
-                            log::error!("Failed to send error response: {}", e);
+
+                    match response {
+                        // Interrupted on purpose, says nothing about the network
+                        Err(LlmError::Cancelled) => None,
+                        // The service answered, so the device isn't offline, only misconfigured
+                        Err(LlmError::Fatal(e)) => {
+                            log::error!("LLM API error: {}", e);
+                            metrics::increment("llm_errors");
+                            playback.speak("大模型服务拒绝了请求，请检查配置");
+                            None
+                        }
+                        Err(e) => {
+                            log::error!("LLM API error: {}", e);
+                            metrics::increment("llm_errors");
+                            if !cancel_token.is_cancelled() && enter_offline(&event_tx) {
+                                playback.speak(OFFLINE_ANNOUNCEMENT);
+                            }
+                            None
+                        }
+                        Ok(response) => {
+                            log::info!("LLM response: {}", response);
+                            metrics::record_time("llm", started.elapsed());
+
+                            // Only plain answers are cached, never replies that trigger an action
+                            let (text, cacheable) = if config.llm.structured_output {
+                                let reply = IntentReply::parse_or_plain(&response);
+                                let text = dispatch_intent(&reply, &config);
+                                let cacheable = reply.intent == CHAT_INTENT;
+                                (text, cacheable)
+                            } else {
+                                (response, true)
+                            };
+
+                            // The notes may have changed by the time the question is asked again
+                            if let (Some(key), true, false) = (cache_key, cacheable, from_notes) {
+                                answer_cache.insert(key, text.clone());
+                            }
+
+                            Some(text)
                         }
                     }
+                };
+
+                if let Some(response) = reply {
+                    let response = content_filter.apply(&response);
+                    transcript_log.record(Speaker::Assistant, &response);
+                    session::save_history(llm.messages());
+
+                    let use_cloud_voice = config
+                        .language(session_language)
+                        .map_or(false, |overrides| overrides.cloud_tts);
+
+                    // Synthesis runs on the playback thread, the next utterance can be handled meanwhile
+                    send_event(&event_tx, TranscriptionEvent::LlmReplyStarted);
+                    playback.speak_reply(&response, use_cloud_voice);
+                }
+            }
+            Ok(StageMessage::TranscriptionFailed { error, outcome }) => {
+                // Send error message back
+                send_event(&event_tx, TranscriptionEvent::Error(error));
+
+                // The service answered, so the device isn't offline, only misconfigured
+                let went_offline =
+                    outcome != QueueOutcome::Rejected && enter_offline(&event_tx);
+                if went_offline {
+                    playback.speak(OFFLINE_ANNOUNCEMENT);
                 }
+
+                let announcement = match outcome {
+                    // Already covered by the offline announcement
+                    QueueOutcome::Queued if went_offline => None,
+                    QueueOutcome::Queued => Some("网络好像不太好，我稍后再试"),
+                    QueueOutcome::Retrying => None,
+                    QueueOutcome::Dropped => Some("有录音一直没能识别，已经放弃了"),
+                    QueueOutcome::Rejected => Some("语音识别服务拒绝了请求，请检查配置"),
+                };
+                if let Some(text) = announcement {
+                    playback.speak(text);
+                }
+            }
+            Ok(StageMessage::QueueDrained) => {
+                playback.speak("网络恢复了，离线时的录音都处理完了");
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::OfflineCommand(command))) => {
+                log::info!("Offline command: {:?}", command);
+                let reply = match command {
+                    OfflineCommand::Uptime => {
+                        let uptime_us = unsafe { esp_idf_svc::sys::esp_timer_get_time() };
+                        format_uptime(uptime_us as u64 / 1_000_000)
+                    }
+                    OfflineCommand::VolumeUp | OfflineCommand::VolumeDown => {
+                        volume = if command == OfflineCommand::VolumeUp {
+                            volume.saturating_add(VOLUME_STEP).min(100)
+                        } else {
+                            volume.saturating_sub(VOLUME_STEP).max(MIN_VOLUME)
+                        };
+                        if let Err(e) = settings.set(KEY_VOLUME, volume) {
+                            log::warn!("Failed to persist volume: {}", e);
+                        }
+                        playback.set_volume(volume);
+                        format!("音量{}", volume)
+                    }
+                    OfflineCommand::ReplayLastAnswer => {
+                        playback.replay_last();
+                        continue;
+                    }
+                    OfflineCommand::SelfTest => self_test_report(),
+                    OfflineCommand::TimerFiveMinutes => {
+                        alarms::set_timer(5 * 60, "").unwrap_or_else(|error| error)
+                    }
+                    OfflineCommand::TimerTenMinutes => {
+                        alarms::set_timer(10 * 60, "").unwrap_or_else(|error| error)
+                    }
+                    OfflineCommand::CancelAlarms => alarms::cancel_all(),
+                    OfflineCommand::SmartHome(id) => match smart_home::offline_action(&config.smart_home, id) {
+                        Some((device, action)) => {
+                            smart_home::execute(device, &action).unwrap_or_else(|error| error)
+                        }
+                        // The device was removed from the configuration since boot
+                        None => continue,
+                    },
+                };
+                playback.speak(&reply);
             }
-            Ok(TranscriptionMessage::RestartSession) => {
+            Ok(StageMessage::RestartSession(new_config)) => {
                 log::info!("Received restart session request, clearing LLM history");
+                config = *new_config;
+                translating = false;
+                content_filter = ContentFilter::load(&config.filter);
+                answer_cache = AnswerCache::load(&config.cache);
+                transcript_log = TranscriptLog::new(&config.transcripts);
+                knowledge.reconfigure(&config.knowledge);
+                playback.set_cloud_tts(CloudTts::from_config(&config.cloud_tts));
+                session_language = Language::default();
+                speaker_registry = SpeakerRegistry::load(&config.speakers);
+                current_speaker = None;
+                usage_tracker.reset_session();
+                usage::set_utc_offset(config.alarms.utc_offset_mins);
                 llm.clear_history();
                 // Re-add the system message
-                llm.send_message(
-                    "接下来的请求来自一个语音转文字服务，请小心中间可能有一些字词被识别成同音的字词。请不要使用列表，不要包含*，回答保持一个段落。"
-                        .to_string(),
-                    ChatRole::System,
+                start_llm_session(&mut llm, &playback, &config, active_persona.as_deref(), current_speaker.as_ref(), &base_params, reply_length, session_language);
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::SetGenerationParams {
+                max_tokens,
+                temperature,
+                top_p,
+            })) => {
+                update_generation_params(
+                    &mut llm,
+                    &mut settings,
+                    &mut base_params,
+                    max_tokens,
+                    temperature,
+                    top_p,
                 );
             }
-            Ok(TranscriptionMessage::Shutdown) => {
+            Ok(StageMessage::Control(TranscriptionMessage::StorageLow { free_bytes })) => {
+                log::warn!("Only {} KB left on the SD card", free_bytes / 1024);
+                playback.speak("存储卡快满了，请清理一下存储卡");
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::NetworkChanged { connected })) => {
+                if !connected {
+                    if enter_offline(&event_tx) {
+                        playback.speak(OFFLINE_ANNOUNCEMENT);
+                    }
+                } else if connectivity::go_online() {
+                    log::info!("WiFi is back, leaving offline mode");
+                    send_event(&event_tx, TranscriptionEvent::Online);
+                    playback.speak("网络恢复了");
+                }
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::RunDiagnostics)) => {
+                diagnostics::run(&diagnostics::targets(&config));
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::UpdateFirmware)) => {
+                let reply = update_firmware(&config, &playback);
+                playback.speak(&reply);
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::FactoryReset)) => {
+                let reply = factory_reset(&playback);
+                playback.speak(&reply);
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::Speak { text })) => {
+                playback.speak(&text);
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::Earcon(earcon))) => {
+                playback.earcon(earcon);
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::SetVolume(new_volume))) => {
+                volume = new_volume.min(100);
+                if let Err(e) = settings.set(KEY_VOLUME, volume) {
+                    log::warn!("Failed to persist volume: {}", e);
+                }
+                playback.set_volume(volume);
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::Chat { id, text, speak })) => {
+                log::info!("Chat request: {}", text);
+                transcript_log.record(Speaker::User, &text);
+                remember_transcript(&text);
+
+                let response = {
+                    let _thinking = speak.then(|| playback.thinking(&config.thinking));
+                    llm.set_context(knowledge.context(&text));
+                    llm.send_message(text, ChatRole::User)
+                };
+                if let Some(usage) = llm.take_last_usage() {
+                    usage_tracker.record(&usage);
+                }
+
+                let response = match response {
+                    Ok(response) => response,
+                    Err(e) => {
+                        log::error!("LLM API error: {}", e);
+                        dashboard::chat_answered(id, Err(e.to_string()));
+                        continue;
+                    }
+                };
+
+                let response = if config.llm.structured_output {
+                    let intent_reply = IntentReply::parse_or_plain(&response);
+                    dispatch_intent(&intent_reply, &config)
+                } else {
+                    response
+                };
+                let response = content_filter.apply(&response);
+                transcript_log.record(Speaker::Assistant, &response);
+                session::save_history(llm.messages());
+
+                if speak {
+                    let use_cloud_voice = config
+                        .language(session_language)
+                        .map_or(false, |overrides| overrides.cloud_tts);
+                    playback.speak_reply(&response, use_cloud_voice);
+                } else {
+                    dashboard::publish(LiveEvent::Reply {
+                        text: response.clone(),
+                    });
+                }
+                dashboard::chat_answered(id, Ok(response));
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::SatelliteTranscript { text })) => {
+                if text.trim().is_empty() {
+                    // Home Assistant heard nothing, keep listening without asking to repeat
+                    send_event(&event_tx, TranscriptionEvent::NotUnderstood);
+                    continue;
+                }
+
+                log::info!("Home Assistant heard: {}", text);
+                transcript_log.record(Speaker::User, &text);
+                if is_exit_phrase(&text, &config.assistant.exit_phrases) {
+                    send_event(&event_tx, TranscriptionEvent::ExitCommand);
+                    continue;
+                }
+
+                remember_transcript(&text);
+                send_event(&event_tx, TranscriptionEvent::Transcript(text));
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::SatelliteReply {
+                text,
+                samples,
+                played,
+            })) => {
+                if let Some(text) = text {
+                    transcript_log.record(Speaker::Assistant, &text);
+                    dashboard::publish(LiveEvent::Reply { text });
+                }
+                send_event(&event_tx, TranscriptionEvent::LlmReplyStarted);
+                playback.play_reply_audio(samples, played);
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::ChangeVolume { delta })) => {
+                volume = (volume as i32 + delta).clamp(MIN_VOLUME as i32, 100) as u8;
+                if let Err(e) = settings.set(KEY_VOLUME, volume) {
+                    log::warn!("Failed to persist volume: {}", e);
+                }
+                playback.set_volume(volume);
+                playback.earcon(Earcon::VolumeTick);
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::CancelPending)) => {
+                // Handled by the dispatcher since the worker is blocked while a request is in flight
+                log::debug!("No LLM request in flight to cancel");
+            }
+            Ok(StageMessage::Control(TranscriptionMessage::Shutdown)) => {
                 log::info!("Transcription worker received shutdown signal");
                 break;
             }
+            Ok(StageMessage::Control(_)) => {
+                // Utterances and session restarts are turned into stage messages before reaching the worker
+                log::warn!("Unexpected message passed through the speech to text stage");
+            }
             Err(e) => {
                 log::error!("Error receiving message in transcription worker: {}", e);
                 break;
@@ -160,54 +1017,434 @@ fn transcription_worker(
     Ok(())
 }
 
+/// Apply the active persona and send its system prompt to start a new conversation
+fn start_llm_session(
+    llm: &mut LlmHelper,
+    playback: &Playback,
+    config: &AppConfig,
+    active_persona: Option<&str>,
+    speaker: Option<&SpeakerProfile>,
+    base_params: &GenerationParams,
+    reply_length: ReplyLength,
+    language: Language,
+) {
+    llm.set_providers(create_providers(&config.llm, BUILT_IN_API_KEY));
+    llm.set_retry_policy(config.llm.retry.clone());
+    llm.set_history_budget(config.llm.history.clone());
+    llm.set_json_output(config.llm.structured_output);
+
+    match active_persona.and_then(|name| config.find_persona(name)) {
+        Some(persona) => {
+            log::info!("Starting session with persona '{}'", persona.name);
+            llm.configure(
+                Some(base_params.max_tokens),
+                Some(persona.temperature.unwrap_or(base_params.temperature)),
+                Some(base_params.top_p),
+            );
+        }
+        None => {
+            llm.configure(
+                Some(base_params.max_tokens),
+                Some(base_params.temperature),
+                Some(base_params.top_p),
+            );
+        }
+    }
+    playback.set_speed(persona_tts_speed(config, active_persona));
+    apply_reply_length(llm, base_params, reply_length);
+
+    // System messages are only recorded, never sent, so there's nothing to fail
+    let _ = llm.send_message(
+        session_system_prompt(config, active_persona, speaker, language),
+        ChatRole::System,
+    );
+}
+
+/// TTS speed of the active persona, the default without one
+fn persona_tts_speed(config: &AppConfig, active_persona: Option<&str>) -> u32 {
+    active_persona
+        .and_then(|name| config.find_persona(name))
+        .and_then(|persona| persona.tts_speed)
+        .unwrap_or(DEFAULT_TTS_SPEED)
+}
+
+/// System prompt for the active persona, the person talking and the language they speak
+fn session_system_prompt(
+    config: &AppConfig,
+    active_persona: Option<&str>,
+    speaker: Option<&SpeakerProfile>,
+    language: Language,
+) -> String {
+    let language_prompt = config
+        .language(language)
+        .and_then(|overrides| overrides.system_prompt.clone());
+
+    let system_prompt = match (language_prompt, active_persona.and_then(|name| config.find_persona(name))) {
+        // Personas are written for Chinese conversations, a language prompt takes precedence
+        (Some(prompt), _) => prompt,
+        (None, Some(persona)) => config.assistant.persona_system_prompt(persona),
+        (None, None) => config.assistant.full_system_prompt(),
+    };
+
+    let system_prompt = match speaker {
+        Some(speaker) => format!("{}\n\n{}", system_prompt, speaker.prompt()),
+        None => system_prompt,
+    };
+
+    // Remembered in earlier sessions, read again for every session so new facts are included
+    let system_prompt = match facts::prompt() {
+        Some(facts) => format!("{}\n\n{}", system_prompt, facts),
+        None => system_prompt,
+    };
+
+    let system_prompt = if config.filter.kids_mode {
+        format!("{}\n\n{}", system_prompt, KIDS_MODE_PROMPT)
+    } else {
+        system_prompt
+    };
+
+    if config.llm.structured_output {
+        match smart_home::prompt(&config.smart_home) {
+            Some(devices) => format!("{}\n\n{}{}", system_prompt, INTENT_INSTRUCTION, devices),
+            None => format!("{}\n\n{}", system_prompt, INTENT_INSTRUCTION),
+        }
+    } else {
+        system_prompt
+    }
+}
+
+/// Limit the reply tokens and tell the LLM how long its replies should be
+fn apply_reply_length(llm: &mut LlmHelper, base_params: &GenerationParams, length: ReplyLength) {
+    llm.configure(Some(length.max_tokens(base_params.max_tokens)), None, None);
+    llm.set_style_hint(length.style_hint());
+}
+
+/// Act on the intent of a structured reply, returns the text to speak
+///
+/// That is the reply text, unless the intent couldn't be carried out or the reply is empty.
+fn dispatch_intent(reply: &IntentReply, config: &AppConfig) -> String {
+    match reply.intent.as_str() {
+        CHAT_INTENT => {}
+        intent => match alarms::handle_intent(intent, &reply.slots)
+            .or_else(|| smart_home::handle_intent(&config.smart_home, intent, &reply.slots))
+            .or_else(|| facts::handle_intent(intent, &reply.slots))
+        {
+            Some(Ok(confirmation)) if reply.reply_text.trim().is_empty() => return confirmation,
+            Some(Ok(_)) => {}
+            Some(Err(error)) => return error,
+            None => {
+                log::info!(
+                    "No handler for intent '{}' (slots: {:?}), only speaking the reply",
+                    intent,
+                    reply.slots
+                );
+            }
+        },
+    }
+    reply.reply_text.clone()
+}
+
+/// Apply generation parameter overrides immediately and persist them to NVS
+fn update_generation_params(
+    llm: &mut LlmHelper,
+    settings: &mut Settings,
+    base_params: &mut GenerationParams,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+) {
+    llm.configure(max_tokens, temperature, top_p);
+
+    if let Some(tokens) = max_tokens {
+        base_params.max_tokens = tokens;
+        if let Err(e) = settings.set(KEY_MAX_TOKENS, tokens) {
+            log::warn!("Failed to persist max_tokens: {}", e);
+        }
+    }
+
+    if let Some(temp) = temperature {
+        base_params.temperature = temp;
+        if let Err(e) = settings.set(KEY_TEMPERATURE, temp) {
+            log::warn!("Failed to persist temperature: {}", e);
+        }
+    }
+
+    if let Some(p) = top_p {
+        base_params.top_p = p;
+        if let Err(e) = settings.set(KEY_TOP_P, p) {
+            log::warn!("Failed to persist top_p: {}", e);
+        }
+    }
+
+    log::info!("Generation parameters: {:?}", llm.generation_params());
+}
+
+/// Remember that the network services are down, returns true when they just became unreachable
+fn enter_offline(event_tx: &Sender<TranscriptionEvent>) -> bool {
+    if !connectivity::go_offline() {
+        return false;
+    }
+
+    log::warn!("Network services are unreachable, switching to on-device commands");
+    send_event(event_tx, TranscriptionEvent::Offline);
+    true
+}
+
+/// Report an event to the fetch task, which only misses it if it has exited
+fn send_event(event_tx: &Sender<TranscriptionEvent>, event: TranscriptionEvent) {
+    if let Err(e) = event_tx.send(event) {
+        log::error!("Failed to send transcription event: {}", e);
+    }
+}
+
+/// Install new firmware, models or voice data and reboot to use them, or say why not
+fn update_firmware(config: &AppConfig, playback: &Playback) -> String {
+    let update = match ota::check(&config.ota, &config.speech_models) {
+        Ok(Some(update)) => update,
+        Ok(None) => return format!("已经是最新版本{}", ota::current_version()),
+        Err(e) => {
+            log::warn!("Update check failed: {}", e);
+            return "检查更新失败，请确认设置了更新地址并且网络正常".to_string();
+        }
+    };
+
+    playback.speak(&format!("发现{}，正在更新，完成后会自动重启", update.spoken_summary()));
+    match ota::install(&update) {
+        Ok(()) => {
+            playback.speak("更新完成，正在重启");
+            // Let the announcement play before the restart cuts it off
+            std::thread::sleep(Duration::from_secs(3));
+            console::reboot()
+        }
+        Err(e) => {
+            log::error!("Update failed: {}", e);
+            "更新失败，请稍后再试".to_string()
+        }
+    }
+}
+
+/// Wipe everything on the next boot and restart, only returns with the reply to a failure
+fn factory_reset(playback: &Playback) -> String {
+    if let Err(e) = factory_reset::request() {
+        log::error!("Factory reset failed: {}", e);
+        return "恢复出厂设置失败".to_string();
+    }
+
+    playback.speak("正在恢复出厂设置，马上重启");
+    // Let the announcement play before the restart cuts it off
+    std::thread::sleep(Duration::from_secs(3));
+    console::reboot()
+}
+
+/// Check what can be checked without network and describe it in one sentence
+fn self_test_report() -> String {
+    use esp_idf_svc::sys;
+
+    let sd_card_ok = self_test::storage_writable();
+
+    let network = match connectivity::state() {
+        Connectivity::Online => "已连接",
+        Connectivity::ServicesUnreachable => "已连接，但在线服务连不上",
+        Connectivity::NoWifi => "未连接",
+    };
+
+    let free_heap_kb = unsafe { sys::esp_get_free_heap_size() } / 1024;
+
+    format!(
+        "自检完成。存储卡{}，无线网络{}，剩余内存{}千字节",
+        if sd_card_ok { "正常" } else { "无法写入" },
+        network,
+        free_heap_kb
+    )
+}
+
+/// Forward messages to the worker, flagging the in-flight request on CancelPending
+fn dispatch_messages(
+    rx: Receiver<TranscriptionMessage>,
+    worker_tx: SyncSender<TranscriptionMessage>,
+    cancel_token: CancellationToken,
+) {
+    // Messages the busy worker hasn't taken yet, in the order they arrived
+    let mut backlog: VecDeque<TranscriptionMessage> = VecDeque::new();
+
+    loop {
+        let message = if backlog.is_empty() {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            rx.recv_timeout(DISPATCH_POLL_INTERVAL)
+        };
+
+        match message {
+            Ok(TranscriptionMessage::CancelPending) => {
+                log::info!("Cancelling pending LLM request");
+                cancel_token.cancel();
+            }
+            Ok(TranscriptionMessage::NetworkChanged { connected: false }) => {
+                // The request in flight can't succeed, give up on it instead of waiting for a timeout
+                cancel_token.cancel();
+                backlog.push_back(TranscriptionMessage::NetworkChanged { connected: false });
+            }
+            Ok(TranscriptionMessage::Shutdown) => {
+                // Unblock a request in flight so the worker sees the shutdown
+                cancel_token.cancel();
+                let _ = worker_tx.send(TranscriptionMessage::Shutdown);
+                break;
+            }
+            Ok(message) => {
+                if message.is_utterance() {
+                    drop_stale_utterances(&mut backlog);
+                }
+                backlog.push_back(message);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        // Hand over as much as the worker's queue takes
+        while let Some(message) = backlog.pop_front() {
+            match worker_tx.try_send(message) {
+                Ok(()) => {}
+                Err(TrySendError::Full(message)) => {
+                    backlog.push_front(message);
+                    break;
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    log::warn!("Transcription worker has exited, stop dispatching");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Make room for one more utterance, answering the oldest ones is pointless once the user moved on
+fn drop_stale_utterances(backlog: &mut VecDeque<TranscriptionMessage>) {
+    while backlog.iter().filter(|m| m.is_utterance()).count() >= MAX_PENDING_UTTERANCES {
+        let Some(index) = backlog.iter().position(|m| m.is_utterance()) else {
+            break;
+        };
+        match backlog.remove(index) {
+            Some(TranscriptionMessage::TranscribeFile { path }) => {
+                log::warn!("Worker is busy, dropping stale recording {}", path)
+            }
+            Some(TranscriptionMessage::Transcript { transcription, .. }) => {
+                log::warn!("Worker is busy, dropping stale transcript: {}", transcription.text)
+            }
+            _ => log::warn!("Worker is busy, dropping a stale utterance"),
+        }
+    }
+}
+
+/// Keep the utterance for the dashboard and tell its live view
+fn remember_transcript(text: &str) {
+    *LAST_TRANSCRIPT.lock().unwrap() = Some(text.to_string());
+    dashboard::publish(LiveEvent::Transcript {
+        text: text.to_string(),
+    });
+}
+
+/// Text of the last usable utterance, None before anything was said
+pub fn last_transcript() -> Option<String> {
+    LAST_TRANSCRIPT.lock().unwrap().clone()
+}
+
 /// Function to create and start the transcription worker thread
 pub fn start_transcription_worker(
     i2s_driver: I2sDriver<'static, I2sTx>,
     sd_pin_driver: PinDriver<'static, impl OutputPin, esp_idf_svc::hal::gpio::Output>,
-) -> anyhow::Result<(Sender<TranscriptionMessage>, Receiver<String>)> {
+    mut config_store: ConfigStore,
+    settings: Settings,
+) -> anyhow::Result<(Sender<TranscriptionMessage>, Receiver<TranscriptionEvent>)> {
     let (tx, rx) = mpsc::channel();
-    let (response_tx, response_rx) = mpsc::channel();
+    // Bounded so utterances pile up in the dispatcher, where stale ones can still be dropped
+    let (stt_tx, stt_rx) = mpsc::sync_channel(1);
+    // One transcript may wait for the LLM while the next utterance is being transcribed
+    let (worker_tx, worker_rx) = mpsc::sync_channel(1);
+    let (event_tx, event_rx) = mpsc::channel();
+    let cancel_token = CancellationToken::default();
+    let config = config_store.load();
 
+    let stt_config = config.clone();
+    let stt_cancel_token = cancel_token.clone();
+    thread::Builder::new()
+        .name("stt_stage".to_string())
+        .stack_size(STT_STAGE_STACK_SIZE) // HTTPS uploads need the room
+        .spawn(move || {
+            let _stack = stacks::register("stt_stage", STT_STAGE_STACK_SIZE);
+            let _alive = supervisor::alive("stt_stage");
+            stt_stage::stt_stage(stt_rx, worker_tx, config_store, stt_config, stt_cancel_token)
+        })?;
+
+    let worker_cancel_token = cancel_token.clone();
     thread::Builder::new()
         .name("transcription_worker".to_string())
-        .stack_size(16 * 1024) // Increase stack size for TTS operations
+        .stack_size(WORKER_STACK_SIZE) // Increase stack size for LLM requests
         .spawn(move || {
-            if let Err(e) = transcription_worker(rx, response_tx, i2s_driver, sd_pin_driver) {
+            let _stack = stacks::register("transcription_worker", WORKER_STACK_SIZE);
+            // Owns the speaker, so it can't be started again without restarting the device
+            let _alive = supervisor::alive("transcription_worker");
+            if let Err(e) = transcription_worker(
+                worker_rx,
+                event_tx,
+                i2s_driver,
+                sd_pin_driver,
+                config,
+                settings,
+                worker_cancel_token,
+            ) {
                 log::error!("Transcription worker failed: {}", e);
             }
         })?;
 
+    // Cancel requests are acted on here because the worker can't read its queue mid-request
+    thread::Builder::new()
+        .name("transcription_dispatch".to_string())
+        .stack_size(DISPATCH_STACK_SIZE)
+        .spawn(move || {
+            let _stack = stacks::register("transcription_dispatch", DISPATCH_STACK_SIZE);
+            let _alive = supervisor::alive("transcription_dispatch");
+            dispatch_messages(rx, stt_tx, cancel_token)
+        })?;
+
     log::info!("Transcription worker thread created successfully");
-    Ok((tx, response_rx))
+    Ok((tx, event_rx))
 }
 
-/// Function to send WAV file to transcription API with improved structure
-/// This now runs in the separate thread
-fn transcribe_audio(file_path: &str) -> anyhow::Result<String> {
-    log::info!("Transcribing audio file: {}", file_path);
-
-    // Read the WAV file
-    let file_data = std::fs::read(file_path)?;
-    log::info!("Read {} bytes from WAV file", file_data.len());
-
-    // Set up the API endpoint
-    let transcription_api_url = env!("VOS_URL");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Create HTTP client
-    let http_config = HttpConfiguration {
-        timeout: Some(std::time::Duration::from_secs(30)),
-        ..Default::default()
-    };
-    let mut client = EspHttpConnection::new(&http_config)?;
+    fn utterance(path: &str) -> TranscriptionMessage {
+        TranscriptionMessage::TranscribeFile {
+            path: path.to_string(),
+        }
+    }
 
-    // Send the multipart request and get response
-    send_multipart_request(&mut client, transcription_api_url, file_path, &file_data)?;
+    #[test]
+    fn test_queued_utterance_keeps_request() {
+        let (tx, rx) = mpsc::channel();
+        let (worker_tx, worker_rx) = mpsc::sync_channel(1);
+        let cancel_token = CancellationToken::default();
+        let dispatcher_token = cancel_token.clone();
+        let dispatcher = thread::spawn(move || dispatch_messages(rx, worker_tx, dispatcher_token));
 
-    // Process the response
-    let response_text = read_response(&mut client)?;
+        // The worker takes the first question and asks the LLM, the second is asked meanwhile
+        tx.send(utterance("first.wav")).unwrap();
+        assert!(matches!(
+            worker_rx.recv().unwrap(),
+            TranscriptionMessage::TranscribeFile { path } if path == "first.wav"
+        ));
+        tx.send(utterance("second.wav")).unwrap();
+        assert!(matches!(
+            worker_rx.recv().unwrap(),
+            TranscriptionMessage::TranscribeFile { path } if path == "second.wav"
+        ));
+        assert!(!cancel_token.is_cancelled());
 
-    Ok(response_text
-        .trim_end_matches('"')
-        .trim_start_matches('"')
-        .to_string())
+        // Talking over the reply or the exit phrase does cancel it
+        tx.send(TranscriptionMessage::CancelPending).unwrap();
+        drop(tx);
+        dispatcher.join().unwrap();
+        assert!(cancel_token.is_cancelled());
+    }
 }
@@ -0,0 +1,247 @@
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError, SyncSender};
+use std::time::Instant;
+
+use super::{StageMessage, TranscriptionMessage};
+use crate::audio_codec::AudioUpload;
+use crate::config::{AppConfig, ConfigStore};
+use crate::connectivity;
+use crate::http_client::{enter_turn, new_turn_id, set_tracing, HttpError};
+use crate::llm_intf::CancellationToken;
+use crate::metrics;
+use crate::recordings::RecordingRetention;
+use crate::stt::{create_stt_provider, SttProvider, Transcription};
+use crate::upload_queue::{QueueOutcome, UploadQueue};
+use crate::voice_commands::is_exit_phrase;
+use crate::watchdog;
+
+/// Audio of one utterance handed to the speech to text service
+enum UtteranceAudio {
+    /// WAV file on the SD card
+    File(String),
+    /// PCM samples kept in RAM
+    Samples(Vec<i16>),
+}
+
+/// First stage of the pipeline: turns utterances into text, so the next question
+/// is transcribed while the worker is still waiting for the LLM
+///
+/// An exit phrase cancels the answer in flight through `cancel_token`, the worker only gets to
+/// it once that answer is done.
+pub(super) fn stt_stage(
+    rx: Receiver<TranscriptionMessage>,
+    worker_tx: SyncSender<StageMessage>,
+    mut config_store: ConfigStore,
+    mut config: AppConfig,
+    cancel_token: CancellationToken,
+) {
+    log::info!("Speech to text stage started");
+
+    set_tracing(config.http.trace);
+    let mut stt = create_stt_provider(&config.stt);
+    let mut upload_queue = UploadQueue::load();
+    let mut recording_retention = RecordingRetention::new(&config.recordings);
+
+    loop {
+        let Ok(message) = next_message(&rx, &upload_queue) else {
+            break;
+        };
+
+        // Requests made for this message and for the LLM reply to it share the turn ID
+        let turn_id = new_turn_id();
+        let _turn = enter_turn(turn_id);
+
+        let stage_message = match message {
+            TranscriptionMessage::Transcript {
+                transcription,
+                path,
+            } => {
+                if let Some(path) = path {
+                    if transcribed_recording(&path, &mut upload_queue, &mut recording_retention) {
+                        let _ = worker_tx.send(StageMessage::QueueDrained);
+                    }
+                }
+                StageMessage::Utterance {
+                    transcription,
+                    turn_id,
+                }
+            }
+            TranscriptionMessage::TranscribeFile { path } => {
+                log::info!("Received request to transcribe file: {}", path);
+                let audio = UtteranceAudio::File(path.clone());
+
+                match transcribe_audio(stt.as_ref(), &config, &audio) {
+                    Ok(transcription) => {
+                        if transcribed_recording(&path, &mut upload_queue, &mut recording_retention)
+                        {
+                            let _ = worker_tx.send(StageMessage::QueueDrained);
+                        }
+                        StageMessage::Utterance {
+                            transcription,
+                            turn_id,
+                        }
+                    }
+                    Err(e) => failed(e, &audio, &mut upload_queue),
+                }
+            }
+            TranscriptionMessage::TranscribeSamples { samples } => {
+                let audio = UtteranceAudio::Samples(samples);
+
+                match transcribe_audio(stt.as_ref(), &config, &audio) {
+                    Ok(transcription) => {
+                        upload_queue.connectivity_restored();
+                        StageMessage::Utterance {
+                            transcription,
+                            turn_id,
+                        }
+                    }
+                    Err(e) => failed(e, &audio, &mut upload_queue),
+                }
+            }
+            TranscriptionMessage::RestartSession => {
+                // Pick up edits to the configuration file so the persona can change without reflashing
+                config = config_store.load();
+                set_tracing(config.http.trace);
+                stt = create_stt_provider(&config.stt);
+                recording_retention.set_config(&config.recordings);
+                StageMessage::RestartSession(Box::new(config.clone()))
+            }
+            TranscriptionMessage::NetworkChanged { connected } => {
+                if connected {
+                    // Queued recordings don't need to wait out their backoff
+                    upload_queue.connectivity_restored();
+                }
+                StageMessage::Control(TranscriptionMessage::NetworkChanged { connected })
+            }
+            TranscriptionMessage::Shutdown => {
+                let _ = worker_tx.send(StageMessage::Control(TranscriptionMessage::Shutdown));
+                break;
+            }
+            other => StageMessage::Control(other),
+        };
+
+        if let StageMessage::Utterance { transcription, .. } = &stage_message {
+            if is_exit_phrase(&transcription.text, &config.assistant.exit_phrases) {
+                log::info!("Exit phrase heard, cancelling the pending LLM request");
+                cancel_token.cancel();
+            }
+        }
+
+        if worker_tx.send(stage_message).is_err() {
+            log::warn!("Transcription worker has exited, stop transcribing");
+            break;
+        }
+    }
+
+    log::info!("Speech to text stage terminated");
+}
+
+/// Bookkeeping for a recording that was transcribed, returns true when it emptied the offline queue
+fn transcribed_recording(
+    path: &str,
+    upload_queue: &mut UploadQueue,
+    recording_retention: &mut RecordingRetention,
+) -> bool {
+    if upload_queue.complete(path) {
+        upload_queue.is_empty()
+    } else {
+        upload_queue.connectivity_restored();
+        recording_retention.transcribed(path);
+        false
+    }
+}
+
+/// Keep the recording so the question isn't lost while the network is down
+fn failed(
+    error: anyhow::Error,
+    audio: &UtteranceAudio,
+    upload_queue: &mut UploadQueue,
+) -> StageMessage {
+    log::error!("Failed to transcribe audio: {}", error);
+
+    // Retrying later only helps when the network or the service was down
+    if let Some(http_error) = error.downcast_ref::<HttpError>() {
+        if http_error.is_client_error() {
+            if let UtteranceAudio::File(path) = audio {
+                // Removes it if it was waiting in the queue
+                upload_queue.complete(path);
+            }
+            return StageMessage::TranscriptionFailed {
+                error: error.to_string(),
+                outcome: QueueOutcome::Rejected,
+            };
+        }
+    }
+
+    let outcome = match audio {
+        UtteranceAudio::File(path) => upload_queue.record_failure(path),
+        UtteranceAudio::Samples(samples) => upload_queue.record_failed_samples(samples),
+    };
+
+    StageMessage::TranscriptionFailed {
+        error: error.to_string(),
+        outcome,
+    }
+}
+
+/// Wait for the next message, or retry the oldest queued recording once its backoff expired
+fn next_message(
+    rx: &Receiver<TranscriptionMessage>,
+    upload_queue: &UploadQueue,
+) -> Result<TranscriptionMessage, RecvError> {
+    // Retrying without WiFi would only use up the recordings' attempts
+    let Some(wait) = upload_queue
+        .time_until_retry()
+        .filter(|_| connectivity::has_wifi())
+    else {
+        return rx.recv();
+    };
+
+    match rx.recv_timeout(wait) {
+        Ok(message) => Ok(message),
+        Err(RecvTimeoutError::Timeout) => match upload_queue.next_path() {
+            Some(path) => {
+                log::info!("Retrying queued recording {}", path);
+                Ok(TranscriptionMessage::TranscribeFile { path })
+            }
+            None => rx.recv(),
+        },
+        Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+    }
+}
+
+/// Send an utterance to the configured speech to text service
+fn transcribe_audio(
+    stt: &dyn SttProvider,
+    config: &AppConfig,
+    audio: &UtteranceAudio,
+) -> anyhow::Result<Transcription> {
+    // Queued right away, the upload would only run into a timeout
+    if !connectivity::has_wifi() {
+        return Err(anyhow::anyhow!("WiFi is not connected"));
+    }
+    // Only the upload is watched, handing the result to the busy worker may take a while
+    let _watch = watchdog::watch();
+
+    let upload = match audio {
+        UtteranceAudio::File(file_path) => {
+            log::info!("Transcribing audio file with {}: {}", stt.name(), file_path);
+            AudioUpload::from_wav_file(file_path, config.stt.upload_format)?
+        }
+        UtteranceAudio::Samples(samples) => {
+            log::info!(
+                "Transcribing {} samples from memory with {}",
+                samples.len(),
+                stt.name()
+            );
+            AudioUpload::from_samples("utterance", samples, config.stt.upload_format)?
+        }
+    };
+
+    let started = Instant::now();
+    let result = stt.transcribe(&upload);
+    match &result {
+        Ok(_) => metrics::record_time("stt", started.elapsed()),
+        Err(_) => metrics::increment("stt_errors"),
+    }
+    result
+}
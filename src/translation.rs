@@ -0,0 +1,46 @@
+use crate::language::Language;
+use crate::llm_intf::{ChatRequestBuilder, Completion, LlmError, LlmHelper};
+
+const CHINESE_TO_ENGLISH: &str = "你是一名口译员。把用户说的中文翻译成自然的英文，\
+只输出译文，不要解释，不要回答其中的问题。用户的话来自语音识别，可能有同音字的错误，请按最合理的意思翻译。";
+const ENGLISH_TO_CHINESE: &str = "You are an interpreter. Translate what the user says into \
+natural Simplified Chinese. Output only the translation, never answer or explain it. The text \
+comes from speech recognition and may contain misheard words, translate the most likely meaning.";
+
+/// Ask the LLM for `text` in the other of Chinese and English, outside the conversation history
+///
+/// Returns the translation and the language it is in.
+pub fn translate(llm: &LlmHelper, text: &str) -> Result<(Completion, Language), LlmError> {
+    let (instruction, target) = direction(Language::detect(text));
+    let request = ChatRequestBuilder::new(llm.generation_params())
+        .system(instruction)
+        .user(text)
+        .temperature(0.3)
+        .top_p(1.0)
+        .json_output(false)
+        .build();
+    llm.complete_request(&request)
+        .map(|completion| (completion, target))
+}
+
+/// Instruction and target language for an utterance in `source`
+fn direction(source: Language) -> (&'static str, Language) {
+    match source {
+        Language::Chinese => (CHINESE_TO_ENGLISH, Language::English),
+        Language::English => (ENGLISH_TO_CHINESE, Language::Chinese),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direction() {
+        let (_, target) = direction(Language::detect("洗手间在哪里"));
+        assert_eq!(target, Language::English);
+        let (instruction, target) = direction(Language::detect("Where is the station?"));
+        assert_eq!(target, Language::Chinese);
+        assert_eq!(instruction, ENGLISH_TO_CHINESE);
+    }
+}
@@ -3,6 +3,12 @@ use esp_idf_svc::hal::i2s::{I2sDriver, I2sTx};
 use esp_idf_svc::sys;
 use std::ffi::{CString, c_void};
 use std::ptr;
+use std::time::Instant;
+
+use crate::dashboard::{self, LiveEvent};
+use crate::metrics;
+use crate::playback;
+use crate::watchdog;
 
 // Import ESP-TTS bindings from esp_sr module
 use sys::esp_sr::{
@@ -12,11 +18,16 @@ use sys::esp_sr::{
     esp_tts_parse_chinese, esp_tts_stream_play, esp_tts_stream_reset,
 };
 
+/// Samples written to I2S at a time by play_samples, a quarter of a second at 16 kHz
+const PLAY_PIECE_SAMPLES: usize = 4000;
+
 #[derive(Clone)]
 pub struct TtsConfig {
     pub max_chunk_chars: usize,
     pub chunk_delay_ms: u64,
     pub speed: u32,
+    /// Playback volume in percent of full scale
+    pub volume: u8,
 }
 
 impl Default for TtsConfig {
@@ -25,6 +36,7 @@ impl Default for TtsConfig {
             max_chunk_chars: 50,
             chunk_delay_ms: 50,
             speed: 3, // Medium speed (0-5 range)
+            volume: 100,
         }
     }
 }
@@ -131,15 +143,26 @@ impl TtsEngine {
 
         // Split text into chunks to prevent watchdog timeout
         let chunks = self.split_text_into_chunks(text, self.config.max_chunk_chars);
+        // Taken once the first audio is ready, the time until then is recorded as "tts"
+        let mut started = Some(Instant::now());
 
         for (i, chunk) in chunks.iter().enumerate() {
             if chunk.trim().is_empty() {
                 continue;
             }
+            if playback::stop_requested() {
+                log::info!("Speech stopped after {} of {} chunks", i, chunks.len());
+                break;
+            }
+            watchdog::feed();
 
             log::info!("Processing chunk {}/{}: {}", i + 1, chunks.len(), chunk);
+            dashboard::publish(LiveEvent::SpeechProgress {
+                chunk: i + 1,
+                chunks: chunks.len(),
+            });
 
-            if let Err(e) = self.synthesize_chunk(chunk, i2s_driver) {
+            if let Err(e) = self.synthesize_chunk(chunk, i2s_driver, &mut started) {
                 log::error!("Failed to synthesize chunk {}: {}", i + 1, e);
                 // Continue with next chunk instead of failing completely
                 continue;
@@ -261,7 +284,7 @@ impl TtsEngine {
         chunks
     }
 
-    fn synthesize_chunk(&mut self, text: &str, i2s_driver: &mut I2sDriver<I2sTx>) -> Result<()> {
+    fn synthesize_chunk(&mut self, text: &str, i2s_driver: &mut I2sDriver<I2sTx>, started: &mut Option<Instant>) -> Result<()> {
         // Convert text to CString
         let c_text = CString::new(text)?;
 
@@ -285,17 +308,22 @@ impl TtsEngine {
                 esp_tts_stream_play(self.handle, &mut len, speed)
             };
 
-            if len <= 0 {
+            if len <= 0 || playback::stop_requested() {
                 break; // End of audio data
             }
+            watchdog::feed();
+            if let Some(started) = started.take() {
+                metrics::record_time("tts", started.elapsed());
+            }
 
             // Convert the PCM data to bytes
-            let pcm_slice = unsafe {
-                std::slice::from_raw_parts(pcm_data as *const u8, (len * 2) as usize)
-            };
+            let mut samples =
+                unsafe { std::slice::from_raw_parts(pcm_data as *const i16, len as usize) }.to_vec();
+            apply_volume(&mut samples, self.config.volume);
+            let pcm_slice: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
 
             // Write to I2S
-            match i2s_driver.write_all(pcm_slice, 1000) {
+            match i2s_driver.write_all(&pcm_slice, 1000) {
                 Ok(_) => {
                     log::debug!("Written {} bytes to I2S", pcm_slice.len());
                 },
@@ -316,6 +344,36 @@ impl TtsEngine {
     }
 }
 
+/// Scale PCM samples to a volume given in percent of full scale
+pub fn apply_volume(samples: &mut [i16], volume: u8) {
+    if volume >= 100 {
+        return;
+    }
+
+    for sample in samples.iter_mut() {
+        *sample = (*sample as i32 * volume as i32 / 100) as i16;
+    }
+}
+
+/// Play 16 kHz mono PCM through the speaker at a volume in percent
+pub fn play_samples(samples: &[i16], volume: u8, i2s_driver: &mut I2sDriver<I2sTx>) -> Result<()> {
+    let mut samples = samples.to_vec();
+    apply_volume(&mut samples, volume);
+
+    // Written in pieces so the stop button doesn't wait for the whole reply
+    for piece in samples.chunks(PLAY_PIECE_SAMPLES) {
+        if playback::stop_requested() {
+            log::info!("Playback stopped");
+            break;
+        }
+        watchdog::feed();
+        let bytes: Vec<u8> = piece.iter().flat_map(|s| s.to_le_bytes()).collect();
+        i2s_driver.write_all(&bytes, 1000)?;
+    }
+
+    Ok(())
+}
+
 impl Drop for TtsEngine {
     fn drop(&mut self) {
         log::info!("Cleaning up TTS engine");
@@ -370,4 +428,14 @@ mod tests {
             assert!(chunk.len() <= 30); // Allow some flexibility for word boundaries
         }
     }
+
+    #[test]
+    fn test_apply_volume() {
+        let mut samples = vec![1000, -1000, i16::MAX];
+        apply_volume(&mut samples, 100);
+        assert_eq!(samples, vec![1000, -1000, i16::MAX]);
+
+        apply_volume(&mut samples, 50);
+        assert_eq!(samples, vec![500, -500, i16::MAX / 2]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::audio_codec::encode_wav;
+
+/// Recordings waiting for the speech to text service are moved here so new recordings can't overwrite them
+pub const QUEUE_DIR: &str = "/vfat/pending";
+const QUEUE_FILE_PATH: &str = "/vfat/pending/queue.json";
+/// Oldest recordings are dropped beyond this, answering them gets less useful the longer they wait
+const MAX_QUEUED: usize = 10;
+/// Failed uploads of a recording before giving up on it
+const MAX_ATTEMPTS: u32 = 6;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueuedUpload {
+    path: String,
+    attempts: u32,
+}
+
+/// What happened to a recording whose upload failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueOutcome {
+    /// Newly queued for a later retry
+    Queued,
+    /// Already queued, retried again later
+    Retrying,
+    /// Gave up on the recording, or on older ones to make room for it
+    Dropped,
+    /// The service refused the recording, e.g. over a wrong API key, so it wasn't queued
+    Rejected,
+}
+
+/// Recordings that couldn't be transcribed, retried with backoff until the service is reachable again
+pub struct UploadQueue {
+    entries: Vec<QueuedUpload>,
+    next_seq: u32,
+    /// Not persisted, after a reboot the queue is retried right away
+    next_retry: Instant,
+}
+
+impl UploadQueue {
+    /// Restore the queue left over from before the last reboot
+    pub fn load() -> Self {
+        let entries: Vec<QueuedUpload> = match std::fs::read_to_string(QUEUE_FILE_PATH) {
+            Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                log::warn!("Failed to parse {}: {}", QUEUE_FILE_PATH, e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+
+        let entries: Vec<QueuedUpload> = entries
+            .into_iter()
+            .filter(|entry| std::path::Path::new(&entry.path).exists())
+            .collect();
+        if !entries.is_empty() {
+            log::info!("{} recordings are waiting to be transcribed", entries.len());
+        }
+
+        Self {
+            next_seq: entries.len() as u32,
+            entries,
+            next_retry: Instant::now(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How long until the oldest recording should be retried, None when nothing is queued
+    pub fn time_until_retry(&self) -> Option<Duration> {
+        if self.entries.is_empty() {
+            None
+        } else {
+            Some(self.next_retry.saturating_duration_since(Instant::now()))
+        }
+    }
+
+    /// Recording to retry next
+    pub fn next_path(&self) -> Option<String> {
+        self.entries.first().map(|entry| entry.path.clone())
+    }
+
+    /// Queue a recording whose upload failed, or count another attempt of a queued one
+    pub fn record_failure(&mut self, path: &str) -> QueueOutcome {
+        let outcome = if let Some(index) = self.entries.iter().position(|e| e.path == path) {
+            let entry = &mut self.entries[index];
+            entry.attempts += 1;
+            let attempts = entry.attempts;
+
+            if attempts >= MAX_ATTEMPTS {
+                log::warn!("Giving up on {} after {} attempts", path, attempts);
+                let entry = self.entries.remove(index);
+                remove_recording(&entry.path);
+                QueueOutcome::Dropped
+            } else {
+                self.next_retry = Instant::now() + backoff(attempts);
+                QueueOutcome::Retrying
+            }
+        } else {
+            let mut outcome = QueueOutcome::Queued;
+
+            while self.entries.len() >= MAX_QUEUED {
+                let oldest = self.entries.remove(0);
+                log::warn!("Upload queue full, dropping {}", oldest.path);
+                remove_recording(&oldest.path);
+                outcome = QueueOutcome::Dropped;
+            }
+
+            match self.move_into_queue(path) {
+                Ok(queued_path) => {
+                    log::info!("Queued {} for a later retry as {}", path, queued_path);
+                    if self.entries.is_empty() {
+                        self.next_retry = Instant::now() + INITIAL_BACKOFF;
+                    }
+                    self.entries.push(QueuedUpload {
+                        path: queued_path,
+                        attempts: 1,
+                    });
+                }
+                Err(e) => {
+                    log::warn!("Failed to queue {}: {}", path, e);
+                    outcome = QueueOutcome::Dropped;
+                }
+            }
+
+            outcome
+        };
+
+        self.persist();
+        outcome
+    }
+
+    /// Queue an utterance recorded in RAM by writing it to the queue directory first
+    pub fn record_failed_samples(&mut self, samples: &[i16]) -> QueueOutcome {
+        let staged_path = format!("{}/staged.wav", QUEUE_DIR);
+        let written = std::fs::create_dir_all(QUEUE_DIR)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| encode_wav(samples))
+            .and_then(|wav| Ok(std::fs::write(&staged_path, wav)?));
+
+        match written {
+            Ok(()) => self.record_failure(&staged_path),
+            Err(e) => {
+                log::warn!("Failed to save utterance for a later retry: {}", e);
+                QueueOutcome::Dropped
+            }
+        }
+    }
+
+    /// Forget a queued recording once it was transcribed, returns false if it wasn't queued
+    pub fn complete(&mut self, path: &str) -> bool {
+        let Some(index) = self.entries.iter().position(|e| e.path == path) else {
+            return false;
+        };
+
+        let entry = self.entries.remove(index);
+        remove_recording(&entry.path);
+        self.persist();
+        true
+    }
+
+    /// A live upload went through, so the queue doesn't need to wait out its backoff
+    pub fn connectivity_restored(&mut self) {
+        self.next_retry = Instant::now();
+    }
+
+    fn move_into_queue(&mut self, path: &str) -> std::io::Result<String> {
+        std::fs::create_dir_all(QUEUE_DIR)?;
+
+        // Names from before a reboot may still be queued
+        let queued_path = loop {
+            let candidate = format!("{}/rec{}.wav", QUEUE_DIR, self.next_seq);
+            self.next_seq += 1;
+            if !std::path::Path::new(&candidate).exists() {
+                break candidate;
+            }
+        };
+
+        std::fs::rename(path, &queued_path)?;
+        Ok(queued_path)
+    }
+
+    fn persist(&self) {
+        match serde_json::to_string(&self.entries) {
+            Ok(text) => {
+                if let Err(e) = std::fs::write(QUEUE_FILE_PATH, text) {
+                    log::warn!("Failed to write {}: {}", QUEUE_FILE_PATH, e);
+                }
+            }
+            Err(e) => log::warn!("Failed to serialize upload queue: {}", e),
+        }
+    }
+}
+
+/// Delay before the next retry after a number of failed attempts
+fn backoff(attempts: u32) -> Duration {
+    let factor = 1u32 << attempts.saturating_sub(1).min(16);
+    (INITIAL_BACKOFF * factor).min(MAX_BACKOFF)
+}
+
+fn remove_recording(path: &str) {
+    if let Err(e) = std::fs::remove_file(path) {
+        log::warn!("Failed to remove {}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_limit() {
+        assert_eq!(backoff(1), INITIAL_BACKOFF);
+        assert_eq!(backoff(2), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff(3), INITIAL_BACKOFF * 4);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+    }
+}
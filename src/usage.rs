@@ -0,0 +1,332 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::llm_intf::Usage;
+use crate::metrics;
+use crate::transcript_log::wall_clock_secs;
+
+/// File keeping today's token counts and activity across reboots
+pub const USAGE_FILE_PATH: &str = "/vfat/usage.json";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// Day of the counts made before SNTP set the clock, they are added to the real day's later
+const UNSYNCED_DAY: u64 = 0;
+
+/// Today's counts, shared by the threads that add to them, read from the card once the clock
+/// was set
+static TODAY: Mutex<Option<PersistedUsage>> = Mutex::new(None);
+/// Held while writing the file, so TODAY isn't locked during the write
+static SAVING: Mutex<()> = Mutex::new(());
+/// Days start at local midnight, `alarms.utc_offset_mins`
+static UTC_OFFSET_MINS: AtomicI32 = AtomicI32::new(0);
+
+/// Accumulated token counts
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub requests: u32,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl TokenUsage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    fn add(&mut self, usage: &Usage) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens as u64;
+        self.completion_tokens += usage.completion_tokens as u64;
+    }
+}
+
+/// How much the device was talked to on one day
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Activity {
+    pub wake_words: u32,
+    /// Utterances that were understood
+    pub questions: u32,
+    /// Time spent speaking replies and announcements
+    pub speaking_ms: u64,
+}
+
+/// Snapshot of the token counters, serializable for status reporting
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageSummary {
+    pub session: TokenUsage,
+    pub today: TokenUsage,
+    pub activity: Activity,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct PersistedUsage {
+    day: u64,
+    usage: TokenUsage,
+    /// Missing from files written before activity was tracked
+    #[serde(default)]
+    activity: Activity,
+}
+
+impl PersistedUsage {
+    /// Add counts made before the clock was set
+    fn absorb(&mut self, earlier: &PersistedUsage) {
+        self.usage.requests += earlier.usage.requests;
+        self.usage.prompt_tokens += earlier.usage.prompt_tokens;
+        self.usage.completion_tokens += earlier.usage.completion_tokens;
+        self.activity.wake_words += earlier.activity.wake_words;
+        self.activity.questions += earlier.activity.questions;
+        self.activity.speaking_ms += earlier.activity.speaking_ms;
+    }
+}
+
+/// Tracks token usage for the current session and the current day
+pub struct UsageTracker {
+    session: TokenUsage,
+}
+
+impl UsageTracker {
+    /// Create a tracker, today's counts are restored from the SD card once the clock is set
+    pub fn load(utc_offset_mins: i32) -> Self {
+        set_utc_offset(utc_offset_mins);
+        Self {
+            session: TokenUsage::default(),
+        }
+    }
+
+    /// Start counting a new conversation session
+    pub fn reset_session(&mut self) {
+        self.session = TokenUsage::default();
+    }
+
+    /// Add the usage of one request and persist the daily totals
+    pub fn record(&mut self, usage: &Usage) {
+        self.session.add(usage);
+        let today = update_today(true, |today| {
+            today.usage.add(usage);
+            today.usage
+        });
+
+        log::info!(
+            "Token usage: session {} tokens, today {} tokens",
+            self.session.total_tokens(),
+            today.total_tokens()
+        );
+    }
+
+    pub fn summary(&self) -> UsageSummary {
+        let today = update_today(false, |today| *today);
+        UsageSummary {
+            session: self.session,
+            today: today.usage,
+            activity: today.activity,
+        }
+    }
+
+    /// Text answering "今天用了多少 token"
+    pub fn spoken_summary(&self) -> String {
+        let today = update_today(false, |today| today.usage);
+        format!(
+            "今天一共用了{}个token，其中提问{}个，回答{}个。本次对话用了{}个。",
+            today.total_tokens(),
+            today.prompt_tokens,
+            today.completion_tokens,
+            self.session.total_tokens()
+        )
+    }
+}
+
+/// Where the day starts, in minutes east of UTC
+pub fn set_utc_offset(utc_offset_mins: i32) {
+    UTC_OFFSET_MINS.store(utc_offset_mins, Ordering::Relaxed);
+}
+
+/// Count a wake word, kept in memory until the next count is persisted since it's called from
+/// the audio loop
+pub fn record_wake_word() {
+    update_today(false, |today| today.activity.wake_words += 1);
+}
+
+/// Count an utterance that was understood
+pub fn record_question() {
+    update_today(true, |today| today.activity.questions += 1);
+}
+
+/// Add the time spent playing speech
+pub fn record_speaking(duration: Duration) {
+    update_today(true, |today| {
+        today.activity.speaking_ms += duration.as_millis() as u64
+    });
+}
+
+/// Text answering "今天我们聊了多少"
+pub fn daily_summary() -> String {
+    spoken_activity(&update_today(false, |today| *today))
+}
+
+fn spoken_activity(today: &PersistedUsage) -> String {
+    let activity = &today.activity;
+    if activity.wake_words == 0 && activity.questions == 0 {
+        return "今天我们还没有聊过天".to_string();
+    }
+    let speaking_secs = activity.speaking_ms / 1000;
+    let speaking = if speaking_secs < 60 {
+        format!("{}秒", speaking_secs)
+    } else {
+        format!("{}分钟", speaking_secs / 60)
+    };
+    format!(
+        "今天你叫了我{}次，问了{}个问题，我一共说了{}，用了{}个token。",
+        activity.wake_words,
+        activity.questions,
+        speaking,
+        today.usage.total_tokens()
+    )
+}
+
+/// Change today's counts, starting over when the day changed, and publish them as gauges
+///
+/// Nothing is read or written before the clock is set, the file is only changed with TODAY
+/// unlocked.
+fn update_today<R>(persist: bool, update: impl FnOnce(&mut PersistedUsage) -> R) -> R {
+    let (result, snapshot) = {
+        let mut guard = TODAY.lock().unwrap();
+        let today = guard.get_or_insert_with(PersistedUsage::default);
+        roll_over(today, current_day(), load);
+        (update(today), *today)
+    };
+
+    metrics::set_gauge("today_wake_words", snapshot.activity.wake_words as i64);
+    metrics::set_gauge("today_questions", snapshot.activity.questions as i64);
+    metrics::set_gauge(
+        "today_speaking_secs",
+        (snapshot.activity.speaking_ms / 1000) as i64,
+    );
+    metrics::set_gauge("today_tokens", snapshot.usage.total_tokens() as i64);
+    if persist && snapshot.day != UNSYNCED_DAY {
+        let _saving = SAVING.lock().unwrap();
+        // Another thread may have counted and saved meanwhile, the file gets the latest
+        let latest = TODAY.lock().unwrap().unwrap_or(snapshot);
+        save(&latest);
+    }
+    result
+}
+
+/// Move `today` to `day`, the first real day takes over the counts made before the clock was
+/// set and adds them to those read by `load`
+fn roll_over(today: &mut PersistedUsage, day: u64, load: impl FnOnce(u64) -> PersistedUsage) {
+    if today.day == day {
+        return;
+    }
+    *today = if today.day == UNSYNCED_DAY {
+        let mut loaded = load(day);
+        loaded.absorb(today);
+        loaded
+    } else {
+        PersistedUsage::default()
+    };
+    today.day = day;
+}
+
+fn load(day: u64) -> PersistedUsage {
+    let empty = PersistedUsage {
+        day,
+        ..Default::default()
+    };
+    match std::fs::read_to_string(USAGE_FILE_PATH) {
+        Ok(text) => match serde_json::from_str::<PersistedUsage>(&text) {
+            Ok(persisted) if persisted.day == day => persisted,
+            Ok(_) => empty,
+            Err(e) => {
+                log::warn!("Failed to parse {}: {}", USAGE_FILE_PATH, e);
+                empty
+            }
+        },
+        Err(_) => empty,
+    }
+}
+
+fn save(persisted: &PersistedUsage) {
+    let result = serde_json::to_string(persisted)
+        .map_err(anyhow::Error::from)
+        .and_then(|text| std::fs::write(USAGE_FILE_PATH, text).map_err(anyhow::Error::from));
+
+    if let Err(e) = result {
+        log::warn!("Failed to persist usage: {}", e);
+    }
+}
+
+/// Local days since the epoch, UNSYNCED_DAY until the clock is set
+fn current_day() -> u64 {
+    wall_clock_secs().map_or(UNSYNCED_DAY, |secs| {
+        local_day(secs, UTC_OFFSET_MINS.load(Ordering::Relaxed))
+    })
+}
+
+fn local_day(secs: u64, utc_offset_mins: i32) -> u64 {
+    (secs as i64 + utc_offset_mins as i64 * 60).div_euclid(SECONDS_PER_DAY as i64) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spoken_activity() {
+        let mut today = PersistedUsage::default();
+        assert_eq!(spoken_activity(&today), "今天我们还没有聊过天");
+
+        today.activity = Activity {
+            wake_words: 5,
+            questions: 7,
+            speaking_ms: 185_500,
+        };
+        today.usage.prompt_tokens = 1200;
+        today.usage.completion_tokens = 300;
+        assert_eq!(
+            spoken_activity(&today),
+            "今天你叫了我5次，问了7个问题，我一共说了3分钟，用了1500个token。"
+        );
+
+        // Files written before activity was tracked
+        let old: PersistedUsage = serde_json::from_str(
+            r#"{"day":1,"usage":{"requests":1,"prompt_tokens":2,"completion_tokens":3}}"#,
+        )
+        .unwrap();
+        assert_eq!(old.activity.questions, 0);
+    }
+
+    #[test]
+    fn test_roll_over() {
+        let stored = |day| PersistedUsage {
+            day,
+            activity: Activity {
+                wake_words: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Counted before the clock was set, then added to what the file has for the day
+        let mut today = PersistedUsage::default();
+        today.activity.wake_words = 2;
+        roll_over(&mut today, UNSYNCED_DAY, stored);
+        assert_eq!(today.activity.wake_words, 2);
+        roll_over(&mut today, 20_000, stored);
+        assert_eq!((today.day, today.activity.wake_words), (20_000, 12));
+
+        // The next day starts from nothing, without reading the file
+        roll_over(&mut today, 20_001, |_| panic!("read the file again"));
+        assert_eq!((today.day, today.activity.wake_words), (20_001, 0));
+    }
+
+    #[test]
+    fn test_local_day() {
+        // 2024-10-16 20:00 UTC is already the 17th in China
+        let secs = 1_729_108_800;
+        assert_eq!(local_day(secs, 0), 20_012);
+        assert_eq!(local_day(secs, 8 * 60), 20_013);
+        assert_eq!(local_day(secs, -5 * 60), 20_012);
+    }
+}
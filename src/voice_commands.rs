@@ -0,0 +1,610 @@
+/// Commands recognized in the transcribed text and handled on the device
+/// instead of being forwarded to the LLM
+#[derive(Debug, Clone, PartialEq)]
+pub enum VoiceCommand {
+    /// Switch to the named persona, e.g. "切换到英语老师模式"
+    SwitchPersona(String),
+    /// Go back to the default persona, e.g. "切换到默认模式"
+    ResetPersona,
+    /// Ask how many tokens were used, e.g. "今天用了多少token"
+    QueryTokenUsage,
+    /// Ask how much the device was used today, e.g. "今天我们聊了多少"
+    QueryDailyUsage,
+    /// Raise or lower the sampling temperature, e.g. "回答更有创意一点"/"回答更严谨一点"
+    AdjustTemperature { increase: bool },
+    /// Change how long replies are, e.g. "简短回答"/"详细一点"
+    SetReplyLength(ReplyLength),
+    /// Speak the last reply again, e.g. "再说一遍"
+    ReplayLastAnswer,
+    /// Remember the speaker's voice under a name, e.g. "记住我的声音，我叫小明"
+    EnrollSpeaker(String),
+    /// Ask how much room is left for recordings, e.g. "存储还剩多少"
+    QueryStorage,
+    /// Ask about the WiFi signal and response times, e.g. "网络怎么样"
+    QueryNetwork,
+    /// Check which step to the online services fails, e.g. "检查网络"
+    DiagnoseNetwork,
+    /// Install new firmware if there is any, e.g. "检查更新"
+    UpdateFirmware,
+    /// Wipe settings and conversations after asking for confirmation, e.g. "恢复出厂设置"
+    FactoryReset,
+    /// Play the named radio station, e.g. "播放新闻电台", the name is empty for "播放电台"
+    PlayRadio(String),
+    /// Play music from the card, e.g. "播放音乐" or "播放周杰伦的歌", empty without a name
+    PlayMusic(String),
+    /// Stop the radio or music, e.g. "停止播放"
+    StopPlaying,
+    /// Hold the radio or music, e.g. "暂停"
+    PausePlaying,
+    /// e.g. "继续播放"
+    ResumePlaying,
+    /// Skip to the next track, e.g. "下一首"
+    NextTrack,
+    /// Write the following utterances to the notes instead of answering, e.g. "开始听写"
+    StartDictation,
+    /// e.g. "结束听写"
+    StopDictation,
+    /// Translate every utterance between Chinese and English instead of answering, e.g. "翻译模式"
+    StartTranslation,
+    /// e.g. "退出翻译"
+    StopTranslation,
+    /// Tell a long story slowly and quietly, e.g. "讲个睡前故事" or "讲个关于恐龙的睡前故事"
+    BedtimeStory,
+    /// Take a picture and answer about it, e.g. "看看这是什么" or "帮我看一下这个药怎么吃"
+    DescribeImage,
+}
+
+/// Preferred length of the assistant's replies
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReplyLength {
+    Brief,
+    #[default]
+    Normal,
+    Detailed,
+}
+
+impl ReplyLength {
+    /// Token limit for replies of this length, based on the configured limit
+    pub fn max_tokens(&self, base_max_tokens: u32) -> u32 {
+        match self {
+            ReplyLength::Brief => base_max_tokens.min(150),
+            ReplyLength::Normal => base_max_tokens,
+            ReplyLength::Detailed => base_max_tokens.max(1024),
+        }
+    }
+
+    /// Instruction sent along with every request
+    pub fn style_hint(&self) -> Option<&'static str> {
+        match self {
+            ReplyLength::Brief => Some("请用一两句话简短回答。"),
+            ReplyLength::Normal => None,
+            ReplyLength::Detailed => Some("请回答得详细一些，可以分几个方面说明，但仍然保持一个段落。"),
+        }
+    }
+
+    /// Name stored in the settings
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReplyLength::Brief => "brief",
+            ReplyLength::Normal => "normal",
+            ReplyLength::Detailed => "detailed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "brief" => Some(ReplyLength::Brief),
+            "normal" => Some(ReplyLength::Normal),
+            "detailed" => Some(ReplyLength::Detailed),
+            _ => None,
+        }
+    }
+}
+
+/// Ways to ask for the last reply again
+const REPLAY_PHRASES: [&str; 5] = ["再说一遍", "再说一次", "重复一遍", "重复一下", "你刚才说什么"];
+
+/// Persona names that mean "no persona"
+const DEFAULT_PERSONA_NAMES: [&str; 3] = ["默认", "普通", "正常"];
+/// Ways the STT server may spell "token"
+const TOKEN_WORDS: [&str; 4] = ["token", "令牌", "词元", "托肯"];
+/// A network query names the network and asks how it is
+const NETWORK_WORDS: [&str; 4] = ["网络", "信号", "网速", "wifi"];
+const NETWORK_QUESTIONS: [&str; 4] = ["怎么样", "好不好", "如何", "强不强"];
+/// Ways to ask for new firmware
+const UPDATE_PHRASES: [&str; 4] = ["检查更新", "更新固件", "升级固件", "固件升级"];
+/// Ways to ask for a check of the route to the online services
+const DIAGNOSE_PHRASES: [&str; 4] = ["检查网络", "网络诊断", "诊断网络", "检测网络"];
+/// Ways to ask for a factory reset
+const FACTORY_RESET_PHRASES: [&str; 3] = ["恢复出厂", "出厂设置", "重置设备"];
+/// Ways to start a radio station, followed by its name and "电台" or "广播"
+const PLAY_PREFIXES: [&str; 4] = ["播放", "打开", "收听", "我想听"];
+/// Ways to ask for the whole music library after a play prefix, "播放音乐"
+const MUSIC_WORDS: [&str; 5] = ["音乐", "歌曲", "歌", "首歌", "点音乐"];
+/// Ways to skip to the next track
+const NEXT_TRACK_PHRASES: [&str; 3] = ["下一首", "换一首", "切歌"];
+/// Ways to stop the radio or music
+const STOP_PHRASES: [&str; 5] = ["停止播放", "关掉电台", "关闭电台", "别放了", "关掉广播"];
+/// Ways to start dictation
+const DICTATION_PHRASES: [&str; 3] = ["开始听写", "听写模式", "记笔记"];
+/// Ways to end dictation
+const END_DICTATION_PHRASES: [&str; 3] = ["结束听写", "停止听写", "退出听写"];
+/// Ways to start the interpreter
+const TRANSLATION_PHRASES: [&str; 3] = ["翻译模式", "开始翻译", "同声传译"];
+/// Ways to end the interpreter, in either language since both are spoken in it
+const END_TRANSLATION_PHRASES: [&str; 5] =
+    ["退出翻译", "结束翻译", "停止翻译", "stoptranslating", "exittranslation"];
+/// Ways to ask how much the device was used today
+const DAILY_USAGE_PHRASES: [&str; 3] = ["聊了多少", "聊了多久", "聊了几次"];
+/// Ways to ask for a story, "睡前故事有什么好处" is a question for the LLM
+const STORY_PREFIXES: [&str; 5] = ["讲", "说", "来", "给我讲", "我想听"];
+/// Politeness in front of a request to look, "请帮我看看这是什么"
+const LOOK_FILLERS: [&str; 3] = ["请", "帮我", "你"];
+/// Ways to ask about what the camera sees, "看看明天的天气" is a question for the LLM
+const LOOK_PREFIXES: [&str; 7] = [
+    "看看这",
+    "看一下这",
+    "瞧瞧这",
+    "看看我手",
+    "看到了什么",
+    "看见了什么",
+    "能看到什么",
+];
+/// Answers that confirm a question like "确定要恢复出厂设置吗", nothing that is said in passing
+const CONFIRMATIONS: [&str; 3] = ["确定", "确认", "确定恢复"];
+
+/// Remove whitespace and punctuation the STT server inserts between words
+pub fn normalize_transcript(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_whitespace() && !is_punctuation(*c))
+        .collect()
+}
+
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation() || "，。！？、；：“”‘’（）《》…".contains(c)
+}
+
+/// Whether the transcription is one of the phrases that end the conversation
+pub fn is_exit_phrase(text: &str, exit_phrases: &[String]) -> bool {
+    let text = normalize_transcript(text).to_lowercase();
+
+    !text.is_empty()
+        && exit_phrases
+            .iter()
+            .any(|phrase| normalize_transcript(phrase).to_lowercase() == text)
+}
+
+/// Whether the transcription confirms the question the device just asked
+pub fn is_confirmation(text: &str) -> bool {
+    CONFIRMATIONS.contains(&normalize_transcript(text).as_str())
+}
+
+/// Try to interpret the transcription as a device command
+pub fn parse_voice_command(text: &str) -> Option<VoiceCommand> {
+    let text = normalize_transcript(text);
+
+    if let Some(command) = parse_persona_command(&text) {
+        return Some(command);
+    }
+
+    if let Some(name) = text
+        .strip_prefix("记住我的声音")
+        .and_then(|rest| rest.strip_prefix("我叫").or_else(|| rest.strip_prefix("我是")))
+        .filter(|name| !name.is_empty())
+    {
+        return Some(VoiceCommand::EnrollSpeaker(name.to_string()));
+    }
+
+    // Only short utterances, "把这个故事再说一遍" asks the LLM to retell it
+    if text.chars().count() <= 8 && REPLAY_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::ReplayLastAnswer);
+    }
+
+    if text.contains("更有创意") {
+        return Some(VoiceCommand::AdjustTemperature { increase: true });
+    }
+
+    if text.contains("更严谨") {
+        return Some(VoiceCommand::AdjustTemperature { increase: false });
+    }
+
+    if let Some(length) = parse_reply_length(&text) {
+        return Some(VoiceCommand::SetReplyLength(length));
+    }
+
+    // Only short utterances, "怎么清理手机存储" is a question for the LLM
+    if text.chars().count() <= 10
+        && (text.contains("存储") || text.contains("空间"))
+        && (text.contains("还剩") || text.contains("多少"))
+    {
+        return Some(VoiceCommand::QueryStorage);
+    }
+
+    if text.chars().count() <= 8 && UPDATE_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::UpdateFirmware);
+    }
+
+    if text.chars().count() <= 8 && DIAGNOSE_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::DiagnoseNetwork);
+    }
+
+    // Only short utterances, "手机怎么恢复出厂设置" is a question for the LLM
+    if text.chars().count() <= 8 && FACTORY_RESET_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::FactoryReset);
+    }
+
+    // Only short utterances, "记笔记有什么技巧" is a question for the LLM
+    if text.chars().count() <= 6 && DICTATION_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::StartDictation);
+    }
+    if text.chars().count() <= 6 && END_DICTATION_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::StopDictation);
+    }
+
+    // Only short utterances, "开始翻译这段话" is something to translate
+    if text.chars().count() <= 6 && TRANSLATION_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::StartTranslation);
+    }
+    let lowercase = text.to_lowercase();
+    // "stoptranslating" once the spaces are gone
+    if text.chars().count() <= 16 && END_TRANSLATION_PHRASES.iter().any(|p| lowercase.contains(p)) {
+        return Some(VoiceCommand::StopTranslation);
+    }
+
+    if text.contains("睡前故事") && STORY_PREFIXES.iter().any(|p| text.starts_with(p)) {
+        return Some(VoiceCommand::BedtimeStory);
+    }
+
+    let mut look = text.as_str();
+    while let Some(rest) = LOOK_FILLERS.iter().find_map(|f| look.strip_prefix(f)) {
+        look = rest;
+    }
+    if LOOK_PREFIXES.iter().any(|p| look.starts_with(p)) {
+        return Some(VoiceCommand::DescribeImage);
+    }
+
+    if let Some(name) = parse_radio_station(&text) {
+        return Some(VoiceCommand::PlayRadio(name));
+    }
+
+    if let Some(query) = parse_music_query(&text) {
+        return Some(VoiceCommand::PlayMusic(query));
+    }
+
+    // Only short utterances, "怎么停止播放视频" is a question for the LLM
+    if text.chars().count() <= 6 && STOP_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::StopPlaying);
+    }
+    if text.chars().count() <= 4 && text.contains("暂停") {
+        return Some(VoiceCommand::PausePlaying);
+    }
+    // "继续" alone asks the LLM to go on with its answer
+    if text.chars().count() <= 6 && text.contains("继续播放") {
+        return Some(VoiceCommand::ResumePlaying);
+    }
+    if text.chars().count() <= 6 && NEXT_TRACK_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::NextTrack);
+    }
+
+    // Only short utterances, "怎么让家里的网络更快" is a question for the LLM
+    if text.chars().count() <= 10
+        && NETWORK_WORDS.iter().any(|w| lowercase.contains(w))
+        && NETWORK_QUESTIONS.iter().any(|q| lowercase.contains(q))
+    {
+        return Some(VoiceCommand::QueryNetwork);
+    }
+
+    if lowercase.contains("多少") && TOKEN_WORDS.iter().any(|w| lowercase.contains(w)) {
+        return Some(VoiceCommand::QueryTokenUsage);
+    }
+
+    // Only short utterances, "上次我们聊了多少关于恐龙的事" is a question for the LLM
+    if text.chars().count() <= 10 && DAILY_USAGE_PHRASES.iter().any(|p| text.contains(p)) {
+        return Some(VoiceCommand::QueryDailyUsage);
+    }
+
+    None
+}
+
+/// Name of the station in "播放新闻电台", empty for "播放电台"
+fn parse_radio_station(text: &str) -> Option<String> {
+    let rest = PLAY_PREFIXES
+        .iter()
+        .find_map(|prefix| text.strip_prefix(prefix))?;
+    let name = rest
+        .strip_suffix("电台")
+        .or_else(|| rest.strip_suffix("广播"))?;
+    Some(name.to_string())
+}
+
+/// What to play in "播放周杰伦的歌" or "播放歌曲晴天", empty for "播放音乐"
+fn parse_music_query(text: &str) -> Option<String> {
+    let rest = PLAY_PREFIXES
+        .iter()
+        .find_map(|prefix| text.strip_prefix(prefix))?;
+    if MUSIC_WORDS.contains(&rest) {
+        return Some(String::new());
+    }
+    rest.strip_prefix("歌曲")
+        .or_else(|| rest.strip_suffix("的歌"))
+        .filter(|query| !query.is_empty())
+        .map(str::to_string)
+}
+
+fn parse_persona_command(text: &str) -> Option<VoiceCommand> {
+    let persona = text
+        .strip_prefix("切换到")
+        .or_else(|| text.strip_prefix("切换成"))
+        .and_then(|rest| rest.strip_suffix("模式"))?;
+
+    if persona.is_empty() {
+        None
+    } else if DEFAULT_PERSONA_NAMES.contains(&persona) {
+        Some(VoiceCommand::ResetPersona)
+    } else {
+        Some(VoiceCommand::SwitchPersona(persona.to_string()))
+    }
+}
+
+fn parse_reply_length(text: &str) -> Option<ReplyLength> {
+    // Only short utterances, "详细介绍一下长城" is a question and not a command
+    if text.chars().count() > 8 {
+        return None;
+    }
+
+    if ["简短", "简单点", "简单一点", "短一点", "说短点"].iter().any(|w| text.contains(w)) {
+        Some(ReplyLength::Brief)
+    } else if ["详细一点", "详细点", "详细回答", "说详细"].iter().any(|w| text.contains(w)) {
+        Some(ReplyLength::Detailed)
+    } else if text.contains("正常长度") || text.contains("正常回答") {
+        Some(ReplyLength::Normal)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_switch_persona() {
+        assert_eq!(
+            parse_voice_command("切换 到 英语 老师 模式"),
+            Some(VoiceCommand::SwitchPersona("英语老师".to_string()))
+        );
+        assert_eq!(
+            parse_voice_command("切换到默认模式。"),
+            Some(VoiceCommand::ResetPersona)
+        );
+        assert_eq!(parse_voice_command("切换到模式"), None);
+        assert_eq!(parse_voice_command("今天天气怎么样"), None);
+    }
+
+    #[test]
+    fn test_query_token_usage() {
+        assert_eq!(
+            parse_voice_command("今天 用了 多少 Token"),
+            Some(VoiceCommand::QueryTokenUsage)
+        );
+        assert_eq!(
+            parse_voice_command("今天我们聊了多少？"),
+            Some(VoiceCommand::QueryDailyUsage)
+        );
+    }
+
+    #[test]
+    fn test_adjust_temperature() {
+        assert_eq!(
+            parse_voice_command("回答更有创意一点"),
+            Some(VoiceCommand::AdjustTemperature { increase: true })
+        );
+        assert_eq!(
+            parse_voice_command("回答更严谨一点。"),
+            Some(VoiceCommand::AdjustTemperature { increase: false })
+        );
+    }
+
+    #[test]
+    fn test_reply_length() {
+        assert_eq!(
+            parse_voice_command("简短 回答"),
+            Some(VoiceCommand::SetReplyLength(ReplyLength::Brief))
+        );
+        assert_eq!(
+            parse_voice_command("详细一点。"),
+            Some(VoiceCommand::SetReplyLength(ReplyLength::Detailed))
+        );
+        assert_eq!(parse_voice_command("详细介绍一下长城的历史"), None);
+    }
+
+    #[test]
+    fn test_replay_last_answer() {
+        assert_eq!(
+            parse_voice_command("请再说一遍。"),
+            Some(VoiceCommand::ReplayLastAnswer)
+        );
+        assert_eq!(parse_voice_command("把小红帽的故事再说一遍"), None);
+    }
+
+    #[test]
+    fn test_enroll_speaker() {
+        assert_eq!(
+            parse_voice_command("记住我的声音，我叫小明。"),
+            Some(VoiceCommand::EnrollSpeaker("小明".to_string()))
+        );
+        assert_eq!(parse_voice_command("记住我的声音"), None);
+    }
+
+    #[test]
+    fn test_query_storage() {
+        assert_eq!(
+            parse_voice_command("存储还剩多少？"),
+            Some(VoiceCommand::QueryStorage)
+        );
+        assert_eq!(
+            parse_voice_command("还剩多少空间"),
+            Some(VoiceCommand::QueryStorage)
+        );
+        assert_eq!(parse_voice_command("手机存储空间不够了怎么清理"), None);
+    }
+
+    #[test]
+    fn test_query_network() {
+        assert_eq!(
+            parse_voice_command("网络怎么样？"),
+            Some(VoiceCommand::QueryNetwork)
+        );
+        assert_eq!(
+            parse_voice_command("WiFi信号好不好"),
+            Some(VoiceCommand::QueryNetwork)
+        );
+        assert_eq!(parse_voice_command("怎么让家里的网络信号变得更好"), None);
+    }
+
+    #[test]
+    fn test_diagnose_network() {
+        assert_eq!(
+            parse_voice_command("帮我检查网络。"),
+            Some(VoiceCommand::DiagnoseNetwork)
+        );
+        assert_eq!(
+            parse_voice_command("网络诊断"),
+            Some(VoiceCommand::DiagnoseNetwork)
+        );
+        assert_eq!(parse_voice_command("怎么检查网络是不是被入侵了"), None);
+    }
+
+    #[test]
+    fn test_update_firmware() {
+        assert_eq!(
+            parse_voice_command("检查更新"),
+            Some(VoiceCommand::UpdateFirmware)
+        );
+        assert_eq!(
+            parse_voice_command("帮我升级固件。"),
+            Some(VoiceCommand::UpdateFirmware)
+        );
+        assert_eq!(parse_voice_command("手机怎么检查更新系统"), None);
+    }
+
+    #[test]
+    fn test_factory_reset() {
+        assert_eq!(
+            parse_voice_command("恢复出厂设置。"),
+            Some(VoiceCommand::FactoryReset)
+        );
+        assert_eq!(parse_voice_command("手机怎么恢复出厂设置"), None);
+        assert!(is_confirmation("确定！"));
+        assert!(!is_confirmation("不确定"));
+        assert!(!is_confirmation("对"));
+        assert!(!is_confirmation("是的"));
+    }
+
+    #[test]
+    fn test_radio() {
+        assert_eq!(
+            parse_voice_command("播放新闻电台。"),
+            Some(VoiceCommand::PlayRadio("新闻".to_string()))
+        );
+        assert_eq!(
+            parse_voice_command("我想听广播"),
+            Some(VoiceCommand::PlayRadio(String::new()))
+        );
+        assert_eq!(parse_voice_command("播放一首歌"), None);
+        assert_eq!(
+            parse_voice_command("停止播放！"),
+            Some(VoiceCommand::StopPlaying)
+        );
+        assert_eq!(parse_voice_command("怎么停止播放视频"), None);
+    }
+
+    #[test]
+    fn test_music() {
+        assert_eq!(
+            parse_voice_command("播放音乐。"),
+            Some(VoiceCommand::PlayMusic(String::new()))
+        );
+        assert_eq!(
+            parse_voice_command("我想听周杰伦的歌"),
+            Some(VoiceCommand::PlayMusic("周杰伦".to_string()))
+        );
+        assert_eq!(
+            parse_voice_command("播放歌曲晴天"),
+            Some(VoiceCommand::PlayMusic("晴天".to_string()))
+        );
+        assert_eq!(parse_voice_command("暂停！"), Some(VoiceCommand::PausePlaying));
+        assert_eq!(parse_voice_command("继续播放"), Some(VoiceCommand::ResumePlaying));
+        assert_eq!(parse_voice_command("下一首"), Some(VoiceCommand::NextTrack));
+        assert_eq!(parse_voice_command("为什么要暂停比赛"), None);
+    }
+
+    #[test]
+    fn test_dictation() {
+        assert_eq!(
+            parse_voice_command("开始听写。"),
+            Some(VoiceCommand::StartDictation)
+        );
+        assert_eq!(
+            parse_voice_command("结束听写"),
+            Some(VoiceCommand::StopDictation)
+        );
+        assert_eq!(parse_voice_command("记笔记有什么技巧"), None);
+    }
+
+    #[test]
+    fn test_translation() {
+        assert_eq!(
+            parse_voice_command("翻译模式"),
+            Some(VoiceCommand::StartTranslation)
+        );
+        assert_eq!(
+            parse_voice_command("退出翻译。"),
+            Some(VoiceCommand::StopTranslation)
+        );
+        assert_eq!(
+            parse_voice_command("Stop translating."),
+            Some(VoiceCommand::StopTranslation)
+        );
+        assert_eq!(parse_voice_command("开始翻译这段话给我听"), None);
+    }
+
+    #[test]
+    fn test_bedtime_story() {
+        assert_eq!(
+            parse_voice_command("讲个睡前故事。"),
+            Some(VoiceCommand::BedtimeStory)
+        );
+        assert_eq!(
+            parse_voice_command("给我讲一个关于恐龙的睡前故事"),
+            Some(VoiceCommand::BedtimeStory)
+        );
+        assert_eq!(parse_voice_command("睡前故事有什么好处"), None);
+    }
+
+    #[test]
+    fn test_describe_image() {
+        assert_eq!(
+            parse_voice_command("看看这是什么？"),
+            Some(VoiceCommand::DescribeImage)
+        );
+        assert_eq!(
+            parse_voice_command("请帮我看一下这个药怎么吃"),
+            Some(VoiceCommand::DescribeImage)
+        );
+        assert_eq!(
+            parse_voice_command("你看到了什么"),
+            Some(VoiceCommand::DescribeImage)
+        );
+        assert_eq!(parse_voice_command("帮我看看明天的天气"), None);
+    }
+
+    #[test]
+    fn test_exit_phrase() {
+        let phrases = vec!["再见".to_string(), "Stop".to_string()];
+        assert!(is_exit_phrase("再见。", &phrases));
+        assert!(is_exit_phrase(" stop! ", &phrases));
+        assert!(!is_exit_phrase("明天再见吧", &phrases));
+        assert!(!is_exit_phrase("", &phrases));
+    }
+}
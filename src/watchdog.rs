@@ -0,0 +1,77 @@
+use esp_idf_svc::sys::{self, esp};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::WatchdogConfig;
+
+/// Set once the task watchdog is configured to reset the device
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The calling thread holds a `Watch`, feeding is only allowed then
+    static WATCHED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The calling thread is watched until this is dropped, it has to call `feed` within the timeout
+pub struct Watch {
+    /// The subscription belongs to the thread that made it
+    thread_bound: PhantomData<*const ()>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        WATCHED.with(|watched| watched.set(false));
+        if let Err(e) = esp!(unsafe { sys::esp_task_wdt_delete(std::ptr::null_mut()) }) {
+            log::warn!("Failed to stop watching the thread: {}", e);
+        }
+    }
+}
+
+/// Make the task watchdog reset the device when a watched thread hangs for the configured time
+pub fn init(config: &WatchdogConfig) -> anyhow::Result<()> {
+    if config.timeout_secs == 0 {
+        return Ok(());
+    }
+
+    let wdt_config = sys::esp_task_wdt_config_t {
+        timeout_ms: config.timeout_secs * 1000,
+        // Only the threads that ask for it are watched, not the idle tasks
+        idle_core_mask: 0,
+        trigger_panic: true,
+    };
+    // ESP-IDF starts the watchdog at boot unless that is turned off in sdkconfig
+    match esp!(unsafe { sys::esp_task_wdt_reconfigure(&wdt_config) }) {
+        Err(e) if e.code() == sys::ESP_ERR_INVALID_STATE => {
+            esp!(unsafe { sys::esp_task_wdt_init(&wdt_config) })?
+        }
+        result => result?,
+    }
+
+    ENABLED.store(true, Ordering::Relaxed);
+    log::info!("Task watchdog resets after {} s", config.timeout_secs);
+    Ok(())
+}
+
+/// Watch the calling thread, None when the watchdog is off or the thread is already watched
+pub fn watch() -> Option<Watch> {
+    if !ENABLED.load(Ordering::Relaxed) || WATCHED.with(Cell::get) {
+        return None;
+    }
+    if let Err(e) = esp!(unsafe { sys::esp_task_wdt_add(std::ptr::null_mut()) }) {
+        log::warn!("Failed to watch the thread: {}", e);
+        return None;
+    }
+
+    WATCHED.with(|watched| watched.set(true));
+    Some(Watch {
+        thread_bound: PhantomData,
+    })
+}
+
+/// Tell the watchdog the calling thread is making progress, does nothing unless it is watched
+pub fn feed() {
+    if WATCHED.with(Cell::get) {
+        unsafe { sys::esp_task_wdt_reset() };
+    }
+}
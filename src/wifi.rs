@@ -1,24 +1,503 @@
 use anyhow;
 use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::ipv4::{
+    ClientConfiguration as IpClientConfiguration, ClientSettings as IpClientSettings,
+    Configuration as IpConfiguration, DHCPClientSettings, Mask, Subnet,
+};
+use esp_idf_svc::netif::{EspNetif, IpEvent, NetifConfiguration, NetifStack};
+use esp_idf_svc::sys::{self, esp};
+use esp_idf_svc::wifi::{WifiDriver, WifiEvent};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     nvs::EspDefaultNvsPartition,
     wifi::{AuthMethod, ClientConfiguration, Configuration, EspWifi},
 };
 use heapless;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::config::{NetworkConfig, PowerSaveMode, ProvisioningMode, WifiConfig};
+use crate::factory_reset;
+use crate::known_networks::{connection_order, Credentials, KnownNetworks};
+use crate::llm_intf;
+use crate::metrics::{self, NetworkMetrics};
+use crate::provisioning;
+
+/// A scan for the strongest known network replaces every this many plain reconnects
+const RESCAN_EVERY_ATTEMPTS: u32 = 5;
+const MAX_RECONNECT_BACKOFF_SECS: u64 = 60;
+
+/// How often the signal strength is sampled while connected
+const SAMPLE_INTERVAL_SECS: u64 = 30;
+/// Below this average a warning is logged, replies will be slow or fail
+const WEAK_SIGNAL_RSSI: i8 = -75;
+
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static LINK_STATS: Mutex<LinkStats> = Mutex::new(LinkStats::new());
 
 /// Enhanced WiFi initialization function with better error handling and reconnection logic
-pub fn initialize_wifi(modem: Modem) -> anyhow::Result<Box<EspWifi<'static>>> {
-    // Get SSID and password from environment variables (compile-time)
-    let ssid = env!("WIFI_SSID");
-    let pass = env!("WIFI_PASS");
+///
+/// Tries the network from config.toml and the known networks saved in NVS, the ones in range
+/// strongest first. When none connects, runs the SoftAP portal or BLE provisioning (see
+/// `provisioning`) until a network works or nobody answered within `portal_timeout_secs`.
+/// The driver is returned even without a connection, `start_supervisor` keeps trying.
+pub fn initialize_wifi(
+    modem: Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    config: &WifiConfig,
+    network: &NetworkConfig,
+) -> anyhow::Result<Box<EspWifi<'static>>> {
+    let mut known_networks = KnownNetworks::new(nvs.clone());
+    let driver = WifiDriver::new(modem, sys_loop, Some(nvs))?;
+    let mut wifi = EspWifi::wrap_all(
+        driver,
+        EspNetif::new_with_conf(&station_netif_config(network)?)?,
+        EspNetif::new(NetifStack::Ap)?,
+    )?;
+    apply_power_save(config.power_save);
 
-    log::info!("Connecting to WiFi network: {}", ssid);
+    // Right after a factory reset the device asks for a network, whatever config.toml says
+    let candidates = if factory_reset::take_provisioning_request() {
+        log::info!("First boot after a factory reset, starting WiFi provisioning");
+        Vec::new()
+    } else {
+        candidates(config, &known_networks)
+    };
+    let mut last_error = None;
+    if !candidates.is_empty() {
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+        wifi.start()?;
+        let seen = scan(&mut wifi);
+
+        for credentials in connection_order(&candidates, &seen) {
+            match connect(&mut wifi, &credentials.ssid, &credentials.password) {
+                Ok(()) => return Ok(Box::new(wifi)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+    }
+
+    let timeout = Duration::from_secs(config.portal_timeout_secs as u64);
+    while config.portal_timeout_secs > 0 {
+        let credentials = match config.provisioning {
+            ProvisioningMode::SoftAp => {
+                let message = last_error.as_ref().map(|e| format!("连接失败：{}", e));
+                provisioning::run_portal(&mut wifi, timeout, message.as_deref())?
+            }
+            ProvisioningMode::Ble => {
+                provisioning::run_ble_provisioning(&mut wifi, timeout, &config.ble_pop)?
+            }
+        };
+        let Some(credentials) = credentials else {
+            break;
+        };
 
-    let sys_loop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
+        match connect(&mut wifi, &credentials.ssid, &credentials.password) {
+            Ok(()) => {
+                if let Err(e) = known_networks.add(&credentials) {
+                    log::warn!("Failed to save WiFi network '{}': {}", credentials.ssid, e);
+                }
+                return Ok(Box::new(wifi));
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    match last_error {
+        Some(e) => log::error!("No WiFi connection: {}", e),
+        None => log::error!("No WiFi network configured, set [wifi] ssid in config.toml"),
+    }
+
+    // Leave provisioning behind, the supervisor only needs the station
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    wifi.start()?;
+    Ok(Box::new(wifi))
+}
+
+/// Set how much the modem sleeps, the driver keeps this across stops and restarts
+pub fn apply_power_save(mode: PowerSaveMode) {
+    let ps_type = match mode {
+        PowerSaveMode::None => sys::wifi_ps_type_t_WIFI_PS_NONE,
+        PowerSaveMode::Min => sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+        PowerSaveMode::Max => sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+    };
+    match esp!(unsafe { sys::esp_wifi_set_ps(ps_type) }) {
+        Ok(()) => log::info!("WiFi power save: {:?}", mode),
+        Err(e) => log::warn!("Failed to set WiFi power save to {:?}: {}", mode, e),
+    }
+}
+
+/// DHCP with the configured hostname, or the fixed address from config.toml
+fn station_netif_config(network: &NetworkConfig) -> anyhow::Result<NetifConfiguration> {
+    let ip_configuration = match (network.static_ip, network.gateway) {
+        (Some(ip), Some(gateway)) => {
+            log::info!("Using static IP address {}/{}", ip, network.prefix_len);
+            IpClientConfiguration::Fixed(IpClientSettings {
+                ip,
+                subnet: Subnet {
+                    gateway,
+                    mask: Mask(network.prefix_len),
+                },
+                dns: network.dns.first().copied(),
+                secondary_dns: network.dns.get(1).copied(),
+            })
+        }
+        _ => IpClientConfiguration::DHCP(DHCPClientSettings {
+            hostname: Some(
+                network
+                    .hostname
+                    .as_str()
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Hostname too long"))?,
+            ),
+        }),
+    };
+
+    Ok(NetifConfiguration {
+        ip_configuration: Some(IpConfiguration::Client(ip_configuration)),
+        ..NetifConfiguration::wifi_default_client()
+    })
+}
+
+/// Replace the DNS servers from DHCP with the configured ones, after every new lease
+fn apply_dns(wifi: &EspWifi<'static>, network: &NetworkConfig) {
+    if network.static_ip.is_some() {
+        // Part of the fixed configuration already
+        return;
+    }
+
+    let kinds = [
+        sys::esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN,
+        sys::esp_netif_dns_type_t_ESP_NETIF_DNS_BACKUP,
+    ];
+    for (server, kind) in network.dns.iter().zip(kinds) {
+        let mut info = sys::esp_netif_dns_info_t::default();
+        info.ip.type_ = sys::ESP_IPADDR_TYPE_V4 as _;
+        info.ip.u_addr.ip4 = sys::esp_ip4_addr_t {
+            addr: u32::from_ne_bytes(server.octets()),
+        };
+
+        let result =
+            unsafe { sys::esp_netif_set_dns_info(wifi.sta_netif().handle(), kind, &mut info) };
+        if let Err(e) = esp!(result) {
+            log::warn!("Failed to set DNS server {}: {}", server, e);
+        }
+    }
+}
+
+/// The network from config.toml followed by the known ones, without duplicates
+fn candidates(config: &WifiConfig, known_networks: &KnownNetworks) -> Vec<Credentials> {
+    let mut candidates = Vec::new();
+    if !config.ssid.is_empty() {
+        candidates.push(Credentials {
+            ssid: config.ssid.clone(),
+            password: config.password.clone(),
+        });
+    }
+    for known in known_networks.list() {
+        if !candidates.iter().any(|c| c.ssid == known.ssid) {
+            candidates.push(known);
+        }
+    }
+    candidates
+}
+
+/// Whether the station has an IP address, kept up to date by the supervisor
+pub fn is_connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+/// Events the supervisor reacts to, forwarded from the system event loop
+enum LinkEvent {
+    Up,
+    Down,
+}
+
+/// Keep the connection alive for the rest of the program's lifetime
+///
+/// Reconnects with exponential backoff after the access point went away, and every few
+/// failed attempts scans for the strongest known network instead. `on_change` hears about
+/// every change of connectivity, and once at the start if the device boots offline.
+pub fn start_supervisor(
+    wifi: Box<EspWifi<'static>>,
+    sys_loop: EspSystemEventLoop,
+    config: WifiConfig,
+    network: NetworkConfig,
+    nvs: EspDefaultNvsPartition,
+    on_change: impl Fn(bool) + Send + 'static,
+) -> anyhow::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let wifi_tx = tx.clone();
+    let wifi_subscription = sys_loop.subscribe::<WifiEvent, _>(move |event| {
+        if matches!(event, WifiEvent::StaDisconnected { .. }) {
+            let _ = wifi_tx.send(LinkEvent::Down);
+        }
+    })?;
+    let ip_subscription = sys_loop.subscribe::<IpEvent, _>(move |event| {
+        if matches!(event, IpEvent::DhcpIpAssigned { .. }) {
+            let _ = tx.send(LinkEvent::Up);
+        }
+    })?;
+
+    let connected = has_ip(&wifi);
+    CONNECTED.store(connected, Ordering::Relaxed);
+    if connected {
+        apply_dns(&wifi, &network);
+        sample_link();
+    } else {
+        on_change(false);
+    }
 
-    let mut wifi = EspWifi::new(modem, sys_loop.clone(), Some(nvs))?;
+    std::thread::Builder::new()
+        .name("wifi_supervisor".to_string())
+        .stack_size(6 * 1024)
+        .spawn(move || {
+            // Unsubscribed when the thread ends
+            let _subscriptions = (wifi_subscription, ip_subscription);
+            let known_networks = KnownNetworks::new(nvs);
+            supervise(
+                wifi,
+                rx,
+                &config,
+                &network,
+                &known_networks,
+                connected,
+                on_change,
+            );
+        })?;
+
+    log::info!("WiFi supervisor started");
+    Ok(())
+}
+
+fn supervise(
+    mut wifi: Box<EspWifi<'static>>,
+    rx: Receiver<LinkEvent>,
+    config: &WifiConfig,
+    network: &NetworkConfig,
+    known_networks: &KnownNetworks,
+    mut connected: bool,
+    on_change: impl Fn(bool),
+) {
+    let mut failures: u32 = 0;
+
+    loop {
+        let wait = if connected {
+            Duration::from_secs(SAMPLE_INTERVAL_SECS)
+        } else {
+            reconnect_backoff(failures)
+        };
+        let event = rx.recv_timeout(wait);
+
+        match event {
+            Ok(LinkEvent::Up) => {
+                apply_dns(&wifi, network);
+                if !connected {
+                    log::info!("WiFi connection is back");
+                    connected = true;
+                    failures = 0;
+                    CONNECTED.store(true, Ordering::Relaxed);
+                    LINK_STATS.lock().unwrap().reset();
+                    sample_link();
+                    on_change(true);
+                }
+            }
+            Ok(LinkEvent::Down) => {
+                if connected {
+                    log::warn!("WiFi connection lost, reconnecting");
+                    connected = false;
+                    CONNECTED.store(false, Ordering::Relaxed);
+                    LINK_STATS.lock().unwrap().disconnects += 1;
+                    metrics::increment("wifi_disconnects");
+                    on_change(false);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if connected => sample_link(),
+            Err(RecvTimeoutError::Timeout) => {
+                failures += 1;
+                reconnect(&mut wifi, config, known_networks, failures);
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// One reconnection attempt, a failure shows up as another disconnect event
+fn reconnect(
+    wifi: &mut EspWifi<'static>,
+    config: &WifiConfig,
+    known_networks: &KnownNetworks,
+    attempt: u32,
+) {
+    let has_network = matches!(
+        wifi.get_configuration(),
+        Ok(Configuration::Client(client)) if !client.ssid.is_empty()
+    );
+
+    // The access point may be gone for good, or a network was added from the console
+    if !has_network || attempt % RESCAN_EVERY_ATTEMPTS == 0 {
+        let _ = wifi.disconnect();
+        let seen = scan(wifi);
+        let Some(best) = connection_order(&candidates(config, known_networks), &seen)
+            .into_iter()
+            .next()
+        else {
+            log::debug!("No known WiFi network to reconnect to");
+            return;
+        };
+
+        log::info!("Reconnecting to WiFi network '{}'", best.ssid);
+        let auth_method = if best.password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        };
+        let client_config = ClientConfiguration {
+            ssid: best.ssid.as_str().try_into().unwrap_or_default(),
+            password: best.password.as_str().try_into().unwrap_or_default(),
+            auth_method,
+            ..Default::default()
+        };
+        if let Err(e) = wifi.set_configuration(&Configuration::Client(client_config)) {
+            log::warn!("Failed to configure WiFi network '{}': {}", best.ssid, e);
+            return;
+        }
+    }
+
+    log::debug!("WiFi reconnection attempt {}", attempt);
+    if let Err(e) = wifi.connect() {
+        log::warn!("WiFi reconnection attempt {} failed: {}", attempt, e);
+    }
+}
+
+/// Signal of the current connection, kept between samples
+struct LinkStats {
+    ssid: Option<String>,
+    rssi: Option<i8>,
+    average_rssi: Option<f32>,
+    channel: Option<u8>,
+    disconnects: u32,
+    /// The average is below WEAK_SIGNAL_RSSI, so the warning isn't logged on every sample
+    weak: bool,
+}
+
+impl LinkStats {
+    const fn new() -> Self {
+        Self {
+            ssid: None,
+            rssi: None,
+            average_rssi: None,
+            channel: None,
+            disconnects: 0,
+            weak: false,
+        }
+    }
+
+    /// Forget the signal of the previous connection, it may have been another access point
+    fn reset(&mut self) {
+        *self = Self {
+            disconnects: self.disconnects,
+            ..Self::new()
+        };
+    }
+
+    fn add_sample(&mut self, ssid: String, rssi: i8, channel: u8) {
+        // Exponential moving average, a single reading jumps by several dB
+        let average = match self.average_rssi {
+            Some(average) => average * 0.75 + rssi as f32 * 0.25,
+            None => rssi as f32,
+        };
+
+        let weak = average < WEAK_SIGNAL_RSSI as f32;
+        if weak && !self.weak {
+            log::warn!("Weak WiFi signal from '{}': {:.0} dBm", ssid, average);
+        }
+
+        self.weak = weak;
+        self.ssid = Some(ssid);
+        self.rssi = Some(rssi);
+        self.average_rssi = Some(average);
+        self.channel = Some(channel);
+        metrics::set_gauge("wifi_rssi", average.round() as i64);
+    }
+}
+
+/// Read the signal strength of the access point the station is associated with
+fn sample_link() {
+    let mut ap_info = sys::wifi_ap_record_t::default();
+    if unsafe { sys::esp_wifi_sta_get_ap_info(&mut ap_info) } != sys::ESP_OK {
+        return;
+    }
+
+    let ssid_len = ap_info
+        .ssid
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(ap_info.ssid.len());
+    let ssid = String::from_utf8_lossy(&ap_info.ssid[..ssid_len]).into_owned();
+    LINK_STATS
+        .lock()
+        .unwrap()
+        .add_sample(ssid, ap_info.rssi, ap_info.primary);
+}
+
+/// Current link quality, sampled now, along with the duration of the last LLM request
+pub fn network_metrics() -> NetworkMetrics {
+    let connected = is_connected();
+    if connected {
+        sample_link();
+    }
+
+    let stats = LINK_STATS.lock().unwrap();
+    NetworkMetrics {
+        connected,
+        ssid: stats.ssid.clone(),
+        rssi: stats.rssi,
+        average_rssi: stats.average_rssi.map(|rssi| rssi.round() as i8),
+        channel: stats.channel,
+        disconnects: stats.disconnects,
+        last_llm_ms: llm_intf::last_round_trip_ms(),
+    }
+}
+
+/// Delay before the next reconnection attempt, from 2 seconds doubling up to a minute
+fn reconnect_backoff(failures: u32) -> Duration {
+    Duration::from_secs((2u64 << failures.min(5)).min(MAX_RECONNECT_BACKOFF_SECS))
+}
+
+/// Connected and with an address from DHCP
+pub fn has_ip(wifi: &EspWifi<'static>) -> bool {
+    matches!(wifi.is_connected(), Ok(true))
+        && wifi
+            .sta_netif()
+            .get_ip_info()
+            .is_ok_and(|info| !info.ip.is_unspecified())
+}
+
+/// SSIDs and signal strengths of the networks in range, the WiFi driver must be started
+pub fn scan(wifi: &mut EspWifi<'static>) -> Vec<(String, i8)> {
+    match wifi.scan() {
+        Ok(access_points) => access_points
+            .into_iter()
+            .map(|ap| (ap.ssid.to_string(), ap.signal_strength))
+            .filter(|(ssid, _)| !ssid.is_empty())
+            .collect(),
+        Err(e) => {
+            log::warn!("WiFi scan failed: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Join a network, retrying a few times until DHCP handed out an address
+fn connect(wifi: &mut EspWifi<'static>, ssid: &str, pass: &str) -> anyhow::Result<()> {
+    log::info!("Connecting to WiFi network: {}", ssid);
 
     let mut auth_method = AuthMethod::WPA2Personal;
     if pass.is_empty() {
@@ -43,6 +522,10 @@ pub fn initialize_wifi(modem: Modem) -> anyhow::Result<Box<EspWifi<'static>>> {
         .push_str(pass)
         .map_err(|_| anyhow::anyhow!("Password too long"))?;
 
+    // The setup portal leaves the access point running
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
     wifi.set_configuration(&Configuration::Client(client_config))?;
 
     wifi.start()?;
@@ -128,8 +611,7 @@ pub fn initialize_wifi(modem: Modem) -> anyhow::Result<Box<EspWifi<'static>>> {
             Ok(ip_info) => log::info!("IP info: {:?}", ip_info),
             Err(e) => log::warn!("Failed to get IP info: {}", e),
         }
-        // Return the wifi object in a Box to maintain ownership
-        Ok(Box::new(wifi))
+        Ok(())
     } else {
         let err_msg = format!(
             "Failed to connect to WiFi '{}' after {} attempts",
@@ -19,6 +19,13 @@ fi
 FILE_SIZE=$(stat -c%s "voice_data.dat")
 echo "Voice data file size: $FILE_SIZE bytes"
 
+# A truncated image would leave the TTS reading past the end of its data
+PARTITION_KB=$(awk -F, '$1 ~ /^voice_data *$/ { gsub(/[ K]/, "", $5); print $5 }' "$(dirname "$0")/partitions.csv")
+if [ -n "$PARTITION_KB" ] && [ "$FILE_SIZE" -gt $(( PARTITION_KB * 1024 )) ]; then
+    echo "Error: voice_data.dat does not fit the ${PARTITION_KB}K voice_data partition in partitions.csv"
+    exit 1
+fi
+
 # Flash the voice data to the partition
 python -m esptool --chip esp32s3 --port $DEVICE --baud 115200 write_flash 0x410000 voice_data.dat
 